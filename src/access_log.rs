@@ -0,0 +1,441 @@
+//! JSON access logs for proxied requests
+//!
+//! Entries are handed to a writer thread over a bounded channel, so a slow
+//! disk or syslog never holds up a request; if the writer falls behind,
+//! entries are dropped and counted.
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// Entries buffered for the writer before new ones are dropped
+const QUEUE_SIZE: usize = 8192;
+
+/// Replaces redacted path segments
+const REDACTED: &str = "[redacted]";
+
+/// Where access log lines go
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+    Stdout,
+    /// A file rotated to `path.1`, `path.2`, ... once it reaches `max_bytes`
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        max_files: usize,
+    },
+    /// A local syslog socket such as `/dev/log`
+    Syslog(PathBuf),
+}
+
+impl Sink {
+    /// Parse `stdout`, `file:PATH` or `syslog[:SOCKET]`. Files use the
+    /// default rotation, see `with_rotation`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            _ if s == "stdout" => Ok(Sink::Stdout),
+            _ if s == "syslog" => Ok(Sink::Syslog("/dev/log".into())),
+            Some(("file", path)) if !path.is_empty() => Ok(Sink::File {
+                path: path.into(),
+                max_bytes: 100 * 1024 * 1024,
+                max_files: 5,
+            }),
+            Some(("syslog", socket)) if !socket.is_empty() => Ok(Sink::Syslog(socket.into())),
+            _ => Err(format!(
+                "expected stdout, file:PATH or syslog[:SOCKET], got: {}",
+                s
+            )),
+        }
+    }
+
+    /// Set the rotation of a file sink; other sinks are unchanged
+    pub fn with_rotation(self, max_bytes: u64, max_files: usize) -> Self {
+        match self {
+            Sink::File { path, .. } => Sink::File {
+                path,
+                max_bytes,
+                max_files,
+            },
+            sink => sink,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub sink: Sink,
+    /// Fraction of requests logged, 0.0 to 1.0. 5xx responses are always logged.
+    pub sample_rate: f64,
+    /// Path patterns whose `*` segments (or, for a trailing `**`, the rest
+    /// of the path) are replaced before logging, e.g. `/invite/*`
+    pub redact: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: String,
+    pub request_id: String,
+    pub tenant: Option<String>,
+    pub server: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Until the upstream's response headers; absent if it wasn't reached
+    pub upstream_latency_ms: Option<f64>,
+    pub client_ip: Option<String>,
+}
+
+pub struct AccessLog {
+    sender: SyncSender<String>,
+    sample_rate: f64,
+    redact: Vec<Vec<String>>,
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    /// Open the sink and start the writer thread
+    pub fn start(config: AccessLogConfig) -> Result<Self> {
+        if !(0.0..=1.0).contains(&config.sample_rate) {
+            return Err(anyhow!(
+                "Sample rate must be between 0 and 1, got {}",
+                config.sample_rate
+            ));
+        }
+        let mut writer = Writer::open(config.sink)?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(Self {
+            sender,
+            sample_rate: config.sample_rate,
+            redact: config
+                .redact
+                .iter()
+                .map(|pattern| segments(pattern).map(String::from).collect())
+                .collect(),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether a request should be logged, decided when it arrives
+    pub fn sample(&self) -> bool {
+        self.sample_rate >= 1.0
+            || (uuid::Uuid::new_v4().as_u128() % 1_000_000) as f64 / 1_000_000.0 < self.sample_rate
+    }
+
+    /// Apply the redaction patterns to a path
+    pub fn redact(&self, path: &str) -> String {
+        let mut parts: Vec<&str> = segments(path).collect();
+        for pattern in &self.redact {
+            redact_with(pattern, &mut parts);
+        }
+        format!("/{}", parts.join("/"))
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("Failed to encode access log entry: {}", e);
+                return;
+            }
+        };
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    tracing::warn!("Access log writer is behind; dropped {} entries", dropped);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                tracing::error!("Access log writer has stopped");
+            }
+        }
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+fn redact_with(pattern: &[String], parts: &mut Vec<&str>) {
+    let rest = pattern.last().is_some_and(|p| p == "**");
+    let fixed = if rest {
+        &pattern[..pattern.len() - 1]
+    } else {
+        pattern
+    };
+    if parts.len() < fixed.len()
+        || !fixed.iter().zip(parts.iter()).all(|(p, part)| p == "*" || p == part)
+    {
+        return;
+    }
+
+    for (i, p) in fixed.iter().enumerate() {
+        if p == "*" {
+            parts[i] = REDACTED;
+        }
+    }
+    if rest && parts.len() > fixed.len() {
+        parts.truncate(fixed.len());
+        parts.push(REDACTED);
+    }
+}
+
+enum Writer {
+    Stdout,
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_bytes: u64,
+        max_files: usize,
+    },
+    Syslog {
+        socket: PathBuf,
+        conn: Option<UnixDatagram>,
+    },
+}
+
+impl Writer {
+    fn open(sink: Sink) -> Result<Self> {
+        Ok(match sink {
+            Sink::Stdout => Writer::Stdout,
+            Sink::File {
+                path,
+                max_bytes,
+                max_files,
+            } => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                let size = file.metadata()?.len();
+                Writer::File {
+                    path,
+                    file,
+                    size,
+                    max_bytes,
+                    max_files,
+                }
+            }
+            Sink::Syslog(socket) => {
+                let conn = connect_syslog(&socket)?;
+                Writer::Syslog {
+                    socket,
+                    conn: Some(conn),
+                }
+            }
+        })
+    }
+
+    fn run(&mut self, receiver: Receiver<String>) {
+        for line in receiver {
+            if let Err(e) = self.write(&line) {
+                tracing::error!("Failed to write access log: {}", e);
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) -> Result<()> {
+        match self {
+            Writer::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                writeln!(stdout, "{}", line)?;
+            }
+            Writer::File {
+                path,
+                file,
+                size,
+                max_bytes,
+                max_files,
+            } => {
+                let len = line.len() as u64 + 1;
+                if *size > 0 && *size + len > *max_bytes {
+                    rotate(path, *max_files)?;
+                    *file = OpenOptions::new().create(true).append(true).open(&*path)?;
+                    *size = 0;
+                }
+                writeln!(file, "{}", line)?;
+                *size += len;
+            }
+            Writer::Syslog { socket, conn } => {
+                // local0.info
+                let message = format!("<134>slum: {}", line);
+                if let Some(c) = conn {
+                    if c.send(message.as_bytes()).is_ok() {
+                        return Ok(());
+                    }
+                }
+                // syslogd may have restarted; reconnect once
+                *conn = None;
+                let c = connect_syslog(socket)?;
+                c.send(message.as_bytes())?;
+                *conn = Some(c);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn connect_syslog(socket: &PathBuf) -> Result<UnixDatagram> {
+    let conn = UnixDatagram::unbound()?;
+    conn.connect(socket)
+        .map_err(|e| anyhow!("Failed to connect to syslog socket {}: {}", socket.display(), e))?;
+    Ok(conn)
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2`, ..., keeping `max_files`
+/// rotated files
+fn rotate(path: &PathBuf, max_files: usize) -> Result<()> {
+    let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+    if max_files == 0 {
+        fs::remove_file(path)?;
+        return Ok(());
+    }
+    let _ = fs::remove_file(rotated(max_files));
+    for n in (1..max_files).rev() {
+        let from = rotated(n);
+        if from.exists() {
+            fs::rename(&from, rotated(n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(1))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(path: &str) -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp: "2026-01-01T00:00:00.000Z".into(),
+            request_id: "req-1".into(),
+            tenant: Some("romneys".into()),
+            server: Some("server-1".into()),
+            method: "GET".into(),
+            path: path.into(),
+            status: 200,
+            bytes_in: 0,
+            bytes_out: 12,
+            upstream_latency_ms: Some(3.5),
+            client_ip: Some("127.0.0.1".into()),
+        }
+    }
+
+    fn config(sink: Sink) -> AccessLogConfig {
+        AccessLogConfig {
+            sink,
+            sample_rate: 1.0,
+            redact: vec!["/invite/*".into(), "/files/*/raw/**".into()],
+        }
+    }
+
+    /// Wait for the writer thread to catch up
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out waiting for access log");
+    }
+
+    #[test]
+    fn test_parse_sink() {
+        assert_eq!(Sink::parse("stdout").unwrap(), Sink::Stdout);
+        assert_eq!(
+            Sink::parse("syslog").unwrap(),
+            Sink::Syslog("/dev/log".into())
+        );
+        assert_eq!(
+            Sink::parse("syslog:/run/log.sock").unwrap(),
+            Sink::Syslog("/run/log.sock".into())
+        );
+        assert!(matches!(Sink::parse("file:/var/log/slum.log"), Ok(Sink::File { .. })));
+        assert!(Sink::parse("file:").is_err());
+        assert!(Sink::parse("kafka").is_err());
+    }
+
+    #[test]
+    fn test_redact() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AccessLog::start(config(Sink::parse(&format!(
+            "file:{}",
+            dir.path().join("access.log").display()
+        ))
+        .unwrap()))
+        .unwrap();
+
+        assert_eq!(log.redact("/invite/abc123"), "/invite/[redacted]");
+        assert_eq!(
+            log.redact("/invite/abc123/accept"),
+            "/invite/[redacted]/accept"
+        );
+        assert_eq!(
+            log.redact("/files/42/raw/a/b.txt"),
+            "/files/[redacted]/raw/[redacted]"
+        );
+        assert_eq!(log.redact("/files/42"), "/files/42");
+        assert_eq!(log.redact("/events"), "/events");
+        assert_eq!(log.redact("/"), "/");
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let line_len = serde_json::to_string(&entry("/a")).unwrap().len() as u64 + 1;
+        let sink = Sink::parse(&format!("file:{}", path.display()))
+            .unwrap()
+            .with_rotation(line_len * 2, 2);
+        let log = AccessLog::start(config(sink)).unwrap();
+
+        for _ in 0..7 {
+            log.log(&entry("/a"));
+        }
+        let rotated = |n| PathBuf::from(format!("{}.{}", path.display(), n));
+        wait_for(|| {
+            fs::read_to_string(&path)
+                .map(|s| s.lines().count() == 1)
+                .unwrap_or(false)
+                && rotated(2).exists()
+        });
+        assert_eq!(fs::read_to_string(rotated(1)).unwrap().lines().count(), 2);
+        assert!(!rotated(3).exists());
+
+        let line: serde_json::Value =
+            serde_json::from_str(fs::read_to_string(&path).unwrap().trim()).unwrap();
+        assert_eq!(line["tenant"], "romneys");
+        assert_eq!(line["status"], 200);
+    }
+
+    #[test]
+    fn test_syslog() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("log.sock");
+        let server = UnixDatagram::bind(&socket).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        let log = AccessLog::start(config(Sink::Syslog(socket))).unwrap();
+        log.log(&entry("/events"));
+
+        let mut buf = [0u8; 4096];
+        let n = server.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(message.starts_with("<134>slum: {"));
+        assert!(message.contains(r#""path":"/events""#));
+    }
+}
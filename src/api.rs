@@ -1,16 +1,19 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     Json,
 };
 use serde::Deserialize;
 
+use crate::db::{AccessPolicy, Limits, LoadBalance, Protocol, RouteRule, Tenant};
+use crate::mirror;
+use crate::usage::parse_time;
 use crate::AppState;
 
-// Health check
-pub async fn health() -> impl IntoResponse {
-    Json(serde_json::json!({ "status": "ok" }))
+// Health check, with servers whose circuits have seen failures
+pub async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok", "servers": state.circuits.health() }))
 }
 
 // Server endpoints
@@ -19,13 +22,24 @@ pub async fn health() -> impl IntoResponse {
 pub struct AddServerRequest {
     pub name: String,
     pub address: String,
+    #[serde(default)]
+    pub protocol: Protocol,
 }
 
 pub async fn add_server(
     State(state): State<AppState>,
     Json(req): Json<AddServerRequest>,
 ) -> impl IntoResponse {
-    match state.db.add_server(&req.name, &req.address).await {
+    let result = state
+        .db
+        .transaction(move |tx| {
+            Box::pin(async move {
+                let server = tx.add_server(&req.name, &req.address).await?;
+                tx.set_server_protocol(&server.id, req.protocol).await
+            })
+        })
+        .await;
+    match result {
         Ok(server) => (StatusCode::CREATED, Json(server)).into_response(),
         Err(e) => (
             StatusCode::BAD_REQUEST,
@@ -51,7 +65,10 @@ pub async fn remove_server(
     Path(id): Path<String>,
 ) -> impl IntoResponse {
     match state.db.remove_server(&id).await {
-        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Ok(()) => {
+            state.routes.clear();
+            StatusCode::NO_CONTENT.into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({ "error": e.to_string() })),
@@ -66,7 +83,17 @@ pub async fn remove_server(
 pub struct AddTenantRequest {
     pub id: String,
     pub server: Option<String>,
-    pub config: Option<String>,
+    /// A JSON object, or a string holding one
+    pub config: Option<serde_json::Value>,
+}
+
+/// Config text for the registry. Strings are taken to hold the JSON, as
+/// clients sent configs before they were objects.
+fn config_text(config: &serde_json::Value) -> String {
+    match config {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
 }
 
 pub async fn add_tenant(
@@ -75,10 +102,20 @@ pub async fn add_tenant(
 ) -> impl IntoResponse {
     match state
         .db
-        .add_tenant(&req.id, req.server.as_deref(), req.config.as_deref())
+        .add_tenant(&req.id, req.server.as_deref(), req.config.as_ref().map(config_text).as_deref())
         .await
     {
-        Ok(tenant) => (StatusCode::CREATED, Json(tenant)).into_response(),
+        Ok(tenant) => {
+            // Failures are recorded on the tenant and retried in the background
+            if let Err(e) = state.provisioner.sync_tenant(&tenant.id).await {
+                tracing::error!("Failed to provision tenant {}: {}", tenant.id, e);
+            }
+            let tenant = match state.db.get_tenant(&tenant.id).await {
+                Ok(Some(current)) => current,
+                _ => tenant,
+            };
+            (StatusCode::CREATED, Json(tenant)).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({ "error": e.to_string() })),
@@ -103,7 +140,306 @@ pub async fn remove_tenant(
     Path(id): Path<String>,
 ) -> impl IntoResponse {
     match state.db.remove_tenant(&id).await {
-        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Ok(()) => {
+            state.routes.clear();
+            if let Err(e) = state.provisioner.sync_tenant(&id).await {
+                tracing::error!("Failed to deprovision tenant {}: {}", id, e);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn set_tenant_limits(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(limits): Json<Limits>,
+) -> impl IntoResponse {
+    match state.db.set_tenant_limits(&id, &limits).await {
+        Ok(()) => {
+            state.routes.clear();
+            match state.db.get_tenant(&id).await {
+                Ok(Some(tenant)) => Json(tenant).into_response(),
+                _ => StatusCode::NO_CONTENT.into_response(),
+            }
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ScaleRequest {
+    pub replicas: u32,
+    pub balance: Option<LoadBalance>,
+}
+
+pub async fn scale_tenant(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<ScaleRequest>,
+) -> impl IntoResponse {
+    let result = state
+        .db
+        .scale_tenant(&id, req.replicas, req.balance.as_ref())
+        .await;
+    placed(&state, &id, result).await
+}
+
+#[derive(Deserialize)]
+pub struct CanaryRequest {
+    pub server: String,
+    pub weight: u8,
+}
+
+pub async fn set_canary(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<CanaryRequest>,
+) -> impl IntoResponse {
+    let result = state.db.set_canary(&id, &req.server, req.weight).await;
+    placed(&state, &id, result).await
+}
+
+pub async fn remove_canary(
+    State(state): State<AppState>,
+    Path((id, server)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let result = state.db.remove_canary(&id, &server).await;
+    placed(&state, &id, result).await
+}
+
+#[derive(Deserialize)]
+pub struct PromoteRequest {
+    pub server: String,
+}
+
+pub async fn promote_canary(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<PromoteRequest>,
+) -> impl IntoResponse {
+    let result = state.db.promote_canary(&id, &req.server).await;
+    placed(&state, &id, result).await
+}
+
+#[derive(Deserialize)]
+pub struct MirrorRequest {
+    pub server: String,
+    #[serde(default = "default_mirror_percent")]
+    pub percent: u8,
+    #[serde(default = "default_mirror_max_body_size")]
+    pub max_body_size: u64,
+}
+
+fn default_mirror_percent() -> u8 {
+    100
+}
+
+fn default_mirror_max_body_size() -> u64 {
+    mirror::DEFAULT_MAX_BODY_SIZE
+}
+
+pub async fn set_tenant_mirror(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<MirrorRequest>,
+) -> impl IntoResponse {
+    match state
+        .db
+        .set_tenant_mirror(&id, &req.server, req.percent, req.max_body_size)
+        .await
+    {
+        Ok(tenant) => {
+            state.routes.clear();
+            state.mirrors.reset(&id);
+            // Failures are retried in the background
+            if let Err(e) = state.provisioner.sync_tenant(&id).await {
+                tracing::error!("Failed to provision tenant {}: {}", id, e);
+            }
+            let tenant = match state.db.get_tenant(&id).await {
+                Ok(Some(current)) => current,
+                _ => tenant,
+            };
+            Json(tenant).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn remove_tenant_mirror(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.db.clear_tenant_mirror(&id).await {
+        Ok(()) => {
+            state.routes.clear();
+            if let Err(e) = state.provisioner.sync_tenant(&id).await {
+                tracing::error!("Failed to deprovision tenant {}: {}", id, e);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// A tenant's mirror and how its responses compared, since this slum
+/// started or the mirror was last set
+pub async fn tenant_mirror(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.db.get_tenant(&id).await {
+        Ok(Some(tenant)) => Json(serde_json::json!({
+            "tenant_id": id,
+            "mirror": tenant.mirror,
+            "summary": state.mirrors.summary(&id),
+        }))
+        .into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": format!("Tenant not found: {}", id) })),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// A tenant's config, or `null`
+pub async fn tenant_config(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.db.get_tenant(&id).await {
+        Ok(Some(tenant)) => Json(tenant.config_json()).into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": format!("Tenant not found: {}", id) })),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Replace a tenant's config and provision it
+pub async fn set_tenant_config(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(config): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    if let Some(response) = missing_tenant(&state, &id).await {
+        return response;
+    }
+    let result = state.db.set_tenant_config(&id, Some(&config.to_string())).await;
+    config_changed(&state, &id, result).await
+}
+
+/// Change a tenant's config with a JSON Merge Patch (RFC 7396) and
+/// provision it
+pub async fn patch_tenant_config(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(patch): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    if let Some(response) = missing_tenant(&state, &id).await {
+        return response;
+    }
+    let result = state.db.patch_tenant_config(&id, &patch).await;
+    config_changed(&state, &id, result).await
+}
+
+/// A 404 if there's no tenant `id`
+async fn missing_tenant(state: &AppState, id: &str) -> Option<Response> {
+    match state.db.get_tenant(id).await {
+        Ok(Some(_)) => None,
+        Ok(None) => Some(
+            (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({ "error": format!("Tenant not found: {}", id) })),
+            )
+                .into_response(),
+        ),
+        Err(e) => Some(
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": e.to_string() })),
+            )
+                .into_response(),
+        ),
+    }
+}
+
+async fn config_changed(
+    state: &AppState,
+    id: &str,
+    result: anyhow::Result<Option<serde_json::Value>>,
+) -> Response {
+    match result {
+        Ok(config) => {
+            // Failures are recorded on the tenant and retried in the background
+            if let Err(e) = state.provisioner.sync_tenant(id).await {
+                tracing::error!("Failed to provision tenant {}: {}", id, e);
+            }
+            Json(config).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// The JSON Schema tenant configs must match, or `null`
+pub async fn config_schema(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db.config_schema().await {
+        Ok(schema) => Json(schema.map(|s| s.as_json().clone())).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Register the JSON Schema tenant configs must match. Existing configs are
+/// kept; those that don't match are listed.
+pub async fn set_config_schema(
+    State(state): State<AppState>,
+    Json(schema): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    match state.db.set_config_schema(Some(schema)).await {
+        Ok(invalid) => {
+            let invalid: Vec<_> = invalid
+                .into_iter()
+                .map(|(tenant_id, error)| serde_json::json!({ "tenant_id": tenant_id, "error": error }))
+                .collect();
+            Json(serde_json::json!({ "invalid_tenants": invalid })).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({ "error": e.to_string() })),
@@ -111,3 +447,313 @@ pub async fn remove_tenant(
             .into_response(),
     }
 }
+
+pub async fn remove_config_schema(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db.set_config_schema(None).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// A tenant's access policy. Users are listed with their password hashes.
+pub async fn tenant_access(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.db.get_tenant(&id).await {
+        Ok(Some(tenant)) => Json(tenant.access).into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": format!("Tenant not found: {}", id) })),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Replace a tenant's access policy. Users may be given a `password`,
+/// which is hashed, or an argon2 `password_hash`.
+pub async fn set_tenant_access(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(policy): Json<AccessPolicy>,
+) -> impl IntoResponse {
+    match state.db.set_tenant_access(&id, policy).await {
+        Ok(policy) => {
+            state.routes.clear();
+            Json(policy).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Open a tenant to everyone
+pub async fn remove_tenant_access(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.db.set_tenant_access(&id, AccessPolicy::default()).await {
+        Ok(_) => {
+            state.routes.clear();
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EnableRequest {
+    pub enabled: bool,
+}
+
+/// Turn the HTTP cache on or off for a tenant. Turning it off drops the
+/// tenant's cached responses.
+pub async fn set_tenant_cache(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<EnableRequest>,
+) -> impl IntoResponse {
+    let result = state.db.set_tenant_cache(&id, req.enabled).await;
+    if result.is_ok() && !req.enabled {
+        state.cache.purge(&id);
+    }
+    http_options_set(&state, &id, result).await
+}
+
+pub async fn set_tenant_compress(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<EnableRequest>,
+) -> impl IntoResponse {
+    let result = state.db.set_tenant_compress(&id, req.enabled).await;
+    http_options_set(&state, &id, result).await
+}
+
+async fn http_options_set(state: &AppState, id: &str, result: anyhow::Result<()>) -> Response {
+    match result {
+        Ok(()) => {
+            state.routes.clear();
+            match state.db.get_tenant(id).await {
+                Ok(Some(tenant)) => Json(tenant).into_response(),
+                _ => StatusCode::NO_CONTENT.into_response(),
+            }
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Drop a tenant's cached responses
+pub async fn purge_tenant_cache(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.db.get_tenant(&id).await {
+        Ok(Some(_)) => Json(serde_json::json!({
+            "tenant_id": id,
+            "purged": state.cache.purge(&id),
+        }))
+        .into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": format!("Tenant not found: {}", id) })),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+// Route rules
+
+pub async fn fleet_route_rules(State(state): State<AppState>) -> impl IntoResponse {
+    route_rules(&state, None).await
+}
+
+pub async fn set_fleet_route_rules(
+    State(state): State<AppState>,
+    Json(rules): Json<Vec<RouteRule>>,
+) -> impl IntoResponse {
+    set_route_rules(&state, None, rules).await
+}
+
+pub async fn tenant_route_rules(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    route_rules(&state, Some(&id)).await
+}
+
+pub async fn set_tenant_route_rules(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(rules): Json<Vec<RouteRule>>,
+) -> impl IntoResponse {
+    set_route_rules(&state, Some(&id), rules).await
+}
+
+async fn route_rules(state: &AppState, tenant_id: Option<&str>) -> Response {
+    if let Some(response) = missing_route_tenant(state, tenant_id).await {
+        return response;
+    }
+    match state.db.route_rules(tenant_id).await {
+        Ok(rules) => Json(rules).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+async fn set_route_rules(state: &AppState, tenant_id: Option<&str>, rules: Vec<RouteRule>) -> Response {
+    if let Some(response) = missing_route_tenant(state, tenant_id).await {
+        return response;
+    }
+    match state.db.set_route_rules(tenant_id, &rules).await {
+        Ok(()) => {
+            state.routes.clear();
+            Json(rules).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// `missing_tenant` for a tenant's rules; the fleet's always exist
+async fn missing_route_tenant(state: &AppState, tenant_id: Option<&str>) -> Option<Response> {
+    match tenant_id {
+        Some(id) => missing_tenant(state, id).await,
+        None => None,
+    }
+}
+
+/// Respond to a change in where a tenant runs, provisioning it first
+async fn placed(state: &AppState, id: &str, result: anyhow::Result<Tenant>) -> Response {
+    match result {
+        Ok(tenant) => {
+            state.routes.clear();
+            // Failures are recorded on the tenant and retried in the background
+            if let Err(e) = state.provisioner.sync_tenant(id).await {
+                tracing::error!("Failed to provision tenant {}: {}", id, e);
+            }
+            let tenant = match state.db.get_tenant(id).await {
+                Ok(Some(current)) => current,
+                _ => tenant,
+            };
+            Json(tenant).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    /// Unix seconds or RFC 3339; defaults to 24 hours before `to`
+    pub from: Option<String>,
+    /// Unix seconds or RFC 3339; defaults to now
+    pub to: Option<String>,
+    /// Interval length in seconds
+    pub step: Option<i64>,
+}
+
+impl UsageQuery {
+    fn range(&self) -> anyhow::Result<(i64, i64)> {
+        let to = match &self.to {
+            Some(to) => parse_time(to)?,
+            None => chrono::Utc::now().timestamp(),
+        };
+        let from = match &self.from {
+            Some(from) => parse_time(from)?,
+            None => to - 86400,
+        };
+        Ok((from, to))
+    }
+}
+
+pub async fn tenant_usage(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<UsageQuery>,
+) -> impl IntoResponse {
+    let (from, to) = match query.range() {
+        Ok(range) => range,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": e.to_string() })),
+            )
+                .into_response()
+        }
+    };
+    let step = query.step.unwrap_or(3600);
+
+    match state.db.tenant_usage(&id, from, to, step).await {
+        Ok(usage) => Json(serde_json::json!({
+            "tenant_id": id,
+            "from": from,
+            "to": to,
+            "step": step,
+            "usage": usage,
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+// Reconciliation
+
+pub async fn check_drift(State(state): State<AppState>) -> impl IntoResponse {
+    match state.reconciler.check().await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn repair_drift(State(state): State<AppState>) -> impl IntoResponse {
+    match state.reconciler.repair().await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
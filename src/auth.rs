@@ -0,0 +1,392 @@
+//! Edge authentication
+//!
+//! A tenant's access policy can keep out client networks, ask for HTTP
+//! basic auth, or have an external service approve each request, so that
+//! staging apps don't each need their own. The proxy checks the policy
+//! before anything is served from the cache or forwarded.
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_rustls::rustls::{self, ClientConfig};
+
+use crate::db::{AccessPolicy, BasicUser};
+use crate::server::Tls;
+
+/// How long a forward auth service has to answer
+const FORWARD_AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Verified credentials remembered before the set is cleared
+const MAX_VERIFIED: usize = 1024;
+
+pub struct EdgeAuth {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    /// Digests of (password hash, password) pairs that have verified. Argon2
+    /// is slow by design, too slow to run on every request of a page load.
+    verified: Mutex<HashSet<[u8; 32]>>,
+    /// Mixed into the digests, so they can't be checked against guesses
+    /// without it
+    secret: [u8; 16],
+}
+
+impl Default for EdgeAuth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EdgeAuth {
+    pub fn new() -> Self {
+        Self::with_tls(
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default protocol versions")
+                .with_webpki_roots()
+                .with_no_client_auth(),
+        )
+    }
+
+    /// Forward auth services at `https://` URLs are verified with `tls`
+    fn with_tls(tls: ClientConfig) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            verified: Mutex::new(HashSet::new()),
+            secret: *uuid::Uuid::new_v4().as_bytes(),
+        }
+    }
+
+    /// Check a request to `tenant` against its policy, returning the
+    /// response that refuses it, if any. Basic auth credentials are removed
+    /// from a request that is let in, since they are slum's, not the
+    /// tenant's.
+    pub async fn check(
+        &self,
+        tenant: &str,
+        policy: &AccessPolicy,
+        client: Option<IpAddr>,
+        host: &str,
+        req: &mut Request<Body>,
+    ) -> Option<Response> {
+        if policy.unreadable {
+            tracing::error!("Tenant {}'s access policy is unreadable; refusing its requests", tenant);
+            return Some((StatusCode::INTERNAL_SERVER_ERROR, "Access policy unreadable").into_response());
+        }
+        if !policy.admits(client) {
+            return Some((StatusCode::FORBIDDEN, "Forbidden").into_response());
+        }
+        if !policy.users.is_empty() && !self.authenticate(&policy.users, req.headers()).await {
+            let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", tenant);
+            return Some(
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, challenge)],
+                    "Unauthorized",
+                )
+                    .into_response(),
+            );
+        }
+        if let Some(url) = &policy.forward_auth {
+            let auth_req = forward_auth_request(client, host, req);
+            if let Some(response) = self.forward_auth(url, auth_req).await {
+                return Some(response);
+            }
+        }
+        if !policy.users.is_empty() {
+            req.headers_mut().remove(header::AUTHORIZATION);
+        }
+        None
+    }
+
+    /// Whether the request's basic auth credentials are one of `users`'
+    async fn authenticate(&self, users: &[BasicUser], headers: &HeaderMap) -> bool {
+        let Some((username, password)) = basic_credentials(headers) else {
+            return false;
+        };
+        let Some(user) = users.iter().find(|u| u.username == username) else {
+            return false;
+        };
+        let key = self.digest(&user.password_hash, &password);
+        if self.verified.lock().unwrap().contains(&key) {
+            return true;
+        }
+
+        let user = user.clone();
+        let verified = tokio::task::spawn_blocking(move || user.verify(&password))
+            .await
+            .unwrap_or(false);
+        if verified {
+            let mut cache = self.verified.lock().unwrap();
+            if cache.len() >= MAX_VERIFIED {
+                cache.clear();
+            }
+            cache.insert(key);
+        }
+        verified
+    }
+
+    /// What `verified` holds for a password, rather than the password itself
+    fn digest(&self, password_hash: &str, password: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.secret);
+        hasher.update((password_hash.len() as u64).to_le_bytes());
+        hasher.update(password_hash);
+        hasher.update(password);
+        hasher.finalize().into()
+    }
+
+    /// Ask the forward auth service at `url` about `auth_req`, returning the
+    /// service's answer unless it is a 2xx
+    async fn forward_auth(&self, url: &str, mut auth_req: Request<Body>) -> Option<Response> {
+        let Ok(uri) = url.parse::<Uri>() else {
+            return Some((StatusCode::BAD_GATEWAY, "Invalid forward auth URL").into_response());
+        };
+        *auth_req.uri_mut() = uri;
+
+        match tokio::time::timeout(FORWARD_AUTH_TIMEOUT, self.client.request(auth_req)).await {
+            Ok(Ok(response)) if response.status().is_success() => None,
+            Ok(Ok(response)) => {
+                let (parts, body) = response.into_parts();
+                Some(Response::from_parts(parts, Body::new(body)))
+            }
+            Ok(Err(e)) => {
+                tracing::error!("Forward auth request to {} failed: {}", url, e);
+                Some((StatusCode::BAD_GATEWAY, "Forward auth failed").into_response())
+            }
+            Err(_) => {
+                tracing::error!("Forward auth request to {} timed out", url);
+                Some((StatusCode::GATEWAY_TIMEOUT, "Forward auth timed out").into_response())
+            }
+        }
+    }
+}
+
+/// A request for the forward auth service about `req`, with its headers and
+/// where it was going in `X-Forwarded-*`
+fn forward_auth_request(client: Option<IpAddr>, host: &str, req: &Request<Body>) -> Request<Body> {
+    let mut headers = req.headers().clone();
+    for name in [header::HOST, header::CONTENT_LENGTH, header::TRANSFER_ENCODING, header::CONNECTION] {
+        headers.remove(name);
+    }
+    // Set below only when the client's address is known; never the client's own
+    headers.remove("x-forwarded-for");
+    let proto = if req.extensions().get::<Tls>().is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let forwarded = [
+        ("x-forwarded-method", req.method().as_str().to_string()),
+        ("x-forwarded-proto", proto.to_string()),
+        ("x-forwarded-host", host.to_string()),
+        (
+            "x-forwarded-uri",
+            req.uri().path_and_query().map_or("/", |p| p.as_str()).to_string(),
+        ),
+    ];
+    for (name, value) in forwarded.into_iter().chain(client.map(|ip| ("x-forwarded-for", ip.to_string()))) {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+
+    let mut auth_req = Request::new(Body::empty());
+    *auth_req.headers_mut() = headers;
+    auth_req
+}
+
+/// The username and password from an `Authorization: Basic` header
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::Router;
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::builder().uri("/reports?q=1");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+    }
+
+    async fn check(auth: &EdgeAuth, policy: &AccessPolicy, ip: &str, req: &mut Request<Body>) -> Option<StatusCode> {
+        let ip = ip.parse().ok();
+        auth.check("staging", policy, ip, "staging.ourfam.lol", req)
+            .await
+            .map(|response| response.status())
+    }
+
+    #[tokio::test]
+    async fn test_networks() {
+        let auth = EdgeAuth::new();
+        let policy = AccessPolicy {
+            allow: vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()],
+            deny: vec!["10.6.6.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(check(&auth, &policy, "10.1.2.3", &mut request(&[])).await, None);
+        assert_eq!(check(&auth, &policy, "::ffff:10.1.2.3", &mut request(&[])).await, None);
+        assert_eq!(check(&auth, &policy, "2001:db8::1", &mut request(&[])).await, None);
+        assert_eq!(check(&auth, &policy, "10.6.6.6", &mut request(&[])).await, Some(StatusCode::FORBIDDEN));
+        assert_eq!(check(&auth, &policy, "192.168.1.1", &mut request(&[])).await, Some(StatusCode::FORBIDDEN));
+        assert_eq!(check(&auth, &policy, "", &mut request(&[])).await, Some(StatusCode::FORBIDDEN));
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let auth = EdgeAuth::new();
+        let policy = AccessPolicy {
+            users: vec![BasicUser::new("qa", "hunter2").unwrap()],
+            ..Default::default()
+        }
+        .prepare()
+        .unwrap();
+        assert!(policy.users[0].password_hash.starts_with("$argon2id$"));
+
+        let response = auth
+            .check("staging", &policy, None, "staging.ourfam.lol", &mut request(&[]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            "Basic realm=\"staging\", charset=\"UTF-8\""
+        );
+
+        for wrong in ["qa:hunter3", "dev:hunter2", "qa"] {
+            let mut req = request(&[("authorization", &basic(wrong))]);
+            assert_eq!(check(&auth, &policy, "", &mut req).await, Some(StatusCode::UNAUTHORIZED));
+        }
+        for _ in 0..2 {
+            let mut req = request(&[("authorization", &basic("qa:hunter2"))]);
+            assert_eq!(check(&auth, &policy, "", &mut req).await, None);
+            assert!(req.headers().get(header::AUTHORIZATION).is_none());
+        }
+        // Verified once, and remembered only as a digest
+        let verified = auth.verified.lock().unwrap();
+        assert_eq!(verified.len(), 1);
+        assert!(verified.contains(&auth.digest(&policy.users[0].password_hash, "hunter2")));
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth() {
+        let service = Router::new().route(
+            "/verify",
+            get(|headers: HeaderMap| async move {
+                let forwarded = |name: &str| headers.get(name).map(|v| v.to_str().unwrap().to_string());
+                assert_eq!(forwarded("x-forwarded-uri").as_deref(), Some("/reports?q=1"));
+                assert_eq!(forwarded("x-forwarded-host").as_deref(), Some("staging.ourfam.lol"));
+                assert_eq!(forwarded("x-forwarded-for").as_deref(), Some("10.1.2.3"));
+                match forwarded("cookie").as_deref() {
+                    Some("session=ok") => StatusCode::NO_CONTENT.into_response(),
+                    _ => (StatusCode::FOUND, [(header::LOCATION, "https://sso.ourfam.lol/login")]).into_response(),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+
+        let auth = EdgeAuth::new();
+        let policy = AccessPolicy {
+            forward_auth: Some(format!("http://{}/verify", addr)),
+            ..Default::default()
+        };
+        let mut req = request(&[("cookie", "session=ok")]);
+        assert_eq!(check(&auth, &policy, "10.1.2.3", &mut req).await, None);
+
+        let response = auth
+            .check("staging", &policy, "10.1.2.3".parse().ok(), "staging.ourfam.lol", &mut request(&[]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::LOCATION], "https://sso.ourfam.lol/login");
+
+        let policy = AccessPolicy {
+            forward_auth: Some("http://127.0.0.1:1/verify".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&auth, &policy, "10.1.2.3", &mut request(&[])).await,
+            Some(StatusCode::BAD_GATEWAY)
+        );
+
+        // A client can't pass itself off as another address
+        let auth_req = forward_auth_request(None, "staging.ourfam.lol", &request(&[("x-forwarded-for", "10.1.2.3")]));
+        assert!(auth_req.headers().get("x-forwarded-for").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth_over_https() {
+        use crate::server::{serve, tls_acceptor};
+        use tokio_rustls::rustls::RootCertStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        let service = Router::new().route("/verify", get(|| async { StatusCode::NO_CONTENT }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, service, None, Some(tls_acceptor(&cert_path, &key_path).unwrap())));
+
+        let policy = AccessPolicy {
+            forward_auth: Some(format!("https://localhost:{}/verify", port)),
+            ..Default::default()
+        }
+        .prepare()
+        .unwrap();
+
+        // An untrusted certificate fails like an unreachable service
+        let auth = EdgeAuth::new();
+        assert_eq!(
+            check(&auth, &policy, "10.1.2.3", &mut request(&[])).await,
+            Some(StatusCode::BAD_GATEWAY)
+        );
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let auth = EdgeAuth::with_tls(
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        assert_eq!(check(&auth, &policy, "10.1.2.3", &mut request(&[])).await, None);
+    }
+}
@@ -0,0 +1,324 @@
+//! Load balancing over a tenant's replicas
+//!
+//! The proxy first splits a tenant's traffic between its canaries and its
+//! stable servers, then picks one server from that group, skipping servers
+//! whose circuit is open. Hashing uses rendezvous hashing, so adding or
+//! removing a replica only moves the clients that hashed to it.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::db::{LoadBalance, Server, Tenant};
+
+/// Lets testers choose canary traffic: `1` for any canary, a server name or
+/// ID for that canary, or `0` to stay on the stable servers
+pub const CANARY_HEADER: &str = "x-slum-canary";
+
+/// Cookie with the same meaning as `CANARY_HEADER`
+pub const CANARY_COOKIE: &str = "slum-canary";
+
+#[derive(Default)]
+pub struct LoadBalancer {
+    /// Round-robin position per tenant
+    next: Mutex<HashMap<String, usize>>,
+    /// Requests in progress per server
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// A request counted against its server for least-connections until dropped
+pub struct Connection {
+    server_id: String,
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.server_id) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.server_id);
+            }
+        }
+    }
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is the same in every build, so
+/// several slum instances agree on where a client goes
+fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for &b in part.as_bytes().iter().chain(b"\0") {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// The value of cookie `name` in a `Cookie` header
+pub fn cookie<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split(';').find_map(|pair| {
+        let (k, v) = pair.trim().split_once('=')?;
+        (k == name).then_some(v)
+    })
+}
+
+/// The servers a request may go to: the canaries it was assigned to or
+/// chose, or otherwise the stable servers. `choice` is the request's
+/// `CANARY_HEADER` or `CANARY_COOKIE`. Without a choice, the client's `key`
+/// decides, so each client stays on the same side of the split.
+pub fn split<'a>(
+    tenant: &Tenant,
+    servers: &'a [Server],
+    choice: Option<&str>,
+    key: Option<&str>,
+) -> Vec<&'a Server> {
+    let weight = |s: &Server| {
+        tenant
+            .replicas
+            .iter()
+            .find(|r| r.server_id == s.id)
+            .and_then(|r| r.weight)
+    };
+    let (canaries, stable): (Vec<&Server>, Vec<&Server>) =
+        servers.iter().partition(|s| weight(s).is_some());
+    if canaries.is_empty() {
+        return stable;
+    }
+
+    match choice {
+        Some("0" | "false" | "off") => return stable,
+        Some("1" | "true" | "on") => return canaries,
+        Some(name) => {
+            let chosen: Vec<_> = canaries
+                .iter()
+                .copied()
+                .filter(|s| s.name == name || s.id == name)
+                .collect();
+            if !chosen.is_empty() {
+                return chosen;
+            }
+        }
+        None => {}
+    }
+
+    // Each client lands in one of 100 buckets; canaries take the first ones
+    let bucket = match key {
+        Some(key) => fnv1a(&[key, &tenant.id]) % 100,
+        None => (uuid::Uuid::new_v4().as_u128() % 100) as u64,
+    };
+    let mut threshold = 0;
+    for canary in &canaries {
+        threshold += weight(canary).unwrap_or(0) as u64;
+        if bucket < threshold {
+            return vec![canary];
+        }
+    }
+    stable
+}
+
+impl LoadBalancer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pick one of `servers` for a request to `tenant`. `key` is what hashing
+    /// strategies hash: the client IP or session cookie. Servers that aren't
+    /// `available` are skipped unless none are.
+    pub fn pick<'a>(
+        &self,
+        tenant: &str,
+        balance: &LoadBalance,
+        servers: &[&'a Server],
+        key: Option<&str>,
+        available: impl Fn(&Server) -> bool,
+    ) -> &'a Server {
+        let mut candidates: Vec<&'a Server> =
+            servers.iter().copied().filter(|s| available(s)).collect();
+        if candidates.is_empty() {
+            candidates = servers.to_vec();
+        }
+        if candidates.len() == 1 {
+            return candidates[0];
+        }
+
+        match (balance, key) {
+            (LoadBalance::ClientHash | LoadBalance::CookieHash(_), Some(key)) => candidates
+                .into_iter()
+                .max_by_key(|s| fnv1a(&[key, &s.id]))
+                .unwrap(),
+            (LoadBalance::LeastConn, _) => {
+                let active = self.active.lock().unwrap();
+                candidates
+                    .into_iter()
+                    .min_by_key(|s| active.get(&s.id).copied().unwrap_or(0))
+                    .unwrap()
+            }
+            _ => {
+                let mut next = self.next.lock().unwrap();
+                let n = next.entry(tenant.to_string()).or_insert(0);
+                let server = candidates[*n % candidates.len()];
+                *n = n.wrapping_add(1);
+                server
+            }
+        }
+    }
+
+    /// Count a request to `server_id` until the returned guard is dropped
+    pub fn connect(&self, server_id: &str) -> Connection {
+        *self
+            .active
+            .lock()
+            .unwrap()
+            .entry(server_id.to_string())
+            .or_insert(0) += 1;
+        Connection {
+            server_id: server_id.to_string(),
+            active: self.active.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers(n: usize) -> Vec<Server> {
+        (1..=n)
+            .map(|i| Server {
+                id: format!("s{}", i),
+                name: format!("server-{}", i),
+                address: String::new(),
+                tenant_count: 0,
+                created_at: String::new(),
+                labels: Default::default(),
+                protocol: Default::default(),
+            })
+            .collect()
+    }
+
+    fn pick(lb: &LoadBalancer, balance: &LoadBalance, servers: &[Server], key: Option<&str>) -> String {
+        let servers: Vec<_> = servers.iter().collect();
+        lb.pick("romneys", balance, &servers, key, |_| true).id.clone()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let lb = LoadBalancer::new();
+        let servers = servers(3);
+        let picks: Vec<_> = (0..6)
+            .map(|_| pick(&lb, &LoadBalance::RoundRobin, &servers, None))
+            .collect();
+        assert_eq!(picks, ["s1", "s2", "s3", "s1", "s2", "s3"]);
+
+        // Unavailable servers are skipped
+        let all: Vec<_> = servers.iter().collect();
+        let picked = lb.pick("romneys", &LoadBalance::RoundRobin, &all, None, |s| s.id == "s2");
+        assert_eq!(picked.id, "s2");
+        let picked = lb.pick("romneys", &LoadBalance::RoundRobin, &all, None, |_| false);
+        assert!(servers.iter().any(|s| s.id == picked.id));
+    }
+
+    #[test]
+    fn test_least_conn() {
+        let lb = LoadBalancer::new();
+        let servers = servers(2);
+        let first = lb.connect("s1");
+        assert_eq!(pick(&lb, &LoadBalance::LeastConn, &servers, None), "s2");
+        let _second = lb.connect("s2");
+        let _third = lb.connect("s2");
+        assert_eq!(pick(&lb, &LoadBalance::LeastConn, &servers, None), "s1");
+        drop(first);
+        assert_eq!(pick(&lb, &LoadBalance::LeastConn, &servers, None), "s1");
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let lb = LoadBalancer::new();
+        let three = servers(3);
+        let hash = LoadBalance::ClientHash;
+
+        // The same key always lands on the same server
+        let clients: Vec<String> = (0..100).map(|i| format!("10.0.0.{}", i)).collect();
+        let before: Vec<_> = clients.iter().map(|c| pick(&lb, &hash, &three, Some(c))).collect();
+        let again: Vec<_> = clients.iter().map(|c| pick(&lb, &hash, &three, Some(c))).collect();
+        assert_eq!(before, again);
+        assert!(three.iter().all(|s| before.contains(&s.id)));
+
+        // Adding a server only moves clients onto it
+        let four = servers(4);
+        for (client, old) in clients.iter().zip(&before) {
+            let new = pick(&lb, &hash, &four, Some(client));
+            assert!(&new == old || new == "s4");
+        }
+    }
+
+    #[test]
+    fn test_cookie() {
+        let header = "theme=dark; session=abc123; other=x";
+        assert_eq!(cookie(header, "session"), Some("abc123"));
+        assert_eq!(cookie(header, "missing"), None);
+        assert_eq!(cookie("session", "session"), None);
+    }
+
+    fn tenant_with_canary(weight: u8) -> Tenant {
+        let replica = |server_id: &str, weight| crate::db::Replica {
+            server_id: server_id.to_string(),
+            provisioned: true,
+            provision_error: None,
+            weight,
+        };
+        Tenant {
+            id: "romneys".to_string(),
+            server_id: "s1".to_string(),
+            config: None,
+            status: "active".to_string(),
+            created_at: String::new(),
+            provisioned: true,
+            provision_error: None,
+            pinned: false,
+            constraints: Default::default(),
+            limits: Default::default(),
+            replicas: vec![replica("s2", None), replica("s3", Some(weight))],
+            balance: LoadBalance::RoundRobin,
+            mirror: None,
+            http: Default::default(),
+            access: Default::default(),
+        }
+    }
+
+    fn ids(servers: Vec<&Server>) -> Vec<&str> {
+        servers.into_iter().map(|s| s.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_canary_split() {
+        let servers = servers(3);
+        let tenant = tenant_with_canary(20);
+
+        // About 20% of clients go to the canary, and always the same ones
+        let on_canary: Vec<bool> = (0..1000)
+            .map(|i| ids(split(&tenant, &servers, None, Some(&format!("client-{}", i)))) == ["s3"])
+            .collect();
+        let share = on_canary.iter().filter(|&&c| c).count();
+        assert!((150..250).contains(&share), "{}", share);
+        for (i, &canary) in on_canary.iter().enumerate().take(100) {
+            let again = ids(split(&tenant, &servers, None, Some(&format!("client-{}", i))));
+            assert_eq!(again == ["s3"], canary);
+            if !canary {
+                assert_eq!(again, ["s1", "s2"]);
+            }
+        }
+
+        // Testers can choose
+        assert_eq!(ids(split(&tenant, &servers, Some("1"), Some("client-1"))), ["s3"]);
+        assert_eq!(ids(split(&tenant, &servers, Some("server-3"), None)), ["s3"]);
+        assert_eq!(ids(split(&tenant, &servers, Some("0"), None)), ["s1", "s2"]);
+
+        // A zero weight only gets chosen traffic
+        let tenant = tenant_with_canary(0);
+        assert!((0..100).all(|i| ids(split(&tenant, &servers, None, Some(&i.to_string()))) != ["s3"]));
+        assert_eq!(ids(split(&tenant, &servers, Some("on"), None)), ["s3"]);
+    }
+}
@@ -0,0 +1,658 @@
+//! Shared HTTP cache for tenant responses
+//!
+//! Responses to GET requests are cached when `Cache-Control` (`s-maxage` or
+//! `max-age`) or `Expires` gives them a lifetime and nothing marks them
+//! private, following the rules for shared caches. Entries are keyed by
+//! tenant first, so tenants never see each other's responses, then by URL
+//! and the request headers the response `Vary`s on. Bodies are kept in
+//! memory, or in files under a directory, up to a total size; the least
+//! recently used entries are evicted first.
+
+use anyhow::Result;
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::body::{Frame, SizeHint};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Set on responses for tenants with caching on: `HIT` or `MISS`
+pub const CACHE_HEADER: &str = "x-cache";
+
+/// Statuses a response may be cached with
+const CACHEABLE: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// A cacheable request, kept until its response arrives
+pub struct Lookup {
+    tenant: String,
+    key: String,
+    headers: HeaderMap,
+    /// The client asked for a fresh response, which may still be stored
+    revalidate: bool,
+}
+
+enum Stored {
+    Memory(Bytes),
+    Disk(PathBuf),
+}
+
+struct Entry {
+    /// Request headers the response varies on, and their values
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Stored,
+    stored_at: Instant,
+    /// `Age` when the response arrived
+    initial_age: Duration,
+    ttl: Duration,
+    size: u64,
+    /// When the entry was last used, on `Inner::clock`; unique, and its key
+    /// in `Inner::lru`
+    used: u64,
+}
+
+impl Entry {
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Tenant -> key -> entries, one per `Vary` combination
+    tenants: HashMap<String, HashMap<String, Vec<Entry>>>,
+    /// Entries by when they were last used, least recent first: `used` ->
+    /// (tenant, key)
+    lru: BTreeMap<u64, (String, String)>,
+    size: u64,
+    clock: u64,
+}
+
+impl Inner {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn add(&mut self, tenant: String, key: String, entry: Entry) {
+        self.size += entry.size;
+        self.lru.insert(entry.used, (tenant.clone(), key.clone()));
+        self.tenants
+            .entry(tenant)
+            .or_default()
+            .entry(key)
+            .or_default()
+            .push(entry);
+    }
+
+    /// Remove the entry last used at `used`
+    fn remove(&mut self, used: u64) -> Option<Entry> {
+        let (tenant, key) = self.lru.remove(&used)?;
+        let entries = self.tenants.get_mut(&tenant)?;
+        let variants = entries.get_mut(&key)?;
+        let entry = variants.swap_remove(variants.iter().position(|e| e.used == used)?);
+        if variants.is_empty() {
+            entries.remove(&key);
+            if entries.is_empty() {
+                self.tenants.remove(&tenant);
+            }
+        }
+        self.size -= entry.size;
+        Some(entry)
+    }
+}
+
+pub struct HttpCache {
+    /// Total body bytes kept
+    max_size: u64,
+    /// Larger responses aren't cached
+    max_entry_size: u64,
+    /// Keep bodies in files here instead of in memory
+    dir: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+impl HttpCache {
+    /// A cache of up to `max_size` bytes. Files left in `dir` by a previous
+    /// run are removed, since the index lives in memory.
+    pub fn new(max_size: u64, max_entry_size: u64, dir: Option<PathBuf>) -> Result<Self> {
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir)?;
+            for file in std::fs::read_dir(dir)? {
+                let path = file?.path();
+                if path.extension().is_some_and(|e| e == "body") {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(Self {
+            max_size,
+            max_entry_size,
+            dir,
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    /// The cache lookup for a request, or `None` if it must go to the
+    /// tenant's server
+    pub fn lookup(tenant: &str, host: &str, req: &Request<Body>) -> Option<Lookup> {
+        let headers = req.headers();
+        if req.method() != Method::GET
+            || headers.contains_key(header::AUTHORIZATION)
+            || headers.contains_key(header::RANGE)
+        {
+            return None;
+        }
+        let directives = directives(headers);
+        if has(&directives, "no-store") {
+            return None;
+        }
+        let pragma_no_cache = headers
+            .get(header::PRAGMA)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"no-cache"));
+        Some(Lookup {
+            tenant: tenant.to_string(),
+            key: format!(
+                "{}{}",
+                host.to_ascii_lowercase(),
+                req.uri().path_and_query().map_or("/", |p| p.as_str())
+            ),
+            headers: headers.clone(),
+            revalidate: has(&directives, "no-cache") || pragma_no_cache,
+        })
+    }
+
+    /// A fresh cached response for the request
+    pub async fn get(&self, lookup: &Lookup) -> Option<Response<Body>> {
+        if lookup.revalidate {
+            return None;
+        }
+        let (status, mut headers, body, age) = {
+            let mut inner = self.inner.lock().unwrap();
+            let clock = inner.tick();
+            let variants = inner.tenants.get_mut(&lookup.tenant)?.get_mut(&lookup.key)?;
+            let entry = variants.iter_mut().find(|e| e.matches(&lookup.headers))?;
+            let age = entry.age();
+            let used = entry.used;
+            if age >= entry.ttl {
+                let expired = inner.remove(used);
+                drop(inner);
+                remove_files(expired.into_iter().collect());
+                return None;
+            }
+            entry.used = clock;
+            let body = match &entry.body {
+                Stored::Memory(bytes) => Ok(bytes.clone()),
+                Stored::Disk(path) => Err(path.clone()),
+            };
+            let found = (entry.status, entry.headers.clone(), body, age);
+            let place = inner.lru.remove(&used);
+            if let Some(place) = place {
+                inner.lru.insert(clock, place);
+            }
+            found
+        };
+        let body = match body {
+            Ok(bytes) => bytes,
+            // Evicted while we read it
+            Err(path) => Bytes::from(tokio::fs::read(path).await.ok()?),
+        };
+
+        headers.insert(header::AGE, age.as_secs().into());
+        headers.insert(CACHE_HEADER, HeaderValue::from_static("HIT"));
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Some(response)
+    }
+
+    /// Pass a response on, storing it as its body is sent if it may be
+    /// cached
+    pub fn store(self: &Arc<Self>, lookup: Lookup, response: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = response.into_parts();
+        let entry = self.entry(&lookup, &parts);
+        parts
+            .headers
+            .insert(CACHE_HEADER, HeaderValue::from_static("MISS"));
+        let Some(entry) = entry else {
+            return Response::from_parts(parts, body);
+        };
+        let capture = Capture {
+            inner: body,
+            buf: Some(Vec::new()),
+            limit: self.max_entry_size,
+            pending: Some((self.clone(), lookup, entry)),
+        };
+        // Nothing will poll an empty body to its end
+        if hyper::body::Body::is_end_stream(&capture.inner) {
+            let mut capture = capture;
+            capture.finish();
+            return Response::from_parts(parts, capture.inner);
+        }
+        Response::from_parts(parts, Body::new(capture))
+    }
+
+    /// The entry a response would be stored as, if it may be stored
+    fn entry(&self, lookup: &Lookup, parts: &axum::http::response::Parts) -> Option<Entry> {
+        let headers = &parts.headers;
+        if !CACHEABLE.contains(&parts.status.as_u16()) || headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        if content_length(headers).is_some_and(|len| len > self.max_entry_size) {
+            return None;
+        }
+        let ttl = ttl(headers)?;
+
+        let mut vary = Vec::new();
+        for value in headers.get_all(header::VARY) {
+            for name in value.to_str().ok()?.split(',').map(str::trim) {
+                if name == "*" {
+                    return None;
+                }
+                if let Ok(name) = HeaderName::try_from(name) {
+                    let value = lookup.headers.get(&name).cloned();
+                    vary.push((name, value));
+                }
+            }
+        }
+
+        let mut headers = headers.clone();
+        // Recomputed for each hit
+        headers.remove(header::AGE);
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.remove(header::CONTENT_LENGTH);
+        headers.remove(CACHE_HEADER);
+        Some(Entry {
+            vary,
+            status: parts.status,
+            headers,
+            body: Stored::Memory(Bytes::new()),
+            stored_at: Instant::now(),
+            initial_age: parts
+                .headers
+                .get(header::AGE)
+                .and_then(|v| v.to_str().ok()?.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or_default(),
+            ttl,
+            size: 0,
+            used: 0,
+        })
+    }
+
+    fn insert(self: &Arc<Self>, lookup: Lookup, mut entry: Entry, body: Bytes) {
+        entry.size = body.len() as u64;
+        match &self.dir {
+            None => {
+                entry.body = Stored::Memory(body);
+                self.insert_entry(lookup, entry);
+            }
+            Some(dir) => {
+                let path = dir.join(format!("{}.body", uuid::Uuid::new_v4()));
+                let cache = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = tokio::fs::write(&path, &body).await {
+                        tracing::warn!("Failed to write cache file {}: {}", path.display(), e);
+                        return;
+                    }
+                    entry.body = Stored::Disk(path);
+                    cache.insert_entry(lookup, entry);
+                });
+            }
+        }
+    }
+
+    fn insert_entry(&self, lookup: Lookup, mut entry: Entry) {
+        if entry.age() >= entry.ttl {
+            remove_files(vec![entry]);
+            return;
+        }
+        let removed = {
+            let mut inner = self.inner.lock().unwrap();
+            entry.used = inner.tick();
+            let replaced = inner
+                .tenants
+                .get(&lookup.tenant)
+                .and_then(|entries| entries.get(&lookup.key))
+                .and_then(|variants| variants.iter().find(|e| e.vary == entry.vary))
+                .map(|e| e.used);
+            let mut removed: Vec<Entry> = replaced.and_then(|used| inner.remove(used)).into_iter().collect();
+            inner.add(lookup.tenant, lookup.key, entry);
+
+            // Evict the least recently used entries until the cache fits.
+            // Expired entries are dropped when they're next looked up.
+            while inner.size > self.max_size {
+                let Some((&oldest, _)) = inner.lru.first_key_value() else {
+                    break;
+                };
+                removed.extend(inner.remove(oldest));
+            }
+            removed
+        };
+        remove_files(removed);
+    }
+
+    /// Drop a tenant's cached responses, returning how many there were
+    pub fn purge(&self, tenant: &str) -> usize {
+        let removed: Vec<Entry> = {
+            let mut inner = self.inner.lock().unwrap();
+            let Some(entries) = inner.tenants.remove(tenant) else {
+                return 0;
+            };
+            let removed: Vec<Entry> = entries.into_values().flatten().collect();
+            for entry in &removed {
+                inner.lru.remove(&entry.used);
+            }
+            inner.size -= removed.iter().map(|e| e.size).sum::<u64>();
+            removed
+        };
+        let count = removed.len();
+        remove_files(removed);
+        count
+    }
+}
+
+fn remove_files(entries: Vec<Entry>) {
+    for entry in entries {
+        if let Stored::Disk(path) = entry.body {
+            tokio::spawn(async move {
+                let _ = tokio::fs::remove_file(path).await;
+            });
+        }
+    }
+}
+
+/// Lowercased `Cache-Control` directives and their values
+fn directives(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|d| {
+            let d = d.trim();
+            if d.is_empty() {
+                return None;
+            }
+            Some(match d.split_once('=') {
+                Some((name, value)) => (
+                    name.trim().to_ascii_lowercase(),
+                    Some(value.trim().trim_matches('"').to_string()),
+                ),
+                None => (d.to_ascii_lowercase(), None),
+            })
+        })
+        .collect()
+}
+
+fn has(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(d, _)| d == name)
+}
+
+fn seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives
+        .iter()
+        .find(|(d, _)| d == name)
+        .and_then(|(_, v)| v.as_ref()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// How long a shared cache may serve a response, or `None` if it must not
+/// store it
+fn ttl(headers: &HeaderMap) -> Option<Duration> {
+    let directives = directives(headers);
+    if ["no-store", "no-cache", "private"]
+        .iter()
+        .any(|d| has(&directives, d))
+    {
+        return None;
+    }
+    let ttl = match seconds(&directives, "s-maxage").or_else(|| seconds(&directives, "max-age")) {
+        Some(ttl) => ttl,
+        None => {
+            let date = |name| {
+                headers
+                    .get(name)
+                    .and_then(|v: &HeaderValue| v.to_str().ok())
+                    .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+                    .map(SystemTime::from)
+            };
+            let expires = date(header::EXPIRES)?;
+            let now = date(header::DATE).unwrap_or_else(SystemTime::now);
+            expires.duration_since(now).ok()?
+        }
+    };
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// A response body that is copied into the cache once it has been sent in
+/// full, unless it outgrows the entry limit or fails
+struct Capture {
+    inner: Body,
+    buf: Option<Vec<u8>>,
+    limit: u64,
+    pending: Option<(Arc<HttpCache>, Lookup, Entry)>,
+}
+
+impl Capture {
+    fn finish(&mut self) {
+        if let (Some(buf), Some((cache, lookup, entry))) = (self.buf.take(), self.pending.take()) {
+            cache.insert(lookup, entry, Bytes::from(buf));
+        }
+    }
+}
+
+impl hyper::body::Body for Capture {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        let frame = match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(None) => {
+                this.finish();
+                return Poll::Ready(None);
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.buf = None;
+                return Poll::Ready(Some(Err(e)));
+            }
+            Poll::Ready(Some(Ok(frame))) => frame,
+        };
+        match (frame.data_ref(), &mut this.buf) {
+            (Some(data), Some(buf)) if (buf.len() + data.len()) as u64 <= this.limit => {
+                buf.extend_from_slice(data)
+            }
+            // Too large, or trailers, which aren't cached
+            _ => this.buf = None,
+        }
+        // Callers may stop polling once the body says it has ended
+        if this.inner.is_end_stream() {
+            this.finish();
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn request(path: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::builder().uri(path);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn response(body: &'static str, headers: &[(&str, &str)]) -> Response<Body> {
+        let mut response = Response::builder();
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        response.body(Body::from(body)).unwrap()
+    }
+
+    /// Send a response through the cache, reading its body to the end
+    async fn fill(cache: &Arc<HttpCache>, tenant: &str, req: &Request<Body>, response: Response<Body>) {
+        let lookup = HttpCache::lookup(tenant, "romneys.ourfam.lol", req).unwrap();
+        let response = cache.store(lookup, response);
+        response.into_body().collect().await.unwrap();
+        // Disk entries are indexed once written
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    async fn get(cache: &HttpCache, tenant: &str, req: &Request<Body>) -> Option<String> {
+        let lookup = HttpCache::lookup(tenant, "romneys.ourfam.lol", req)?;
+        let response = cache.get(&lookup).await?;
+        assert_eq!(response.headers()[CACHE_HEADER], "HIT");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        Some(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_ttl() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+        let ttl = |pairs| ttl(&headers(pairs));
+        assert_eq!(ttl(&[("cache-control", "max-age=60")]), Some(Duration::from_secs(60)));
+        assert_eq!(
+            ttl(&[("cache-control", "public, max-age=60, s-maxage=600")]),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(ttl(&[("cache-control", "private, max-age=60")]), None);
+        assert_eq!(ttl(&[("cache-control", "no-store")]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age=0")]), None);
+        assert_eq!(ttl(&[]), None);
+        assert_eq!(
+            ttl(&[
+                ("date", "Tue, 15 Nov 1994 08:12:31 GMT"),
+                ("expires", "Tue, 15 Nov 1994 08:17:31 GMT"),
+            ]),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(ttl(&[("expires", "0")]), None);
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let cache = Arc::new(HttpCache::new(1 << 20, 1024, None).unwrap());
+        let req = request("/app.js?v=1", &[]);
+        fill(&cache, "romneys", &req, response("js", &[("cache-control", "max-age=60")])).await;
+        assert_eq!(get(&cache, "romneys", &req).await.as_deref(), Some("js"));
+
+        // Keyed by tenant, URL and method
+        assert_eq!(get(&cache, "smiths", &req).await, None);
+        assert_eq!(get(&cache, "romneys", &request("/app.js?v=2", &[])).await, None);
+        let post = Request::post("/app.js?v=1").body(Body::empty()).unwrap();
+        assert!(HttpCache::lookup("romneys", "romneys.ourfam.lol", &post).is_none());
+
+        // Clients can skip the cache
+        let no_cache = request("/app.js?v=1", &[("cache-control", "no-cache")]);
+        assert_eq!(get(&cache, "romneys", &no_cache).await, None);
+        let auth = request("/app.js?v=1", &[("authorization", "Bearer x")]);
+        assert!(HttpCache::lookup("romneys", "romneys.ourfam.lol", &auth).is_none());
+
+        // Private, cookie-setting and oversized responses aren't stored
+        for (path, response) in [
+            ("/private", response("x", &[("cache-control", "private, max-age=60")])),
+            ("/cookie", response("x", &[("cache-control", "max-age=60"), ("set-cookie", "a=b")])),
+            ("/large", response(include_str!("cache.rs"), &[("cache-control", "max-age=60")])),
+            ("/vary-all", response("x", &[("cache-control", "max-age=60"), ("vary", "*")])),
+        ] {
+            let req = request(path, &[]);
+            fill(&cache, "romneys", &req, response).await;
+            assert_eq!(get(&cache, "romneys", &req).await, None, "{}", path);
+        }
+
+        // Variants by the headers a response varies on
+        let gzip = request("/page", &[("accept-encoding", "gzip")]);
+        let plain = request("/page", &[]);
+        let headers = [("cache-control", "max-age=60"), ("vary", "Accept-Encoding")];
+        fill(&cache, "romneys", &gzip, response("gzipped", &headers)).await;
+        assert_eq!(get(&cache, "romneys", &plain).await, None);
+        fill(&cache, "romneys", &plain, response("plain", &headers)).await;
+        assert_eq!(get(&cache, "romneys", &gzip).await.as_deref(), Some("gzipped"));
+        assert_eq!(get(&cache, "romneys", &plain).await.as_deref(), Some("plain"));
+
+        assert_eq!(cache.purge("romneys"), 3);
+        assert_eq!(get(&cache, "romneys", &req).await, None);
+        assert_eq!(cache.purge("romneys"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_and_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(HttpCache::new(4, 4, Some(dir.path().to_path_buf())).unwrap());
+
+        // Age from upstream caches counts against the lifetime
+        let req = request("/aged", &[]);
+        let headers = [("cache-control", "max-age=60"), ("age", "60")];
+        fill(&cache, "romneys", &req, response("old", &headers)).await;
+        assert_eq!(get(&cache, "romneys", &req).await, None);
+
+        let a = request("/a", &[]);
+        let b = request("/b", &[]);
+        let c = request("/c", &[]);
+        let headers = [("cache-control", "max-age=60")];
+        fill(&cache, "romneys", &a, response("aa", &headers)).await;
+        fill(&cache, "romneys", &b, response("bb", &headers)).await;
+        assert_eq!(get(&cache, "romneys", &a).await.as_deref(), Some("aa"));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // /b is the least recently used
+        fill(&cache, "romneys", &c, response("cc", &headers)).await;
+        assert_eq!(get(&cache, "romneys", &b).await, None);
+        assert_eq!(get(&cache, "romneys", &a).await.as_deref(), Some("aa"));
+        assert_eq!(get(&cache, "romneys", &c).await.as_deref(), Some("cc"));
+
+        {
+            let inner = cache.inner.lock().unwrap();
+            assert_eq!(inner.lru.len(), 2);
+            assert_eq!(inner.size, 4);
+        }
+
+        cache.purge("romneys");
+        assert!(cache.inner.lock().unwrap().lru.is_empty());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,288 @@
+//! Per-server circuit breakers
+//!
+//! After `threshold` consecutive failed requests to a server its circuit
+//! opens, and the proxy answers 503 at once instead of waiting on a server
+//! that is down. Once `open_for` has passed one request is let through as a
+//! probe: if it succeeds the circuit closes, otherwise it opens again.
+//!
+//! State lives in memory; changes are written to the `server_health` table
+//! in the background for `slum status`.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::db::{Database, ServerHealth};
+
+/// How often state changes are written to the database
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    /// A probe request is in flight
+    HalfOpen { since: Instant },
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+struct Circuit {
+    state: State,
+    failures: u32,
+    last_error: Option<String>,
+    changed_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Circuit {
+    fn health(&self, server_id: &str) -> ServerHealth {
+        ServerHealth {
+            server_id: server_id.to_string(),
+            state: self.state.as_str().to_string(),
+            failures: self.failures,
+            last_error: self.last_error.clone(),
+            changed_at: self.changed_at.to_rfc3339(),
+        }
+    }
+}
+
+pub struct CircuitBreaker {
+    /// Consecutive failures that open a circuit; 0 disables breaking
+    threshold: u32,
+    open_for: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+    /// Servers whose state changed since the last flush
+    changed: Mutex<Vec<String>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, open_for: Duration) -> Self {
+        Self {
+            threshold,
+            open_for,
+            circuits: Mutex::new(HashMap::new()),
+            changed: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Whether a request may be sent to `server_id`. While the circuit is
+    /// open, returns how long until the server is tried again.
+    pub fn check(&self, server_id: &str) -> Result<(), Duration> {
+        self.check_at(server_id, Instant::now())
+    }
+
+    fn check_at(&self, server_id: &str, now: Instant) -> Result<(), Duration> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(server_id) else {
+            return Ok(());
+        };
+        match circuit.state {
+            State::Closed => Ok(()),
+            State::Open { until } if now < until => Err(until - now),
+            // A probe that never reported back doesn't hold the circuit forever
+            State::HalfOpen { since } if now < since + self.open_for => Err(self.open_for),
+            State::Open { .. } | State::HalfOpen { .. } => {
+                circuit.state = State::HalfOpen { since: now };
+                self.changed(server_id, circuit);
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether `check` would let a request through, without claiming the
+    /// probe of a half-open circuit
+    pub fn available(&self, server_id: &str) -> bool {
+        let now = Instant::now();
+        match self.circuits.lock().unwrap().get(server_id).map(|c| c.state) {
+            None | Some(State::Closed) => true,
+            Some(State::Open { until }) => now >= until,
+            Some(State::HalfOpen { since }) => now >= since + self.open_for,
+        }
+    }
+
+    /// Record a response from `server_id`
+    pub fn success(&self, server_id: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(server_id) {
+            let was_closed = circuit.state == State::Closed;
+            circuit.state = State::Closed;
+            circuit.failures = 0;
+            if !was_closed {
+                self.changed(server_id, circuit);
+            }
+        }
+    }
+
+    /// Record a request to `server_id` that got no response
+    pub fn failure(&self, server_id: &str, error: &str) {
+        self.failure_at(server_id, error, Instant::now())
+    }
+
+    fn failure_at(&self, server_id: &str, error: &str, now: Instant) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(server_id.to_string()).or_insert_with(|| Circuit {
+            state: State::Closed,
+            failures: 0,
+            last_error: None,
+            changed_at: chrono::Utc::now(),
+        });
+        circuit.failures += 1;
+        circuit.last_error = Some(error.to_string());
+        let open = match circuit.state {
+            State::Closed => circuit.failures >= self.threshold,
+            State::HalfOpen { .. } => true,
+            State::Open { .. } => false,
+        };
+        if open {
+            circuit.state = State::Open {
+                until: now + self.open_for,
+            };
+            tracing::warn!("Circuit opened for server {}: {}", server_id, error);
+            self.changed(server_id, circuit);
+        }
+    }
+
+    fn changed(&self, server_id: &str, circuit: &mut Circuit) {
+        circuit.changed_at = chrono::Utc::now();
+        let mut changed = self.changed.lock().unwrap();
+        if !changed.iter().any(|id| id == server_id) {
+            changed.push(server_id.to_string());
+        }
+    }
+
+    /// Servers that have failed since they last answered
+    pub fn health(&self) -> Vec<ServerHealth> {
+        let circuits = self.circuits.lock().unwrap();
+        let mut health: Vec<_> = circuits
+            .iter()
+            .filter(|(_, c)| c.failures > 0)
+            .map(|(id, c)| c.health(id))
+            .collect();
+        health.sort_by(|a, b| a.server_id.cmp(&b.server_id));
+        health
+    }
+
+    /// Write changed states to the database
+    pub async fn flush(&self, db: &Database) -> Result<usize> {
+        let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+        let health: Vec<_> = {
+            let circuits = self.circuits.lock().unwrap();
+            changed
+                .iter()
+                .filter_map(|id| circuits.get(id).map(|c| c.health(id)))
+                .collect()
+        };
+        for h in &health {
+            db.set_server_health(h).await?;
+        }
+        Ok(health.len())
+    }
+
+    /// Flush in the background. Health left by a previous run is cleared,
+    /// since every circuit starts closed.
+    pub fn spawn(self: Arc<Self>, db: Arc<Database>) {
+        tokio::spawn(async move {
+            if let Err(e) = db.clear_server_health().await {
+                tracing::error!("Failed to clear server health: {}", e);
+            }
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.flush(&db).await {
+                    tracing::error!("Failed to write server health: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(10));
+        let start = Instant::now();
+
+        breaker.failure_at("s1", "refused", start);
+        breaker.failure_at("s1", "refused", start);
+        assert!(breaker.check_at("s1", start).is_ok());
+
+        // A response resets the count
+        breaker.success("s1");
+        breaker.failure_at("s1", "refused", start);
+        breaker.failure_at("s1", "refused", start);
+        assert!(breaker.check_at("s1", start).is_ok());
+
+        breaker.failure_at("s1", "refused", start);
+        let later = start + Duration::from_secs(4);
+        assert_eq!(breaker.check_at("s1", later), Err(Duration::from_secs(6)));
+        assert!(breaker.check_at("s2", later).is_ok());
+
+        let health = breaker.health();
+        assert_eq!(health.len(), 1);
+        assert_eq!(health[0].state, "open");
+        assert_eq!(health[0].failures, 3);
+        assert_eq!(health[0].last_error.as_deref(), Some("refused"));
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(10));
+        let start = Instant::now();
+        breaker.failure_at("s1", "refused", start);
+
+        // One probe after the open period; others wait for its result
+        let probe = start + Duration::from_secs(10);
+        assert!(breaker.check_at("s1", probe).is_ok());
+        assert!(breaker.check_at("s1", probe).is_err());
+
+        // A failed probe reopens the circuit
+        breaker.failure_at("s1", "refused", probe);
+        assert!(breaker.check_at("s1", probe + Duration::from_secs(5)).is_err());
+
+        // A successful one closes it
+        let probe = probe + Duration::from_secs(10);
+        assert!(breaker.check_at("s1", probe).is_ok());
+        breaker.success("s1");
+        assert!(breaker.check_at("s1", probe).is_ok());
+        assert!(breaker.health().is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(10));
+        for _ in 0..10 {
+            breaker.failure("s1", "refused");
+        }
+        assert!(breaker.check("s1").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Database::open(&path).await.unwrap();
+        let server = db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(10));
+        breaker.failure(&server.id, "refused");
+        assert_eq!(breaker.flush(&db).await.unwrap(), 1);
+        assert_eq!(breaker.flush(&db).await.unwrap(), 0);
+
+        let health = db.list_server_health().await.unwrap();
+        assert_eq!(health[0].state, "open");
+    }
+}
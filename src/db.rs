@@ -1,12 +1,98 @@
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
+use sqlx::{
+    pool::PoolConnection,
+    sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePoolOptions},
+    Pool, Sqlite,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+mod latency;
+mod schema;
+
+pub use latency::Latencies;
+pub use schema::{merge_patch, ConfigSchema};
 
 #[derive(Clone)]
 pub struct Database {
     pool: Pool<Sqlite>,
+    /// Serializes writes within this process, so they queue here rather
+    /// than on SQLite's busy timeout. Other processes using the file are kept
+    /// out by `BEGIN IMMEDIATE`.
+    write_lock: Arc<Mutex<()>>,
 }
 
+/// A registry transaction. Changes are committed by `commit` and rolled back
+/// if the transaction is dropped.
+pub struct Tx {
+    tx: Immediate,
+    _guard: OwnedMutexGuard<()>,
+}
+
+/// A connection in a transaction started with `BEGIN IMMEDIATE`, which takes
+/// the database's write lock up front. A deferred transaction that reads
+/// before it writes fails with `SQLITE_BUSY`, without waiting, if another
+/// process writes in between. Rolled back if dropped before `commit`.
+struct Immediate {
+    conn: Option<PoolConnection<Sqlite>>,
+}
+
+impl Immediate {
+    async fn begin(pool: &Pool<Sqlite>) -> Result<Self> {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+        Ok(Self { conn: Some(conn) })
+    }
+
+    async fn commit(mut self) -> Result<()> {
+        sqlx::query("COMMIT").execute(&mut *self).await?;
+        // Back to the pool as is
+        self.conn.take();
+        Ok(())
+    }
+}
+
+impl Deref for Immediate {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &SqliteConnection {
+        self.conn.as_ref().expect("transaction is open")
+    }
+}
+
+impl DerefMut for Immediate {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        self.conn.as_mut().expect("transaction is open")
+    }
+}
+
+impl Drop for Immediate {
+    fn drop(&mut self) {
+        let Some(mut conn) = self.conn.take() else {
+            return;
+        };
+        // The connection goes back to the pool once the rollback is done.
+        // One that can't roll back is closed instead.
+        tokio::spawn(async move {
+            if sqlx::query("ROLLBACK").execute(&mut *conn).await.is_err() {
+                let _ = conn.close().await;
+            }
+        });
+    }
+}
+
+/// Server labels, and the labels a tenant requires of its server
+pub type Labels = BTreeMap<String, String>;
+
+/// Future returned by the closure passed to `Database::transaction`
+pub type TxFuture<'t, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 't>>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Server {
     pub id: String,
@@ -14,23 +100,843 @@ pub struct Server {
     pub address: String,
     pub tenant_count: i32,
     pub created_at: String,
+    pub labels: Labels,
+    /// How the proxy talks to the server
+    pub protocol: Protocol,
+}
+
+impl Server {
+    /// Whether this server carries every label a tenant requires
+    pub fn satisfies(&self, constraints: &Labels) -> bool {
+        constraints
+            .iter()
+            .all(|(k, v)| self.labels.get(k) == Some(v))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tenant {
     pub id: String,
     pub server_id: String,
+    /// Config passed to tenement, as JSON text. Serialized as the JSON itself.
+    #[serde(serialize_with = "serialize_config", deserialize_with = "deserialize_config")]
     pub config: Option<String>,
     pub status: String,
     pub created_at: String,
+    /// Whether the tenant's server has acknowledged its current config
+    pub provisioned: bool,
+    /// Last error from provisioning the tenant, cleared on success
+    pub provision_error: Option<String>,
+    /// Pinned tenants are never moved by the rebalancer
+    pub pinned: bool,
+    /// Labels the tenant's server must carry
+    pub constraints: Labels,
+    /// Request limits; unset fields use the fleet default
+    pub limits: Limits,
+    /// Servers the tenant also runs on, besides `server_id`: replicas and
+    /// canaries
+    pub replicas: Vec<Replica>,
+    /// How the proxy spreads requests over the tenant's servers
+    pub balance: LoadBalance,
+    /// Server that gets a copy of the tenant's requests
+    pub mirror: Option<Mirror>,
+    /// Response caching and compression
+    pub http: HttpOptions,
+    /// Who may reach the tenant through the proxy
+    pub access: AccessPolicy,
+}
+
+impl Tenant {
+    /// The config as the JSON it holds. Configs stored before they were
+    /// required to be JSON come out as strings.
+    pub fn config_json(&self) -> Option<serde_json::Value> {
+        config_json(self.config.as_deref())
+    }
+}
+
+fn config_json(config: Option<&str>) -> Option<serde_json::Value> {
+    config.map(|c| serde_json::from_str(c).unwrap_or_else(|_| serde_json::Value::String(c.to_string())))
+}
+
+fn serialize_config<S: serde::Serializer>(config: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    config_json(config.as_deref()).serialize(serializer)
+}
+
+fn deserialize_config<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s),
+        value => Some(value.to_string()),
+    })
+}
+
+/// Parse a tenant config for storing: a JSON object, or `null` for none
+pub fn parse_config(config: Option<&str>) -> Result<Option<serde_json::Value>> {
+    let Some(config) = config else {
+        return Ok(None);
+    };
+    match serde_json::from_str(config) {
+        Ok(serde_json::Value::Null) => Ok(None),
+        Ok(value @ serde_json::Value::Object(_)) => Ok(Some(value)),
+        Ok(_) => Err(anyhow!("Tenant config must be a JSON object, got: {}", config)),
+        Err(e) => Err(anyhow!("Tenant config isn't valid JSON: {}", e)),
+    }
+}
+
+/// What the proxy does with a tenant's responses on their way out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct HttpOptions {
+    /// Keep cacheable responses in the proxy's shared HTTP cache
+    #[serde(default)]
+    pub cache: bool,
+    /// Compress responses with gzip or brotli for clients that accept it
+    #[serde(default)]
+    pub compress: bool,
+}
+
+/// A rule the proxy applies to a tenant's requests before forwarding them.
+/// Fleet rules come first, then the tenant's own, each in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum RouteRule {
+    /// Redirect `from` to `to`. A `from` ending in `/*` also matches the
+    /// paths under it, which are appended to `to`.
+    Redirect {
+        from: String,
+        to: String,
+        #[serde(default = "RouteRule::default_status")]
+        status: u16,
+    },
+    /// Replace the path prefix `from` with `to`
+    Rewrite { from: String, to: String },
+    /// Redirect plain HTTP requests to HTTPS
+    ForceHttps,
+    /// Set a header on responses
+    SetHeader { name: String, value: String },
+    /// Remove a header from responses
+    RemoveHeader { name: String },
+}
+
+/// Headers rules may not touch, since the proxy and HTTP framing own them
+const RESERVED_HEADERS: &[&str] = &["connection", "content-length", "transfer-encoding", "upgrade"];
+
+impl RouteRule {
+    fn default_status() -> u16 {
+        302
+    }
+
+    /// A rule from its command-line form, such as `redirect /old /new 301`
+    /// or `set-header X-Frame-Options DENY`
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let rule = match args.as_slice() {
+            ["redirect", from, to] => RouteRule::Redirect {
+                from: from.to_string(),
+                to: to.to_string(),
+                status: Self::default_status(),
+            },
+            ["redirect", from, to, status] => RouteRule::Redirect {
+                from: from.to_string(),
+                to: to.to_string(),
+                status: status
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid redirect status: {}", status))?,
+            },
+            ["rewrite", from, to] => RouteRule::Rewrite {
+                from: from.to_string(),
+                to: to.to_string(),
+            },
+            ["force-https"] => RouteRule::ForceHttps,
+            ["set-header", name, value] => RouteRule::SetHeader {
+                name: name.to_string(),
+                value: value.to_string(),
+            },
+            ["remove-header", name] => RouteRule::RemoveHeader {
+                name: name.to_string(),
+            },
+            _ => {
+                return Err(anyhow!(
+                    "Expected one of: redirect FROM TO [STATUS], rewrite FROM TO, force-https, \
+                     set-header NAME VALUE, remove-header NAME; got: {}",
+                    args.join(" ")
+                ))
+            }
+        };
+        rule.validate()?;
+        Ok(rule)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let path = |path: &str| {
+            if path.starts_with('/') && !path.contains(['?', '#', ' ']) {
+                Ok(())
+            } else {
+                Err(anyhow!("Expected a path starting with /, got: {}", path))
+            }
+        };
+        let header = |name: &str| {
+            let name = axum::http::HeaderName::try_from(name)
+                .map_err(|_| anyhow!("Invalid header name: {}", name))?;
+            if RESERVED_HEADERS.contains(&name.as_str()) {
+                return Err(anyhow!("Header {} can't be changed by a rule", name));
+            }
+            Ok(())
+        };
+        match self {
+            RouteRule::Redirect { from, to, status } => {
+                path(from.strip_suffix("/*").unwrap_or(from))?;
+                if !(to.starts_with('/') || to.starts_with("http://") || to.starts_with("https://"))
+                    || to.parse::<axum::http::Uri>().is_err()
+                {
+                    return Err(anyhow!("Redirect target must be a path or http(s) URL, got: {}", to));
+                }
+                if ![301, 302, 303, 307, 308].contains(status) {
+                    return Err(anyhow!("Redirect status must be 301, 302, 303, 307 or 308, got {}", status));
+                }
+            }
+            RouteRule::Rewrite { from, to } => {
+                path(from)?;
+                path(to)?;
+            }
+            RouteRule::ForceHttps => {}
+            RouteRule::SetHeader { name, value } => {
+                header(name)?;
+                axum::http::HeaderValue::from_str(value)
+                    .map_err(|_| anyhow!("Invalid value for header {}: {}", name, value))?;
+            }
+            RouteRule::RemoveHeader { name } => header(name)?,
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for RouteRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteRule::Redirect { from, to, status } => write!(f, "redirect {} {} {}", from, to, status),
+            RouteRule::Rewrite { from, to } => write!(f, "rewrite {} {}", from, to),
+            RouteRule::ForceHttps => write!(f, "force-https"),
+            RouteRule::SetHeader { name, value } => write!(f, "set-header {} {}", name, value),
+            RouteRule::RemoveHeader { name } => write!(f, "remove-header {}", name),
+        }
+    }
+}
+
+/// Who may reach a tenant through the proxy. Client networks are checked
+/// first, then basic auth, then forward auth; an empty policy lets
+/// everyone in.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessPolicy {
+    /// Client networks let in; when set, all others are refused
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<ipnet::IpNet>,
+    /// Client networks refused, even if allowed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<ipnet::IpNet>,
+    /// Basic auth users; when set, requests need one's credentials
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<BasicUser>,
+    /// HTTP or HTTPS URL asked about each request: a 2xx answer lets it in, and any
+    /// other is returned to the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_auth: Option<String>,
+    /// Set when the stored policy couldn't be read. Nobody is let in then,
+    /// rather than everybody.
+    #[serde(skip)]
+    pub unreadable: bool,
+}
+
+impl AccessPolicy {
+    pub fn is_open(&self) -> bool {
+        *self == AccessPolicy::default()
+    }
+
+    /// Whether a client at `ip` is let in by the allow and deny lists.
+    /// Clients with no known address are only let in without an allow list.
+    pub fn admits(&self, ip: Option<std::net::IpAddr>) -> bool {
+        if self.unreadable {
+            return false;
+        }
+        let Some(ip) = ip.map(|ip| ip.to_canonical()) else {
+            return self.allow.is_empty();
+        };
+        (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+            && !self.deny.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Validate the policy and hash any plain passwords, for storing
+    pub fn prepare(mut self) -> Result<Self> {
+        for user in &mut self.users {
+            if user.username.is_empty() || user.username.contains(':') || user.username.contains(char::is_control) {
+                return Err(anyhow!("Invalid username: {:?}", user.username));
+            }
+            match (user.password.take(), user.password_hash.is_empty()) {
+                (Some(password), true) => *user = BasicUser::new(&user.username, &password)?,
+                (None, false) => {
+                    argon2::PasswordHash::new(&user.password_hash)
+                        .ok()
+                        .filter(|hash| hash.algorithm.as_str().starts_with("argon2"))
+                        .ok_or_else(|| anyhow!("Password hash for {} must be an argon2 PHC string", user.username))?;
+                }
+                _ => return Err(anyhow!("User {} needs a password or a password_hash", user.username)),
+            }
+        }
+        for (i, user) in self.users.iter().enumerate() {
+            if self.users[..i].iter().any(|u| u.username == user.username) {
+                return Err(anyhow!("Duplicate user: {}", user.username));
+            }
+        }
+        if let Some(url) = &self.forward_auth {
+            let uri = url
+                .parse::<axum::http::Uri>()
+                .map_err(|_| anyhow!("Invalid forward auth URL: {}", url))?;
+            if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+                return Err(anyhow!("Forward auth URL must be http(s)://HOST[:PORT]/PATH, got: {}", url));
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// A basic auth user. Only the password's argon2 hash is stored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicUser {
+    pub username: String,
+    /// Plain password, hashed when the policy is stored
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Argon2 hash in PHC string format
+    #[serde(default)]
+    pub password_hash: String,
+}
+
+impl BasicUser {
+    /// A user whose password is hashed with a fresh salt
+    pub fn new(username: &str, password: &str) -> Result<Self> {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+        // A v4 UUID is 122 random bits from the OS
+        let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())
+            .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+        let hash = argon2::Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+        Ok(BasicUser {
+            username: username.to_string(),
+            password: None,
+            password_hash: hash.to_string(),
+        })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        use argon2::password_hash::PasswordVerifier;
+        argon2::PasswordHash::new(&self.password_hash).is_ok_and(|hash| {
+            argon2::Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+}
+
+impl FromStr for BasicUser {
+    type Err = anyhow::Error;
+
+    /// `NAME:PASSWORD`, as given on the command line
+    fn from_str(s: &str) -> Result<Self> {
+        let (username, password) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected NAME:PASSWORD, got: {}", s))?;
+        Ok(BasicUser {
+            username: username.to_string(),
+            password: Some(password.to_string()),
+            password_hash: String::new(),
+        })
+    }
+}
+
+/// Where and how much of a tenant's traffic the proxy mirrors. Mirrored
+/// responses are discarded after their status is compared.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mirror {
+    pub server_id: String,
+    /// Percentage of requests mirrored, 1 to 100
+    pub percent: u8,
+    /// Requests with larger bodies aren't mirrored
+    pub max_body_size: u64,
+    /// Whether the tenant is on the mirror server with its current config
+    #[serde(default)]
+    pub provisioned: bool,
+}
+
+/// An extra server a tenant is placed on, from `tenant_placements`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Replica {
+    pub server_id: String,
+    pub provisioned: bool,
+    pub provision_error: Option<String>,
+    /// For a canary, the percentage of the tenant's traffic it gets;
+    /// `None` for a replica sharing the stable traffic
+    pub weight: Option<u8>,
+}
+
+impl Replica {
+    pub fn is_canary(&self) -> bool {
+        self.weight.is_some()
+    }
+}
+
+/// The HTTP version the proxy speaks to a tenement server. `Http2` is
+/// cleartext HTTP/2 with prior knowledge (h2c), which gRPC services need.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Http1,
+    Http2,
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "http1" => Ok(Protocol::Http1),
+            "http2" => Ok(Protocol::Http2),
+            _ => Err(anyhow!("Invalid protocol: {} (expected http1 or http2)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Http1 => write!(f, "http1"),
+            Protocol::Http2 => write!(f, "http2"),
+        }
+    }
+}
+
+/// How the proxy picks one of a tenant's servers for a request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum LoadBalance {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    /// The same client IP always reaches the same server
+    ClientHash,
+    /// The same value of this cookie always reaches the same server,
+    /// falling back to the client IP without it
+    CookieHash(String),
+}
+
+impl FromStr for LoadBalance {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round-robin" => Ok(LoadBalance::RoundRobin),
+            "least-conn" => Ok(LoadBalance::LeastConn),
+            "client-hash" => Ok(LoadBalance::ClientHash),
+            _ => match s.strip_prefix("cookie-hash:") {
+                Some(name) if !name.is_empty() => Ok(LoadBalance::CookieHash(name.to_string())),
+                _ => Err(anyhow!(
+                    "Invalid load balancing: {} (expected round-robin, least-conn, client-hash or cookie-hash:NAME)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for LoadBalance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadBalance::RoundRobin => write!(f, "round-robin"),
+            LoadBalance::LeastConn => write!(f, "least-conn"),
+            LoadBalance::ClientHash => write!(f, "client-hash"),
+            LoadBalance::CookieHash(name) => write!(f, "cookie-hash:{}", name),
+        }
+    }
+}
+
+impl TryFrom<String> for LoadBalance {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<LoadBalance> for String {
+    fn from(balance: LoadBalance) -> String {
+        balance.to_string()
+    }
+}
+
+/// Request limits enforced by the proxy. `None` means no limit, or for a
+/// tenant, the fleet default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Sustained requests per second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<f64>,
+    /// Requests that may arrive at once before `rate` applies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    /// Requests in progress at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<u32>,
+    /// Largest request body in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<u64>,
+}
+
+impl Limits {
+    /// These limits, falling back to `default` for unset fields
+    pub fn or(self, default: Limits) -> Limits {
+        Limits {
+            rate: self.rate.or(default.rate),
+            burst: self.burst.or(default.burst),
+            max_concurrent: self.max_concurrent.or(default.max_concurrent),
+            max_body_size: self.max_body_size.or(default.max_body_size),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(rate) = self.rate {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(anyhow!("Rate limit must be positive, got {}", rate));
+            }
+        }
+        if self.burst == Some(0) {
+            return Err(anyhow!("Burst must be at least 1"));
+        }
+        if self.max_concurrent == Some(0) {
+            return Err(anyhow!("Max concurrent requests must be at least 1"));
+        }
+        if self.max_body_size == Some(0) {
+            return Err(anyhow!("Max body size must be at least 1 byte"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DomainAlias {
+    pub domain: String,
+    pub tenant_id: String,
+}
+
+/// A tenant that still has to be removed from a server it no longer lives on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deprovision {
+    pub id: i64,
+    pub tenant_id: String,
+    pub server_id: String,
+    pub server_address: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: String,
+}
+
+/// A server's circuit breaker state, as last reported by the proxy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerHealth {
+    pub server_id: String,
+    /// `closed`, `open` or `half_open`
+    pub state: String,
+    /// Consecutive failed requests
+    pub failures: u32,
+    pub last_error: Option<String>,
+    pub changed_at: String,
+}
+
+/// A tenant's traffic over one interval. Rows are stored per minute;
+/// coarser steps sum the counters and merge the latency histograms.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Start of the interval, in Unix seconds
+    pub start: i64,
+    pub requests: i64,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub status_4xx: i64,
+    pub status_5xx: i64,
+    /// Percentiles of `latencies`
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    #[serde(skip)]
+    pub latencies: Latencies,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.status_4xx += other.status_4xx;
+        self.status_5xx += other.status_5xx;
+        self.latencies.merge(&other.latencies);
+        self.p50_ms = self.latencies.percentile(0.50);
+        self.p95_ms = self.latencies.percentile(0.95);
+    }
+}
+
+/// Statuses a tenant may be set to. Only `active` tenants receive traffic.
+pub const TENANT_STATUSES: &[&str] = &["active", "suspended"];
+
+/// Subdomains too common to give a tenant: they usually name the fleet's
+/// own sites and services
+pub const RESERVED_TENANT_IDS: &[&str] = &[
+    "admin", "api", "app", "assets", "auth", "autoconfig", "autodiscover", "blog", "cdn", "dashboard",
+    "docs", "ftp", "help", "imap", "localhost", "mail", "metrics", "mx", "ns", "ns1", "ns2", "pop",
+    "slum", "smtp", "static", "status", "support", "tenement", "webmail", "www",
+];
+
+/// Check that `id` can be a tenant ID. IDs are the first label of the
+/// tenant's hostname, so they follow DNS label rules: 1 to 63 lowercase
+/// letters, digits and hyphens, not starting or ending with a hyphen.
+pub fn validate_tenant_id(id: &str) -> Result<()> {
+    let invalid = |reason: &str| Err(anyhow!("Invalid tenant ID {:?}: {}", id, reason));
+    if id.is_empty() {
+        return invalid("it is empty");
+    }
+    if id.len() > 63 {
+        return invalid("it is longer than 63 characters");
+    }
+    if id.bytes().any(|b| b.is_ascii_uppercase()) {
+        return invalid("use lowercase letters");
+    }
+    if let Some(c) = id.chars().find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-')) {
+        return invalid(&format!("{:?} isn't allowed, only a-z, 0-9 and -", c));
+    }
+    if id.starts_with('-') || id.ends_with('-') {
+        return invalid("it can't start or end with a hyphen");
+    }
+    if id.get(2..4) == Some("--") && !id.starts_with("xn--") {
+        return invalid("hyphens in the third and fourth places are for punycode (xn--)");
+    }
+    if RESERVED_TENANT_IDS.contains(&id) {
+        return invalid("the name is reserved");
+    }
+    Ok(())
+}
+
+/// Tenant counts include replicas
+const SERVER_SELECT: &str = r#"
+    SELECT s.id, s.name, s.address, s.created_at,
+        COUNT(t.id) + (SELECT COUNT(*) FROM tenant_placements p WHERE p.server_id = s.id),
+        s.labels, s.protocol
+    FROM servers s
+    LEFT JOIN tenants t ON t.server_id = s.id
+"#;
+
+type ServerRow = (String, String, String, String, i32, String, String);
+type TenantRow = (
+    String,
+    String,
+    Option<String>,
+    String,
+    String,
+    bool,
+    Option<String>,
+    bool,
+    String,
+    String,
+    String,
+    String,
+    Option<String>,
+    String,
+    String,
+);
+
+const TENANT_COLUMNS: &str = r#"id, server_id, config, status, created_at, provisioned, provision_error, pinned, constraints, limits,
+    (SELECT json_group_array(json_object(
+        'server_id', p.server_id,
+        'provisioned', json(CASE WHEN p.provisioned THEN 'true' ELSE 'false' END),
+        'provision_error', p.provision_error,
+        'weight', p.weight))
+     FROM (SELECT * FROM tenant_placements WHERE tenant_id = tenants.id ORDER BY created_at) p),
+    balance, mirror, http, access"#;
+
+fn server_from_row(
+    (id, name, address, created_at, tenant_count, labels, protocol): ServerRow,
+) -> Server {
+    Server {
+        id,
+        name,
+        address,
+        tenant_count,
+        created_at,
+        labels: serde_json::from_str(&labels).unwrap_or_default(),
+        protocol: protocol.parse().unwrap_or_default(),
+    }
+}
+
+fn tenant_from_row(
+    (
+        id,
+        server_id,
+        config,
+        status,
+        created_at,
+        provisioned,
+        provision_error,
+        pinned,
+        constraints,
+        limits,
+        replicas,
+        balance,
+        mirror,
+        http,
+        access,
+    ): TenantRow,
+) -> Tenant {
+    Tenant {
+        id,
+        server_id,
+        config,
+        status,
+        created_at,
+        provisioned,
+        provision_error,
+        pinned,
+        constraints: serde_json::from_str(&constraints).unwrap_or_default(),
+        limits: serde_json::from_str(&limits).unwrap_or_default(),
+        replicas: serde_json::from_str(&replicas).unwrap_or_default(),
+        balance: balance.parse().unwrap_or_default(),
+        mirror: mirror.and_then(|m| serde_json::from_str(&m).ok()),
+        http: serde_json::from_str(&http).unwrap_or_default(),
+        access: serde_json::from_str(&access).unwrap_or(AccessPolicy {
+            unreadable: true,
+            ..Default::default()
+        }),
+    }
+}
+
+/// A tenant's route rules, or the fleet's for `None`
+async fn route_rules(conn: &mut SqliteConnection, tenant_id: Option<&str>) -> Result<Vec<RouteRule>> {
+    if let Some(id) = tenant_id {
+        let exists = sqlx::query_as::<_, (String,)>("SELECT id FROM tenants WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&mut *conn)
+            .await?;
+        if exists.is_none() {
+            return Err(anyhow!("Tenant not found: {}", id));
+        }
+    }
+    let rows = sqlx::query_as::<_, (String,)>(
+        "SELECT rule FROM route_rules WHERE tenant_id IS ? ORDER BY position",
+    )
+    .bind(tenant_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    rows.into_iter()
+        .map(|(rule,)| Ok(serde_json::from_str(&rule)?))
+        .collect()
+}
+
+async fn config_schema(conn: &mut SqliteConnection) -> Result<Option<ConfigSchema>> {
+    let row = sqlx::query_as::<_, (String,)>("SELECT value FROM settings WHERE key = 'config_schema'")
+        .fetch_optional(&mut *conn)
+        .await?;
+    row.map(|(schema,)| ConfigSchema::new(serde_json::from_str(&schema)?))
+        .transpose()
+}
+
+fn format_labels(labels: &Labels) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Reads shared by `Database` (on a pooled connection) and `Tx`
+
+/// Add a column to an existing table; `CREATE TABLE IF NOT EXISTS` won't
+async fn add_column_if_missing(
+    pool: &Pool<Sqlite>,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let columns = sqlx::query_as::<_, (String,)>(&format!(
+        "SELECT name FROM pragma_table_info('{}')",
+        table
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    if !columns.iter().any(|(name,)| name == column) {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn fetch_servers(conn: &mut SqliteConnection) -> Result<Vec<Server>> {
+    let rows = sqlx::query_as::<_, ServerRow>(&format!(
+        "{} GROUP BY s.id ORDER BY s.created_at",
+        SERVER_SELECT
+    ))
+    .fetch_all(conn)
+    .await?;
+
+    Ok(rows.into_iter().map(server_from_row).collect())
+}
+
+async fn fetch_server(conn: &mut SqliteConnection, id_or_name: &str) -> Result<Option<Server>> {
+    let row = sqlx::query_as::<_, ServerRow>(&format!(
+        "{} WHERE s.id = ? OR s.name = ? GROUP BY s.id",
+        SERVER_SELECT
+    ))
+    .bind(id_or_name)
+    .bind(id_or_name)
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(row.map(server_from_row))
+}
+
+async fn fetch_tenants(conn: &mut SqliteConnection) -> Result<Vec<Tenant>> {
+    let rows = sqlx::query_as::<_, TenantRow>(&format!(
+        "SELECT {} FROM tenants ORDER BY created_at",
+        TENANT_COLUMNS
+    ))
+    .fetch_all(conn)
+    .await?;
+
+    Ok(rows.into_iter().map(tenant_from_row).collect())
+}
+
+async fn fetch_tenant(conn: &mut SqliteConnection, id: &str) -> Result<Option<Tenant>> {
+    let row = sqlx::query_as::<_, TenantRow>(&format!(
+        "SELECT {} FROM tenants WHERE id = ?",
+        TENANT_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(row.map(tenant_from_row))
 }
 
 impl Database {
     pub async fn open(path: &str) -> Result<Self> {
-        let url = format!("sqlite:{}?mode=rwc", path);
+        let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", path))?
+            .create_if_missing(true)
+            .foreign_keys(true);
         let pool = SqlitePoolOptions::new()
             .max_connections(5)
-            .connect(&url)
+            .connect_with(options)
             .await?;
 
         // Run migrations
@@ -61,23 +967,1059 @@ impl Database {
         .execute(&pool)
         .await?;
 
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS domain_aliases (
-                domain TEXT PRIMARY KEY,
-                tenant_id TEXT NOT NULL REFERENCES tenants(id)
-            )
-            "#,
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS domain_aliases (
+                domain TEXT PRIMARY KEY,
+                tenant_id TEXT NOT NULL REFERENCES tenants(id)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        add_column_if_missing(&pool, "tenants", "provisioned", "INTEGER NOT NULL DEFAULT 0").await?;
+        add_column_if_missing(&pool, "tenants", "provision_error", "TEXT").await?;
+        add_column_if_missing(&pool, "servers", "labels", "TEXT NOT NULL DEFAULT '{}'").await?;
+        add_column_if_missing(&pool, "servers", "protocol", "TEXT NOT NULL DEFAULT 'http1'").await?;
+        add_column_if_missing(&pool, "tenants", "pinned", "INTEGER NOT NULL DEFAULT 0").await?;
+        add_column_if_missing(&pool, "tenants", "constraints", "TEXT NOT NULL DEFAULT '{}'")
+            .await?;
+        add_column_if_missing(&pool, "tenants", "limits", "TEXT NOT NULL DEFAULT '{}'").await?;
+        add_column_if_missing(&pool, "tenants", "balance", "TEXT NOT NULL DEFAULT 'round-robin'")
+            .await?;
+        add_column_if_missing(&pool, "tenants", "mirror", "TEXT").await?;
+        add_column_if_missing(&pool, "tenants", "http", "TEXT NOT NULL DEFAULT '{}'").await?;
+        add_column_if_missing(&pool, "tenants", "access", "TEXT NOT NULL DEFAULT '{}'").await?;
+
+        // Aliases are stored in lowercase so lookups can use the primary key.
+        // Rows from before that which only differ in case from another are
+        // dropped.
+        sqlx::query("UPDATE OR IGNORE domain_aliases SET domain = lower(domain) WHERE domain != lower(domain)")
+            .execute(&pool)
+            .await?;
+        sqlx::query("DELETE FROM domain_aliases WHERE domain != lower(domain)")
+            .execute(&pool)
+            .await?;
+
+        // Route rules, in order by position. Fleet rules have no tenant.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS route_rules (
+                tenant_id TEXT REFERENCES tenants(id),
+                position INTEGER NOT NULL,
+                rule TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Servers a tenant runs on besides its primary `server_id`
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tenant_placements (
+                tenant_id TEXT NOT NULL REFERENCES tenants(id),
+                server_id TEXT NOT NULL REFERENCES servers(id),
+                provisioned INTEGER NOT NULL DEFAULT 0,
+                provision_error TEXT,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (tenant_id, server_id)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        add_column_if_missing(&pool, "tenant_placements", "weight", "INTEGER").await?;
+
+        // Outbox of tenants to remove from servers. Rows are written in the same
+        // transaction that removes or moves the tenant, so nothing is lost on a crash.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS deprovisions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                tenant_id TEXT NOT NULL,
+                server_id TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Per-minute traffic rollups. Kept after a tenant is removed so usage
+        // can still be billed.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tenant_usage (
+                tenant_id TEXT NOT NULL,
+                minute INTEGER NOT NULL,
+                requests INTEGER NOT NULL,
+                bytes_in INTEGER NOT NULL,
+                bytes_out INTEGER NOT NULL,
+                status_4xx INTEGER NOT NULL,
+                status_5xx INTEGER NOT NULL,
+                p50_ms REAL NOT NULL,
+                p95_ms REAL NOT NULL,
+                PRIMARY KEY (tenant_id, minute)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS tenant_usage_minute ON tenant_usage (minute)")
+            .execute(&pool)
+            .await?;
+        // JSON `Latencies`. Rows from before it have only p50_ms and p95_ms.
+        add_column_if_missing(&pool, "tenant_usage", "latencies", "TEXT").await?;
+
+        // Fleet-wide settings, such as the tenant config schema
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Written by the proxy's circuit breakers so `slum status` can show them
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS server_health (
+                server_id TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                failures INTEGER NOT NULL,
+                last_error TEXT,
+                changed_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self {
+            pool,
+            write_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    // Transactions
+
+    /// Start a write transaction. Only one runs at a time on the database
+    /// file, across processes.
+    pub async fn begin(&self) -> Result<Tx> {
+        let guard = self.write_lock.clone().lock_owned().await;
+        let tx = Immediate::begin(&self.pool).await?;
+        Ok(Tx { tx, _guard: guard })
+    }
+
+    /// Run `f` in a transaction, committing if it returns `Ok` and rolling
+    /// back otherwise.
+    ///
+    /// ```ignore
+    /// db.transaction(|tx| Box::pin(async move {
+    ///     tx.add_server("server-1", "10.0.0.1:9000").await?;
+    ///     tx.add_tenant("romneys", Some("server-1"), None).await
+    /// }))
+    /// .await?;
+    /// ```
+    pub async fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: for<'t> FnOnce(&'t mut Tx) -> TxFuture<'t, T>,
+    {
+        let mut tx = self.begin().await?;
+        let value = f(&mut tx).await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+
+    // Server operations
+
+    pub async fn add_server(&self, name: &str, address: &str) -> Result<Server> {
+        let mut tx = self.begin().await?;
+        let server = tx.add_server(name, address).await?;
+        tx.commit().await?;
+        Ok(server)
+    }
+
+    pub async fn list_servers(&self) -> Result<Vec<Server>> {
+        fetch_servers(&mut *self.pool.acquire().await?).await
+    }
+
+    pub async fn get_server(&self, id_or_name: &str) -> Result<Option<Server>> {
+        fetch_server(&mut *self.pool.acquire().await?, id_or_name).await
+    }
+
+    pub async fn remove_server(&self, id_or_name: &str) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.remove_server(id_or_name).await?;
+        tx.commit().await
+    }
+
+    pub async fn update_server_address(&self, id_or_name: &str, address: &str) -> Result<Server> {
+        let mut tx = self.begin().await?;
+        let server = tx.update_server_address(id_or_name, address).await?;
+        tx.commit().await?;
+        Ok(server)
+    }
+
+    pub async fn set_server_protocol(&self, id_or_name: &str, protocol: Protocol) -> Result<Server> {
+        let mut tx = self.begin().await?;
+        let server = tx.set_server_protocol(id_or_name, protocol).await?;
+        tx.commit().await?;
+        Ok(server)
+    }
+
+    pub async fn set_server_labels(&self, id_or_name: &str, labels: &Labels) -> Result<Server> {
+        let mut tx = self.begin().await?;
+        let server = tx.set_server_labels(id_or_name, labels).await?;
+        tx.commit().await?;
+        Ok(server)
+    }
+
+    // Tenant operations
+
+    pub async fn add_tenant(
+        &self,
+        id: &str,
+        server_id_or_name: Option<&str>,
+        config: Option<&str>,
+    ) -> Result<Tenant> {
+        let mut tx = self.begin().await?;
+        let tenant = tx.add_tenant(id, server_id_or_name, config).await?;
+        tx.commit().await?;
+        Ok(tenant)
+    }
+
+    /// Add a tenant that may only be placed on servers carrying `constraints`
+    pub async fn add_tenant_with_constraints(
+        &self,
+        id: &str,
+        server_id_or_name: Option<&str>,
+        config: Option<&str>,
+        constraints: &Labels,
+    ) -> Result<Tenant> {
+        let mut tx = self.begin().await?;
+        let tenant = tx
+            .add_tenant_with_constraints(id, server_id_or_name, config, constraints)
+            .await?;
+        tx.commit().await?;
+        Ok(tenant)
+    }
+
+    pub async fn list_tenants(&self) -> Result<Vec<Tenant>> {
+        fetch_tenants(&mut *self.pool.acquire().await?).await
+    }
+
+    pub async fn get_tenant(&self, id: &str) -> Result<Option<Tenant>> {
+        fetch_tenant(&mut *self.pool.acquire().await?, id).await
+    }
+
+    pub async fn remove_tenant(&self, id: &str) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.remove_tenant(id).await?;
+        tx.commit().await
+    }
+
+    /// Replace a tenant's config, returning it as stored
+    pub async fn set_tenant_config(&self, id: &str, config: Option<&str>) -> Result<Option<serde_json::Value>> {
+        let mut tx = self.begin().await?;
+        let config = tx.set_tenant_config(id, config).await?;
+        tx.commit().await?;
+        Ok(config)
+    }
+
+    /// Apply a JSON Merge Patch to a tenant's config, returning the new config
+    pub async fn patch_tenant_config(&self, id: &str, patch: &serde_json::Value) -> Result<Option<serde_json::Value>> {
+        let mut tx = self.begin().await?;
+        let tenant = tx
+            .get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))?;
+        let mut config = match tenant.config.as_deref() {
+            Some(config) => serde_json::from_str(config)
+                .map_err(|_| anyhow!("Tenant {}'s config isn't JSON; set it instead of patching it", id))?,
+            None => serde_json::Value::Null,
+        };
+        merge_patch(&mut config, patch);
+        let config = (!config.is_null()).then(|| config.to_string());
+        let config = tx.set_tenant_config(id, config.as_deref()).await?;
+        tx.commit().await?;
+        Ok(config)
+    }
+
+    /// The schema tenant configs must match, if the fleet has one
+    pub async fn config_schema(&self) -> Result<Option<ConfigSchema>> {
+        let mut conn = self.pool.acquire().await?;
+        config_schema(&mut conn).await
+    }
+
+    /// Register the schema tenant configs must match, or remove it with
+    /// `None`. Configs already stored aren't changed; the tenants whose
+    /// configs don't match are returned with the reason.
+    pub async fn set_config_schema(&self, schema: Option<serde_json::Value>) -> Result<Vec<(String, String)>> {
+        let schema = schema.map(ConfigSchema::new).transpose()?;
+        let mut tx = self.begin().await?;
+        match &schema {
+            Some(schema) => {
+                sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('config_schema', ?)")
+                    .bind(schema.as_json().to_string())
+                    .execute(&mut *tx.tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM settings WHERE key = 'config_schema'")
+                    .execute(&mut *tx.tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+
+        let Some(schema) = schema else {
+            return Ok(Vec::new());
+        };
+        let mut invalid = Vec::new();
+        for tenant in self.list_tenants().await? {
+            let config = tenant
+                .config
+                .as_deref()
+                .map(|c| serde_json::from_str(c).map_err(|e| anyhow!("Config isn't valid JSON: {}", e)));
+            if let Some(Err(e)) = config.map(|c| c.and_then(|c| schema.validate(&c))) {
+                invalid.push((tenant.id, e.to_string()));
+            }
+        }
+        Ok(invalid)
+    }
+
+    pub async fn set_tenant_status(&self, id: &str, status: &str) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.set_tenant_status(id, status).await?;
+        tx.commit().await
+    }
+
+    /// Set how many servers a tenant runs on, and optionally how requests
+    /// are spread over them
+    pub async fn scale_tenant(
+        &self,
+        id: &str,
+        replicas: u32,
+        balance: Option<&LoadBalance>,
+    ) -> Result<Tenant> {
+        let mut tx = self.begin().await?;
+        if let Some(balance) = balance {
+            tx.set_tenant_balance(id, balance).await?;
+        }
+        let tenant = tx.scale_tenant(id, replicas).await?;
+        tx.commit().await?;
+        Ok(tenant)
+    }
+
+    pub async fn set_canary(&self, id: &str, server_id_or_name: &str, weight: u8) -> Result<Tenant> {
+        let mut tx = self.begin().await?;
+        let tenant = tx.set_canary(id, server_id_or_name, weight).await?;
+        tx.commit().await?;
+        Ok(tenant)
+    }
+
+    pub async fn remove_canary(&self, id: &str, server_id_or_name: &str) -> Result<Tenant> {
+        let mut tx = self.begin().await?;
+        let tenant = tx.remove_canary(id, server_id_or_name).await?;
+        tx.commit().await?;
+        Ok(tenant)
+    }
+
+    pub async fn promote_canary(&self, id: &str, server_id_or_name: &str) -> Result<Tenant> {
+        let mut tx = self.begin().await?;
+        let tenant = tx.promote_canary(id, server_id_or_name).await?;
+        tx.commit().await?;
+        Ok(tenant)
+    }
+
+    pub async fn set_tenant_pinned(&self, id: &str, pinned: bool) -> Result<()> {
+        let _write = self.write_lock.lock().await;
+        let result = sqlx::query("UPDATE tenants SET pinned = ? WHERE id = ?")
+            .bind(pinned)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("Tenant not found: {}", id));
+        }
+        Ok(())
+    }
+
+    pub async fn set_tenant_limits(&self, id: &str, limits: &Limits) -> Result<()> {
+        limits.validate()?;
+        let _write = self.write_lock.lock().await;
+        let result = sqlx::query("UPDATE tenants SET limits = ? WHERE id = ?")
+            .bind(serde_json::to_string(limits)?)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("Tenant not found: {}", id));
+        }
+        Ok(())
+    }
+
+    /// Replace a tenant's access policy, hashing any plain passwords in it
+    pub async fn set_tenant_access(&self, id: &str, policy: AccessPolicy) -> Result<AccessPolicy> {
+        let policy = policy.prepare()?;
+        let _write = self.write_lock.lock().await;
+        let result = sqlx::query("UPDATE tenants SET access = ? WHERE id = ?")
+            .bind(serde_json::to_string(&policy)?)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("Tenant not found: {}", id));
+        }
+        Ok(policy)
+    }
+
+    /// Turn the HTTP cache on or off for a tenant
+    pub async fn set_tenant_cache(&self, id: &str, enabled: bool) -> Result<()> {
+        self.set_tenant_http_option(id, "$.cache", enabled).await
+    }
+
+    /// Turn response compression on or off for a tenant
+    pub async fn set_tenant_compress(&self, id: &str, enabled: bool) -> Result<()> {
+        self.set_tenant_http_option(id, "$.compress", enabled).await
+    }
+
+    async fn set_tenant_http_option(&self, id: &str, path: &str, enabled: bool) -> Result<()> {
+        let _write = self.write_lock.lock().await;
+        let result = sqlx::query("UPDATE tenants SET http = json_set(http, ?, json(?)) WHERE id = ?")
+            .bind(path)
+            .bind(enabled.to_string())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("Tenant not found: {}", id));
+        }
+        Ok(())
+    }
+
+    /// Mirror `percent` of a tenant's requests with bodies of up to
+    /// `max_body_size` bytes to a server
+    pub async fn set_tenant_mirror(
+        &self,
+        id: &str,
+        server_id_or_name: &str,
+        percent: u8,
+        max_body_size: u64,
+    ) -> Result<Tenant> {
+        if !(1..=100).contains(&percent) {
+            return Err(anyhow!("Mirror percentage must be 1 to 100, got {}", percent));
+        }
+        let mut tx = self.begin().await?;
+        let server = tx
+            .get_server(server_id_or_name)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found: {}", server_id_or_name))?;
+        let tenant = tx
+            .get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))?;
+        if server.id == tenant.server_id {
+            return Err(anyhow!("Server {} is tenant {}'s primary", server.name, id));
+        }
+        if tenant.replicas.iter().any(|r| r.server_id == server.id) {
+            return Err(anyhow!("Server {} already has a replica of tenant {}", server.name, id));
+        }
+
+        let provisioned = match &tenant.mirror {
+            Some(old) if old.server_id == server.id => old.provisioned,
+            Some(old) => {
+                tx.queue_mirror_deprovision(&tenant, old).await?;
+                false
+            }
+            None => false,
+        };
+        tx.cancel_deprovision(id, &server.id).await?;
+        let mirror = Mirror {
+            server_id: server.id,
+            percent,
+            max_body_size,
+            provisioned,
+        };
+        sqlx::query("UPDATE tenants SET mirror = ? WHERE id = ?")
+            .bind(serde_json::to_string(&mirror)?)
+            .bind(id)
+            .execute(&mut *tx.tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Tenant {
+            mirror: Some(mirror),
+            ..tenant
+        })
+    }
+
+    /// Stop mirroring a tenant's requests, and queue its removal from the
+    /// mirror server
+    pub async fn clear_tenant_mirror(&self, id: &str) -> Result<()> {
+        let mut tx = self.begin().await?;
+        let tenant = tx
+            .get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))?;
+        if let Some(mirror) = &tenant.mirror {
+            tx.queue_mirror_deprovision(&tenant, mirror).await?;
+        }
+        sqlx::query("UPDATE tenants SET mirror = NULL WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx.tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Move a tenant to another server. Only the registry entry changes;
+    /// the tenant's data on the old server is left alone.
+    pub async fn move_tenant(&self, id: &str, server_id_or_name: &str) -> Result<Tenant> {
+        let mut tx = self.begin().await?;
+        let tenant = tx.move_tenant(id, server_id_or_name).await?;
+        tx.commit().await?;
+        Ok(tenant)
+    }
+
+    // Domain aliases
+
+    pub async fn add_domain_alias(&self, domain: &str, tenant_id: &str) -> Result<DomainAlias> {
+        let mut tx = self.begin().await?;
+        let alias = tx.add_domain_alias(domain, tenant_id).await?;
+        tx.commit().await?;
+        Ok(alias)
+    }
+
+    pub async fn list_domain_aliases(&self) -> Result<Vec<DomainAlias>> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            "SELECT domain, tenant_id FROM domain_aliases ORDER BY domain",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(domain, tenant_id)| DomainAlias { domain, tenant_id })
+            .collect())
+    }
+
+    pub async fn remove_domain_alias(&self, domain: &str) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.remove_domain_alias(domain).await?;
+        tx.commit().await
+    }
+
+    // Provisioning
+
+    pub async fn list_unprovisioned_tenants(&self) -> Result<Vec<Tenant>> {
+        let rows = sqlx::query_as::<_, TenantRow>(&format!(
+            r#"
+            SELECT {} FROM tenants
+            WHERE provisioned = 0
+                OR EXISTS (SELECT 1 FROM tenant_placements p WHERE p.tenant_id = tenants.id AND p.provisioned = 0)
+                OR (mirror IS NOT NULL AND NOT IFNULL(json_extract(mirror, '$.provisioned'), 0))
+            ORDER BY created_at
+            "#,
+            TENANT_COLUMNS
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(tenant_from_row).collect())
+    }
+
+    /// Mark a tenant provisioned, but only if its server and config are still
+    /// the ones that were sent. Returns false if the tenant changed meanwhile.
+    pub async fn mark_provisioned(
+        &self,
+        id: &str,
+        server_id: &str,
+        config: Option<&str>,
+    ) -> Result<bool> {
+        let _write = self.write_lock.lock().await;
+        let result = sqlx::query(
+            r#"
+            UPDATE tenants SET provisioned = 1, provision_error = NULL
+            WHERE id = ? AND server_id = ? AND config IS ?
+            "#,
+        )
+        .bind(id)
+        .bind(server_id)
+        .bind(config)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Mark a tenant's replica provisioned, if its config is still the one
+    /// that was sent and the replica hasn't been removed
+    pub async fn mark_replica_provisioned(
+        &self,
+        id: &str,
+        server_id: &str,
+        config: Option<&str>,
+    ) -> Result<bool> {
+        let _write = self.write_lock.lock().await;
+        let result = sqlx::query(
+            r#"
+            UPDATE tenant_placements SET provisioned = 1, provision_error = NULL
+            WHERE tenant_id = ? AND server_id = ?
+                AND (SELECT config FROM tenants WHERE id = ?) IS ?
+            "#,
+        )
+        .bind(id)
+        .bind(server_id)
+        .bind(id)
+        .bind(config)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Mark a tenant provisioned on its mirror server, if the mirror and
+    /// config are still the ones that were sent
+    pub async fn mark_mirror_provisioned(
+        &self,
+        id: &str,
+        server_id: &str,
+        config: Option<&str>,
+    ) -> Result<bool> {
+        let _write = self.write_lock.lock().await;
+        let result = sqlx::query(
+            r#"
+            UPDATE tenants SET mirror = json_set(mirror, '$.provisioned', json('true'))
+            WHERE id = ? AND json_extract(mirror, '$.server_id') = ? AND config IS ?
+            "#,
+        )
+        .bind(id)
+        .bind(server_id)
+        .bind(config)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Force a tenant to be provisioned again on all its servers, e.g. after
+    /// one of them lost it
+    pub async fn mark_unprovisioned(&self, id: &str) -> Result<()> {
+        let mut tx = self.begin().await?;
+        sqlx::query("UPDATE tenants SET provisioned = 0 WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx.tx)
+            .await?;
+        tx.mark_placements_unprovisioned(id).await?;
+        tx.commit().await
+    }
+
+    /// Queue removal of a tenant from a server it shouldn't be on
+    pub async fn queue_deprovision(&self, tenant_id: &str, server_id: &str) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.queue_deprovision(tenant_id, server_id).await?;
+        tx.commit().await
+    }
+
+    pub async fn record_replica_error(&self, id: &str, server_id: &str, error: &str) -> Result<()> {
+        let _write = self.write_lock.lock().await;
+        sqlx::query(
+            "UPDATE tenant_placements SET provision_error = ? WHERE tenant_id = ? AND server_id = ?",
+        )
+        .bind(error)
+        .bind(id)
+        .bind(server_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn record_provision_error(&self, id: &str, error: &str) -> Result<()> {
+        let _write = self.write_lock.lock().await;
+        sqlx::query("UPDATE tenants SET provision_error = ? WHERE id = ?")
+            .bind(error)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn list_deprovisions(&self) -> Result<Vec<Deprovision>> {
+        let rows = sqlx::query_as::<_, (i64, String, String, String, i32, Option<String>, String)>(
+            r#"
+            SELECT d.id, d.tenant_id, d.server_id, s.address, d.attempts, d.last_error, d.created_at
+            FROM deprovisions d
+            JOIN servers s ON s.id = d.server_id
+            ORDER BY d.id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(id, tenant_id, server_id, server_address, attempts, last_error, created_at)| {
+                    Deprovision {
+                        id,
+                        tenant_id,
+                        server_id,
+                        server_address,
+                        attempts,
+                        last_error,
+                        created_at,
+                    }
+                },
+            )
+            .collect())
+    }
+
+    pub async fn complete_deprovision(&self, id: i64) -> Result<()> {
+        let _write = self.write_lock.lock().await;
+        sqlx::query("DELETE FROM deprovisions WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn record_deprovision_error(&self, id: i64, error: &str) -> Result<()> {
+        let _write = self.write_lock.lock().await;
+        sqlx::query("UPDATE deprovisions SET attempts = attempts + 1, last_error = ? WHERE id = ?")
+            .bind(error)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // Usage rollups
+
+    /// Add per-minute rollups (`start` must be minute-aligned). A minute that
+    /// already has a row is merged into it. Percentiles are recomputed from
+    /// `latencies`.
+    pub async fn record_usage(&self, rollups: &[(String, Usage)]) -> Result<()> {
+        let mut tx = self.begin().await?;
+        for (tenant_id, usage) in rollups {
+            let mut latencies = usage.latencies.clone();
+            let stored = sqlx::query_as::<_, (Option<String>,)>(
+                "SELECT latencies FROM tenant_usage WHERE tenant_id = ? AND minute = ?",
+            )
+            .bind(tenant_id)
+            .bind(usage.start)
+            .fetch_optional(&mut *tx.tx)
+            .await?;
+            if let Some((Some(stored),)) = stored {
+                latencies.merge(&serde_json::from_str(&stored)?);
+            }
+
+            sqlx::query(
+                r#"
+                INSERT INTO tenant_usage
+                    (tenant_id, minute, requests, bytes_in, bytes_out, status_4xx, status_5xx,
+                     p50_ms, p95_ms, latencies)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT (tenant_id, minute) DO UPDATE SET
+                    requests = requests + excluded.requests,
+                    bytes_in = bytes_in + excluded.bytes_in,
+                    bytes_out = bytes_out + excluded.bytes_out,
+                    status_4xx = status_4xx + excluded.status_4xx,
+                    status_5xx = status_5xx + excluded.status_5xx,
+                    p50_ms = excluded.p50_ms,
+                    p95_ms = excluded.p95_ms,
+                    latencies = excluded.latencies
+                "#,
+            )
+            .bind(tenant_id)
+            .bind(usage.start)
+            .bind(usage.requests)
+            .bind(usage.bytes_in)
+            .bind(usage.bytes_out)
+            .bind(usage.status_4xx)
+            .bind(usage.status_5xx)
+            .bind(latencies.percentile(0.50))
+            .bind(latencies.percentile(0.95))
+            .bind(serde_json::to_string(&latencies)?)
+            .execute(&mut *tx.tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// A tenant's usage in `[from, to)` (Unix seconds), in `step`-second
+    /// intervals. Intervals without traffic are omitted.
+    pub async fn tenant_usage(
+        &self,
+        tenant_id: &str,
+        from: i64,
+        to: i64,
+        step: i64,
+    ) -> Result<Vec<Usage>> {
+        if step <= 0 || step % 60 != 0 {
+            return Err(anyhow!("Step must be a positive multiple of 60 seconds, got {}", step));
+        }
+
+        let rows = sqlx::query_as::<_, (i64, i64, i64, i64, i64, i64, f64, f64, Option<String>)>(
+            r#"
+            SELECT minute, requests, bytes_in, bytes_out, status_4xx, status_5xx,
+                   p50_ms, p95_ms, latencies
+            FROM tenant_usage
+            WHERE tenant_id = ? AND minute >= ? AND minute < ?
+            ORDER BY minute
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut intervals: Vec<Usage> = Vec::new();
+        for (minute, requests, bytes_in, bytes_out, status_4xx, status_5xx, p50_ms, p95_ms, latencies) in rows {
+            let usage = Usage {
+                start: minute - minute.rem_euclid(step),
+                requests,
+                bytes_in,
+                bytes_out,
+                status_4xx,
+                status_5xx,
+                p50_ms,
+                p95_ms,
+                latencies: latencies
+                    .map(|l| serde_json::from_str(&l))
+                    .transpose()?
+                    .unwrap_or_default(),
+            };
+            match intervals.last_mut() {
+                Some(last) if last.start == usage.start => last.add(&usage),
+                // A legacy row on its own keeps its stored percentiles
+                _ => intervals.push(usage),
+            }
+        }
+        Ok(intervals)
+    }
+
+    /// Requests per tenant in `[from, to)` (Unix seconds). Tenants without
+    /// traffic are omitted.
+    pub async fn request_counts(&self, from: i64, to: i64) -> Result<HashMap<String, u64>> {
+        let rows = sqlx::query_as::<_, (String, i64)>(
+            r#"
+            SELECT tenant_id, SUM(requests) FROM tenant_usage
+            WHERE minute >= ? AND minute < ?
+            GROUP BY tenant_id
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(tenant_id, requests)| (tenant_id, requests as u64))
+            .collect())
+    }
+
+    /// Delete rollups for minutes before `before` (Unix seconds)
+    pub async fn prune_usage(&self, before: i64) -> Result<u64> {
+        let _write = self.write_lock.lock().await;
+        let result = sqlx::query("DELETE FROM tenant_usage WHERE minute < ?")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Server health
+
+    pub async fn set_server_health(&self, health: &ServerHealth) -> Result<()> {
+        let _write = self.write_lock.lock().await;
+        sqlx::query(
+            r#"
+            INSERT INTO server_health (server_id, state, failures, last_error, changed_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (server_id) DO UPDATE SET
+                state = excluded.state,
+                failures = excluded.failures,
+                last_error = excluded.last_error,
+                changed_at = excluded.changed_at
+            "#,
+        )
+        .bind(&health.server_id)
+        .bind(&health.state)
+        .bind(health.failures)
+        .bind(&health.last_error)
+        .bind(&health.changed_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Health of servers still in the fleet
+    pub async fn list_server_health(&self) -> Result<Vec<ServerHealth>> {
+        let rows = sqlx::query_as::<_, (String, String, u32, Option<String>, String)>(
+            r#"
+            SELECT h.server_id, h.state, h.failures, h.last_error, h.changed_at
+            FROM server_health h
+            JOIN servers s ON s.id = h.server_id
+            ORDER BY s.created_at
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(server_id, state, failures, last_error, changed_at)| ServerHealth {
+                server_id,
+                state,
+                failures,
+                last_error,
+                changed_at,
+            })
+            .collect())
+    }
+
+    /// Forget all server health, when a proxy starts with every circuit closed
+    pub async fn clear_server_health(&self) -> Result<()> {
+        let _write = self.write_lock.lock().await;
+        sqlx::query("DELETE FROM server_health")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Routing lookup
+
+    pub async fn lookup_tenant(&self, tenant_id: &str) -> Result<Option<(Tenant, Server)>> {
+        let mut conn = self.pool.acquire().await?;
+        let tenant = match fetch_tenant(&mut conn, tenant_id).await? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        let server = fetch_server(&mut conn, &tenant.server_id)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found for tenant: {}", tenant_id))?;
+
+        Ok(Some((tenant, server)))
+    }
+
+    /// A tenant with all its servers, the primary first
+    pub async fn lookup_tenant_servers(&self, tenant_id: &str) -> Result<Option<(Tenant, Vec<Server>)>> {
+        let mut conn = self.pool.acquire().await?;
+        let tenant = match fetch_tenant(&mut conn, tenant_id).await? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        let mut servers = Vec::with_capacity(1 + tenant.replicas.len());
+        let ids = std::iter::once(&tenant.server_id).chain(tenant.replicas.iter().map(|r| &r.server_id));
+        for id in ids {
+            let server = fetch_server(&mut conn, id)
+                .await?
+                .ok_or_else(|| anyhow!("Server not found for tenant: {}", tenant_id))?;
+            servers.push(server);
+        }
+
+        Ok(Some((tenant, servers)))
+    }
+
+    /// The rules the proxy applies to a tenant's requests: the fleet's,
+    /// then the tenant's own
+    pub async fn tenant_route_rules(&self, tenant_id: &str) -> Result<Vec<RouteRule>> {
+        let rows = sqlx::query_as::<_, (String,)>(
+            "SELECT rule FROM route_rules WHERE tenant_id IS NULL OR tenant_id = ? \
+             ORDER BY tenant_id IS NOT NULL, position",
         )
-        .execute(&pool)
+        .bind(tenant_id)
+        .fetch_all(&self.pool)
         .await?;
+        rows.into_iter()
+            .map(|(rule,)| Ok(serde_json::from_str(&rule)?))
+            .collect()
+    }
+
+    /// A tenant's own route rules, or the fleet's for `None`
+    pub async fn route_rules(&self, tenant_id: Option<&str>) -> Result<Vec<RouteRule>> {
+        let mut conn = self.pool.acquire().await?;
+        route_rules(&mut conn, tenant_id).await
+    }
+
+    /// Replace a tenant's route rules, or the fleet's for `None`
+    pub async fn set_route_rules(&self, tenant_id: Option<&str>, rules: &[RouteRule]) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.set_route_rules(tenant_id, rules).await?;
+        tx.commit().await
+    }
+
+    /// Insert a route rule at `position`, or at the end, returning the rules
+    pub async fn add_route_rule(
+        &self,
+        tenant_id: Option<&str>,
+        rule: RouteRule,
+        position: Option<usize>,
+    ) -> Result<Vec<RouteRule>> {
+        let mut tx = self.begin().await?;
+        let mut rules = tx.route_rules(tenant_id).await?;
+        let position = position.unwrap_or(rules.len());
+        if position > rules.len() {
+            return Err(anyhow!("Position {} is past the end of {} rules", position, rules.len()));
+        }
+        rules.insert(position, rule);
+        tx.set_route_rules(tenant_id, &rules).await?;
+        tx.commit().await?;
+        Ok(rules)
+    }
+
+    /// Remove the route rule at `position`, returning it
+    pub async fn remove_route_rule(&self, tenant_id: Option<&str>, position: usize) -> Result<RouteRule> {
+        let mut tx = self.begin().await?;
+        let mut rules = tx.route_rules(tenant_id).await?;
+        if position >= rules.len() {
+            return Err(anyhow!("No rule at position {} ({} rules)", position, rules.len()));
+        }
+        let rule = rules.remove(position);
+        tx.set_route_rules(tenant_id, &rules).await?;
+        tx.commit().await?;
+        Ok(rule)
+    }
+
+    pub async fn lookup_by_domain(&self, domain: &str) -> Result<Option<String>> {
+        let row = sqlx::query_as::<_, (String,)>("SELECT tenant_id FROM domain_aliases WHERE domain = ?")
+            .bind(domain.to_ascii_lowercase())
+            .fetch_optional(&self.pool)
+            .await?;
 
-        Ok(Self { pool })
+        Ok(row.map(|(tenant_id,)| tenant_id))
+    }
+}
+
+impl Tx {
+    pub async fn commit(self) -> Result<()> {
+        self.tx.commit().await
     }
 
     // Server operations
 
-    pub async fn add_server(&self, name: &str, address: &str) -> Result<Server> {
+    pub async fn add_server(&mut self, name: &str, address: &str) -> Result<Server> {
         let id = uuid::Uuid::new_v4().to_string();
         let now = chrono::Utc::now().to_rfc3339();
 
@@ -88,7 +2030,7 @@ impl Database {
         .bind(name)
         .bind(address)
         .bind(&now)
-        .execute(&self.pool)
+        .execute(&mut *self.tx)
         .await?;
 
         Ok(Server {
@@ -97,69 +2039,20 @@ impl Database {
             address: address.to_string(),
             tenant_count: 0,
             created_at: now,
+            labels: Labels::new(),
+            protocol: Protocol::default(),
         })
     }
 
-    pub async fn list_servers(&self) -> Result<Vec<Server>> {
-        let rows = sqlx::query_as::<_, (String, String, String, String)>(
-            r#"
-            SELECT s.id, s.name, s.address, s.created_at
-            FROM servers s
-            ORDER BY s.created_at
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await?;
-
-        let mut servers = Vec::new();
-        for (id, name, address, created_at) in rows {
-            let count: (i32,) = sqlx::query_as("SELECT COUNT(*) FROM tenants WHERE server_id = ?")
-                .bind(&id)
-                .fetch_one(&self.pool)
-                .await?;
-
-            servers.push(Server {
-                id,
-                name,
-                address,
-                tenant_count: count.0,
-                created_at,
-            });
-        }
-
-        Ok(servers)
+    pub async fn list_servers(&mut self) -> Result<Vec<Server>> {
+        fetch_servers(&mut self.tx).await
     }
 
-    pub async fn get_server(&self, id_or_name: &str) -> Result<Option<Server>> {
-        let row = sqlx::query_as::<_, (String, String, String, String)>(
-            "SELECT id, name, address, created_at FROM servers WHERE id = ? OR name = ?",
-        )
-        .bind(id_or_name)
-        .bind(id_or_name)
-        .fetch_optional(&self.pool)
-        .await?;
-
-        match row {
-            Some((id, name, address, created_at)) => {
-                let count: (i32,) =
-                    sqlx::query_as("SELECT COUNT(*) FROM tenants WHERE server_id = ?")
-                        .bind(&id)
-                        .fetch_one(&self.pool)
-                        .await?;
-
-                Ok(Some(Server {
-                    id,
-                    name,
-                    address,
-                    tenant_count: count.0,
-                    created_at,
-                }))
-            }
-            None => Ok(None),
-        }
+    pub async fn get_server(&mut self, id_or_name: &str) -> Result<Option<Server>> {
+        fetch_server(&mut self.tx, id_or_name).await
     }
 
-    pub async fn remove_server(&self, id_or_name: &str) -> Result<()> {
+    pub async fn remove_server(&mut self, id_or_name: &str) -> Result<()> {
         let server = self
             .get_server(id_or_name)
             .await?
@@ -172,134 +2065,617 @@ impl Database {
             ));
         }
 
+        // Nothing left to deprovision on a server that is leaving the fleet
+        sqlx::query("DELETE FROM deprovisions WHERE server_id = ?")
+            .bind(&server.id)
+            .execute(&mut *self.tx)
+            .await?;
+        sqlx::query("UPDATE tenants SET mirror = NULL WHERE json_extract(mirror, '$.server_id') = ?")
+            .bind(&server.id)
+            .execute(&mut *self.tx)
+            .await?;
+
         sqlx::query("DELETE FROM servers WHERE id = ?")
             .bind(&server.id)
-            .execute(&self.pool)
+            .execute(&mut *self.tx)
             .await?;
 
         Ok(())
     }
 
+    pub async fn update_server_address(
+        &mut self,
+        id_or_name: &str,
+        address: &str,
+    ) -> Result<Server> {
+        let server = self
+            .get_server(id_or_name)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found: {}", id_or_name))?;
+
+        sqlx::query("UPDATE servers SET address = ? WHERE id = ?")
+            .bind(address)
+            .bind(&server.id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        Ok(Server {
+            address: address.to_string(),
+            ..server
+        })
+    }
+
+    pub async fn set_server_protocol(&mut self, id_or_name: &str, protocol: Protocol) -> Result<Server> {
+        let server = self
+            .get_server(id_or_name)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found: {}", id_or_name))?;
+
+        sqlx::query("UPDATE servers SET protocol = ? WHERE id = ?")
+            .bind(protocol.to_string())
+            .bind(&server.id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        Ok(Server { protocol, ..server })
+    }
+
+    pub async fn set_server_labels(&mut self, id_or_name: &str, labels: &Labels) -> Result<Server> {
+        let server = self
+            .get_server(id_or_name)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found: {}", id_or_name))?;
+
+        sqlx::query("UPDATE servers SET labels = ? WHERE id = ?")
+            .bind(serde_json::to_string(labels)?)
+            .bind(&server.id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        Ok(Server {
+            labels: labels.clone(),
+            ..server
+        })
+    }
+
     // Tenant operations
 
     pub async fn add_tenant(
-        &self,
+        &mut self,
+        id: &str,
+        server_id_or_name: Option<&str>,
+        config: Option<&str>,
+    ) -> Result<Tenant> {
+        self.add_tenant_with_constraints(id, server_id_or_name, config, &Labels::new())
+            .await
+    }
+
+    pub async fn add_tenant_with_constraints(
+        &mut self,
         id: &str,
         server_id_or_name: Option<&str>,
         config: Option<&str>,
+        constraints: &Labels,
     ) -> Result<Tenant> {
+        validate_tenant_id(id)?;
+        let config = self.check_config(config).await?.map(|c| c.to_string());
+        let config = config.as_deref();
+
         // Find server (specified or pick one with least tenants)
         let server = match server_id_or_name {
-            Some(s) => self
-                .get_server(s)
-                .await?
-                .ok_or_else(|| anyhow!("Server not found: {}", s))?,
+            Some(s) => {
+                let server = self
+                    .get_server(s)
+                    .await?
+                    .ok_or_else(|| anyhow!("Server not found: {}", s))?;
+                if !server.satisfies(constraints) {
+                    return Err(anyhow!(
+                        "Server {} does not have labels {}",
+                        server.name,
+                        format_labels(constraints)
+                    ));
+                }
+                server
+            }
             None => {
                 // Pick server with least tenants
                 let servers = self.list_servers().await?;
+                if servers.is_empty() {
+                    return Err(anyhow!("No servers available. Add a server first."));
+                }
                 servers
                     .into_iter()
+                    .filter(|s| s.satisfies(constraints))
                     .min_by_key(|s| s.tenant_count)
-                    .ok_or_else(|| anyhow!("No servers available. Add a server first."))?
+                    .ok_or_else(|| {
+                        anyhow!("No servers have labels {}", format_labels(constraints))
+                    })?
             }
         };
 
         let now = chrono::Utc::now().to_rfc3339();
 
         sqlx::query(
-            "INSERT INTO tenants (id, server_id, config, status, created_at) VALUES (?, ?, ?, 'active', ?)",
+            "INSERT INTO tenants (id, server_id, config, status, created_at, constraints) VALUES (?, ?, ?, 'active', ?, ?)",
         )
         .bind(id)
         .bind(&server.id)
         .bind(config)
         .bind(&now)
-        .execute(&self.pool)
+        .bind(serde_json::to_string(constraints)?)
+        .execute(&mut *self.tx)
         .await?;
 
+        // Provisioning overwrites the tenant on this server, so a pending
+        // deprovision from an earlier removal must not run after it
+        self.cancel_deprovision(id, &server.id).await?;
+
         Ok(Tenant {
             id: id.to_string(),
             server_id: server.id,
             config: config.map(|s| s.to_string()),
             status: "active".to_string(),
             created_at: now,
+            provisioned: false,
+            provision_error: None,
+            pinned: false,
+            constraints: constraints.clone(),
+            limits: Limits::default(),
+            replicas: Vec::new(),
+            balance: LoadBalance::default(),
+            mirror: None,
+            http: HttpOptions::default(),
+            access: AccessPolicy::default(),
         })
     }
 
-    pub async fn list_tenants(&self) -> Result<Vec<Tenant>> {
-        let rows = sqlx::query_as::<_, (String, String, Option<String>, String, String)>(
-            "SELECT id, server_id, config, status, created_at FROM tenants ORDER BY created_at",
-        )
-        .fetch_all(&self.pool)
-        .await?;
+    pub async fn get_tenant(&mut self, id: &str) -> Result<Option<Tenant>> {
+        fetch_tenant(&mut self.tx, id).await
+    }
 
-        Ok(rows
-            .into_iter()
-            .map(|(id, server_id, config, status, created_at)| Tenant {
-                id,
-                server_id,
-                config,
+    pub async fn remove_tenant(&mut self, id: &str) -> Result<()> {
+        if let Some(tenant) = self.get_tenant(id).await? {
+            self.queue_deprovision(id, &tenant.server_id).await?;
+            for replica in &tenant.replicas {
+                self.queue_deprovision(id, &replica.server_id).await?;
+            }
+            if let Some(mirror) = &tenant.mirror {
+                self.queue_mirror_deprovision(&tenant, mirror).await?;
+            }
+        }
+
+        sqlx::query("DELETE FROM tenant_placements WHERE tenant_id = ?")
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        sqlx::query("DELETE FROM domain_aliases WHERE tenant_id = ?")
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        sqlx::query("DELETE FROM route_rules WHERE tenant_id = ?")
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        sqlx::query("DELETE FROM tenants WHERE id = ?")
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn route_rules(&mut self, tenant_id: Option<&str>) -> Result<Vec<RouteRule>> {
+        route_rules(&mut self.tx, tenant_id).await
+    }
+
+    pub async fn set_route_rules(&mut self, tenant_id: Option<&str>, rules: &[RouteRule]) -> Result<()> {
+        for (i, rule) in rules.iter().enumerate() {
+            rule.validate()
+                .map_err(|e| anyhow!("Rule {}: {}", i, e))?;
+        }
+        if let Some(id) = tenant_id {
+            if self.get_tenant(id).await?.is_none() {
+                return Err(anyhow!("Tenant not found: {}", id));
+            }
+        }
+        sqlx::query("DELETE FROM route_rules WHERE tenant_id IS ?")
+            .bind(tenant_id)
+            .execute(&mut *self.tx)
+            .await?;
+        for (position, rule) in rules.iter().enumerate() {
+            sqlx::query("INSERT INTO route_rules (tenant_id, position, rule) VALUES (?, ?, ?)")
+                .bind(tenant_id)
+                .bind(position as i64)
+                .bind(serde_json::to_string(rule)?)
+                .execute(&mut *self.tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Replace a tenant's config, returning it as stored
+    pub async fn set_tenant_config(&mut self, id: &str, config: Option<&str>) -> Result<Option<serde_json::Value>> {
+        let config = self.check_config(config).await?;
+        let result = sqlx::query("UPDATE tenants SET config = ?, provisioned = 0 WHERE id = ?")
+            .bind(config.as_ref().map(|c| c.to_string()))
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("Tenant not found: {}", id));
+        }
+
+        self.mark_placements_unprovisioned(id).await?;
+        Ok(config)
+    }
+
+    /// Mark a tenant's replicas and mirror unprovisioned
+    async fn mark_placements_unprovisioned(&mut self, id: &str) -> Result<()> {
+        sqlx::query("UPDATE tenant_placements SET provisioned = 0 WHERE tenant_id = ?")
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+        sqlx::query("UPDATE tenants SET mirror = json_set(mirror, '$.provisioned', json('false')) WHERE id = ?")
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Parse a config and check it against the fleet's schema, if any
+    async fn check_config(&mut self, config: Option<&str>) -> Result<Option<serde_json::Value>> {
+        let config = parse_config(config)?;
+        if let (Some(config), Some(schema)) = (&config, config_schema(&mut self.tx).await?) {
+            schema.validate(config)?;
+        }
+        Ok(config)
+    }
+
+    pub async fn set_tenant_status(&mut self, id: &str, status: &str) -> Result<()> {
+        if !TENANT_STATUSES.contains(&status) {
+            return Err(anyhow!(
+                "Invalid tenant status: {} (expected one of: {})",
                 status,
-                created_at,
-            })
-            .collect())
+                TENANT_STATUSES.join(", ")
+            ));
+        }
+
+        let result = sqlx::query("UPDATE tenants SET status = ? WHERE id = ?")
+            .bind(status)
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("Tenant not found: {}", id));
+        }
+        Ok(())
     }
 
-    pub async fn get_tenant(&self, id: &str) -> Result<Option<Tenant>> {
-        let row = sqlx::query_as::<_, (String, String, Option<String>, String, String)>(
-            "SELECT id, server_id, config, status, created_at FROM tenants WHERE id = ?",
+    pub async fn move_tenant(&mut self, id: &str, server_id_or_name: &str) -> Result<Tenant> {
+        let server = self
+            .get_server(server_id_or_name)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found: {}", server_id_or_name))?;
+        let tenant = self
+            .get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))?;
+
+        if tenant.server_id == server.id {
+            return Ok(tenant);
+        }
+        if tenant.replicas.iter().any(|r| r.server_id == server.id) {
+            return Err(anyhow!(
+                "Tenant {} already has a replica on server {}",
+                id,
+                server.name
+            ));
+        }
+        if !server.satisfies(&tenant.constraints) {
+            return Err(anyhow!(
+                "Server {} does not have labels {} required by tenant {}",
+                server.name,
+                format_labels(&tenant.constraints),
+                id
+            ));
+        }
+
+        sqlx::query("UPDATE tenants SET server_id = ?, provisioned = 0 WHERE id = ?")
+            .bind(&server.id)
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        self.queue_deprovision(id, &tenant.server_id).await?;
+        self.cancel_deprovision(id, &server.id).await?;
+
+        self.get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))
+    }
+
+    /// Place a tenant on `replicas` servers in all, adding the least loaded
+    /// servers that satisfy its constraints or removing the newest replicas
+    pub async fn scale_tenant(&mut self, id: &str, replicas: u32) -> Result<Tenant> {
+        if replicas == 0 {
+            return Err(anyhow!("A tenant needs at least 1 replica"));
+        }
+        let tenant = self
+            .get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))?;
+        let wanted = replicas as usize - 1;
+        let current: Vec<&Replica> = tenant.replicas.iter().filter(|r| !r.is_canary()).collect();
+
+        if wanted > current.len() {
+            let mut candidates: Vec<Server> = self
+                .list_servers()
+                .await?
+                .into_iter()
+                .filter(|s| {
+                    s.id != tenant.server_id
+                        && !tenant.replicas.iter().any(|r| r.server_id == s.id)
+                        && s.satisfies(&tenant.constraints)
+                })
+                .collect();
+            let needed = wanted - current.len();
+            if candidates.len() < needed {
+                return Err(anyhow!(
+                    "Not enough servers for {} replicas of tenant {} ({} available)",
+                    replicas,
+                    id,
+                    1 + current.len() + candidates.len()
+                ));
+            }
+            candidates.sort_by_key(|s| s.tenant_count);
+
+            let now = chrono::Utc::now().to_rfc3339();
+            for server in candidates.into_iter().take(needed) {
+                sqlx::query(
+                    "INSERT INTO tenant_placements (tenant_id, server_id, created_at) VALUES (?, ?, ?)",
+                )
+                .bind(id)
+                .bind(&server.id)
+                .bind(&now)
+                .execute(&mut *self.tx)
+                .await?;
+                self.cancel_deprovision(id, &server.id).await?;
+            }
+        } else {
+            for replica in current.iter().skip(wanted).rev() {
+                sqlx::query("DELETE FROM tenant_placements WHERE tenant_id = ? AND server_id = ?")
+                    .bind(id)
+                    .bind(&replica.server_id)
+                    .execute(&mut *self.tx)
+                    .await?;
+                self.queue_deprovision(id, &replica.server_id).await?;
+            }
+        }
+
+        self.get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))
+    }
+
+    /// Place a tenant on a canary server that gets `weight` percent of its
+    /// traffic, or change the weight of an existing canary
+    pub async fn set_canary(&mut self, id: &str, server_id_or_name: &str, weight: u8) -> Result<Tenant> {
+        let server = self
+            .get_server(server_id_or_name)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found: {}", server_id_or_name))?;
+        let tenant = self
+            .get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))?;
+
+        if server.id == tenant.server_id {
+            return Err(anyhow!("Server {} is tenant {}'s primary", server.name, id));
+        }
+        let existing = tenant.replicas.iter().find(|r| r.server_id == server.id);
+        if existing.is_some_and(|r| !r.is_canary()) {
+            return Err(anyhow!(
+                "Tenant {} already has a replica on server {}",
+                id,
+                server.name
+            ));
+        }
+        if !server.satisfies(&tenant.constraints) {
+            return Err(anyhow!(
+                "Server {} does not have labels {} required by tenant {}",
+                server.name,
+                format_labels(&tenant.constraints),
+                id
+            ));
+        }
+        let total: u32 = tenant
+            .replicas
+            .iter()
+            .filter(|r| r.server_id != server.id)
+            .filter_map(|r| r.weight)
+            .map(u32::from)
+            .sum::<u32>()
+            + weight as u32;
+        if total > 100 {
+            return Err(anyhow!(
+                "Canary weights for tenant {} would add up to {}%",
+                id,
+                total
+            ));
+        }
+
+        if existing.is_some() {
+            sqlx::query("UPDATE tenant_placements SET weight = ? WHERE tenant_id = ? AND server_id = ?")
+                .bind(weight)
+                .bind(id)
+                .bind(&server.id)
+                .execute(&mut *self.tx)
+                .await?;
+        } else {
+            sqlx::query(
+                "INSERT INTO tenant_placements (tenant_id, server_id, weight, created_at) VALUES (?, ?, ?, ?)",
+            )
+            .bind(id)
+            .bind(&server.id)
+            .bind(weight)
+            .bind(chrono::Utc::now().to_rfc3339())
+            .execute(&mut *self.tx)
+            .await?;
+            self.cancel_deprovision(id, &server.id).await?;
+        }
+
+        self.get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))
+    }
+
+    /// Take a tenant off a canary server
+    pub async fn remove_canary(&mut self, id: &str, server_id_or_name: &str) -> Result<Tenant> {
+        let (_, server) = self.canary(id, server_id_or_name).await?;
+        sqlx::query("DELETE FROM tenant_placements WHERE tenant_id = ? AND server_id = ?")
+            .bind(id)
+            .bind(&server.id)
+            .execute(&mut *self.tx)
+            .await?;
+        self.queue_deprovision(id, &server.id).await?;
+
+        self.get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))
+    }
+
+    /// Make a canary the tenant's primary server, removing the tenant from
+    /// the old primary
+    pub async fn promote_canary(&mut self, id: &str, server_id_or_name: &str) -> Result<Tenant> {
+        let (tenant, server) = self.canary(id, server_id_or_name).await?;
+        let canary = tenant
+            .replicas
+            .iter()
+            .find(|r| r.server_id == server.id)
+            .expect("canary() checked the placement");
+
+        sqlx::query("DELETE FROM tenant_placements WHERE tenant_id = ? AND server_id = ?")
+            .bind(id)
+            .bind(&server.id)
+            .execute(&mut *self.tx)
+            .await?;
+        sqlx::query(
+            "UPDATE tenants SET server_id = ?, provisioned = ?, provision_error = ? WHERE id = ?",
         )
+        .bind(&server.id)
+        .bind(canary.provisioned)
+        .bind(&canary.provision_error)
         .bind(id)
-        .fetch_optional(&self.pool)
+        .execute(&mut *self.tx)
         .await?;
+        self.queue_deprovision(id, &tenant.server_id).await?;
 
-        Ok(row.map(|(id, server_id, config, status, created_at)| Tenant {
-            id,
-            server_id,
-            config,
-            status,
-            created_at,
-        }))
+        self.get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))
     }
 
-    pub async fn remove_tenant(&self, id: &str) -> Result<()> {
-        sqlx::query("DELETE FROM domain_aliases WHERE tenant_id = ?")
+    /// A tenant and one of its canary servers
+    async fn canary(&mut self, id: &str, server_id_or_name: &str) -> Result<(Tenant, Server)> {
+        let server = self
+            .get_server(server_id_or_name)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found: {}", server_id_or_name))?;
+        let tenant = self
+            .get_tenant(id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant not found: {}", id))?;
+        if !tenant
+            .replicas
+            .iter()
+            .any(|r| r.server_id == server.id && r.is_canary())
+        {
+            return Err(anyhow!("Tenant {} has no canary on server {}", id, server.name));
+        }
+        Ok((tenant, server))
+    }
+
+    pub async fn set_tenant_balance(&mut self, id: &str, balance: &LoadBalance) -> Result<()> {
+        let result = sqlx::query("UPDATE tenants SET balance = ? WHERE id = ?")
+            .bind(balance.to_string())
             .bind(id)
-            .execute(&self.pool)
+            .execute(&mut *self.tx)
             .await?;
 
-        sqlx::query("DELETE FROM tenants WHERE id = ?")
-            .bind(id)
-            .execute(&self.pool)
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("Tenant not found: {}", id));
+        }
+        Ok(())
+    }
+
+    // Domain aliases
+
+    pub async fn add_domain_alias(&mut self, domain: &str, tenant_id: &str) -> Result<DomainAlias> {
+        if self.get_tenant(tenant_id).await?.is_none() {
+            return Err(anyhow!("Tenant not found: {}", tenant_id));
+        }
+        // Hostnames are case-insensitive, and the proxy looks them up in lowercase
+        let domain = domain.to_ascii_lowercase();
+        let domain = domain.as_str();
+
+        sqlx::query("INSERT INTO domain_aliases (domain, tenant_id) VALUES (?, ?)")
+            .bind(domain)
+            .bind(tenant_id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        Ok(DomainAlias {
+            domain: domain.to_string(),
+            tenant_id: tenant_id.to_string(),
+        })
+    }
+
+    pub async fn remove_domain_alias(&mut self, domain: &str) -> Result<()> {
+        sqlx::query("DELETE FROM domain_aliases WHERE domain = ?")
+            .bind(domain.to_ascii_lowercase())
+            .execute(&mut *self.tx)
             .await?;
 
         Ok(())
     }
 
-    // Routing lookup
+    // Provisioning outbox
 
-    pub async fn lookup_tenant(&self, tenant_id: &str) -> Result<Option<(Tenant, Server)>> {
-        let tenant = match self.get_tenant(tenant_id).await? {
-            Some(t) => t,
-            None => return Ok(None),
-        };
+    async fn queue_deprovision(&mut self, tenant_id: &str, server_id: &str) -> Result<()> {
+        sqlx::query("INSERT INTO deprovisions (tenant_id, server_id, created_at) VALUES (?, ?, ?)")
+            .bind(tenant_id)
+            .bind(server_id)
+            .bind(chrono::Utc::now().to_rfc3339())
+            .execute(&mut *self.tx)
+            .await?;
 
-        let server = self
-            .get_server(&tenant.server_id)
-            .await?
-            .ok_or_else(|| anyhow!("Server not found for tenant: {}", tenant_id))?;
+        Ok(())
+    }
 
-        Ok(Some((tenant, server)))
+    /// Queue removal from a mirror server, unless the tenant has since been
+    /// placed there
+    async fn queue_mirror_deprovision(&mut self, tenant: &Tenant, mirror: &Mirror) -> Result<()> {
+        let placed = mirror.server_id == tenant.server_id
+            || tenant.replicas.iter().any(|r| r.server_id == mirror.server_id);
+        if placed {
+            return Ok(());
+        }
+        self.queue_deprovision(&tenant.id, &mirror.server_id).await
     }
 
-    pub async fn lookup_by_domain(&self, domain: &str) -> Result<Option<String>> {
-        let row = sqlx::query_as::<_, (String,)>(
-            "SELECT tenant_id FROM domain_aliases WHERE domain = ?",
-        )
-        .bind(domain)
-        .fetch_optional(&self.pool)
-        .await?;
+    async fn cancel_deprovision(&mut self, tenant_id: &str, server_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM deprovisions WHERE tenant_id = ? AND server_id = ?")
+            .bind(tenant_id)
+            .bind(server_id)
+            .execute(&mut *self.tx)
+            .await?;
 
-        Ok(row.map(|(tenant_id,)| tenant_id))
+        Ok(())
     }
 }
 
@@ -458,4 +2834,735 @@ mod tests {
         let not_found = db.lookup_tenant("nonexistent").await.unwrap();
         assert!(not_found.is_none());
     }
+
+    #[tokio::test]
+    async fn test_move_tenant() {
+        let db = test_db().await;
+
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        let s2 = db.add_server("server-2", "10.0.0.2:9000").await.unwrap();
+        db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+
+        let moved = db.move_tenant("romneys", "server-2").await.unwrap();
+        assert_eq!(moved.server_id, s2.id);
+
+        // Unknown tenant or server
+        assert!(db.move_tenant("nonexistent", "server-2").await.is_err());
+        assert!(db.move_tenant("romneys", "nonexistent").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_domain_aliases() {
+        let db = test_db().await;
+
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+
+        db.add_domain_alias("romneys.com", "romneys").await.unwrap();
+        assert_eq!(
+            db.lookup_by_domain("romneys.com").await.unwrap().as_deref(),
+            Some("romneys")
+        );
+        assert_eq!(db.list_domain_aliases().await.unwrap().len(), 1);
+
+        // Alias for unknown tenant
+        assert!(db.add_domain_alias("smiths.com", "smiths").await.is_err());
+
+        db.remove_domain_alias("romneys.com").await.unwrap();
+        assert!(db.lookup_by_domain("romneys.com").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lowercases_stored_aliases() {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Database::open(&path).await.unwrap();
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+        db.add_tenant("smiths", None, None).await.unwrap();
+        db.add_domain_alias("Smiths.com", "smiths").await.unwrap();
+        // As stored before aliases were lowercased
+        for domain in ["Romneys.COM", "SMITHS.com"] {
+            sqlx::query("INSERT INTO domain_aliases (domain, tenant_id) VALUES (?, 'romneys')")
+                .bind(domain)
+                .execute(&db.pool)
+                .await
+                .unwrap();
+        }
+
+        let db = Database::open(&path).await.unwrap();
+        let domains: Vec<_> = db.list_domain_aliases().await.unwrap().into_iter().map(|a| a.domain).collect();
+        assert_eq!(domains, ["romneys.com", "smiths.com"]);
+        assert_eq!(db.lookup_by_domain("ROMNEYS.com").await.unwrap().as_deref(), Some("romneys"));
+        assert_eq!(db.lookup_by_domain("smiths.com").await.unwrap().as_deref(), Some("smiths"));
+        db.remove_domain_alias("Romneys.com").await.unwrap();
+        assert!(db.lookup_by_domain("romneys.com").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_set_tenant_status() {
+        let db = test_db().await;
+
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+
+        db.set_tenant_status("romneys", "suspended").await.unwrap();
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert_eq!(tenant.status, "suspended");
+
+        assert!(db.set_tenant_status("romneys", "bogus").await.is_err());
+        assert!(db.set_tenant_status("nonexistent", "active").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rolls_back_on_error() {
+        let db = test_db().await;
+
+        let result: Result<()> = db
+            .transaction(|tx| {
+                Box::pin(async move {
+                    tx.add_server("server-1", "10.0.0.1:9000").await?;
+                    tx.add_tenant("romneys", Some("nonexistent"), None).await?;
+                    Ok(())
+                })
+            })
+            .await;
+        assert!(result.is_err());
+
+        // The server added before the failure was rolled back
+        assert!(db.list_servers().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_tx_rolls_back() {
+        let db = test_db().await;
+
+        {
+            let mut tx = db.begin().await.unwrap();
+            tx.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        }
+
+        assert!(db.list_servers().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transactions_across_processes() {
+        // Two `Database`s on one file, as `slum serve` and the CLI
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let server = Database::open(&path).await.unwrap();
+        let cli = Database::open(&path).await.unwrap();
+        server.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+
+        // Reads, then writes after the other process has tried to
+        let mut tx = server.begin().await.unwrap();
+        assert!(tx.get_tenant("romneys").await.unwrap().is_none());
+        let other = tokio::spawn(async move { cli.add_tenant("smiths", None, None).await });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        tx.add_tenant("romneys", None, None).await.unwrap();
+        tx.commit().await.unwrap();
+        other.await.unwrap().unwrap();
+        assert_eq!(server.list_tenants().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_foreign_keys_enforced() {
+        let db = test_db().await;
+
+        let result = sqlx::query(
+            "INSERT INTO tenants (id, server_id, status, created_at) VALUES ('romneys', 'nonexistent', 'active', '')",
+        )
+        .execute(&db.pool)
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_tenant_removes_aliases() {
+        let db = test_db().await;
+
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+        db.add_domain_alias("romneys.com", "romneys").await.unwrap();
+
+        db.remove_tenant("romneys").await.unwrap();
+        assert!(db.list_domain_aliases().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_add_tenant_balances() {
+        let db = test_db().await;
+
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_server("server-2", "10.0.0.2:9000").await.unwrap();
+        db.add_server("server-3", "10.0.0.3:9000").await.unwrap();
+
+        let handles: Vec<_> = (0..30)
+            .map(|i| {
+                let db = db.clone();
+                tokio::spawn(async move { db.add_tenant(&format!("tenant-{}", i), None, None).await })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        let servers = db.list_servers().await.unwrap();
+        let counts: Vec<i32> = servers.iter().map(|s| s.tenant_count).collect();
+        assert_eq!(counts, vec![10, 10, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_remove_and_move_queue_deprovisions() {
+        let db = test_db().await;
+
+        let s1 = db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_server("server-2", "10.0.0.2:9000").await.unwrap();
+        let tenant = db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+        assert!(!tenant.provisioned);
+
+        // Moving queues removal from the old server
+        db.move_tenant("romneys", "server-2").await.unwrap();
+        let pending = db.list_deprovisions().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].server_id, s1.id);
+        assert_eq!(pending[0].server_address, "10.0.0.1:9000");
+
+        // Moving back cancels it, since provisioning overwrites the tenant
+        db.move_tenant("romneys", "server-1").await.unwrap();
+        let pending = db.list_deprovisions().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].server_address, "10.0.0.2:9000");
+
+        db.remove_tenant("romneys").await.unwrap();
+        assert_eq!(db.list_deprovisions().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mark_provisioned_checks_current_state() {
+        let db = test_db().await;
+
+        let server = db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, Some(r#"{"a":1}"#)).await.unwrap();
+
+        // Config changed after it was sent
+        db.set_tenant_config("romneys", Some(r#"{"a":2}"#)).await.unwrap();
+        assert!(!db
+            .mark_provisioned("romneys", &server.id, Some(r#"{"a":1}"#))
+            .await
+            .unwrap());
+
+        assert!(db
+            .mark_provisioned("romneys", &server.id, Some(r#"{"a":2}"#))
+            .await
+            .unwrap());
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert!(tenant.provisioned);
+        assert!(db.list_unprovisioned_tenants().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_placement_respects_constraints() {
+        let db = test_db().await;
+
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        let s2 = db.add_server("server-2", "10.0.0.2:9000").await.unwrap();
+        let labels = Labels::from([("region".to_string(), "eu".to_string())]);
+        db.set_server_labels("server-2", &labels).await.unwrap();
+
+        // server-1 is emptier, but only server-2 is in the EU
+        db.add_tenant("smiths", Some("server-2"), None).await.unwrap();
+        let tenant = db
+            .add_tenant_with_constraints("romneys", None, None, &labels)
+            .await
+            .unwrap();
+        assert_eq!(tenant.server_id, s2.id);
+        assert_eq!(
+            db.get_tenant("romneys").await.unwrap().unwrap().constraints,
+            labels
+        );
+
+        // Explicit placement and moves are checked too
+        assert!(db
+            .add_tenant_with_constraints("jones", Some("server-1"), None, &labels)
+            .await
+            .is_err());
+        assert!(db.move_tenant("romneys", "server-1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_usage_rollups() {
+        let db = test_db().await;
+        let usage = |start, requests, ms| {
+            let mut latencies = Latencies::default();
+            for _ in 0..requests {
+                latencies.record(ms);
+            }
+            Usage {
+                start,
+                requests,
+                bytes_in: requests * 10,
+                bytes_out: requests * 100,
+                status_4xx: 1,
+                status_5xx: 0,
+                p50_ms: 0.0,
+                p95_ms: 0.0,
+                latencies,
+            }
+        };
+        let close = |actual: f64, expected: f64| (actual - expected).abs() <= expected * 0.05;
+
+        db.record_usage(&[
+            ("romneys".into(), usage(3600, 1, 10.0)),
+            ("romneys".into(), usage(3660, 3, 30.0)),
+            ("smiths".into(), usage(3600, 5, 50.0)),
+        ])
+        .await
+        .unwrap();
+        // A late flush for the same minute merges into it
+        db.record_usage(&[("romneys".into(), usage(3600, 1, 20.0))])
+            .await
+            .unwrap();
+
+        let minutes = db.tenant_usage("romneys", 0, 7200, 60).await.unwrap();
+        assert_eq!(minutes.len(), 2);
+        assert_eq!(minutes[0].requests, 2);
+        assert_eq!(minutes[0].status_4xx, 2);
+        assert!(close(minutes[0].p50_ms, 10.0));
+        assert!(close(minutes[0].p95_ms, 20.0));
+
+        // Percentiles of the hour, not averages of the minutes'
+        let hours = db.tenant_usage("romneys", 0, 7200, 3600).await.unwrap();
+        assert_eq!(hours.len(), 1);
+        assert_eq!(hours[0].start, 3600);
+        assert_eq!(hours[0].requests, 5);
+        assert_eq!((hours[0].bytes_in, hours[0].bytes_out), (50, 500));
+        assert_eq!(hours[0].status_4xx, 3);
+        assert!(close(hours[0].p50_ms, 30.0));
+        assert!(close(hours[0].p95_ms, 30.0));
+        assert_eq!(hours[0].latencies.count(), 5);
+
+        assert!(db.tenant_usage("romneys", 0, 7200, 90).await.is_err());
+
+        assert_eq!(db.prune_usage(3660).await.unwrap(), 2);
+        assert_eq!(db.tenant_usage("romneys", 0, 7200, 60).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_limits() {
+        let db = test_db().await;
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+
+        let limits = Limits {
+            rate: Some(10.0),
+            burst: None,
+            max_concurrent: Some(5),
+            max_body_size: None,
+        };
+        db.set_tenant_limits("romneys", &limits).await.unwrap();
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert_eq!(tenant.limits, limits);
+
+        let default = Limits {
+            rate: Some(100.0),
+            burst: Some(200),
+            max_concurrent: None,
+            max_body_size: Some(1 << 20),
+        };
+        assert_eq!(
+            tenant.limits.or(default),
+            Limits {
+                rate: Some(10.0),
+                burst: Some(200),
+                max_concurrent: Some(5),
+                max_body_size: Some(1 << 20),
+            }
+        );
+
+        let invalid = Limits {
+            rate: Some(-1.0),
+            ..Default::default()
+        };
+        assert!(db.set_tenant_limits("romneys", &invalid).await.is_err());
+        assert!(db.set_tenant_limits("smiths", &limits).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_health() {
+        let db = test_db().await;
+        let server = db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+
+        let mut health = ServerHealth {
+            server_id: server.id.clone(),
+            state: "open".to_string(),
+            failures: 5,
+            last_error: Some("connection refused".to_string()),
+            changed_at: chrono::Utc::now().to_rfc3339(),
+        };
+        db.set_server_health(&health).await.unwrap();
+        health.state = "half_open".to_string();
+        db.set_server_health(&health).await.unwrap();
+        assert_eq!(db.list_server_health().await.unwrap(), vec![health.clone()]);
+
+        // Removed servers are left out
+        db.remove_server("server-1").await.unwrap();
+        assert!(db.list_server_health().await.unwrap().is_empty());
+
+        db.clear_server_health().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scale_tenant() {
+        let db = test_db().await;
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_server("server-2", "10.0.0.2:9000").await.unwrap();
+        let s3 = db.add_server("server-3", "10.0.0.3:9000").await.unwrap();
+        db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+        db.add_tenant("smiths", Some("server-2"), None).await.unwrap();
+
+        // The least loaded server is added first
+        let tenant = db.scale_tenant("romneys", 2, None).await.unwrap();
+        assert_eq!(tenant.replicas.len(), 1);
+        assert_eq!(tenant.replicas[0].server_id, s3.id);
+        assert!(!tenant.replicas[0].provisioned);
+
+        let balance = LoadBalance::CookieHash("session".to_string());
+        let tenant = db.scale_tenant("romneys", 3, Some(&balance)).await.unwrap();
+        assert_eq!(tenant.replicas.len(), 2);
+        assert_eq!(tenant.balance, balance);
+        assert!(db.scale_tenant("romneys", 4, None).await.is_err());
+        assert!(db.scale_tenant("romneys", 0, None).await.is_err());
+
+        let (_, servers) = db.lookup_tenant_servers("romneys").await.unwrap().unwrap();
+        let names: Vec<_> = servers.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["server-1", "server-3", "server-2"]);
+
+        // Replicas count toward servers' tenants and can't be moved onto
+        let s3 = db.get_server("server-3").await.unwrap().unwrap();
+        assert_eq!(s3.tenant_count, 1);
+        assert!(db.remove_server("server-3").await.is_err());
+        assert!(db.move_tenant("romneys", "server-3").await.is_err());
+
+        // Scaling down removes the newest replicas and deprovisions them
+        let tenant = db.scale_tenant("romneys", 2, None).await.unwrap();
+        assert_eq!(tenant.replicas[0].server_id, s3.id);
+        let deprovisions = db.list_deprovisions().await.unwrap();
+        assert_eq!(deprovisions.len(), 1);
+        assert_eq!(deprovisions[0].server_address, "10.0.0.2:9000");
+
+        db.remove_tenant("romneys").await.unwrap();
+        assert_eq!(db.list_deprovisions().await.unwrap().len(), 3);
+        assert_eq!(db.get_server("server-3").await.unwrap().unwrap().tenant_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_canary() {
+        let db = test_db().await;
+        let s1 = db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        let s2 = db.add_server("server-2", "10.0.0.2:9000").await.unwrap();
+        db.add_server("server-3", "10.0.0.3:9000").await.unwrap();
+        db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+        db.scale_tenant("romneys", 2, None).await.unwrap();
+
+        // Canaries sit beside regular replicas, which scaling leaves alone
+        let tenant = db.set_canary("romneys", "server-3", 10).await.unwrap();
+        assert_eq!(tenant.replicas.len(), 2);
+        assert_eq!(tenant.replicas[1].weight, Some(10));
+        let tenant = db.scale_tenant("romneys", 2, None).await.unwrap();
+        assert_eq!(tenant.replicas.len(), 2);
+
+        // Weights can change but not pass 100%, and only canaries are canaries
+        let tenant = db.set_canary("romneys", "server-3", 40).await.unwrap();
+        assert_eq!(tenant.replicas[1].weight, Some(40));
+        assert!(db.set_canary("romneys", "server-3", 101).await.is_err());
+        assert!(db.set_canary("romneys", "server-1", 10).await.is_err());
+        assert!(db.set_canary("romneys", "server-2", 10).await.is_err());
+        assert!(db.remove_canary("romneys", "server-2").await.is_err());
+        assert!(db.promote_canary("romneys", "server-1").await.is_err());
+
+        // Promoting swaps the primary and deprovisions the old one
+        let tenant = db.promote_canary("romneys", "server-3").await.unwrap();
+        assert_eq!(tenant.server_id, db.get_server("server-3").await.unwrap().unwrap().id);
+        assert_eq!(tenant.replicas.len(), 1);
+        assert_eq!(tenant.replicas[0].server_id, s2.id);
+        let deprovisions = db.list_deprovisions().await.unwrap();
+        assert_eq!(deprovisions.len(), 1);
+        assert_eq!(deprovisions[0].server_address, "10.0.0.1:9000");
+
+        let tenant = db.set_canary("romneys", &s1.id, 5).await.unwrap();
+        assert!(tenant.replicas.iter().any(|r| r.server_id == s1.id && r.is_canary()));
+        assert!(db.list_deprovisions().await.unwrap().is_empty());
+        let tenant = db.remove_canary("romneys", "server-1").await.unwrap();
+        assert_eq!(tenant.replicas.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_http_options() {
+        let db = test_db().await;
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert_eq!(tenant.http, HttpOptions::default());
+
+        db.set_tenant_cache("romneys", true).await.unwrap();
+        db.set_tenant_compress("romneys", true).await.unwrap();
+        db.set_tenant_cache("romneys", false).await.unwrap();
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert_eq!(
+            tenant.http,
+            HttpOptions {
+                cache: false,
+                compress: true,
+            }
+        );
+        assert!(db.set_tenant_cache("smiths", true).await.is_err());
+    }
+
+    #[test]
+    fn test_validate_tenant_id() {
+        for valid in ["romneys", "smith-family", "a", "2024-reunion", "xn--bcher-kva", &"a".repeat(63)] {
+            assert!(validate_tenant_id(valid).is_ok(), "{}", valid);
+        }
+        for (invalid, reason) in [
+            ("", "empty"),
+            (&"a".repeat(64) as &str, "longer than 63"),
+            ("Romneys", "lowercase"),
+            ("the.romneys", "'.' isn't allowed"),
+            ("the_romneys", "'_' isn't allowed"),
+            ("café", "'é' isn't allowed"),
+            ("-romneys", "hyphen"),
+            ("romneys-", "hyphen"),
+            ("ab--cd", "punycode"),
+            ("www", "reserved"),
+            ("api", "reserved"),
+        ] {
+            let err = validate_tenant_id(invalid).unwrap_err().to_string();
+            assert!(err.contains(reason), "{}: {}", invalid, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_tenant_checks_id() {
+        let db = test_db().await;
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        let err = db.add_tenant("The_Romneys", None, None).await.unwrap_err();
+        assert_eq!(err.to_string(), r#"Invalid tenant ID "The_Romneys": use lowercase letters"#);
+        assert!(db.add_tenant("admin", None, None).await.is_err());
+        assert!(db.list_tenants().await.unwrap().is_empty());
+
+        db.add_tenant("romneys", None, None).await.unwrap();
+        db.add_domain_alias("Romneys.Example.COM", "romneys").await.unwrap();
+        assert_eq!(
+            db.lookup_by_domain("romneys.example.com").await.unwrap().as_deref(),
+            Some("romneys")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tenant_access() {
+        let db = test_db().await;
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert!(tenant.access.is_open());
+
+        let policy: AccessPolicy = serde_json::from_value(serde_json::json!({
+            "allow": ["10.0.0.0/8"],
+            "users": [{"username": "qa", "password": "hunter2"}],
+            "forward_auth": "http://auth.internal:4180/verify",
+        }))
+        .unwrap();
+        let stored = db.set_tenant_access("romneys", policy).await.unwrap();
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert_eq!(tenant.access, stored);
+        assert_eq!(tenant.access.users[0].password, None);
+        assert!(tenant.access.users[0].verify("hunter2"));
+        assert!(!tenant.access.users[0].verify("hunter3"));
+        assert!(!serde_json::to_string(&tenant.access).unwrap().contains("hunter2"));
+
+        // A stored hash can be given back as is
+        let again = db.set_tenant_access("romneys", tenant.access.clone()).await.unwrap();
+        assert_eq!(again, tenant.access);
+
+        for invalid in [
+            serde_json::json!({"users": [{"username": "qa"}]}),
+            serde_json::json!({"users": [{"username": "q:a", "password": "x"}]}),
+            serde_json::json!({"users": [{"username": "qa", "password_hash": "$2b$12$notargon"}]}),
+            serde_json::json!({"users": [{"username": "qa", "password": "x"}, {"username": "qa", "password": "y"}]}),
+            serde_json::json!({"forward_auth": "ftp://auth.internal/verify"}),
+            serde_json::json!({"forward_auth": "/verify"}),
+        ] {
+            let policy = serde_json::from_value(invalid.clone()).unwrap();
+            assert!(db.set_tenant_access("romneys", policy).await.is_err(), "{}", invalid);
+        }
+        assert!(serde_json::from_value::<AccessPolicy>(serde_json::json!({"allow": ["10.0.0.0/33"]})).is_err());
+        assert!(db.set_tenant_access("smiths", AccessPolicy::default()).await.is_err());
+
+        db.set_tenant_access("romneys", AccessPolicy::default()).await.unwrap();
+        assert!(db.get_tenant("romneys").await.unwrap().unwrap().access.is_open());
+    }
+
+    #[tokio::test]
+    async fn test_tenant_config() {
+        let db = test_db().await;
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, Some(r#"{ "plan": "free" }"#)).await.unwrap();
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert_eq!(tenant.config.as_deref(), Some(r#"{"plan":"free"}"#));
+        assert_eq!(serde_json::json!(tenant)["config"], serde_json::json!({"plan": "free"}));
+
+        for invalid in ["{plan: free}", "[1, 2]", r#""pro""#] {
+            assert!(db.add_tenant("smiths", None, Some(invalid)).await.is_err(), "{}", invalid);
+            assert!(db.set_tenant_config("romneys", Some(invalid)).await.is_err(), "{}", invalid);
+        }
+
+        let config = db
+            .patch_tenant_config("romneys", &serde_json::json!({"plan": "pro", "seats": 5}))
+            .await
+            .unwrap();
+        assert_eq!(config, Some(serde_json::json!({"plan": "pro", "seats": 5})));
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert!(!tenant.provisioned);
+        assert_eq!(tenant.config_json(), config);
+        let config = db
+            .patch_tenant_config("romneys", &serde_json::json!({"seats": null}))
+            .await
+            .unwrap();
+        assert_eq!(config, Some(serde_json::json!({"plan": "pro"})));
+        assert!(db.patch_tenant_config("smiths", &serde_json::json!({})).await.is_err());
+
+        // Existing configs are reported, not changed, when a schema arrives
+        db.add_tenant("smiths", None, Some(r#"{"plan": "team"}"#)).await.unwrap();
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"plan": {"enum": ["free", "pro"]}, "seats": {"type": "integer", "minimum": 1}},
+            "required": ["plan"],
+        });
+        let invalid = db.set_config_schema(Some(schema.clone())).await.unwrap();
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].0, "smiths");
+        assert_eq!(db.config_schema().await.unwrap().unwrap().as_json(), &schema);
+
+        assert!(db.set_tenant_config("romneys", Some(r#"{"plan": "team"}"#)).await.is_err());
+        assert!(db.patch_tenant_config("romneys", &serde_json::json!({"seats": 0})).await.is_err());
+        db.patch_tenant_config("romneys", &serde_json::json!({"seats": 2})).await.unwrap();
+        assert!(db.add_tenant("joneses", None, Some("{}")).await.is_err());
+        db.add_tenant("joneses", None, None).await.unwrap();
+        assert!(db.set_config_schema(Some(serde_json::json!({"pattern": "^a"}))).await.is_err());
+
+        db.set_config_schema(None).await.unwrap();
+        assert!(db.config_schema().await.unwrap().is_none());
+        db.set_tenant_config("romneys", None).await.unwrap();
+        assert_eq!(db.get_tenant("romneys").await.unwrap().unwrap().config_json(), None);
+    }
+
+    #[tokio::test]
+    async fn test_route_rules() {
+        let db = test_db().await;
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+        db.add_tenant("smiths", None, None).await.unwrap();
+        let args = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+
+        db.add_route_rule(None, RouteRule::ForceHttps, None).await.unwrap();
+        let redirect = RouteRule::parse(&args("redirect /old/* /new 301")).unwrap();
+        let header = RouteRule::parse(&args("remove-header Server")).unwrap();
+        db.add_route_rule(Some("romneys"), header.clone(), None).await.unwrap();
+        let rules = db.add_route_rule(Some("romneys"), redirect.clone(), Some(0)).await.unwrap();
+        assert_eq!(rules, vec![redirect.clone(), header.clone()]);
+        assert_eq!(
+            db.tenant_route_rules("romneys").await.unwrap(),
+            vec![RouteRule::ForceHttps, redirect.clone(), header.clone()]
+        );
+        assert_eq!(db.tenant_route_rules("smiths").await.unwrap(), vec![RouteRule::ForceHttps]);
+
+        // Rules round-trip through JSON and their command-line form
+        let json = serde_json::to_string(&redirect).unwrap();
+        assert_eq!(json, r#"{"action":"redirect","from":"/old/*","to":"/new","status":301}"#);
+        let rule: RouteRule = serde_json::from_str(r#"{"action":"redirect","from":"/a","to":"/b"}"#).unwrap();
+        assert_eq!(rule.to_string(), "redirect /a /b 302");
+        assert_eq!(RouteRule::parse(&args(&redirect.to_string())).unwrap(), redirect);
+
+        // Invalid rules are rejected whole
+        for invalid in [
+            "redirect old /new",
+            "redirect /old /new 200",
+            "redirect /old ftp://x",
+            "rewrite /a b",
+            "set-header Content-Length 5",
+            "set-header X-Bad\u{1} x",
+            "remove-header",
+            "proxy /a http://x",
+        ] {
+            assert!(RouteRule::parse(&args(invalid)).is_err(), "{}", invalid);
+        }
+        let invalid = RouteRule::Rewrite {
+            from: "/a".to_string(),
+            to: "b".to_string(),
+        };
+        assert!(db.set_route_rules(Some("romneys"), &[header.clone(), invalid]).await.is_err());
+        assert_eq!(db.route_rules(Some("romneys")).await.unwrap().len(), 2);
+        assert!(db.set_route_rules(Some("jones"), &[RouteRule::ForceHttps]).await.is_err());
+        assert!(db.route_rules(Some("jones")).await.is_err());
+
+        assert_eq!(db.remove_route_rule(Some("romneys"), 0).await.unwrap(), redirect);
+        assert!(db.remove_route_rule(Some("romneys"), 1).await.is_err());
+        assert!(db.add_route_rule(None, header.clone(), Some(5)).await.is_err());
+        db.set_route_rules(None, &[]).await.unwrap();
+        db.remove_tenant("romneys").await.unwrap();
+        assert!(db.tenant_route_rules("romneys").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tenant_mirror() {
+        let db = test_db().await;
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        let s2 = db.add_server("server-2", "10.0.0.2:9000").await.unwrap();
+        db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+
+        assert!(db.set_tenant_mirror("romneys", "server-1", 100, 1024).await.is_err());
+        assert!(db.set_tenant_mirror("romneys", "server-2", 0, 1024).await.is_err());
+        assert!(db.set_tenant_mirror("romneys", "server-3", 10, 1024).await.is_err());
+        db.set_tenant_mirror("romneys", "server-2", 10, 1024).await.unwrap();
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert_eq!(
+            tenant.mirror,
+            Some(Mirror {
+                server_id: s2.id,
+                percent: 10,
+                max_body_size: 1024,
+                provisioned: false,
+            })
+        );
+        assert_eq!(db.list_unprovisioned_tenants().await.unwrap().len(), 1);
+
+        // Mirrors don't hold on to servers
+        db.remove_server("server-2").await.unwrap();
+        assert!(db.get_tenant("romneys").await.unwrap().unwrap().mirror.is_none());
+        db.clear_tenant_mirror("romneys").await.unwrap();
+        assert!(db.clear_tenant_mirror("smiths").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_protocol() {
+        let db = test_db().await;
+        let server = db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        assert_eq!(server.protocol, Protocol::Http1);
+
+        db.set_server_protocol("server-1", Protocol::Http2).await.unwrap();
+        let server = db.get_server("server-1").await.unwrap().unwrap();
+        assert_eq!(server.protocol, Protocol::Http2);
+        assert!(db.set_server_protocol("server-2", Protocol::Http2).await.is_err());
+
+        assert_eq!("http2".parse::<Protocol>().unwrap(), Protocol::Http2);
+        assert!("h3".parse::<Protocol>().is_err());
+    }
+
+    #[test]
+    fn test_load_balance_parse() {
+        for s in ["round-robin", "least-conn", "client-hash", "cookie-hash:sid"] {
+            assert_eq!(s.parse::<LoadBalance>().unwrap().to_string(), s);
+        }
+        assert!("random".parse::<LoadBalance>().is_err());
+        assert!("cookie-hash:".parse::<LoadBalance>().is_err());
+    }
 }
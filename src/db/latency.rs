@@ -0,0 +1,111 @@
+//! Latency histograms for usage rollups
+//!
+//! Percentiles of separate minutes can't be combined into the percentile of
+//! the hour, so rollups keep a histogram per minute and percentiles are read
+//! off the merged histogram. Bucket bounds grow by 10%, so a percentile is
+//! within 5% of the exact one.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Upper bound of the first bucket, in milliseconds
+const BASE_MS: f64 = 0.1;
+
+/// Ratio between consecutive bucket bounds
+const GROWTH: f64 = 1.1;
+
+/// The last bucket, from about 18 minutes up
+const MAX_BUCKET: u16 = 170;
+
+/// Request counts per latency bucket. Empty buckets aren't stored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Latencies(BTreeMap<u16, u64>);
+
+impl Latencies {
+    pub fn record(&mut self, ms: f64) {
+        *self.0.entry(bucket(ms)).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: &Latencies) {
+        for (&bucket, &count) in &other.0 {
+            *self.0.entry(bucket).or_default() += count;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.values().sum()
+    }
+
+    /// Nearest-rank percentile, as the geometric middle of its bucket
+    pub fn percentile(&self, p: f64) -> f64 {
+        let total = self.count();
+        if total == 0 {
+            return 0.0;
+        }
+        let rank = ((p * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (&bucket, &count) in &self.0 {
+            seen += count;
+            if seen >= rank {
+                return BASE_MS * GROWTH.powf(bucket as f64 - 0.5);
+            }
+        }
+        unreachable!("rank is at most the total count")
+    }
+}
+
+fn bucket(ms: f64) -> u16 {
+    if ms <= BASE_MS {
+        return 0;
+    }
+    ((ms / BASE_MS).ln() / GROWTH.ln())
+        .ceil()
+        .min(MAX_BUCKET as f64) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(actual: f64, expected: f64) -> bool {
+        (actual - expected).abs() <= expected * 0.05
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut latencies = Latencies::default();
+        for ms in 1..=100 {
+            latencies.record(ms as f64);
+        }
+        assert!(close(latencies.percentile(0.50), 50.0));
+        assert!(close(latencies.percentile(0.95), 95.0));
+        assert_eq!(Latencies::default().percentile(0.5), 0.0);
+
+        // Out of range latencies land in the end buckets
+        latencies.record(0.0);
+        latencies.record(1e9);
+        assert_eq!(latencies.count(), 102);
+    }
+
+    #[test]
+    fn test_merge_is_exact() {
+        // A fast minute and a slow one: averaging their medians gives 55ms,
+        // but the median of all requests is the fast minute's slowest
+        let (mut fast, mut slow) = (Latencies::default(), Latencies::default());
+        for _ in 0..98 {
+            fast.record(10.0);
+        }
+        fast.record(12.0);
+        for _ in 0..99 {
+            slow.record(100.0);
+        }
+        fast.merge(&slow);
+        assert_eq!(fast.count(), 198);
+        assert!(close(fast.percentile(0.50), 12.0));
+        assert!(close(fast.percentile(0.95), 100.0));
+
+        let json = serde_json::to_string(&fast).unwrap();
+        assert_eq!(serde_json::from_str::<Latencies>(&json).unwrap(), fast);
+    }
+}
@@ -0,0 +1,492 @@
+//! JSON Schema for tenant configs
+//!
+//! Supports the common core of JSON Schema (draft 7 through 2020-12):
+//! `type`, `enum`, `const`, the numeric, string length, array and object
+//! keywords, `allOf`/`anyOf`/`oneOf`/`not`, and `$ref` to definitions in
+//! the same schema. Keywords it doesn't know, such as `pattern`, are
+//! refused when the schema is registered rather than silently ignored.
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+/// Keywords that only describe, and never fail validation
+const ANNOTATIONS: &[&str] = &[
+    "$schema", "$id", "$comment", "title", "description", "default", "examples", "deprecated",
+    "readOnly", "writeOnly", "format",
+];
+
+/// Errors reported for one config, at most
+const MAX_ERRORS: usize = 10;
+
+/// `$ref`s followed in a row before a schema is taken to be cyclic
+const MAX_DEPTH: usize = 64;
+
+/// Subschemas visited checking one config, at most. `$ref`s under
+/// `allOf`/`anyOf`/`oneOf` can make the work exponential in the schema's size.
+const MAX_VISITS: usize = 100_000;
+
+/// A validated schema tenant configs are checked against
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigSchema(Value);
+
+impl ConfigSchema {
+    /// A schema, if it is one: an object or boolean using only supported
+    /// keywords, with `$ref`s that resolve
+    pub fn new(schema: Value) -> Result<Self> {
+        check(&schema, &schema, "#")?;
+        Ok(ConfigSchema(schema))
+    }
+
+    pub fn as_json(&self) -> &Value {
+        &self.0
+    }
+
+    /// Check a config, with every way it fails (up to a limit) in the error
+    pub fn validate(&self, config: &Value) -> Result<()> {
+        let mut errors = Vec::new();
+        let mut budget = MAX_VISITS;
+        validate(&self.0, &self.0, config, "", 0, &mut budget, &mut errors);
+        if budget == 0 {
+            return Err(anyhow!("Config is too costly to check against the fleet's schema"));
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.truncate(MAX_ERRORS);
+        Err(anyhow!("Config doesn't match the fleet's schema: {}", errors.join("; ")))
+    }
+}
+
+/// Check that `schema`, found at `at` in `root`, is one we can validate with
+fn check(root: &Value, schema: &Value, at: &str) -> Result<()> {
+    let object = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(object) => object,
+        _ => return Err(anyhow!("Schema at {} must be an object or boolean", at)),
+    };
+    let subschema = |key: &str, value: &Value| check(root, value, &format!("{}/{}", at, key));
+    let count = |key: &str, value: &Value| match value.as_u64() {
+        Some(_) => Ok(()),
+        None => Err(anyhow!("{} at {} must be a non-negative integer", key, at)),
+    };
+    let number = |key: &str, value: &Value| match value.as_f64() {
+        Some(_) => Ok(()),
+        None => Err(anyhow!("{} at {} must be a number", key, at)),
+    };
+
+    for (key, value) in object {
+        match key.as_str() {
+            k if ANNOTATIONS.contains(&k) => {}
+            "$defs" | "definitions" | "properties" => {
+                let Value::Object(schemas) = value else {
+                    return Err(anyhow!("{} at {} must be an object", key, at));
+                };
+                for (name, schema) in schemas {
+                    check(root, schema, &format!("{}/{}/{}", at, key, name))?;
+                }
+            }
+            "type" => {
+                let types: Vec<&Value> = match value {
+                    Value::Array(types) => types.iter().collect(),
+                    other => vec![other],
+                };
+                for t in types {
+                    if !matches!(
+                        t.as_str(),
+                        Some("null" | "boolean" | "object" | "array" | "number" | "string" | "integer")
+                    ) {
+                        return Err(anyhow!("Unknown type at {}: {}", at, t));
+                    }
+                }
+            }
+            "enum" => {
+                if !value.is_array() {
+                    return Err(anyhow!("enum at {} must be an array", at));
+                }
+            }
+            "const" => {}
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => number(key, value)?,
+            "multipleOf" => {
+                if !value.as_f64().is_some_and(|n| n > 0.0) {
+                    return Err(anyhow!("multipleOf at {} must be a positive number", at));
+                }
+            }
+            "minLength" | "maxLength" | "minItems" | "maxItems" | "minProperties" | "maxProperties" => {
+                count(key, value)?
+            }
+            "uniqueItems" => {
+                if !value.is_boolean() {
+                    return Err(anyhow!("uniqueItems at {} must be a boolean", at));
+                }
+            }
+            "required" => {
+                if !value.as_array().is_some_and(|names| names.iter().all(Value::is_string)) {
+                    return Err(anyhow!("required at {} must be an array of strings", at));
+                }
+            }
+            "items" | "additionalProperties" | "not" => subschema(key, value)?,
+            "allOf" | "anyOf" | "oneOf" => {
+                let Some(schemas) = value.as_array().filter(|s| !s.is_empty()) else {
+                    return Err(anyhow!("{} at {} must be a non-empty array", key, at));
+                };
+                for (i, schema) in schemas.iter().enumerate() {
+                    subschema(&format!("{}/{}", key, i), schema)?;
+                }
+            }
+            "$ref" => {
+                let target = value.as_str().unwrap_or_default();
+                if resolve(root, target).is_none() {
+                    return Err(anyhow!("$ref at {} doesn't resolve: {}", at, value));
+                }
+            }
+            _ => return Err(anyhow!("Unsupported JSON Schema keyword at {}: {}", at, key)),
+        }
+    }
+    Ok(())
+}
+
+/// The schema a local `$ref` such as `#/$defs/plan` points to
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    root.pointer(pointer)
+}
+
+/// Check `value` against `schema`, spending one of `budget` per schema
+/// visited. Once it runs out the result means nothing.
+fn validate(
+    root: &Value,
+    schema: &Value,
+    value: &Value,
+    path: &str,
+    depth: usize,
+    budget: &mut usize,
+    errors: &mut Vec<String>,
+) {
+    if *budget == 0 {
+        return;
+    }
+    *budget -= 1;
+    let at = if path.is_empty() { "/" } else { path };
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => return errors.push(format!("{}: not allowed", at)),
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match resolve(root, reference) {
+            Some(_) if depth >= MAX_DEPTH => errors.push(format!("{}: schema $refs are cyclic", at)),
+            Some(target) => validate(root, target, value, path, depth + 1, budget, errors),
+            None => errors.push(format!("{}: $ref doesn't resolve: {}", at, reference)),
+        }
+    }
+
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            other => other.as_str().into_iter().collect(),
+        };
+        if !types.iter().any(|t| has_type(value, t)) {
+            return errors.push(format!("{}: expected {}, got {}", at, types.join(" or "), type_name(value)));
+        }
+    }
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.iter().any(|option| equal(option, value)) {
+            let options: Vec<String> = options.iter().map(Value::to_string).collect();
+            errors.push(format!("{}: expected one of {}", at, options.join(", ")));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if !equal(expected, value) {
+            errors.push(format!("{}: expected {}", at, expected));
+        }
+    }
+
+    match value {
+        Value::Number(n) => validate_number(schema, n.as_f64().unwrap_or_default(), at, errors),
+        Value::String(s) => {
+            let len = s.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64).filter(|min| len < *min) {
+                errors.push(format!("{}: shorter than {} characters", at, min));
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64).filter(|max| len > *max) {
+                errors.push(format!("{}: longer than {} characters", at, max));
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64).filter(|min| len < *min) {
+                errors.push(format!("{}: fewer than {} items", at, min));
+            }
+            if let Some(max) = schema.get("maxItems").and_then(Value::as_u64).filter(|max| len > *max) {
+                errors.push(format!("{}: more than {} items", at, max));
+            }
+            if schema.get("uniqueItems") == Some(&Value::Bool(true))
+                && items.iter().enumerate().any(|(i, a)| items[..i].iter().any(|b| equal(a, b)))
+            {
+                errors.push(format!("{}: items aren't unique", at));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(root, item_schema, item, &format!("{}/{}", path, i), depth, budget, errors);
+                }
+            }
+        }
+        Value::Object(object) => validate_object(root, schema, object, path, depth, budget, errors),
+        _ => {}
+    }
+
+    if let Some(schemas) = schema.get("allOf").and_then(Value::as_array) {
+        for schema in schemas {
+            validate(root, schema, value, path, depth, budget, errors);
+        }
+    }
+    let mut matching = |schemas: &Vec<Value>| {
+        schemas
+            .iter()
+            .filter(|schema| {
+                let mut errors = Vec::new();
+                validate(root, schema, value, path, depth, budget, &mut errors);
+                errors.is_empty()
+            })
+            .count()
+    };
+    if let Some(schemas) = schema.get("anyOf").and_then(Value::as_array) {
+        if matching(schemas) == 0 {
+            errors.push(format!("{}: doesn't match any schema in anyOf", at));
+        }
+    }
+    if let Some(schemas) = schema.get("oneOf").and_then(Value::as_array) {
+        let n = matching(schemas);
+        if n != 1 {
+            errors.push(format!("{}: matches {} schemas in oneOf, expected exactly 1", at, n));
+        }
+    }
+    if let Some(not) = schema.get("not") {
+        let mut not_errors = Vec::new();
+        validate(root, not, value, path, depth, budget, &mut not_errors);
+        if not_errors.is_empty() {
+            errors.push(format!("{}: matches a schema in not", at));
+        }
+    }
+}
+
+fn validate_number(schema: &Map<String, Value>, n: f64, at: &str, errors: &mut Vec<String>) {
+    let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+    if let Some(min) = bound("minimum").filter(|min| n < *min) {
+        errors.push(format!("{}: less than {}", at, min));
+    }
+    if let Some(max) = bound("maximum").filter(|max| n > *max) {
+        errors.push(format!("{}: greater than {}", at, max));
+    }
+    if let Some(min) = bound("exclusiveMinimum").filter(|min| n <= *min) {
+        errors.push(format!("{}: not greater than {}", at, min));
+    }
+    if let Some(max) = bound("exclusiveMaximum").filter(|max| n >= *max) {
+        errors.push(format!("{}: not less than {}", at, max));
+    }
+    if let Some(step) = bound("multipleOf") {
+        let quotient = n / step;
+        if (quotient - quotient.round()).abs() > 1e-9 {
+            errors.push(format!("{}: not a multiple of {}", at, step));
+        }
+    }
+}
+
+fn validate_object(
+    root: &Value,
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+    depth: usize,
+    budget: &mut usize,
+    errors: &mut Vec<String>,
+) {
+    let at = if path.is_empty() { "/" } else { path };
+    let len = object.len() as u64;
+    if let Some(min) = schema.get("minProperties").and_then(Value::as_u64).filter(|min| len < *min) {
+        errors.push(format!("{}: fewer than {} properties", at, min));
+    }
+    if let Some(max) = schema.get("maxProperties").and_then(Value::as_u64).filter(|max| len > *max) {
+        errors.push(format!("{}: more than {} properties", at, max));
+    }
+    for name in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+        if let Some(name) = name.as_str().filter(|name| !object.contains_key(*name)) {
+            errors.push(format!("{}: missing required property {:?}", at, name));
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, value) in object {
+        // JSON Pointer escaping, so paths can be followed back into the config
+        let path = format!("{}/{}", path, name.replace('~', "~0").replace('/', "~1"));
+        match properties.and_then(|p| p.get(name)) {
+            Some(property) => validate(root, property, value, &path, depth, budget, errors),
+            None => {
+                if let Some(additional) = schema.get("additionalProperties") {
+                    validate(root, additional, value, &path, depth, budget, errors);
+                }
+            }
+        }
+    }
+}
+
+fn has_type(value: &Value, t: &str) -> bool {
+    match t {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+    }
+}
+
+/// JSON equality, with numbers compared by value, so that `1` equals `1.0`
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(a, b)),
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len() && a.iter().all(|(k, a)| b.get(k).is_some_and(|b| equal(a, b)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Apply a JSON Merge Patch (RFC 7396) to `target`: objects are merged key
+/// by key, `null` removes a key, and anything else replaces the target
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!("target was just made an object");
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn plans() -> ConfigSchema {
+        ConfigSchema::new(json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "required": ["plan"],
+            "properties": {
+                "plan": {"$ref": "#/$defs/plan"},
+                "seats": {"type": "integer", "minimum": 1, "maximum": 50},
+                "domains": {"type": "array", "items": {"type": "string", "minLength": 3}, "uniqueItems": true},
+                "theme": {"anyOf": [{"const": "dark"}, {"type": "object", "properties": {"accent": {"type": "string"}}}]}
+            },
+            "additionalProperties": false,
+            "$defs": {"plan": {"enum": ["family", "pro"]}}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validates() {
+        let schema = plans();
+        assert!(schema.validate(&json!({"plan": "family"})).is_ok());
+        assert!(schema.validate(&json!({"plan": "pro", "seats": 5.0, "domains": ["abc.io"], "theme": "dark"})).is_ok());
+        assert!(schema.validate(&json!({"plan": "pro", "theme": {"accent": "red"}})).is_ok());
+
+        let error = |config: Value| schema.validate(&config).unwrap_err().to_string();
+        assert_eq!(
+            error(json!({})),
+            r#"Config doesn't match the fleet's schema: /: missing required property "plan""#
+        );
+        assert!(error(json!({"plan": "free"})).contains(r#"/plan: expected one of "family", "pro""#));
+        assert!(error(json!({"plan": "pro", "seats": 1.5})).contains("/seats: expected integer, got number"));
+        assert!(error(json!({"plan": "pro", "seats": 51})).contains("/seats: greater than 50"));
+        assert!(error(json!({"plan": "pro", "domains": ["ab", "abc", "abc"]})).contains("/domains/0: shorter than 3"));
+        assert!(error(json!({"plan": "pro", "domains": ["abc", "abc"]})).contains("/domains: items aren't unique"));
+        assert!(error(json!({"plan": "pro", "theme": "light"})).contains("/theme: doesn't match any schema in anyOf"));
+        assert!(error(json!({"plan": "pro", "colour": "red"})).contains("/colour: not allowed"));
+        assert!(error(json!([])).contains("/: expected object, got array"));
+
+        // Every problem is reported
+        let all = error(json!({"plan": 1, "seats": 0}));
+        assert!(all.contains("/plan") && all.contains("/seats"), "{}", all);
+    }
+
+    #[test]
+    fn test_checks_schema() {
+        assert!(ConfigSchema::new(json!(true)).is_ok());
+        assert!(ConfigSchema::new(json!({"type": "object", "properties": {"a": {"type": ["string", "null"]}}})).is_ok());
+        for (schema, error) in [
+            (json!("object"), "must be an object or boolean"),
+            (json!({"type": "text"}), "Unknown type"),
+            (json!({"properties": {"name": {"pattern": "^a"}}}), "Unsupported JSON Schema keyword at #/properties/name: pattern"),
+            (json!({"$ref": "#/$defs/missing"}), "doesn't resolve"),
+            (json!({"minLength": -1}), "non-negative integer"),
+            (json!({"anyOf": []}), "non-empty array"),
+        ] {
+            let e = ConfigSchema::new(schema.clone()).unwrap_err().to_string();
+            assert!(e.contains(error), "{}: {}", schema, e);
+        }
+
+        let cyclic = ConfigSchema::new(json!({"$defs": {"a": {"$ref": "#/$defs/a"}}, "$ref": "#/$defs/a"})).unwrap();
+        assert!(cyclic.validate(&json!({})).unwrap_err().to_string().contains("cyclic"));
+
+        // Each level doubles the work; 2^64 visits without a budget
+        for keyword in ["allOf", "anyOf", "oneOf"] {
+            let schema = json!({ keyword: [{"$ref": "#"}, {"$ref": "#"}] });
+            let exponential = ConfigSchema::new(schema).unwrap();
+            let started = std::time::Instant::now();
+            let e = exponential.validate(&json!({})).unwrap_err().to_string();
+            assert!(e.contains("too costly"), "{}: {}", keyword, e);
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        // Examples from RFC 7396, appendix A
+        for (target, patch, result) in [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"b": "c"}), json!({"a": "b", "b": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (json!({"a": {"b": "c"}}), json!({"a": {"b": "d", "c": null}}), json!({"a": {"b": "d"}})),
+            (json!({"a": [{"b": "c"}]}), json!({"a": [1]}), json!({"a": [1]})),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"e": null}), json!({"a": 1}), json!({"e": null, "a": 1})),
+            (json!([1, 2]), json!({"a": "b", "c": null}), json!({"a": "b"})),
+            (json!({}), json!({"a": {"bb": {"ccc": null}}}), json!({"a": {"bb": {}}})),
+        ] {
+            let mut patched = target.clone();
+            merge_patch(&mut patched, &patch);
+            assert_eq!(patched, result, "{} + {}", target, patch);
+        }
+    }
+}
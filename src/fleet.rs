@@ -0,0 +1,583 @@
+//! Declarative fleet config
+//!
+//! A fleet file describes the desired servers, tenants, placements, aliases and
+//! statuses. `plan` diffs it against the registry and `apply` executes the
+//! resulting actions in a safe order.
+//!
+//! ```yaml
+//! servers:
+//!   - name: tenement-1
+//!     address: 10.0.0.1:9000
+//! tenants:
+//!   - id: romneys
+//!     server: tenement-1
+//!     config: { plan: family }
+//!     status: active
+//!     aliases: [romneys.com]
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use crate::db::{parse_config, Database, Tx, TENANT_STATUSES};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FleetSpec {
+    #[serde(default)]
+    pub servers: Vec<ServerSpec>,
+    #[serde(default)]
+    pub tenants: Vec<TenantSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSpec {
+    pub name: String,
+    pub address: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantSpec {
+    pub id: String,
+    /// Server name to place the tenant on
+    pub server: String,
+    /// Tenant config, a JSON object; a string holding one is accepted too
+    #[serde(default)]
+    pub config: Option<serde_json::Value>,
+    #[serde(default = "default_status")]
+    pub status: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+fn default_status() -> String {
+    "active".to_string()
+}
+
+impl TenantSpec {
+    fn config_string(&self) -> Option<String> {
+        self.config.as_ref().map(|v| match v {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+    }
+}
+
+impl FleetSpec {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        Self::from_yaml(&contents).with_context(|| format!("Invalid fleet file {}", path))
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<Self> {
+        let mut spec: FleetSpec = serde_yaml::from_str(contents)?;
+        // Stored aliases are lowercase, so these must be to compare with them
+        for t in &mut spec.tenants {
+            for alias in &mut t.aliases {
+                alias.make_ascii_lowercase();
+            }
+        }
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Check the spec is internally consistent before anything is diffed
+    fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        let mut addresses = HashSet::new();
+        for s in &self.servers {
+            if !names.insert(s.name.as_str()) {
+                return Err(anyhow!("Duplicate server name: {}", s.name));
+            }
+            if !addresses.insert(s.address.as_str()) {
+                return Err(anyhow!("Duplicate server address: {}", s.address));
+            }
+        }
+
+        let mut ids = HashSet::new();
+        let mut aliases = HashSet::new();
+        for t in &self.tenants {
+            if !ids.insert(t.id.as_str()) {
+                return Err(anyhow!("Duplicate tenant id: {}", t.id));
+            }
+            if !names.contains(t.server.as_str()) {
+                return Err(anyhow!(
+                    "Tenant {} is placed on unknown server: {}",
+                    t.id,
+                    t.server
+                ));
+            }
+            if !TENANT_STATUSES.contains(&t.status.as_str()) {
+                return Err(anyhow!(
+                    "Tenant {} has invalid status: {} (expected one of: {})",
+                    t.id,
+                    t.status,
+                    TENANT_STATUSES.join(", ")
+                ));
+            }
+            parse_config(t.config_string().as_deref()).with_context(|| format!("Tenant {}", t.id))?;
+            for alias in &t.aliases {
+                if !aliases.insert(alias.as_str()) {
+                    return Err(anyhow!("Duplicate domain alias: {}", alias));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A single change needed to bring the registry in line with the spec.
+///
+/// Variants are declared in the order they are applied: servers are added
+/// before the tenants that land on them, and tenants are moved or removed
+/// before the servers they drain from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    AddServer {
+        name: String,
+        address: String,
+    },
+    UpdateServer {
+        name: String,
+        from: String,
+        to: String,
+    },
+    AddTenant {
+        id: String,
+        server: String,
+        config: Option<String>,
+        status: String,
+    },
+    UpdateTenant {
+        id: String,
+        config: Option<Option<String>>,
+        status: Option<String>,
+    },
+    MoveTenant {
+        id: String,
+        from: String,
+        to: String,
+    },
+    RemoveAlias {
+        domain: String,
+        tenant: String,
+    },
+    AddAlias {
+        domain: String,
+        tenant: String,
+    },
+    RemoveTenant {
+        id: String,
+    },
+    RemoveServer {
+        name: String,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::AddServer { name, address } => write!(f, "+ server {} ({})", name, address),
+            Action::UpdateServer { name, from, to } => {
+                write!(f, "~ server {} address {} -> {}", name, from, to)
+            }
+            Action::AddTenant { id, server, .. } => write!(f, "+ tenant {} on {}", id, server),
+            Action::UpdateTenant { id, config, status } => {
+                let mut changes = Vec::new();
+                if config.is_some() {
+                    changes.push("config".to_string());
+                }
+                if let Some(status) = status {
+                    changes.push(format!("status -> {}", status));
+                }
+                write!(f, "~ tenant {} ({})", id, changes.join(", "))
+            }
+            Action::MoveTenant { id, from, to } => {
+                write!(f, "> tenant {} {} -> {}", id, from, to)
+            }
+            Action::RemoveAlias { domain, tenant } => {
+                write!(f, "- alias {} -> {}", domain, tenant)
+            }
+            Action::AddAlias { domain, tenant } => write!(f, "+ alias {} -> {}", domain, tenant),
+            Action::RemoveTenant { id } => write!(f, "- tenant {}", id),
+            Action::RemoveServer { name } => write!(f, "- server {}", name),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// One-line count of actions by kind, e.g. "2 to add, 1 to update, 0 to move, 0 to remove"
+    pub fn summary(&self) -> String {
+        let (mut add, mut update, mut moves, mut remove) = (0, 0, 0, 0);
+        for action in &self.actions {
+            match action {
+                Action::AddServer { .. } | Action::AddTenant { .. } | Action::AddAlias { .. } => {
+                    add += 1
+                }
+                Action::UpdateServer { .. } | Action::UpdateTenant { .. } => update += 1,
+                Action::MoveTenant { .. } => moves += 1,
+                Action::RemoveServer { .. }
+                | Action::RemoveTenant { .. }
+                | Action::RemoveAlias { .. } => remove += 1,
+            }
+        }
+        format!(
+            "{} to add, {} to update, {} to move, {} to remove",
+            add, update, moves, remove
+        )
+    }
+}
+
+/// Two configs are equal if they are the same string or the same JSON value
+fn config_eq(current: Option<&str>, desired: Option<&str>) -> bool {
+    match (current, desired) {
+        (None, None) => true,
+        (Some(a), Some(b)) => {
+            a == b
+                || matches!(
+                    (
+                        serde_json::from_str::<serde_json::Value>(a),
+                        serde_json::from_str::<serde_json::Value>(b),
+                    ),
+                    (Ok(a), Ok(b)) if a == b
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Diff the spec against the registry
+pub async fn plan(db: &Database, spec: &FleetSpec) -> Result<Plan> {
+    let servers = db.list_servers().await?;
+    let tenants = db.list_tenants().await?;
+    let aliases = db.list_domain_aliases().await?;
+
+    let server_names: HashMap<&str, &str> = servers
+        .iter()
+        .map(|s| (s.id.as_str(), s.name.as_str()))
+        .collect();
+    let current_servers: HashMap<&str, &str> = servers
+        .iter()
+        .map(|s| (s.name.as_str(), s.address.as_str()))
+        .collect();
+    let desired_servers: HashSet<&str> = spec.servers.iter().map(|s| s.name.as_str()).collect();
+    let current_tenants: HashMap<&str, _> = tenants.iter().map(|t| (t.id.as_str(), t)).collect();
+    let desired_tenants: HashSet<&str> = spec.tenants.iter().map(|t| t.id.as_str()).collect();
+    let current_aliases: BTreeMap<&str, &str> = aliases
+        .iter()
+        .map(|a| (a.domain.as_str(), a.tenant_id.as_str()))
+        .collect();
+    let desired_aliases: BTreeMap<&str, &str> = spec
+        .tenants
+        .iter()
+        .flat_map(|t| t.aliases.iter().map(|a| (a.as_str(), t.id.as_str())))
+        .collect();
+
+    let mut actions = Vec::new();
+
+    for s in &spec.servers {
+        match current_servers.get(s.name.as_str()) {
+            None => actions.push(Action::AddServer {
+                name: s.name.clone(),
+                address: s.address.clone(),
+            }),
+            Some(&address) if address != s.address => actions.push(Action::UpdateServer {
+                name: s.name.clone(),
+                from: address.to_string(),
+                to: s.address.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for s in &servers {
+        if !desired_servers.contains(s.name.as_str()) {
+            actions.push(Action::RemoveServer {
+                name: s.name.clone(),
+            });
+        }
+    }
+
+    for t in &spec.tenants {
+        let config = t.config_string();
+        match current_tenants.get(t.id.as_str()) {
+            None => actions.push(Action::AddTenant {
+                id: t.id.clone(),
+                server: t.server.clone(),
+                config,
+                status: t.status.clone(),
+            }),
+            Some(current) => {
+                let current_server = server_names
+                    .get(current.server_id.as_str())
+                    .copied()
+                    .unwrap_or(current.server_id.as_str());
+                if current_server != t.server {
+                    actions.push(Action::MoveTenant {
+                        id: t.id.clone(),
+                        from: current_server.to_string(),
+                        to: t.server.clone(),
+                    });
+                }
+
+                let config_change = (!config_eq(current.config.as_deref(), config.as_deref()))
+                    .then_some(config);
+                let status_change = (current.status != t.status).then(|| t.status.clone());
+                if config_change.is_some() || status_change.is_some() {
+                    actions.push(Action::UpdateTenant {
+                        id: t.id.clone(),
+                        config: config_change,
+                        status: status_change,
+                    });
+                }
+            }
+        }
+    }
+    for t in &tenants {
+        if !desired_tenants.contains(t.id.as_str()) {
+            actions.push(Action::RemoveTenant { id: t.id.clone() });
+        }
+    }
+
+    for (&domain, &tenant) in &current_aliases {
+        if desired_aliases.get(domain) != Some(&tenant) {
+            actions.push(Action::RemoveAlias {
+                domain: domain.to_string(),
+                tenant: tenant.to_string(),
+            });
+        }
+    }
+    for (&domain, &tenant) in &desired_aliases {
+        if current_aliases.get(domain) != Some(&tenant) {
+            actions.push(Action::AddAlias {
+                domain: domain.to_string(),
+                tenant: tenant.to_string(),
+            });
+        }
+    }
+
+    actions.sort();
+    Ok(Plan { actions })
+}
+
+/// Execute a plan produced by `plan`. Actions are already in apply order and
+/// run in a single transaction, so a failing action leaves the registry untouched.
+pub async fn apply(db: &Database, plan: &Plan) -> Result<()> {
+    let actions = plan.actions.clone();
+    db.transaction(move |tx| {
+        Box::pin(async move {
+            for action in &actions {
+                apply_action(tx, action)
+                    .await
+                    .with_context(|| format!("Failed to apply: {}", action))?;
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+async fn apply_action(tx: &mut Tx, action: &Action) -> Result<()> {
+    match action {
+        Action::AddServer { name, address } => {
+            tx.add_server(name, address).await?;
+        }
+        Action::UpdateServer { name, to, .. } => {
+            tx.update_server_address(name, to).await?;
+        }
+        Action::AddTenant {
+            id,
+            server,
+            config,
+            status,
+        } => {
+            tx.add_tenant(id, Some(server), config.as_deref()).await?;
+            if status != "active" {
+                tx.set_tenant_status(id, status).await?;
+            }
+        }
+        Action::UpdateTenant { id, config, status } => {
+            if let Some(config) = config {
+                tx.set_tenant_config(id, config.as_deref()).await?;
+            }
+            if let Some(status) = status {
+                tx.set_tenant_status(id, status).await?;
+            }
+        }
+        Action::MoveTenant { id, to, .. } => {
+            tx.move_tenant(id, to).await?;
+        }
+        Action::RemoveAlias { domain, .. } => {
+            tx.remove_domain_alias(domain).await?;
+        }
+        Action::AddAlias { domain, tenant } => {
+            tx.add_domain_alias(domain, tenant).await?;
+        }
+        Action::RemoveTenant { id } => {
+            tx.remove_tenant(id).await?;
+        }
+        Action::RemoveServer { name } => {
+            tx.remove_server(name).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_db() -> Database {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        Database::open(&path).await.unwrap()
+    }
+
+    const FLEET: &str = r#"
+servers:
+  - name: tenement-1
+    address: 10.0.0.1:9000
+  - name: tenement-2
+    address: 10.0.0.2:9000
+tenants:
+  - id: romneys
+    server: tenement-1
+    config: { plan: family }
+    aliases: [romneys.com]
+  - id: smiths
+    server: tenement-2
+    status: suspended
+"#;
+
+    #[test]
+    fn test_spec_validation() {
+        assert!(FleetSpec::from_yaml(FLEET).is_ok());
+
+        let unknown_server = "tenants:\n  - id: romneys\n    server: nowhere\n";
+        assert!(FleetSpec::from_yaml(unknown_server)
+            .unwrap_err()
+            .to_string()
+            .contains("unknown server"));
+
+        let bad_status = "servers:\n  - name: s\n    address: a\ntenants:\n  - id: romneys\n    server: s\n    status: bogus\n";
+        assert!(FleetSpec::from_yaml(bad_status).is_err());
+
+        let dup = "servers:\n  - name: s\n    address: a\n  - name: s\n    address: b\n";
+        assert!(FleetSpec::from_yaml(dup).is_err());
+
+        let dup_alias = "servers:\n  - name: s\n    address: a\ntenants:\n  - id: romneys\n    server: s\n    aliases: [romneys.com, Romneys.COM]\n";
+        assert!(FleetSpec::from_yaml(dup_alias)
+            .unwrap_err()
+            .to_string()
+            .contains("Duplicate domain alias"));
+
+        let bad_config = "servers:\n  - name: s\n    address: a\ntenants:\n  - id: romneys\n    server: s\n    config: family\n";
+        let err = FleetSpec::from_yaml(bad_config).unwrap_err();
+        assert!(format!("{:#}", err).contains("Tenant romneys: Tenant config isn't valid JSON"), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn test_plan_from_empty() {
+        let db = test_db().await;
+        let spec = FleetSpec::from_yaml(FLEET).unwrap();
+
+        let plan = plan(&db, &spec).await.unwrap();
+        assert_eq!(plan.summary(), "5 to add, 0 to update, 0 to move, 0 to remove");
+        // Servers come before the tenants placed on them
+        assert!(matches!(plan.actions[0], Action::AddServer { .. }));
+        assert!(matches!(plan.actions.last(), Some(Action::AddAlias { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_apply_converges() {
+        let db = test_db().await;
+        let spec = FleetSpec::from_yaml(&FLEET.replace("[romneys.com]", "[romneys.com, WWW.Romneys.com]")).unwrap();
+
+        apply(&db, &plan(&db, &spec).await.unwrap()).await.unwrap();
+
+        let smiths = db.get_tenant("smiths").await.unwrap().unwrap();
+        assert_eq!(smiths.status, "suspended");
+        for domain in ["romneys.com", "www.romneys.com"] {
+            assert_eq!(db.lookup_by_domain(domain).await.unwrap().as_deref(), Some("romneys"));
+        }
+
+        // Second plan is a no-op
+        assert!(plan(&db, &spec).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_plan_drains_before_removing_server() {
+        let db = test_db().await;
+        apply(&db, &plan(&db, &FleetSpec::from_yaml(FLEET).unwrap()).await.unwrap())
+            .await
+            .unwrap();
+
+        // Drop tenement-2, move smiths onto tenement-1 and drop the alias
+        let spec = FleetSpec::from_yaml(
+            r#"
+servers:
+  - name: tenement-1
+    address: 10.0.0.1:9000
+tenants:
+  - id: romneys
+    server: tenement-1
+    config: '{"plan":"family"}'
+  - id: smiths
+    server: tenement-1
+    status: suspended
+"#,
+        )
+        .unwrap();
+
+        let plan = plan(&db, &spec).await.unwrap();
+        assert_eq!(
+            plan.actions,
+            vec![
+                Action::MoveTenant {
+                    id: "smiths".into(),
+                    from: "tenement-2".into(),
+                    to: "tenement-1".into(),
+                },
+                Action::RemoveAlias {
+                    domain: "romneys.com".into(),
+                    tenant: "romneys".into(),
+                },
+                Action::RemoveServer {
+                    name: "tenement-2".into(),
+                },
+            ]
+        );
+
+        apply(&db, &plan).await.unwrap();
+        assert_eq!(db.list_servers().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_apply_is_atomic() {
+        let db = test_db().await;
+        let spec = FleetSpec::from_yaml(FLEET).unwrap();
+        let mut plan = plan(&db, &spec).await.unwrap();
+
+        // A step that can't succeed after the servers and tenants are added
+        plan.actions.push(Action::RemoveServer {
+            name: "tenement-1".into(),
+        });
+        assert!(apply(&db, &plan).await.is_err());
+
+        assert!(db.list_servers().await.unwrap().is_empty());
+        assert!(db.list_tenants().await.unwrap().is_empty());
+    }
+}
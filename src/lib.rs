@@ -4,6 +4,8 @@
 //! Use it to add/remove servers, manage tenants, and lookup routing information.
 
 pub mod db;
+pub mod provision;
+pub mod tenement;
 
 #[cfg(feature = "python")]
 mod python;
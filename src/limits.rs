@@ -0,0 +1,332 @@
+//! Per-tenant request limits
+//!
+//! Each tenant gets a token bucket for its request rate and a counter of
+//! requests in progress. Limits come from the tenant, falling back to the
+//! fleet default given to `slum serve`. Request bodies are capped in size
+//! and must keep arriving, so a slow client can't hold a connection open.
+
+use axum::body::{Body, Bytes};
+use hyper::body::{Frame, SizeHint};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+
+use crate::db::Limits;
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// Over the rate limit; a token is available after `retry_after`
+    Rate { retry_after: Duration },
+    /// Too many requests in progress
+    Concurrency,
+}
+
+impl Rejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::Rate { .. } => "rate",
+            Rejection::Concurrency => "concurrency",
+        }
+    }
+
+    /// Whole seconds for the `Retry-After` header
+    pub fn retry_after_secs(&self) -> u64 {
+        match self {
+            Rejection::Rate { retry_after } => retry_after.as_secs_f64().ceil().max(1.0) as u64,
+            Rejection::Concurrency => 1,
+        }
+    }
+}
+
+struct TenantState {
+    tokens: f64,
+    refilled: Instant,
+    in_flight: u32,
+}
+
+pub struct RateLimiter {
+    default: Limits,
+    tenants: Arc<Mutex<HashMap<String, TenantState>>>,
+}
+
+/// A request counted against its tenant's concurrency limit until dropped
+pub struct Permit {
+    tenant: String,
+    tenants: Arc<Mutex<HashMap<String, TenantState>>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(state) = self.tenants.lock().unwrap().get_mut(&self.tenant) {
+            state.in_flight = state.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(default: Limits) -> Self {
+        Self {
+            default,
+            tenants: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// A tenant's `limits` with the fleet default filled in
+    pub fn effective(&self, limits: Limits) -> Limits {
+        limits.or(self.default)
+    }
+
+    /// Admit a request for `tenant` under its `limits`
+    pub fn acquire(&self, tenant: &str, limits: Limits) -> Result<Permit, Rejection> {
+        self.acquire_at(tenant, limits, Instant::now())
+    }
+
+    fn acquire_at(&self, tenant: &str, limits: Limits, now: Instant) -> Result<Permit, Rejection> {
+        let limits = limits.or(self.default);
+        // A bucket holds `burst` tokens, by default one second's worth
+        let capacity = |rate: f64| limits.burst.map_or(rate.ceil().max(1.0), f64::from);
+
+        let mut tenants = self.tenants.lock().unwrap();
+        let state = tenants.entry(tenant.to_string()).or_insert_with(|| TenantState {
+            tokens: limits.rate.map_or(0.0, capacity),
+            refilled: now,
+            in_flight: 0,
+        });
+
+        if let Some(max) = limits.max_concurrent {
+            if state.in_flight >= max {
+                return Err(Rejection::Concurrency);
+            }
+        }
+
+        if let Some(rate) = limits.rate {
+            let elapsed = now.saturating_duration_since(state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(capacity(rate));
+            state.refilled = now;
+            if state.tokens < 1.0 {
+                return Err(Rejection::Rate {
+                    retry_after: Duration::from_secs_f64((1.0 - state.tokens) / rate),
+                });
+            }
+            state.tokens -= 1.0;
+        }
+
+        state.in_flight += 1;
+        Ok(Permit {
+            tenant: tenant.to_string(),
+            tenants: self.tenants.clone(),
+        })
+    }
+}
+
+/// Why a request body was cut off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyError {
+    /// Longer than the tenant's `max_body_size`
+    TooLarge,
+    /// No data within the idle timeout
+    Timeout,
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::TooLarge => write!(f, "request body too large"),
+            BodyError::Timeout => write!(f, "request body timed out"),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+/// A request body that fails once it exceeds `remaining` bytes or stalls
+/// for longer than `idle`
+struct GuardedBody {
+    inner: Body,
+    remaining: Option<u64>,
+    idle: Option<Duration>,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl hyper::body::Body for GuardedBody {
+    type Data = Bytes;
+    type Error = axum::BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(remaining)) = (frame.data_ref(), this.remaining.as_mut()) {
+                    let len = data.len() as u64;
+                    if len > *remaining {
+                        return Poll::Ready(Some(Err(BodyError::TooLarge.into())));
+                    }
+                    *remaining -= len;
+                }
+                this.timer = None;
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Pending => {
+                let Some(idle) = this.idle else {
+                    return Poll::Pending;
+                };
+                let timer = this
+                    .timer
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(idle)));
+                match timer.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Some(Err(BodyError::Timeout.into()))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Limit a request body to `max` bytes, waiting at most `idle` for each
+/// chunk. Reading past either limit fails with a [`BodyError`].
+pub fn guard_body(body: Body, max: Option<u64>, idle: Option<Duration>) -> Body {
+    if max.is_none() && idle.is_none() {
+        return body;
+    }
+    Body::new(GuardedBody {
+        inner: body,
+        remaining: max,
+        idle,
+        timer: None,
+    })
+}
+
+/// The [`BodyError`] behind `err`, if reading the request body failed
+pub fn body_error(err: &(dyn std::error::Error + 'static)) -> Option<BodyError> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(e) = err.downcast_ref::<BodyError>() {
+            return Some(*e);
+        }
+        source = err.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(Limits::default());
+        let limits = Limits {
+            rate: Some(2.0),
+            burst: Some(3),
+            max_concurrent: None,
+            max_body_size: None,
+        };
+        let start = Instant::now();
+
+        // The burst is available at once, then one token every 500ms
+        for _ in 0..3 {
+            limiter.acquire_at("romneys", limits, start).unwrap();
+        }
+        let rejection = limiter.acquire_at("romneys", limits, start).err().unwrap();
+        assert_eq!(
+            rejection,
+            Rejection::Rate {
+                retry_after: Duration::from_millis(500)
+            }
+        );
+        assert_eq!(rejection.retry_after_secs(), 1);
+
+        let later = start + Duration::from_millis(500);
+        limiter.acquire_at("romneys", limits, later).unwrap();
+        assert!(limiter.acquire_at("romneys", limits, later).is_err());
+
+        // Other tenants have their own buckets
+        limiter.acquire_at("smiths", limits, later).unwrap();
+    }
+
+    #[test]
+    fn test_concurrency_and_fleet_default() {
+        let limiter = RateLimiter::new(Limits {
+            rate: None,
+            burst: None,
+            max_concurrent: Some(2),
+            max_body_size: None,
+        });
+
+        let first = limiter.acquire("romneys", Limits::default()).unwrap();
+        let _second = limiter.acquire("romneys", Limits::default()).unwrap();
+        assert_eq!(
+            limiter.acquire("romneys", Limits::default()).err(),
+            Some(Rejection::Concurrency)
+        );
+
+        drop(first);
+        let _third = limiter.acquire("romneys", Limits::default()).unwrap();
+
+        // A tenant's own limit overrides the default
+        let limits = Limits {
+            max_concurrent: Some(3),
+            ..Default::default()
+        };
+        limiter.acquire("romneys", limits).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_guard_body_size() {
+        use http_body_util::BodyExt;
+
+        let body = guard_body(Body::from("hello"), Some(5), None);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+
+        let body = guard_body(Body::from("hello!"), Some(5), None);
+        let err = body.collect().await.unwrap_err();
+        assert_eq!(body_error(&err), Some(BodyError::TooLarge));
+    }
+
+    /// Sends one chunk, then stalls
+    struct Stalled(bool);
+
+    impl hyper::body::Body for Stalled {
+        type Data = Bytes;
+        type Error = axum::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+            if self.0 {
+                return Poll::Pending;
+            }
+            self.0 = true;
+            Poll::Ready(Some(Ok(Frame::data(Bytes::from("hi")))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_guard_body_idle_timeout() {
+        use http_body_util::BodyExt;
+
+        let body = guard_body(Body::new(Stalled(false)), None, Some(Duration::from_millis(50)));
+        let err = body.collect().await.unwrap_err();
+        assert_eq!(body_error(&err), Some(BodyError::Timeout));
+    }
+}
@@ -1,18 +1,54 @@
 mod db;
+mod fleet;
+mod limits;
+mod metrics;
+mod mirror;
+mod provision;
 mod proxy;
+mod access_log;
 mod api;
+mod auth;
+mod balance;
+mod cache;
+mod circuit;
+mod rebalance;
+mod reconcile;
+mod request_id;
+mod resolver;
+mod rules;
+mod server;
+mod telemetry;
+mod tenement;
+mod usage;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use axum::{
-    routing::{get, delete},
+    routing::{delete, get, post, put},
     Router,
 };
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::db::Database;
+use crate::access_log::{AccessLog, AccessLogConfig, Sink};
+use crate::auth::EdgeAuth;
+use crate::balance::LoadBalancer;
+use crate::cache::HttpCache;
+use crate::circuit::CircuitBreaker;
+use crate::db::{AccessPolicy, BasicUser, Database, Labels, Limits, LoadBalance, Protocol, RouteRule, ServerHealth};
+use crate::limits::RateLimiter;
+use crate::metrics::Metrics;
+use crate::mirror::Mirrors;
+use crate::provision::{Provisioner, SyncReport};
+use crate::proxy::RouteCache;
+use crate::rebalance::{Balance, RebalanceOptions, RebalancePlan, Rebalancer};
+use crate::reconcile::{ReconcileReport, Reconciler};
+use crate::resolver::{JwtKey, PathRouting, ResolverSpec, Resolvers};
+use crate::telemetry::Tracer;
+use crate::usage::UsageRecorder;
 
 #[derive(Parser)]
 #[command(name = "slum")]
@@ -23,6 +59,7 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Start the slum server
     Serve {
@@ -30,9 +67,163 @@ enum Commands {
         #[arg(short, long, default_value = "8080")]
         port: u16,
 
+        /// Serve the management API and /metrics on this port instead of the
+        /// proxy port. Without it, /metrics isn't served.
+        #[arg(long)]
+        admin_port: Option<u16>,
+
         /// Database path
         #[arg(short, long, default_value = "slum.db")]
         database: String,
+
+        /// Seconds to cache tenant routing lookups (0 disables)
+        #[arg(long, default_value = "5")]
+        route_cache_ttl: u64,
+
+        /// Also route requests by path under this prefix, e.g. /t for example.com/t/romneys/
+        #[arg(long)]
+        path_prefix: Option<String>,
+
+        /// Forward path-routed requests with the prefix and tenant still on the path
+        #[arg(long, requires = "path_prefix")]
+        keep_path_prefix: bool,
+
+        /// Where to find each request's tenant, in priority order (repeatable): subdomain, path, header:NAME, query:NAME or jwt:CLAIM
+        #[arg(long = "resolve")]
+        resolve: Vec<ResolverSpec>,
+
+        /// Shared secret that verifies bearer tokens for jwt:CLAIM
+        #[arg(long, env = "SLUM_JWT_SECRET", hide_env_values = true, conflicts_with = "jwt_public_key")]
+        jwt_secret: Option<String>,
+
+        /// PEM public key file that verifies bearer tokens for jwt:CLAIM
+        #[arg(long)]
+        jwt_public_key: Option<PathBuf>,
+
+        /// Bearer token signature algorithm [default: HS256 with a secret, RS256 with a public key]
+        #[arg(long)]
+        jwt_algorithm: Option<jsonwebtoken::Algorithm>,
+
+        /// Seconds between retries of pending tenant provisioning
+        #[arg(long, default_value = "30")]
+        provision_interval: u64,
+
+        /// Seconds between checks of server inventories against the registry (0 disables)
+        #[arg(long, default_value = "300")]
+        reconcile_interval: u64,
+
+        /// Repair drift found by the periodic check instead of only logging it
+        #[arg(long)]
+        reconcile_repair: bool,
+
+        /// Seconds between automatic rebalancing runs (0 disables)
+        #[arg(long, default_value = "0")]
+        rebalance_interval: u64,
+
+        /// What automatic rebalancing balances
+        #[arg(long, value_enum, default_value = "tenants")]
+        rebalance_by: Balance,
+
+        /// Largest acceptable gap between the most and least loaded server
+        #[arg(long, default_value = "1")]
+        rebalance_threshold: f64,
+
+        /// Most moves per automatic rebalancing run
+        #[arg(long, default_value = "10")]
+        rebalance_max_moves: usize,
+
+        /// Days to keep per-tenant usage rollups (0 keeps them forever)
+        #[arg(long, default_value = "90")]
+        usage_retention_days: u64,
+
+        /// Write JSON access logs to stdout, file:PATH or syslog[:SOCKET]
+        #[arg(long, value_parser = Sink::parse)]
+        access_log: Option<Sink>,
+
+        /// Rotate the access log file at this many megabytes
+        #[arg(long, default_value = "100")]
+        access_log_max_mb: u64,
+
+        /// Rotated access log files to keep
+        #[arg(long, default_value = "5")]
+        access_log_max_files: usize,
+
+        /// Fraction of requests to log, from 0 to 1 (5xx responses are always logged)
+        #[arg(long, default_value = "1")]
+        access_log_sample: f64,
+
+        /// Redact matching paths in access logs, e.g. /invite/* or /files/** (repeatable)
+        #[arg(long = "access-log-redact")]
+        access_log_redact: Vec<String>,
+
+        /// Default requests per second per tenant, for tenants without their own limit
+        #[arg(long)]
+        rate_limit: Option<f64>,
+
+        /// Default burst size per tenant (default: one second of --rate-limit)
+        #[arg(long)]
+        rate_burst: Option<u32>,
+
+        /// Default maximum concurrent requests per tenant
+        #[arg(long)]
+        max_concurrent: Option<u32>,
+
+        /// Default largest request body per tenant, in bytes or with a K, M or G suffix
+        #[arg(long, value_parser = parse_size)]
+        max_body_size: Option<u64>,
+
+        /// Seconds a client may take to send request headers (0 disables)
+        #[arg(long, default_value = "30")]
+        header_read_timeout: u64,
+
+        /// Serve the proxy over TLS with this PEM certificate chain
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM private key for --tls-cert
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Seconds a request body may go without sending data (0 disables)
+        #[arg(long, default_value = "30")]
+        body_idle_timeout: u64,
+
+        /// Times to retry an idempotent request whose server refused the connection
+        #[arg(long, default_value = "2")]
+        upstream_retries: u32,
+
+        /// Seconds to hold requests for a tenant whose process is starting
+        /// before showing a "waking up" page (0 shows it at once)
+        #[arg(long, default_value = "30")]
+        warmup_secs: u64,
+
+        /// Consecutive failures that open a server's circuit (0 disables)
+        #[arg(long, default_value = "5")]
+        circuit_failures: u32,
+
+        /// Seconds an open circuit waits before probing the server again
+        #[arg(long, default_value = "30")]
+        circuit_open_secs: u64,
+
+        /// Total size of cached response bodies, in bytes or with a K, M or G suffix
+        #[arg(long, value_parser = parse_size, default_value = "256M")]
+        cache_size: u64,
+
+        /// Largest response body cached
+        #[arg(long, value_parser = parse_size, default_value = "8M")]
+        cache_max_entry: u64,
+
+        /// Keep cached response bodies in files here instead of in memory
+        #[arg(long)]
+        cache_dir: Option<PathBuf>,
+
+        /// Export traces to this OTLP/HTTP collector, e.g. http://localhost:4318
+        #[arg(long)]
+        otlp_endpoint: Option<String>,
+
+        /// service.name reported with exported traces
+        #[arg(long, default_value = "slum")]
+        otlp_service_name: String,
     },
 
     /// Add a tenement server to the fleet
@@ -44,6 +235,14 @@ enum Commands {
         #[arg(short, long)]
         name: Option<String>,
 
+        /// Server label as key=value (repeatable)
+        #[arg(short, long = "label", value_parser = parse_label)]
+        labels: Vec<(String, String)>,
+
+        /// Protocol the proxy speaks to the server: http1 or http2 (h2c)
+        #[arg(long, default_value = "http1")]
+        protocol: Protocol,
+
         /// Database path
         #[arg(short, long, default_value = "slum.db")]
         database: String,
@@ -56,6 +255,32 @@ enum Commands {
         database: String,
     },
 
+    /// Replace a server's labels
+    ServerLabel {
+        /// Server ID or name
+        server: String,
+
+        /// Labels as key=value
+        #[arg(value_parser = parse_label)]
+        labels: Vec<(String, String)>,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Set the protocol the proxy speaks to a server: http1 or http2 (h2c)
+    ServerProtocol {
+        /// Server ID or name
+        server: String,
+
+        protocol: Protocol,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
     /// Remove a server from the fleet
     ServerRemove {
         /// Server ID or name
@@ -68,7 +293,8 @@ enum Commands {
 
     /// Add a tenant
     TenantAdd {
-        /// Tenant ID (e.g., "romneys")
+        /// Tenant ID (e.g., "romneys"): lowercase letters, digits and hyphens
+        #[arg(value_parser = parse_tenant_id)]
         id: String,
 
         /// Server to place tenant on (ID or name). If not specified, picks server with capacity.
@@ -79,6 +305,10 @@ enum Commands {
         #[arg(short, long)]
         config: Option<String>,
 
+        /// Only place the tenant on servers with this label, as key=value (repeatable)
+        #[arg(short, long = "require", value_parser = parse_label)]
+        requires: Vec<(String, String)>,
+
         /// Database path
         #[arg(short, long, default_value = "slum.db")]
         database: String,
@@ -91,6 +321,255 @@ enum Commands {
         database: String,
     },
 
+    /// Pin a tenant to its server so rebalancing never moves it
+    TenantPin {
+        /// Tenant ID
+        id: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Let rebalancing move a pinned tenant again
+    TenantUnpin {
+        /// Tenant ID
+        id: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Run a tenant on this many servers, balancing requests across them
+    TenantScale {
+        /// Tenant ID
+        id: String,
+
+        /// Total number of servers, including the primary
+        replicas: u32,
+
+        /// round-robin, least-conn, client-hash or cookie-hash:NAME
+        #[arg(long)]
+        balance: Option<LoadBalance>,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Send a share of a tenant's traffic to a canary server, or change it
+    TenantCanary {
+        /// Tenant ID
+        id: String,
+
+        /// Canary server ID or name
+        server: String,
+
+        /// Percentage of clients sent to the canary
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+        weight: u8,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Take a tenant off a canary server
+    TenantCanaryRemove {
+        /// Tenant ID
+        id: String,
+
+        /// Canary server ID or name
+        server: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Make a canary server the tenant's primary, leaving the old one
+    TenantPromote {
+        /// Tenant ID
+        id: String,
+
+        /// Canary server ID or name
+        server: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Copy a sample of a tenant's requests to another server, discarding
+    /// its responses; `slum serve` compares their statuses
+    TenantMirror {
+        /// Tenant ID
+        id: String,
+
+        /// Mirror server ID or name
+        server: String,
+
+        /// Percentage of requests mirrored
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(1..=100))]
+        percent: u8,
+
+        /// Largest request body mirrored, in bytes or with a K, M or G suffix
+        #[arg(long, value_parser = parse_size, default_value_t = mirror::DEFAULT_MAX_BODY_SIZE)]
+        max_body_size: u64,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Stop mirroring a tenant's requests
+    TenantMirrorRemove {
+        /// Tenant ID
+        id: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Set who may reach a tenant through the proxy, replacing its access
+    /// policy; with no options, anyone may
+    TenantAccess {
+        /// Tenant ID
+        id: String,
+
+        /// Let in only clients in this network (CIDR); repeatable
+        #[arg(long)]
+        allow: Vec<ipnet::IpNet>,
+
+        /// Refuse clients in this network (CIDR); repeatable
+        #[arg(long)]
+        deny: Vec<ipnet::IpNet>,
+
+        /// Require basic auth as NAME:PASSWORD; repeatable. Only a hash of
+        /// the password is stored.
+        #[arg(long = "user", value_name = "NAME:PASSWORD")]
+        users: Vec<BasicUser>,
+
+        /// Ask this http:// URL about each request; a 2xx answer lets it in
+        #[arg(long, value_name = "URL")]
+        forward_auth: Option<String>,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Show or change a tenant's config
+    TenantConfig {
+        #[command(subcommand)]
+        action: TenantConfigAction,
+    },
+
+    /// Show, register or remove the JSON Schema tenant configs must match
+    ConfigSchema {
+        #[command(subcommand)]
+        action: ConfigSchemaAction,
+    },
+
+    /// Turn the proxy's HTTP cache on or off for a tenant
+    TenantCache {
+        /// Tenant ID
+        id: String,
+
+        /// on or off
+        #[arg(value_parser = clap::builder::BoolishValueParser::new(), action = clap::ArgAction::Set)]
+        enabled: bool,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Turn gzip/brotli response compression on or off for a tenant
+    TenantCompress {
+        /// Tenant ID
+        id: String,
+
+        /// on or off
+        #[arg(value_parser = clap::builder::BoolishValueParser::new(), action = clap::ArgAction::Set)]
+        enabled: bool,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// List route rules: a tenant's, or the fleet's that apply to every tenant
+    Rules {
+        /// Tenant ID (default: fleet rules)
+        #[arg(long)]
+        tenant: Option<String>,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Add a route rule: redirect FROM TO [STATUS], rewrite FROM TO, force-https, set-header NAME VALUE or remove-header NAME
+    RuleAdd {
+        /// The rule, e.g. redirect /old /new 301
+        #[arg(required = true, num_args = 1..)]
+        rule: Vec<String>,
+
+        /// Tenant ID (default: a fleet rule)
+        #[arg(long)]
+        tenant: Option<String>,
+
+        /// Insert at this position instead of at the end
+        #[arg(long)]
+        position: Option<usize>,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Remove the route rule at a position, as listed by `slum rules`
+    RuleRemove {
+        position: usize,
+
+        /// Tenant ID (default: a fleet rule)
+        #[arg(long)]
+        tenant: Option<String>,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Set a tenant's request limits; omitted limits use the fleet default
+    TenantLimits {
+        /// Tenant ID
+        id: String,
+
+        /// Requests per second
+        #[arg(long)]
+        rate: Option<f64>,
+
+        /// Requests allowed at once before the rate applies
+        #[arg(long)]
+        burst: Option<u32>,
+
+        /// Maximum concurrent requests
+        #[arg(long)]
+        max_concurrent: Option<u32>,
+
+        /// Largest request body, in bytes or with a K, M or G suffix
+        #[arg(long, value_parser = parse_size)]
+        max_body_size: Option<u64>,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
     /// Remove a tenant
     TenantRemove {
         /// Tenant ID
@@ -101,6 +580,92 @@ enum Commands {
         database: String,
     },
 
+    /// Show a tenant's request usage
+    TenantUsage {
+        /// Tenant ID
+        id: String,
+
+        /// Start of the range, as Unix seconds or RFC 3339 (default: 24 hours before --to)
+        #[arg(long, value_parser = parse_time)]
+        from: Option<i64>,
+
+        /// End of the range, as Unix seconds or RFC 3339 (default: now)
+        #[arg(long, value_parser = parse_time)]
+        to: Option<i64>,
+
+        /// Interval length in seconds
+        #[arg(long, default_value = "3600")]
+        step: i64,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Show the changes needed to match a fleet file
+    Plan {
+        /// Fleet file describing the desired state
+        #[arg(short, long, default_value = "fleet.yaml")]
+        file: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Apply a fleet file to the registry
+    Apply {
+        /// Fleet file describing the desired state
+        #[arg(short, long, default_value = "fleet.yaml")]
+        file: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Compare server inventories with the registry and repair drift
+    Reconcile {
+        /// Only report drift, don't repair it
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Move tenants so servers are evenly loaded
+    Rebalance {
+        /// What to balance
+        #[arg(long, value_enum, default_value = "tenants")]
+        balance: Balance,
+
+        /// Seconds of recorded usage to weigh tenants by with --balance load
+        #[arg(long, default_value = "3600")]
+        window: u64,
+
+        /// Largest acceptable gap between the most and least loaded server
+        #[arg(long, default_value = "1")]
+        threshold: f64,
+
+        /// Most moves to make
+        #[arg(long, default_value = "50")]
+        max_moves: usize,
+
+        /// Most moves in flight at once
+        #[arg(long, default_value = "2")]
+        max_concurrent: usize,
+
+        /// Only show the plan
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
     /// Show fleet status
     Status {
         /// Database path
@@ -109,9 +674,128 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum TenantConfigAction {
+    /// Print a tenant's config
+    Get {
+        /// Tenant ID
+        id: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Replace a tenant's config and provision it
+    Set {
+        /// Tenant ID
+        id: String,
+
+        /// Config as a JSON object, or null to clear it
+        config: String,
+
+        /// Apply the config as a JSON Merge Patch (RFC 7396) instead
+        #[arg(short, long)]
+        merge: bool,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigSchemaAction {
+    /// Print the fleet's config schema
+    Get {
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Register a config schema from a JSON file
+    Set {
+        /// JSON Schema file
+        file: String,
+
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+
+    /// Remove the fleet's config schema
+    Clear {
+        /// Database path
+        #[arg(short, long, default_value = "slum.db")]
+        database: String,
+    },
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<Database>,
+    pub provisioner: Arc<Provisioner>,
+    pub reconciler: Arc<Reconciler>,
+    pub rebalancer: Arc<Rebalancer>,
+    pub metrics: Arc<Metrics>,
+    pub routes: Arc<RouteCache>,
+    pub usage: Arc<UsageRecorder>,
+    pub limiter: Arc<RateLimiter>,
+    pub access_log: Option<Arc<AccessLog>>,
+    /// How long a request body may stall before the request fails with 408
+    pub body_idle_timeout: Option<Duration>,
+    /// Extra attempts for idempotent requests that fail to connect
+    pub upstream_retries: u32,
+    pub circuits: Arc<CircuitBreaker>,
+    pub balancer: Arc<LoadBalancer>,
+    pub mirrors: Arc<Mirrors>,
+    /// Shared HTTP cache for tenants that turn it on
+    pub cache: Arc<HttpCache>,
+    /// How the proxy finds each request's tenant
+    pub resolvers: Arc<Resolvers>,
+    /// Enforces tenants' access policies
+    pub auth: Arc<EdgeAuth>,
+    /// How long to hold a request while its tenant's server reports the
+    /// tenant is starting
+    pub warmup: Option<Duration>,
+}
+
+fn parse_time(s: &str) -> Result<i64, String> {
+    usage::parse_time(s).map_err(|e| e.to_string())
+}
+
+/// Parse a byte count such as `1048576`, `512K` or `10M`
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" => 10,
+        "M" | "MB" => 20,
+        "G" | "GB" => 30,
+        _ => return Err(format!("expected a size like 512K or 10M, got: {}", s)),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("expected a size like 512K or 10M, got: {}", s))?;
+    n.checked_mul(1 << shift).ok_or_else(|| format!("size too large: {}", s))
+}
+
+/// Check a tenant ID given on the command line
+fn parse_tenant_id(s: &str) -> Result<String, String> {
+    db::validate_tenant_id(s)
+        .map(|()| s.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Parse a `key=value` label
+fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => Err(format!("expected key=value, got: {}", s)),
+    }
 }
 
 #[tokio::main]
@@ -126,24 +810,169 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Serve { port, database } => {
-            serve(port, &database).await?;
+        Commands::Serve {
+            port,
+            admin_port,
+            database,
+            route_cache_ttl,
+            path_prefix,
+            keep_path_prefix,
+            resolve,
+            jwt_secret,
+            jwt_public_key,
+            jwt_algorithm,
+            provision_interval,
+            reconcile_interval,
+            reconcile_repair,
+            rebalance_interval,
+            rebalance_by,
+            rebalance_threshold,
+            rebalance_max_moves,
+            usage_retention_days,
+            access_log,
+            access_log_max_mb,
+            access_log_max_files,
+            access_log_sample,
+            access_log_redact,
+            rate_limit,
+            rate_burst,
+            max_concurrent,
+            max_body_size,
+            header_read_timeout,
+            tls_cert,
+            tls_key,
+            body_idle_timeout,
+            upstream_retries,
+            warmup_secs,
+            circuit_failures,
+            circuit_open_secs,
+            cache_size,
+            cache_max_entry,
+            cache_dir,
+            otlp_endpoint,
+            otlp_service_name,
+        } => {
+            let reconcile = (reconcile_interval > 0)
+                .then(|| (Duration::from_secs(reconcile_interval), reconcile_repair));
+            let rebalance = (rebalance_interval > 0).then(|| {
+                let options = RebalanceOptions {
+                    threshold: rebalance_threshold,
+                    max_moves: rebalance_max_moves,
+                    load_window: Duration::from_secs(rebalance_interval),
+                    ..Default::default()
+                };
+                (Duration::from_secs(rebalance_interval), rebalance_by, options)
+            });
+            let path = path_prefix
+                .map(|prefix| PathRouting::new(&prefix, !keep_path_prefix))
+                .transpose()?;
+            let jwt = match (jwt_secret, jwt_public_key) {
+                (Some(secret), _) => Some(JwtKey::secret(
+                    secret.as_bytes(),
+                    jwt_algorithm.unwrap_or(jsonwebtoken::Algorithm::HS256),
+                )?),
+                (None, Some(path)) => Some(JwtKey::public_key(
+                    &std::fs::read(&path)
+                        .with_context(|| format!("Failed to read {}", path.display()))?,
+                    jwt_algorithm.unwrap_or(jsonwebtoken::Algorithm::RS256),
+                )?),
+                (None, None) => None,
+            };
+            serve(ServeOptions {
+                port,
+                admin_port,
+                database,
+                route_cache_ttl: Duration::from_secs(route_cache_ttl),
+                resolvers: Resolvers::new(&resolve, path, jwt)?,
+                provision_interval: Duration::from_secs(provision_interval),
+                reconcile,
+                rebalance,
+                usage_retention: (usage_retention_days > 0)
+                    .then(|| Duration::from_secs(usage_retention_days * 86400)),
+                access_log: access_log.map(|sink| AccessLogConfig {
+                    sink: sink.with_rotation(access_log_max_mb * 1024 * 1024, access_log_max_files),
+                    sample_rate: access_log_sample,
+                    redact: access_log_redact,
+                }),
+                limits: Limits {
+                    rate: rate_limit,
+                    burst: rate_burst,
+                    max_concurrent,
+                    max_body_size,
+                },
+                header_read_timeout: (header_read_timeout > 0)
+                    .then(|| Duration::from_secs(header_read_timeout)),
+                tls: tls_cert.zip(tls_key),
+                body_idle_timeout: (body_idle_timeout > 0)
+                    .then(|| Duration::from_secs(body_idle_timeout)),
+                upstream_retries,
+                warmup: (warmup_secs > 0).then(|| Duration::from_secs(warmup_secs)),
+                circuit: (circuit_failures, Duration::from_secs(circuit_open_secs)),
+                cache: (cache_size, cache_max_entry, cache_dir),
+                otlp: otlp_endpoint.map(|endpoint| (endpoint, otlp_service_name)),
+            })
+            .await?;
         }
-        Commands::ServerAdd { address, name, database } => {
+        Commands::ServerAdd {
+            address,
+            name,
+            labels,
+            protocol,
+            database,
+        } => {
             let db = Database::open(&database).await?;
             let name = name.unwrap_or_else(|| address.clone());
-            let server = db.add_server(&name, &address).await?;
+            let labels: Labels = labels.into_iter().collect();
+            let server = db
+                .transaction(move |tx| {
+                    Box::pin(async move {
+                        let server = tx.add_server(&name, &address).await?;
+                        tx.set_server_protocol(&server.id, protocol).await?;
+                        tx.set_server_labels(&server.id, &labels).await
+                    })
+                })
+                .await?;
             println!("Added server: {} ({})", server.name, server.id);
         }
+        Commands::ServerLabel {
+            server,
+            labels,
+            database,
+        } => {
+            let db = Database::open(&database).await?;
+            let labels: Labels = labels.into_iter().collect();
+            let server = db.set_server_labels(&server, &labels).await?;
+            println!("Labeled server: {} ({})", server.name, format_labels(&server.labels));
+        }
+        Commands::ServerProtocol {
+            server,
+            protocol,
+            database,
+        } => {
+            let db = Database::open(&database).await?;
+            let server = db.set_server_protocol(&server, protocol).await?;
+            println!("Server {} now speaks {}", server.name, server.protocol);
+        }
         Commands::ServerList { database } => {
             let db = Database::open(&database).await?;
             let servers = db.list_servers().await?;
             if servers.is_empty() {
                 println!("No servers in fleet");
             } else {
-                println!("{:<36} {:<20} {:<30} {:<10}", "ID", "NAME", "ADDRESS", "TENANTS");
+                println!(
+                    "{:<36} {:<20} {:<30} {:<10} {:<9} {:<20}",
+                    "ID", "NAME", "ADDRESS", "TENANTS", "PROTOCOL", "LABELS"
+                );
                 for s in servers {
-                    println!("{:<36} {:<20} {:<30} {:<10}", s.id, s.name, s.address, s.tenant_count);
+                    println!(
+                        "{:<36} {:<20} {:<30} {:<10} {:<9} {:<20}",
+                        s.id,
+                        s.name,
+                        s.address,
+                        s.tenant_count,
+                        s.protocol,
+                        format_labels(&s.labels)
+                    );
                 }
             }
         }
@@ -152,10 +981,20 @@ async fn main() -> Result<()> {
             db.remove_server(&server).await?;
             println!("Removed server: {}", server);
         }
-        Commands::TenantAdd { id, server, config, database } => {
-            let db = Database::open(&database).await?;
-            let tenant = db.add_tenant(&id, server.as_deref(), config.as_deref()).await?;
+        Commands::TenantAdd {
+            id,
+            server,
+            config,
+            requires,
+            database,
+        } => {
+            let db = Arc::new(Database::open(&database).await?);
+            let requires: Labels = requires.into_iter().collect();
+            let tenant = db
+                .add_tenant_with_constraints(&id, server.as_deref(), config.as_deref(), &requires)
+                .await?;
             println!("Added tenant: {} on server {}", tenant.id, tenant.server_id);
+            print_sync_report(&Provisioner::new(db).sync_tenant(&tenant.id).await?);
         }
         Commands::TenantList { database } => {
             let db = Database::open(&database).await?;
@@ -163,21 +1002,380 @@ async fn main() -> Result<()> {
             if tenants.is_empty() {
                 println!("No tenants");
             } else {
-                println!("{:<20} {:<36} {:<10}", "ID", "SERVER", "STATUS");
+                println!(
+                    "{:<20} {:<36} {:<10} {:<12} {:<8}",
+                    "ID", "SERVER", "STATUS", "PROVISIONED", "REPLICAS"
+                );
                 for t in tenants {
-                    println!("{:<20} {:<36} {:<10}", t.id, t.server_id, t.status);
+                    let provisioned =
+                        if t.provisioned && t.replicas.iter().all(|r| r.provisioned) {
+                            "yes"
+                        } else {
+                            "pending"
+                        };
+                    println!(
+                        "{:<20} {:<36} {:<10} {:<12} {:<8}",
+                        t.id,
+                        t.server_id,
+                        t.status,
+                        provisioned,
+                        1 + t.replicas.len()
+                    );
                 }
             }
         }
-        Commands::TenantRemove { id, database } => {
+        Commands::TenantPin { id, database } => {
+            let db = Database::open(&database).await?;
+            db.set_tenant_pinned(&id, true).await?;
+            println!("Pinned tenant: {}", id);
+        }
+        Commands::TenantUnpin { id, database } => {
+            let db = Database::open(&database).await?;
+            db.set_tenant_pinned(&id, false).await?;
+            println!("Unpinned tenant: {}", id);
+        }
+        Commands::TenantScale {
+            id,
+            replicas,
+            balance,
+            database,
+        } => {
+            let db = Arc::new(Database::open(&database).await?);
+            let tenant = db.scale_tenant(&id, replicas, balance.as_ref()).await?;
+            println!(
+                "Scaled tenant {} to {} replicas ({})",
+                id,
+                1 + tenant.replicas.len(),
+                tenant.balance
+            );
+            print_sync_report(&Provisioner::new(db).sync_tenant(&id).await?);
+        }
+        Commands::TenantCanary {
+            id,
+            server,
+            weight,
+            database,
+        } => {
+            let db = Arc::new(Database::open(&database).await?);
+            db.set_canary(&id, &server, weight).await?;
+            println!("Sending {}% of tenant {}'s clients to {}", weight, id, server);
+            print_sync_report(&Provisioner::new(db).sync_tenant(&id).await?);
+        }
+        Commands::TenantCanaryRemove {
+            id,
+            server,
+            database,
+        } => {
+            let db = Arc::new(Database::open(&database).await?);
+            db.remove_canary(&id, &server).await?;
+            println!("Removed canary {} from tenant {}", server, id);
+            print_sync_report(&Provisioner::new(db).sync_tenant(&id).await?);
+        }
+        Commands::TenantPromote {
+            id,
+            server,
+            database,
+        } => {
+            let db = Arc::new(Database::open(&database).await?);
+            let tenant = db.promote_canary(&id, &server).await?;
+            println!("Promoted {} to primary of tenant {}", server, tenant.id);
+            print_sync_report(&Provisioner::new(db).sync_tenant(&id).await?);
+        }
+        Commands::TenantMirror {
+            id,
+            server,
+            percent,
+            max_body_size,
+            database,
+        } => {
+            let db = Arc::new(Database::open(&database).await?);
+            db.set_tenant_mirror(&id, &server, percent, max_body_size).await?;
+            println!(
+                "Mirroring {}% of tenant {}'s requests to {} (bodies up to {} bytes)",
+                percent, id, server, max_body_size
+            );
+            print_sync_report(&Provisioner::new(db).sync_tenant(&id).await?);
+        }
+        Commands::TenantMirrorRemove { id, database } => {
+            let db = Arc::new(Database::open(&database).await?);
+            db.clear_tenant_mirror(&id).await?;
+            println!("Stopped mirroring tenant: {}", id);
+            print_sync_report(&Provisioner::new(db).sync_tenant(&id).await?);
+        }
+        Commands::Rules { tenant, database } => {
+            let db = Database::open(&database).await?;
+            let rules = db.route_rules(tenant.as_deref()).await?;
+            if rules.is_empty() {
+                println!("No rules");
+            }
+            for (i, rule) in rules.iter().enumerate() {
+                println!("{:<4} {}", i, rule);
+            }
+        }
+        Commands::RuleAdd {
+            rule,
+            tenant,
+            position,
+            database,
+        } => {
+            let db = Database::open(&database).await?;
+            let rule = RouteRule::parse(&rule)?;
+            let rules = db.add_route_rule(tenant.as_deref(), rule.clone(), position).await?;
+            println!(
+                "Added rule {} for {}: {}",
+                position.unwrap_or(rules.len() - 1),
+                tenant.as_deref().unwrap_or("the fleet"),
+                rule
+            );
+        }
+        Commands::RuleRemove {
+            position,
+            tenant,
+            database,
+        } => {
+            let db = Database::open(&database).await?;
+            let rule = db.remove_route_rule(tenant.as_deref(), position).await?;
+            println!(
+                "Removed rule {} for {}: {}",
+                position,
+                tenant.as_deref().unwrap_or("the fleet"),
+                rule
+            );
+        }
+        Commands::TenantAccess {
+            id,
+            allow,
+            deny,
+            users,
+            forward_auth,
+            database,
+        } => {
+            let db = Database::open(&database).await?;
+            let policy = AccessPolicy {
+                allow,
+                deny,
+                users,
+                forward_auth,
+                ..Default::default()
+            };
+            let policy = db.set_tenant_access(&id, policy).await?;
+            if policy.is_open() {
+                println!("Tenant {} is open to everyone", id);
+            } else {
+                println!("Set access policy for {}: {}", id, format_access(&policy));
+            }
+        }
+        Commands::TenantConfig { action } => match action {
+            TenantConfigAction::Get { id, database } => {
+                let db = Database::open(&database).await?;
+                let tenant = db
+                    .get_tenant(&id)
+                    .await?
+                    .with_context(|| format!("Tenant not found: {}", id))?;
+                println!("{}", serde_json::to_string_pretty(&tenant.config_json())?);
+            }
+            TenantConfigAction::Set {
+                id,
+                config,
+                merge,
+                database,
+            } => {
+                let db = Arc::new(Database::open(&database).await?);
+                let config = if merge {
+                    let patch: serde_json::Value =
+                        serde_json::from_str(&config).context("Patch isn't valid JSON")?;
+                    db.patch_tenant_config(&id, &patch).await?
+                } else {
+                    db.set_tenant_config(&id, Some(&config)).await?
+                };
+                println!("Set config for {}: {}", id, serde_json::to_string(&config)?);
+                print_sync_report(&Provisioner::new(db).sync_tenant(&id).await?);
+            }
+        },
+        Commands::ConfigSchema { action } => match action {
+            ConfigSchemaAction::Get { database } => {
+                let db = Database::open(&database).await?;
+                match db.config_schema().await? {
+                    Some(schema) => println!("{}", serde_json::to_string_pretty(schema.as_json())?),
+                    None => println!("No config schema"),
+                }
+            }
+            ConfigSchemaAction::Set { file, database } => {
+                let db = Database::open(&database).await?;
+                let schema = std::fs::read_to_string(&file)
+                    .with_context(|| format!("Failed to read {}", file))?;
+                let schema = serde_json::from_str(&schema)
+                    .with_context(|| format!("{} isn't valid JSON", file))?;
+                let invalid = db.set_config_schema(Some(schema)).await?;
+                println!("Registered config schema from {}", file);
+                if !invalid.is_empty() {
+                    println!("Tenants whose configs don't match:");
+                    for (tenant_id, error) in invalid {
+                        println!("  {}: {}", tenant_id, error);
+                    }
+                }
+            }
+            ConfigSchemaAction::Clear { database } => {
+                let db = Database::open(&database).await?;
+                db.set_config_schema(None).await?;
+                println!("Removed config schema");
+            }
+        },
+        Commands::TenantCache {
+            id,
+            enabled,
+            database,
+        } => {
             let db = Database::open(&database).await?;
+            db.set_tenant_cache(&id, enabled).await?;
+            println!(
+                "HTTP cache {} for tenant: {}",
+                if enabled { "on" } else { "off" },
+                id
+            );
+        }
+        Commands::TenantCompress {
+            id,
+            enabled,
+            database,
+        } => {
+            let db = Database::open(&database).await?;
+            db.set_tenant_compress(&id, enabled).await?;
+            println!(
+                "Compression {} for tenant: {}",
+                if enabled { "on" } else { "off" },
+                id
+            );
+        }
+        Commands::TenantLimits {
+            id,
+            rate,
+            burst,
+            max_concurrent,
+            max_body_size,
+            database,
+        } => {
+            let db = Database::open(&database).await?;
+            let limits = Limits {
+                rate,
+                burst,
+                max_concurrent,
+                max_body_size,
+            };
+            db.set_tenant_limits(&id, &limits).await?;
+            println!("Set limits for {}: {}", id, format_limits(&limits));
+        }
+        Commands::TenantRemove { id, database } => {
+            let db = Arc::new(Database::open(&database).await?);
             db.remove_tenant(&id).await?;
             println!("Removed tenant: {}", id);
+            print_sync_report(&Provisioner::new(db).sync_tenant(&id).await?);
+        }
+        Commands::TenantUsage {
+            id,
+            from,
+            to,
+            step,
+            database,
+        } => {
+            let db = Database::open(&database).await?;
+            let to = to.unwrap_or_else(|| chrono::Utc::now().timestamp());
+            let from = from.unwrap_or(to - 86400);
+            let usage = db.tenant_usage(&id, from, to, step).await?;
+            if usage.is_empty() {
+                println!("No usage recorded for {}", id);
+            } else {
+                println!(
+                    "{:<20} {:>10} {:>12} {:>12} {:>8} {:>8} {:>10} {:>10}",
+                    "START", "REQUESTS", "BYTES IN", "BYTES OUT", "4XX", "5XX", "P50 MS", "P95 MS"
+                );
+                for u in &usage {
+                    let start = chrono::DateTime::from_timestamp(u.start, 0)
+                        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                        .unwrap_or_else(|| u.start.to_string());
+                    println!(
+                        "{:<20} {:>10} {:>12} {:>12} {:>8} {:>8} {:>10.1} {:>10.1}",
+                        start,
+                        u.requests,
+                        u.bytes_in,
+                        u.bytes_out,
+                        u.status_4xx,
+                        u.status_5xx,
+                        u.p50_ms,
+                        u.p95_ms
+                    );
+                }
+            }
+        }
+        Commands::Plan { file, database } => {
+            let db = Database::open(&database).await?;
+            let spec = fleet::FleetSpec::from_file(&file)?;
+            let plan = fleet::plan(&db, &spec).await?;
+            if plan.is_empty() {
+                println!("No changes. Fleet matches {}", file);
+            } else {
+                for action in &plan.actions {
+                    println!("  {}", action);
+                }
+                println!();
+                println!("Plan: {}", plan.summary());
+            }
+        }
+        Commands::Apply { file, database } => {
+            let db = Arc::new(Database::open(&database).await?);
+            let spec = fleet::FleetSpec::from_file(&file)?;
+            let plan = fleet::plan(&db, &spec).await?;
+            if plan.is_empty() {
+                println!("No changes. Fleet matches {}", file);
+            } else {
+                for action in &plan.actions {
+                    println!("  {}", action);
+                }
+                fleet::apply(&db, &plan).await?;
+                println!();
+                println!("Applied: {}", plan.summary());
+                print_sync_report(&Provisioner::new(db).sync_all().await?);
+            }
+        }
+        Commands::Reconcile { dry_run, database } => {
+            let db = Arc::new(Database::open(&database).await?);
+            let reconciler = Reconciler::new(db.clone(), Arc::new(Provisioner::new(db)));
+            let report = if dry_run {
+                reconciler.check().await?
+            } else {
+                reconciler.repair().await?
+            };
+            print_reconcile_report(&report);
+        }
+        Commands::Rebalance {
+            balance,
+            window,
+            threshold,
+            max_moves,
+            max_concurrent,
+            dry_run,
+            database,
+        } => {
+            let db = Arc::new(Database::open(&database).await?);
+            let rebalancer = Rebalancer::new(db.clone(), Arc::new(Provisioner::new(db)));
+            let options = RebalanceOptions {
+                threshold,
+                max_moves,
+                max_concurrent,
+                load_window: Duration::from_secs(window),
+            };
+            let plan = rebalancer.plan(balance, &options).await?;
+            print_rebalance_plan(&plan);
+            if !dry_run && !plan.moves.is_empty() {
+                let moved = rebalancer.execute(&plan, max_concurrent).await?;
+                println!("Moved {} of {} tenants", moved, plan.moves.len());
+            }
         }
         Commands::Status { database } => {
             let db = Database::open(&database).await?;
             let servers = db.list_servers().await?;
             let tenants = db.list_tenants().await?;
+            let health = db.list_server_health().await?;
             println!("Fleet Status:");
             println!("  Servers: {}", servers.len());
             println!("  Tenants: {}", tenants.len());
@@ -185,7 +1383,14 @@ async fn main() -> Result<()> {
             if !servers.is_empty() {
                 println!("Servers:");
                 for s in &servers {
-                    println!("  {} ({}) - {} tenants", s.name, s.address, s.tenant_count);
+                    let h = health.iter().find(|h| h.server_id == s.id);
+                    println!(
+                        "  {} ({}) - {} tenants - {}",
+                        s.name,
+                        s.address,
+                        s.tenant_count,
+                        h.map_or("healthy".to_string(), format_health)
+                    );
                 }
             }
         }
@@ -194,25 +1399,333 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn serve(port: u16, database: &str) -> Result<()> {
-    let db = Database::open(database).await?;
-    let state = AppState { db: Arc::new(db) };
+/// Warn about tenement calls that failed; they are retried by `slum serve`
+fn print_sync_report(report: &SyncReport) {
+    for error in &report.errors {
+        println!("Warning: failed to {} (will retry)", error);
+    }
+}
+
+fn format_labels(labels: &Labels) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn format_health(health: &ServerHealth) -> String {
+    let state = match health.state.as_str() {
+        "open" => format!("circuit open since {}", health.changed_at),
+        "half_open" => format!("probing since {}", health.changed_at),
+        _ => return "healthy".to_string(),
+    };
+    match &health.last_error {
+        Some(error) => format!("{} after {} failures: {}", state, health.failures, error),
+        None => state,
+    }
+}
+
+fn format_access(policy: &AccessPolicy) -> String {
+    let mut parts = Vec::new();
+    let nets = |nets: &[ipnet::IpNet]| nets.iter().map(ToString::to_string).collect::<Vec<_>>().join(",");
+    if !policy.allow.is_empty() {
+        parts.push(format!("allow={}", nets(&policy.allow)));
+    }
+    if !policy.deny.is_empty() {
+        parts.push(format!("deny={}", nets(&policy.deny)));
+    }
+    if !policy.users.is_empty() {
+        let users: Vec<_> = policy.users.iter().map(|u| u.username.as_str()).collect();
+        parts.push(format!("users={}", users.join(",")));
+    }
+    if let Some(url) = &policy.forward_auth {
+        parts.push(format!("forward-auth={}", url));
+    }
+    parts.join(", ")
+}
+
+fn format_limits(limits: &Limits) -> String {
+    let mut parts = Vec::new();
+    if let Some(rate) = limits.rate {
+        parts.push(format!("rate={}/s", rate));
+    }
+    if let Some(burst) = limits.burst {
+        parts.push(format!("burst={}", burst));
+    }
+    if let Some(max) = limits.max_concurrent {
+        parts.push(format!("max-concurrent={}", max));
+    }
+    if let Some(max) = limits.max_body_size {
+        parts.push(format!("max-body-size={}", max));
+    }
+    if parts.is_empty() {
+        "fleet default".to_string()
+    } else {
+        parts.join(", ")
+    }
+}
+
+fn print_rebalance_plan(plan: &RebalancePlan) {
+    if plan.moves.is_empty() {
+        println!("Fleet is balanced (spread {:.2})", plan.spread_before);
+        return;
+    }
+    println!("{:<20} {:<20} {:<20}", "TENANT", "FROM", "TO");
+    for m in &plan.moves {
+        println!("{:<20} {:<20} {:<20}", m.tenant_id, m.from_name, m.to_name);
+    }
+    println!();
+    println!(
+        "Plan: {} moves, spread {:.2} -> {:.2}",
+        plan.moves.len(),
+        plan.spread_before,
+        plan.spread_after
+    );
+}
+
+fn print_reconcile_report(report: &ReconcileReport) {
+    println!("Checked {} servers", report.servers_checked);
+    for s in &report.unreachable {
+        println!("  unreachable: {} ({})", s.server_name, s.error);
+    }
+    if report.drift.is_empty() {
+        println!("No drift");
+        return;
+    }
+    println!("{:<10} {:<20} {:<20}", "DRIFT", "TENANT", "SERVER");
+    for d in &report.drift {
+        println!(
+            "{:<10} {:<20} {:<20}",
+            d.kind.as_str(),
+            d.tenant_id,
+            d.server_name
+        );
+    }
+    if report.repaired > 0 {
+        println!("Repaired {} entries", report.repaired);
+    }
+}
+
+struct ServeOptions {
+    port: u16,
+    admin_port: Option<u16>,
+    database: String,
+    route_cache_ttl: Duration,
+    resolvers: Resolvers,
+    provision_interval: Duration,
+    reconcile: Option<(Duration, bool)>,
+    rebalance: Option<(Duration, Balance, RebalanceOptions)>,
+    usage_retention: Option<Duration>,
+    access_log: Option<AccessLogConfig>,
+    /// Fleet default tenant limits
+    limits: Limits,
+    header_read_timeout: Option<Duration>,
+    /// Certificate and key for serving the proxy over TLS
+    tls: Option<(PathBuf, PathBuf)>,
+    body_idle_timeout: Option<Duration>,
+    upstream_retries: u32,
+    /// How long to hold requests for a starting tenant
+    warmup: Option<Duration>,
+    /// Failures that open a circuit, and how long it stays open
+    circuit: (u32, Duration),
+    /// HTTP cache size, largest entry, and directory for bodies
+    cache: (u64, u64, Option<PathBuf>),
+    /// OTLP endpoint and service name
+    otlp: Option<(String, String)>,
+}
+
+async fn serve(options: ServeOptions) -> Result<()> {
+    let ServeOptions {
+        port,
+        admin_port,
+        database,
+        route_cache_ttl,
+        resolvers,
+        provision_interval,
+        reconcile,
+        rebalance,
+        usage_retention,
+        access_log,
+        limits,
+        header_read_timeout,
+        tls,
+        body_idle_timeout,
+        upstream_retries,
+        warmup,
+        circuit: (circuit_failures, circuit_open_for),
+        cache: (cache_size, cache_max_entry, cache_dir),
+        otlp,
+    } = options;
+    limits.validate()?;
+    let tls = tls
+        .map(|(cert, key)| server::tls_acceptor(&cert, &key))
+        .transpose()?;
+
+    let db = Arc::new(Database::open(&database).await?);
+    let provisioner = Arc::new(Provisioner::new(db.clone()));
+    provisioner.clone().spawn(provision_interval);
+    let reconciler = Arc::new(Reconciler::new(db.clone(), provisioner.clone()));
+    if let Some((interval, repair)) = reconcile {
+        reconciler.clone().spawn(interval, repair);
+    }
+    let rebalancer = Arc::new(Rebalancer::new(db.clone(), provisioner.clone()));
+    if let Some((interval, balance, options)) = rebalance {
+        rebalancer.clone().spawn(interval, balance, options);
+    }
+    let usage = Arc::new(UsageRecorder::new());
+    usage.clone().spawn(db.clone(), usage_retention);
+    let circuits = Arc::new(CircuitBreaker::new(circuit_failures, circuit_open_for));
+    circuits.clone().spawn(db.clone());
+    let state = AppState {
+        db,
+        provisioner,
+        reconciler,
+        rebalancer,
+        metrics: Arc::new(Metrics::new()?),
+        routes: Arc::new(RouteCache::new(route_cache_ttl)),
+        usage,
+        limiter: Arc::new(RateLimiter::new(limits)),
+        access_log: access_log.map(AccessLog::start).transpose()?.map(Arc::new),
+        body_idle_timeout,
+        upstream_retries,
+        circuits,
+        balancer: Arc::new(LoadBalancer::new()),
+        mirrors: Arc::new(Mirrors::new()),
+        cache: Arc::new(HttpCache::new(cache_size, cache_max_entry, cache_dir)?),
+        resolvers: Arc::new(resolvers),
+        auth: Arc::new(EdgeAuth::new()),
+        warmup,
+    };
+
+    let tracer = match otlp {
+        Some((endpoint, service_name)) => Some(Arc::new(Tracer::start(&endpoint, &service_name)?)),
+        None => None,
+    };
+
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+    tracing::info!(
+        "slum listening on port {}{}",
+        port,
+        if tls.is_some() { " (TLS)" } else { "" }
+    );
+
+    let (db, usage) = (state.db.clone(), state.usage.clone());
+    let serving = match admin_port {
+        Some(admin_port) => {
+            // Metrics name every tenant, so they stay off the public port
+            let admin = api_router().route("/metrics", get(metrics::handler));
+            let admin = with_request_layers(admin, tracer.clone()).with_state(state.clone());
+            let proxy = with_request_layers(proxy_router(), tracer).with_state(state);
+            let admin_listener =
+                tokio::net::TcpListener::bind(format!("0.0.0.0:{}", admin_port)).await?;
+            tracing::info!("slum admin listening on port {}", admin_port);
+            tokio::spawn(server::serve(admin_listener, admin, header_read_timeout, None));
+            server::serve(listener, proxy, header_read_timeout, tls)
+        }
+        None => {
+            tracing::warn!("/metrics is only served with --admin-port");
+            let app = with_request_layers(api_router().merge(proxy_router()), tracer).with_state(state);
+            server::serve(listener, app, header_read_timeout, tls)
+        }
+    };
+    tokio::select! {
+        _ = serving => {}
+        _ = shutdown_signal() => tracing::info!("Shutting down"),
+    }
+
+    // Rollups add up in the database, so the open minute can be written now
+    if let Err(e) = usage.flush(&db, i64::MAX).await {
+        tracing::error!("Failed to write usage rollups: {}", e);
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
 
-    let app = Router::new()
-        // Management API
+/// The management API
+fn api_router() -> Router<AppState> {
+    Router::new()
         .route("/api/health", get(api::health))
         .route("/api/servers", get(api::list_servers).post(api::add_server))
-        .route("/api/servers/{id}", delete(api::remove_server))
+        .route("/api/servers/:id", delete(api::remove_server))
         .route("/api/tenants", get(api::list_tenants).post(api::add_tenant))
-        .route("/api/tenants/{id}", delete(api::remove_tenant))
-        // Catch-all: proxy to tenant
-        .fallback(proxy::handle_request)
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
+        .route("/api/tenants/:id", delete(api::remove_tenant))
+        .route("/api/tenants/:id/usage", get(api::tenant_usage))
+        .route("/api/tenants/:id/limits", put(api::set_tenant_limits))
+        .route(
+            "/api/tenants/:id/config",
+            get(api::tenant_config)
+                .put(api::set_tenant_config)
+                .patch(api::patch_tenant_config),
+        )
+        .route(
+            "/api/tenants/:id/access",
+            get(api::tenant_access)
+                .put(api::set_tenant_access)
+                .delete(api::remove_tenant_access),
+        )
+        .route("/api/tenants/:id/scale", put(api::scale_tenant))
+        .route("/api/tenants/:id/canary", put(api::set_canary))
+        .route("/api/tenants/:id/canary/:server", delete(api::remove_canary))
+        .route("/api/tenants/:id/promote", post(api::promote_canary))
+        .route(
+            "/api/tenants/:id/mirror",
+            get(api::tenant_mirror)
+                .put(api::set_tenant_mirror)
+                .delete(api::remove_tenant_mirror),
+        )
+        .route(
+            "/api/tenants/:id/cache",
+            put(api::set_tenant_cache).delete(api::purge_tenant_cache),
+        )
+        .route("/api/tenants/:id/compression", put(api::set_tenant_compress))
+        .route(
+            "/api/tenants/:id/rules",
+            get(api::tenant_route_rules).put(api::set_tenant_route_rules),
+        )
+        .route("/api/rules", get(api::fleet_route_rules).put(api::set_fleet_route_rules))
+        .route(
+            "/api/config-schema",
+            get(api::config_schema)
+                .put(api::set_config_schema)
+                .delete(api::remove_config_schema),
+        )
+        .route("/api/reconcile", get(api::check_drift).post(api::repair_drift))
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-    tracing::info!("slum listening on port {}", port);
+/// Catch-all: proxy to tenant
+fn proxy_router() -> Router<AppState> {
+    Router::new()
+        .fallback(proxy::handle_request)
+        .layer(proxy::compression())
+}
 
-    axum::serve(listener, app).await?;
-    Ok(())
+/// Tracing and request IDs, applied to every route. The request ID is set
+/// first so the request's spans can include it.
+fn with_request_layers(router: Router<AppState>, tracer: Option<Arc<Tracer>>) -> Router<AppState> {
+    router
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::make_span))
+        .layer(axum::middleware::from_fn_with_state(tracer, telemetry::middleware))
+        .layer(axum::middleware::from_fn(request_id::middleware))
 }
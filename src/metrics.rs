@@ -0,0 +1,237 @@
+//! Prometheus metrics for the proxy and registry
+//!
+//! Request metrics are recorded by the proxy as requests complete. Registry
+//! gauges (servers, tenants, tenants per server) are read from the database
+//! each time `/metrics` is scraped, so they are never stale.
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::time::Duration;
+
+use crate::db::Database;
+use crate::AppState;
+
+/// Label value used before a request has been routed to a tenant or server
+pub const UNROUTED: &str = "-";
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    upstream_errors: IntCounterVec,
+    in_flight: IntGauge,
+    rate_limited: IntCounterVec,
+    route_cache_hits: IntCounter,
+    route_cache_misses: IntCounter,
+    http_cache: IntCounterVec,
+    servers: IntGauge,
+    tenants: IntGauge,
+    server_tenants: IntGaugeVec,
+}
+
+/// Decrements the in-flight gauge when the request finishes
+pub struct InFlight<'a>(&'a IntGauge);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("slum_http_requests_total", "Proxied requests"),
+            &["tenant", "server", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "slum_http_request_duration_seconds",
+                "Time to proxy a request, until upstream response headers",
+            ),
+            &["tenant", "server"],
+        )?;
+        let upstream_errors = IntCounterVec::new(
+            Opts::new(
+                "slum_upstream_errors_total",
+                "Failed requests to tenement servers",
+            ),
+            &["server", "kind"],
+        )?;
+        let in_flight = IntGauge::new("slum_http_requests_in_flight", "Requests being proxied")?;
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "slum_rate_limited_total",
+                "Requests rejected by tenant rate or concurrency limits",
+            ),
+            &["tenant", "reason"],
+        )?;
+        let route_cache_hits =
+            IntCounter::new("slum_route_cache_hits_total", "Routing lookups served from cache")?;
+        let route_cache_misses = IntCounter::new(
+            "slum_route_cache_misses_total",
+            "Routing lookups that went to the database",
+        )?;
+        let http_cache = IntCounterVec::new(
+            Opts::new(
+                "slum_http_cache_requests_total",
+                "Cacheable requests for tenants with the HTTP cache on",
+            ),
+            &["tenant", "result"],
+        )?;
+        let servers = IntGauge::new("slum_servers", "Servers in the fleet")?;
+        let tenants = IntGauge::new("slum_tenants", "Tenants in the fleet")?;
+        let server_tenants = IntGaugeVec::new(
+            Opts::new("slum_server_tenants", "Tenants placed on each server"),
+            &["server"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(upstream_errors.clone()))?;
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(rate_limited.clone()))?;
+        registry.register(Box::new(route_cache_hits.clone()))?;
+        registry.register(Box::new(route_cache_misses.clone()))?;
+        registry.register(Box::new(http_cache.clone()))?;
+        registry.register(Box::new(servers.clone()))?;
+        registry.register(Box::new(tenants.clone()))?;
+        registry.register(Box::new(server_tenants.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            upstream_errors,
+            in_flight,
+            rate_limited,
+            route_cache_hits,
+            route_cache_misses,
+            http_cache,
+            servers,
+            tenants,
+            server_tenants,
+        })
+    }
+
+    pub fn in_flight(&self) -> InFlight<'_> {
+        self.in_flight.inc();
+        InFlight(&self.in_flight)
+    }
+
+    pub fn observe_request(&self, tenant: &str, server: &str, status: StatusCode, elapsed: Duration) {
+        self.requests
+            .with_label_values(&[tenant, server, status.as_str()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[tenant, server])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn upstream_error(&self, server: &str, kind: &str) {
+        self.upstream_errors.with_label_values(&[server, kind]).inc();
+    }
+
+    pub fn rate_limited(&self, tenant: &str, reason: &str) {
+        self.rate_limited.with_label_values(&[tenant, reason]).inc();
+    }
+
+    pub fn route_cache(&self, hit: bool) {
+        if hit {
+            self.route_cache_hits.inc();
+        } else {
+            self.route_cache_misses.inc();
+        }
+    }
+
+    pub fn http_cache(&self, tenant: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.http_cache.with_label_values(&[tenant, result]).inc();
+    }
+
+    /// Update registry gauges from the database
+    pub async fn refresh_registry(&self, db: &Database) -> Result<()> {
+        let servers = db.list_servers().await?;
+
+        // Drop series for servers that left the fleet
+        self.server_tenants.reset();
+        for s in &servers {
+            self.server_tenants
+                .with_label_values(&[&s.name])
+                .set(s.tenant_count as i64);
+        }
+        self.servers.set(servers.len() as i64);
+        self.tenants
+            .set(servers.iter().map(|s| s.tenant_count as i64).sum());
+        Ok(())
+    }
+
+    /// Render in the Prometheus text format
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+pub async fn handler(State(state): State<AppState>) -> Response {
+    if let Err(e) = state.metrics.refresh_registry(&state.db).await {
+        tracing::error!("Failed to read registry for metrics: {}", e);
+    }
+    match state.metrics.render() {
+        Ok(body) => ([(header::CONTENT_TYPE, TextEncoder::new().format_type().to_string())], body)
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render() {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Database::open(&path).await.unwrap();
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+
+        let metrics = Metrics::new().unwrap();
+        metrics.observe_request(
+            "romneys",
+            "server-1",
+            StatusCode::OK,
+            Duration::from_millis(20),
+        );
+        metrics.upstream_error("server-1", "connect");
+        metrics.route_cache(true);
+        metrics.rate_limited("romneys", "rate");
+        {
+            let _request = metrics.in_flight();
+            metrics.refresh_registry(&db).await.unwrap();
+            let body = metrics.render().unwrap();
+            assert!(body.contains("slum_http_requests_in_flight 1"));
+        }
+
+        let body = metrics.render().unwrap();
+        assert!(body.contains(
+            r#"slum_http_requests_total{server="server-1",status="200",tenant="romneys"} 1"#
+        ));
+        assert!(body.contains(r#"slum_upstream_errors_total{kind="connect",server="server-1"} 1"#));
+        assert!(body.contains(r#"slum_server_tenants{server="server-1"} 1"#));
+        assert!(body.contains("slum_tenants 1"));
+        assert!(body.contains("slum_route_cache_hits_total 1"));
+        assert!(body.contains(r#"slum_rate_limited_total{reason="rate",tenant="romneys"} 1"#));
+        assert!(body.contains("slum_http_requests_in_flight 0"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Traffic mirroring
+//!
+//! The proxy sends a sample of a tenant's requests to its mirror server as
+//! well, without waiting for the mirror or returning its response, and
+//! compares the two status codes. Results are kept in memory per tenant
+//! until the mirror is changed or slum restarts.
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, request::Parts, HeaderValue, Request, StatusCode, Uri};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Semaphore};
+
+use crate::db::Server;
+use crate::proxy::upstream_client;
+
+/// Header marking a request as a mirrored copy
+pub const MIRROR_HEADER: &str = "x-slum-mirror";
+
+/// Largest request body mirrored unless a tenant's mirror sets another
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1 << 20;
+
+/// Mirrored requests in flight at once, across tenants; more are skipped
+const MAX_IN_FLIGHT: usize = 256;
+
+/// How long a mirror server has to respond
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Divergent requests kept per tenant
+const RECENT: usize = 20;
+
+/// How a tenant's mirror has answered compared to its servers
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MirrorSummary {
+    /// Requests sent to both
+    pub mirrored: u64,
+    /// The mirror answered with the same status
+    pub matched: u64,
+    /// The mirror answered with another status, or not at all
+    pub diverged: u64,
+    /// Sampled requests that weren't mirrored: bodies over the cap, or too
+    /// many mirrored requests in flight
+    pub skipped: u64,
+    /// Divergences by `"<status> -> <mirror status>"`, with `error` for a
+    /// mirror that didn't answer
+    pub divergences: BTreeMap<String, u64>,
+    /// The latest divergent requests, oldest first
+    pub recent: VecDeque<Divergence>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Divergence {
+    pub at: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub mirror_status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// A mirrored request waiting for the tenant server's status
+pub struct Pending(oneshot::Sender<StatusCode>);
+
+impl Pending {
+    pub fn finish(self, status: StatusCode) {
+        let _ = self.0.send(status);
+    }
+}
+
+pub struct Mirrors {
+    summaries: Mutex<HashMap<String, MirrorSummary>>,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Default for Mirrors {
+    fn default() -> Self {
+        Self {
+            summaries: Mutex::new(HashMap::new()),
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+}
+
+impl Mirrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to mirror a request, `percent` percent of the time
+    pub fn sample(percent: u8) -> bool {
+        uuid::Uuid::new_v4().as_u128() % 100 < percent as u128
+    }
+
+    /// Count a sampled request that wasn't mirrored
+    pub fn skip(&self, tenant: &str) {
+        self.with_summary(tenant, |s| s.skipped += 1);
+    }
+
+    /// Send a copy of a request to `server` in the background. Its status
+    /// is compared once the returned `Pending` is finished.
+    pub fn send(
+        self: &Arc<Self>,
+        tenant: &str,
+        server: &Server,
+        parts: &Parts,
+        body: Bytes,
+    ) -> Option<Pending> {
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            self.skip(tenant);
+            return None;
+        };
+
+        let path_and_query = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+        let uri: Uri = format!("http://{}{}", server.address, path_and_query)
+            .parse()
+            .ok()?;
+        let mut headers = parts.headers.clone();
+        headers.remove(header::HOST);
+        headers.insert("x-tenant-id", HeaderValue::from_str(tenant).ok()?);
+        headers.insert(MIRROR_HEADER, HeaderValue::from_static("1"));
+        let mut req = Request::new(Body::from(body));
+        *req.method_mut() = parts.method.clone();
+        *req.uri_mut() = uri;
+        *req.headers_mut() = headers;
+
+        let (tx, rx) = oneshot::channel();
+        let mirrors = self.clone();
+        let server = server.clone();
+        let tenant = tenant.to_string();
+        let method = parts.method.to_string();
+        let path = parts.uri.path().to_string();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let client = upstream_client(&server);
+            // The response body is dropped unread
+            let mirror_status = match tokio::time::timeout(TIMEOUT, client.request(req)).await {
+                Ok(Ok(response)) => Ok(response.status()),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timed out".to_string()),
+            };
+            // The client went away before the tenant's server answered
+            let Ok(status) = rx.await else {
+                return;
+            };
+            mirrors.record(&tenant, method, path, status, mirror_status);
+        });
+        Some(Pending(tx))
+    }
+
+    fn record(
+        &self,
+        tenant: &str,
+        method: String,
+        path: String,
+        status: StatusCode,
+        mirror_status: Result<StatusCode, String>,
+    ) {
+        self.with_summary(tenant, |s| {
+            s.mirrored += 1;
+            if mirror_status.as_ref() == Ok(&status) {
+                s.matched += 1;
+                return;
+            }
+            s.diverged += 1;
+            let mirror = match &mirror_status {
+                Ok(mirror) => mirror.as_u16().to_string(),
+                Err(_) => "error".to_string(),
+            };
+            *s.divergences
+                .entry(format!("{} -> {}", status.as_u16(), mirror))
+                .or_insert(0) += 1;
+            if s.recent.len() == RECENT {
+                s.recent.pop_front();
+            }
+            s.recent.push_back(Divergence {
+                at: chrono::Utc::now().to_rfc3339(),
+                method,
+                path,
+                status: status.as_u16(),
+                mirror_status: mirror_status.as_ref().ok().map(|s| s.as_u16()),
+                error: mirror_status.err(),
+            });
+        });
+    }
+
+    fn with_summary(&self, tenant: &str, f: impl FnOnce(&mut MirrorSummary)) {
+        let mut summaries = self.summaries.lock().unwrap();
+        f(summaries.entry(tenant.to_string()).or_default());
+    }
+
+    /// What a tenant's mirror has seen since it was set
+    pub fn summary(&self, tenant: &str) -> MirrorSummary {
+        self.summaries
+            .lock()
+            .unwrap()
+            .get(tenant)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Forget a tenant's results, when its mirror changes
+    pub fn reset(&self, tenant: &str) {
+        self.summaries.lock().unwrap().remove(tenant);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mirrors = Mirrors::new();
+        let ok = StatusCode::OK;
+        mirrors.record("romneys", "GET".into(), "/".into(), ok, Ok(ok));
+        mirrors.record("romneys", "GET".into(), "/a".into(), ok, Ok(StatusCode::NOT_FOUND));
+        mirrors.record("romneys", "POST".into(), "/b".into(), ok, Err("refused".into()));
+        mirrors.skip("romneys");
+
+        let summary = mirrors.summary("romneys");
+        assert_eq!(summary.mirrored, 3);
+        assert_eq!(summary.matched, 1);
+        assert_eq!(summary.diverged, 2);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.divergences["200 -> 404"], 1);
+        assert_eq!(summary.divergences["200 -> error"], 1);
+        assert_eq!(summary.recent[0].path, "/a");
+        assert_eq!(summary.recent[1].error.as_deref(), Some("refused"));
+
+        // Only the latest divergences are kept
+        for _ in 0..RECENT {
+            mirrors.record("romneys", "GET".into(), "/c".into(), ok, Ok(StatusCode::BAD_GATEWAY));
+        }
+        let summary = mirrors.summary("romneys");
+        assert_eq!(summary.recent.len(), RECENT);
+        assert!(summary.recent.iter().all(|d| d.path == "/c"));
+
+        mirrors.reset("romneys");
+        assert_eq!(mirrors.summary("romneys"), MirrorSummary::default());
+        assert_eq!(mirrors.summary("smiths").mirrored, 0);
+    }
+
+    #[test]
+    fn test_sample() {
+        assert!((0..100).all(|_| Mirrors::sample(100)));
+        assert!((0..100).all(|_| !Mirrors::sample(0)));
+    }
+}
@@ -0,0 +1,363 @@
+//! Provisioning tenants on tenement servers
+//!
+//! The registry is the source of truth. Writes mark tenants unprovisioned and
+//! queue deprovisions in the same transaction; the `Provisioner` then pushes
+//! that state to the servers, immediately after a change and again from a
+//! background loop until every call has succeeded.
+
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::{Database, Deprovision, Tenant};
+use crate::tenement::TenementClient;
+
+pub struct Provisioner {
+    db: Arc<Database>,
+    client: TenementClient,
+}
+
+/// What a server is to the tenant being provisioned on it
+#[derive(Clone, Copy)]
+enum Role {
+    Primary,
+    Replica,
+    Mirror,
+}
+
+/// Outcome of a provisioning pass
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub provisioned: usize,
+    pub deprovisioned: usize,
+    pub errors: Vec<String>,
+}
+
+impl Provisioner {
+    pub fn new(db: Arc<Database>) -> Self {
+        Self {
+            db,
+            client: TenementClient::new(Duration::from_secs(5)),
+        }
+    }
+
+    /// Push pending work for a single tenant
+    pub async fn sync_tenant(&self, tenant_id: &str) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+
+        for d in self.db.list_deprovisions().await? {
+            if d.tenant_id == tenant_id {
+                self.deprovision(&d, &mut report).await?;
+            }
+        }
+        if let Some(tenant) = self.db.get_tenant(tenant_id).await? {
+            self.provision(&tenant, &mut report).await?;
+        }
+
+        Ok(report)
+    }
+
+    /// Push all pending work. Deprovisions go first so a tenant that moved is
+    /// gone from its old server before it appears on the new one.
+    pub async fn sync_all(&self) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+
+        for d in self.db.list_deprovisions().await? {
+            self.deprovision(&d, &mut report).await?;
+        }
+        for tenant in self.db.list_unprovisioned_tenants().await? {
+            self.provision(&tenant, &mut report).await?;
+        }
+
+        Ok(report)
+    }
+
+    /// Retry pending work every `interval` until the process exits
+    pub fn spawn(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match self.sync_all().await {
+                    Ok(report) if report.provisioned + report.deprovisioned > 0 => {
+                        tracing::info!(
+                            "Provisioning: {} provisioned, {} deprovisioned, {} failed",
+                            report.provisioned,
+                            report.deprovisioned,
+                            report.errors.len()
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::error!("Provisioning pass failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Provision one tenant on each of its servers that needs it. Tenement
+    /// failures are recorded on the tenant and in the report; only registry
+    /// errors are returned.
+    async fn provision(&self, tenant: &Tenant, report: &mut SyncReport) -> Result<()> {
+        if !tenant.provisioned {
+            self.provision_on(tenant, &tenant.server_id, Role::Primary, report).await?;
+        }
+        for replica in tenant.replicas.iter().filter(|r| !r.provisioned) {
+            self.provision_on(tenant, &replica.server_id, Role::Replica, report).await?;
+        }
+        if let Some(mirror) = tenant.mirror.as_ref().filter(|m| !m.provisioned) {
+            self.provision_on(tenant, &mirror.server_id, Role::Mirror, report).await?;
+        }
+        Ok(())
+    }
+
+    async fn provision_on(
+        &self,
+        tenant: &Tenant,
+        server_id: &str,
+        role: Role,
+        report: &mut SyncReport,
+    ) -> Result<()> {
+        let server = self
+            .db
+            .get_server(server_id)
+            .await?
+            .ok_or_else(|| anyhow!("Server not found for tenant: {}", tenant.id))?;
+        let config = tenant.config.as_deref();
+
+        match self.client.provision(&server.address, &tenant.id, config).await {
+            Ok(()) => {
+                // If the tenant changed while the call was in flight it stays
+                // unprovisioned and the next pass sends the new state
+                let marked = match role {
+                    Role::Primary => self.db.mark_provisioned(&tenant.id, server_id, config).await?,
+                    Role::Replica => {
+                        self.db.mark_replica_provisioned(&tenant.id, server_id, config).await?
+                    }
+                    Role::Mirror => {
+                        self.db.mark_mirror_provisioned(&tenant.id, server_id, config).await?
+                    }
+                };
+                if marked {
+                    report.provisioned += 1;
+                }
+            }
+            Err(e) => {
+                let error = e.to_string();
+                tracing::warn!(
+                    "Failed to provision tenant {} on {}: {}",
+                    tenant.id,
+                    server.name,
+                    error
+                );
+                match role {
+                    Role::Primary => self.db.record_provision_error(&tenant.id, &error).await?,
+                    Role::Replica => {
+                        self.db.record_replica_error(&tenant.id, server_id, &error).await?
+                    }
+                    // Only reported; the next pass retries it
+                    Role::Mirror => {}
+                }
+                report
+                    .errors
+                    .push(format!("provision {} on {}: {}", tenant.id, server.name, error));
+            }
+        }
+        Ok(())
+    }
+
+    async fn deprovision(&self, d: &Deprovision, report: &mut SyncReport) -> Result<()> {
+        match self.client.deprovision(&d.server_address, &d.tenant_id).await {
+            Ok(()) => {
+                self.db.complete_deprovision(d.id).await?;
+                report.deprovisioned += 1;
+            }
+            Err(e) => {
+                let error = e.to_string();
+                tracing::warn!(
+                    "Failed to deprovision tenant {} from {}: {}",
+                    d.tenant_id,
+                    d.server_address,
+                    error
+                );
+                self.db.record_deprovision_error(d.id, &error).await?;
+                report.errors.push(format!(
+                    "deprovision {} from {}: {}",
+                    d.tenant_id, d.server_address, error
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenement::mock::MockTenement;
+
+    async fn test_db() -> Arc<Database> {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        Arc::new(Database::open(&path).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_provision_and_deprovision() {
+        let db = test_db().await;
+        let (mock, address) = MockTenement::start().await;
+        let provisioner = Provisioner::new(db.clone());
+
+        db.add_server("server-1", &address).await.unwrap();
+        db.add_tenant("romneys", None, Some(r#"{"plan":"family"}"#))
+            .await
+            .unwrap();
+
+        let report = provisioner.sync_tenant("romneys").await.unwrap();
+        assert_eq!(report.provisioned, 1);
+        assert_eq!(
+            mock.tenants.lock().unwrap()["romneys"],
+            serde_json::json!({ "plan": "family" })
+        );
+        assert!(db.get_tenant("romneys").await.unwrap().unwrap().provisioned);
+
+        // Config updates are pushed again
+        db.set_tenant_config("romneys", Some(r#"{"plan":"pro"}"#))
+            .await
+            .unwrap();
+        provisioner.sync_tenant("romneys").await.unwrap();
+        assert_eq!(
+            mock.tenants.lock().unwrap()["romneys"],
+            serde_json::json!({ "plan": "pro" })
+        );
+
+        db.remove_tenant("romneys").await.unwrap();
+        let report = provisioner.sync_tenant("romneys").await.unwrap();
+        assert_eq!(report.deprovisioned, 1);
+        assert!(!mock.has("romneys"));
+        assert!(db.list_deprovisions().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_move_provisions_new_server() {
+        let db = test_db().await;
+        let (old, old_address) = MockTenement::start().await;
+        let (new, new_address) = MockTenement::start().await;
+        let provisioner = Provisioner::new(db.clone());
+
+        db.add_server("server-1", &old_address).await.unwrap();
+        db.add_server("server-2", &new_address).await.unwrap();
+        db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+        provisioner.sync_all().await.unwrap();
+        assert!(old.has("romneys"));
+
+        db.move_tenant("romneys", "server-2").await.unwrap();
+        let report = provisioner.sync_all().await.unwrap();
+        assert_eq!((report.provisioned, report.deprovisioned), (1, 1));
+        assert!(!old.has("romneys"));
+        assert!(new.has("romneys"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_calls_are_retried() {
+        let db = test_db().await;
+        let provisioner = Provisioner::new(db.clone());
+
+        // Nothing listens here
+        db.add_server("server-1", "127.0.0.1:1").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+
+        let report = provisioner.sync_all().await.unwrap();
+        assert_eq!(report.errors.len(), 1);
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert!(!tenant.provisioned);
+        assert!(tenant.provision_error.is_some());
+
+        // The server comes back at a new address
+        let (mock, address) = MockTenement::start().await;
+        db.update_server_address("server-1", &address).await.unwrap();
+
+        let report = provisioner.sync_all().await.unwrap();
+        assert_eq!(report.provisioned, 1);
+        assert!(mock.has("romneys"));
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert!(tenant.provisioned);
+        assert!(tenant.provision_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replicas() {
+        let db = test_db().await;
+        let (first, first_address) = MockTenement::start().await;
+        let (second, second_address) = MockTenement::start().await;
+        let provisioner = Provisioner::new(db.clone());
+
+        db.add_server("server-1", &first_address).await.unwrap();
+        db.add_server("server-2", &second_address).await.unwrap();
+        db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+        db.scale_tenant("romneys", 2, None).await.unwrap();
+
+        let report = provisioner.sync_all().await.unwrap();
+        assert_eq!(report.provisioned, 2);
+        assert!(first.has("romneys") && second.has("romneys"));
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert!(tenant.replicas[0].provisioned);
+
+        // Config changes reach every replica
+        db.set_tenant_config("romneys", Some(r#"{"plan":"pro"}"#))
+            .await
+            .unwrap();
+        assert_eq!(provisioner.sync_all().await.unwrap().provisioned, 2);
+        assert_eq!(
+            second.tenants.lock().unwrap()["romneys"],
+            serde_json::json!({ "plan": "pro" })
+        );
+
+        db.scale_tenant("romneys", 1, None).await.unwrap();
+        assert_eq!(provisioner.sync_all().await.unwrap().deprovisioned, 1);
+        assert!(first.has("romneys"));
+        assert!(!second.has("romneys"));
+    }
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let db = test_db().await;
+        let (first, first_address) = MockTenement::start().await;
+        let (second, second_address) = MockTenement::start().await;
+        let (third, third_address) = MockTenement::start().await;
+        let provisioner = Provisioner::new(db.clone());
+
+        db.add_server("server-1", &first_address).await.unwrap();
+        db.add_server("server-2", &second_address).await.unwrap();
+        db.add_server("server-3", &third_address).await.unwrap();
+        db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+        provisioner.sync_all().await.unwrap();
+
+        db.set_tenant_mirror("romneys", "server-2", 10, 1024).await.unwrap();
+        assert_eq!(provisioner.sync_tenant("romneys").await.unwrap().provisioned, 1);
+        assert!(second.has("romneys"));
+        let tenant = db.get_tenant("romneys").await.unwrap().unwrap();
+        assert!(tenant.mirror.unwrap().provisioned);
+        assert!(db.list_unprovisioned_tenants().await.unwrap().is_empty());
+
+        // Config changes reach the mirror
+        db.set_tenant_config("romneys", Some(r#"{"plan":"pro"}"#))
+            .await
+            .unwrap();
+        assert_eq!(provisioner.sync_all().await.unwrap().provisioned, 2);
+        assert_eq!(
+            second.tenants.lock().unwrap()["romneys"],
+            serde_json::json!({ "plan": "pro" })
+        );
+
+        // Moving the mirror takes the tenant off the old mirror server
+        db.set_tenant_mirror("romneys", "server-3", 10, 1024).await.unwrap();
+        let report = provisioner.sync_tenant("romneys").await.unwrap();
+        assert_eq!((report.provisioned, report.deprovisioned), (1, 1));
+        assert!(!second.has("romneys"));
+        assert!(third.has("romneys"));
+
+        db.clear_tenant_mirror("romneys").await.unwrap();
+        assert_eq!(provisioner.sync_tenant("romneys").await.unwrap().deprovisioned, 1);
+        assert!(first.has("romneys"));
+        assert!(!third.has("romneys"));
+    }
+}
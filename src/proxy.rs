@@ -1,71 +1,423 @@
 use axum::{
     body::Body,
-    extract::{Host, State},
-    http::{Request, StatusCode, Uri},
+    extract::{ConnectInfo, Host, State},
+    http::{
+        header, uri::Authority, Extensions, HeaderMap, HeaderValue, Method, Request, StatusCode, Uri, Version,
+    },
     response::{IntoResponse, Response},
 };
+use http_body_util::BodyExt;
 use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tower_http::compression::{predicate::DefaultPredicate, CompressionLayer, Predicate};
 
+use crate::access_log::AccessLogEntry;
+use crate::balance;
+use crate::cache::HttpCache;
+use crate::db::{LoadBalance, Protocol, RouteRule, Server, Tenant};
+use crate::limits::{self, BodyError};
+use crate::metrics::UNROUTED;
+use crate::mirror::{Mirrors, Pending};
+use crate::request_id::RequestId;
+use crate::resolver::Resolved;
+use crate::rules;
+use crate::telemetry::{TraceContext, TRACEPARENT, TRACESTATE};
+use crate::tenement::{self, ADMIN_PREFIX};
 use crate::AppState;
 
-/// Extract tenant ID from Host header
-/// Examples:
-///   romneys.ourfam.lol -> romneys
-///   smiths.example.com -> smiths
-///   localhost:8080 -> None (no subdomain)
-fn extract_tenant_from_host(host: &str) -> Option<String> {
-    // Remove port if present
-    let host = host.split(':').next().unwrap_or(host);
-
-    // Split by dots
-    let parts: Vec<&str> = host.split('.').collect();
-
-    // Need at least 3 parts for a subdomain (tenant.domain.tld)
-    // Or 2 parts if it's tenant.localhost
-    if parts.len() >= 3 {
-        Some(parts[0].to_string())
-    } else if parts.len() == 2 && parts[1] == "localhost" {
-        Some(parts[0].to_string())
+/// Wait before retrying a failed connection, times the attempt number
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Wait between tries of a request held while its tenant starts
+const WARMUP_POLL: Duration = Duration::from_millis(500);
+
+/// When clients are told to come back to a tenant that is still starting
+const WARMUP_RETRY_AFTER: Duration = Duration::from_secs(3);
+
+/// Where a request was routed, attached to the response's extensions
+#[derive(Debug, Clone)]
+pub struct RouteInfo {
+    pub tenant: String,
+    pub server_id: String,
+    /// Server name, used in metrics and logs
+    pub server: String,
+}
+
+/// Time the upstream took to return response headers
+#[derive(Debug, Clone, Copy)]
+struct UpstreamLatency(Duration);
+
+/// Marks a response from a tenant with compression on
+#[derive(Debug, Clone, Copy)]
+struct Compress;
+
+/// Compresses responses marked `Compress` with gzip or brotli, as the
+/// client's `Accept-Encoding` allows. Responses that are already encoded,
+/// small, images, gRPC or event streams are left alone.
+pub fn compression() -> CompressionLayer<impl Predicate> {
+    let marked = |_: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions| {
+        extensions.get::<Compress>().is_some()
+    };
+    CompressionLayer::new()
+        .gzip(true)
+        .br(true)
+        .compress_when(DefaultPredicate::new().and(marked))
+}
+
+/// A tenant and the servers its requests go to
+#[derive(Clone)]
+struct Route {
+    tenant: Tenant,
+    /// The primary first
+    servers: Vec<Server>,
+    /// Server that gets copies of sampled requests
+    mirror: Option<Server>,
+    /// Fleet and tenant route rules, in order
+    rules: Vec<RouteRule>,
+}
+
+/// Short-lived cache of Host -> (tenant, servers) lookups. Entries expire
+/// after `ttl`, so registry changes made elsewhere (e.g. by the CLI) are
+/// picked up within that window.
+pub struct RouteCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Route)>>,
+}
+
+impl RouteCache {
+    /// A zero `ttl` disables caching
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, host: &str) -> Option<Route> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(host) {
+            Some((at, route)) if at.elapsed() < self.ttl => Some(route.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, host: &str, route: &Route) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (at, _)| at.elapsed() < self.ttl);
+        entries.insert(host.to_string(), (Instant::now(), route.clone()));
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Count the bytes of a body as it is read
+fn count_body(body: Body, counter: Arc<AtomicU64>) -> Body {
+    Body::new(body.map_frame(move |frame| {
+        if let Some(data) = frame.data_ref() {
+            counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        frame
+    }))
+}
+
+/// Calls its function with the bytes sent when the response body is
+/// dropped: once it has been sent, or the client has gone away
+struct OnComplete<F: FnOnce(u64)> {
+    bytes_out: u64,
+    f: Option<F>,
+}
+
+impl<F: FnOnce(u64)> Drop for OnComplete<F> {
+    fn drop(&mut self) {
+        if let Some(f) = self.f.take() {
+            f(self.bytes_out);
+        }
+    }
+}
+
+fn on_complete(response: Response, f: impl FnOnce(u64) + Send + 'static) -> Response {
+    let mut guard = OnComplete {
+        bytes_out: 0,
+        f: Some(f),
+    };
+    let (parts, body) = response.into_parts();
+    let body = body.map_frame(move |frame| {
+        // Borrow the whole guard so the closure owns it
+        let guard = &mut guard;
+        if let Some(data) = frame.data_ref() {
+            guard.bytes_out += data.len() as u64;
+        }
+        frame
+    });
+    Response::from_parts(parts, Body::new(body))
+}
+
+fn is_admin_path(path: &str) -> bool {
+    path == ADMIN_PREFIX || path.starts_with(&format!("{}/", ADMIN_PREFIX))
+}
+
+/// The request's URI with `mount` taken off the front of its path
+fn strip_mount(uri: &Uri, mount: &str) -> Option<Uri> {
+    let path_and_query = uri.path_and_query().map_or("/", |p| p.as_str());
+    let rest = &path_and_query[mount.len()..];
+    let rest = if rest.starts_with('/') {
+        rest.to_string()
     } else {
-        None
+        format!("/{}", rest)
+    };
+    rest.parse().ok()
+}
+
+/// Point `Location` headers that redirect within the tenant back under its
+/// mount point: absolute paths, and URLs on the host the client used, on
+/// any port
+fn rewrite_location(response: &mut Response, mount: &str, host: &str) {
+    let Some(location) = response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return;
+    };
+    let rewritten = if location.starts_with('/') && !location.starts_with("//") {
+        format!("{}{}", mount, location)
+    } else {
+        let Ok(uri) = location.parse::<Uri>() else {
+            return;
+        };
+        let client_host = host.parse::<Authority>().ok();
+        match (uri.scheme_str(), uri.authority(), client_host) {
+            (Some(scheme), Some(authority), Some(client_host))
+                if authority.host().eq_ignore_ascii_case(client_host.host()) && uri.path().starts_with('/') =>
+            {
+                let path_and_query = uri.path_and_query().map_or("/", |p| p.as_str());
+                format!("{}://{}{}{}", scheme, authority, mount, path_and_query)
+            }
+            _ => return,
+        }
+    };
+    if let Ok(value) = HeaderValue::from_str(&rewritten) {
+        response.headers_mut().insert(header::LOCATION, value);
     }
 }
 
 pub async fn handle_request(
     State(state): State<AppState>,
     Host(host): Host,
+    client: Option<ConnectInfo<SocketAddr>>,
     req: Request<Body>,
 ) -> Response {
-    // Extract tenant from subdomain
-    let tenant_id = match extract_tenant_from_host(&host) {
-        Some(id) => id,
-        None => {
-            return (
-                StatusCode::BAD_REQUEST,
-                "No tenant specified. Use subdomain like: tenant.yourdomain.com",
-            )
-                .into_response();
+    let _in_flight = state.metrics.in_flight();
+    let start = Instant::now();
+    let started_at = chrono::Utc::now();
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_default();
+    let sampled = state.access_log.as_ref().is_some_and(|log| log.sample());
+    let bytes_in = Arc::new(AtomicU64::new(0));
+    let req = req.map(|body| count_body(body, bytes_in.clone()));
+
+    let client_ip = client.as_ref().map(|ConnectInfo(addr)| addr.ip());
+    let response = proxy_request(&state, &host, client_ip, req).await;
+    let elapsed = start.elapsed();
+    let status = response.status();
+
+    let route = response.extensions().get::<RouteInfo>().cloned();
+    let upstream_latency = response.extensions().get::<UpstreamLatency>().copied();
+    let (tenant, server) = match &route {
+        Some(route) => (route.tenant.as_str(), route.server.as_str()),
+        None => (UNROUTED, UNROUTED),
+    };
+    state.metrics.observe_request(tenant, server, status, elapsed);
+
+    let usage = state.usage.clone();
+    let access_log = state
+        .access_log
+        .clone()
+        .filter(|_| sampled || status.is_server_error());
+    if route.is_none() && access_log.is_none() {
+        return response;
+    }
+
+    on_complete(response, move |bytes_out| {
+        let bytes_in = bytes_in.load(Ordering::Relaxed);
+        if let Some(route) = &route {
+            usage.record(
+                &route.tenant,
+                started_at.timestamp(),
+                status,
+                elapsed,
+                bytes_in,
+                bytes_out,
+            );
+        }
+        if let Some(log) = access_log {
+            log.log(&AccessLogEntry {
+                timestamp: started_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                request_id,
+                tenant: route.as_ref().map(|r| r.tenant.clone()),
+                server: route.map(|r| r.server),
+                method,
+                path: log.redact(&path),
+                status: status.as_u16(),
+                bytes_in,
+                bytes_out,
+                upstream_latency_ms: upstream_latency
+                    .map(|UpstreamLatency(latency)| latency.as_secs_f64() * 1000.0),
+                client_ip: client.map(|ConnectInfo(addr)| addr.ip().to_string()),
+            });
+        }
+    })
+}
+
+/// A Host header in the form tenants and aliases are stored in: lowercase,
+/// without the trailing dot of a fully qualified name
+fn normalize_host(host: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') => format!("{}:{}", name.trim_end_matches('.'), port),
+        _ if host.starts_with('[') => host,
+        _ => host.trim_end_matches('.').to_string(),
+    }
+}
+
+/// Find the tenant and its servers for a request, using the route cache.
+/// Routes are cached by `key`, such as the host or the tenant's mount
+/// point, and `alias_host` is tried as a domain alias if there is no such
+/// tenant.
+async fn resolve(
+    state: &AppState,
+    key: &str,
+    tenant_id: &str,
+    alias_host: Option<&str>,
+) -> anyhow::Result<Option<Route>> {
+    if let Some(route) = state.routes.get(key) {
+        state.metrics.route_cache(true);
+        return Ok(Some(route));
+    }
+    state.metrics.route_cache(false);
+
+    let found = match state.db.lookup_tenant_servers(tenant_id).await? {
+        Some(found) => Some(found),
+        // Try domain alias lookup
+        None => match alias_host {
+            Some(host) => match state.db.lookup_by_domain(host).await? {
+                Some(tid) => state.db.lookup_tenant_servers(&tid).await?,
+                None => None,
+            },
+            None => None,
+        },
+    };
+    let Some((tenant, servers)) = found else {
+        return Ok(None);
+    };
+
+    let mirror = match &tenant.mirror {
+        Some(mirror) => state.db.get_server(&mirror.server_id).await?,
+        None => None,
+    };
+    let rules = state.db.tenant_route_rules(&tenant.id).await?;
+    let route = Route {
+        tenant,
+        servers,
+        mirror,
+        rules,
+    };
+    state.routes.insert(key, &route);
+    Ok(Some(route))
+}
+
+/// A tester's canary choice, from the header or else the cookie
+fn canary_choice(req: &Request<Body>) -> Option<&str> {
+    let headers = req.headers();
+    headers
+        .get(balance::CANARY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .find_map(|v| balance::cookie(v, balance::CANARY_COOKIE))
+        })
+}
+
+/// What hashing load balancers hash for a request
+fn balance_key(balance: &LoadBalance, req: &Request<Body>, client: Option<IpAddr>) -> Option<String> {
+    if let LoadBalance::CookieHash(name) = balance {
+        let session = req
+            .headers()
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .find_map(|v| balance::cookie(v, name));
+        if let Some(session) = session {
+            return Some(session.to_string());
+        }
+    }
+    client.map(|ip| ip.to_string())
+}
+
+async fn proxy_request(
+    state: &AppState,
+    host: &str,
+    client: Option<IpAddr>,
+    mut req: Request<Body>,
+) -> Response {
+    let host = normalize_host(host);
+    let host = host.as_str();
+    let Some(resolved) = state.resolvers.resolve(host, &req) else {
+        return (StatusCode::BAD_REQUEST, state.resolvers.to_string()).into_response();
+    };
+    let tenant_id = resolved.tenant_id().to_string();
+    // Routes are cached by how the tenant was named
+    let (route_key, alias_host, mount) = match resolved {
+        Resolved::Subdomain(_) => (host.to_string(), Some(host), None),
+        Resolved::Path { mount, strip, .. } => {
+            if strip {
+                let Some(uri) = strip_mount(req.uri(), &mount) else {
+                    return (StatusCode::BAD_REQUEST, "Invalid path").into_response();
+                };
+                *req.uri_mut() = uri;
+                // Lets the tenant build links that include the mount point
+                if let Ok(value) = HeaderValue::from_str(&mount) {
+                    req.headers_mut().insert("x-forwarded-prefix", value);
+                }
+            }
+            (mount.clone(), None, strip.then_some(mount))
         }
+        Resolved::Named(_) => (format!("#{}", tenant_id), None, None),
     };
 
+    // The tenement admin API is only for slum itself
+    if is_admin_path(req.uri().path()) {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+
     // Look up tenant -> server mapping
-    let (tenant, server) = match state.db.lookup_tenant(&tenant_id).await {
+    let Route {
+        tenant,
+        servers,
+        mirror,
+        rules,
+    } = match resolve(state, &route_key, &tenant_id, alias_host).await {
         Ok(Some(result)) => result,
         Ok(None) => {
-            // Try domain alias lookup
-            match state.db.lookup_by_domain(&host).await {
-                Ok(Some(tid)) => match state.db.lookup_tenant(&tid).await {
-                    Ok(Some(result)) => result,
-                    _ => {
-                        return (StatusCode::NOT_FOUND, format!("Tenant not found: {}", tenant_id))
-                            .into_response()
-                    }
-                },
-                _ => {
-                    return (StatusCode::NOT_FOUND, format!("Tenant not found: {}", tenant_id))
-                        .into_response()
-                }
-            }
+            return (StatusCode::NOT_FOUND, format!("Tenant not found: {}", tenant_id))
+                .into_response()
         }
         Err(e) => {
             tracing::error!("Database error looking up tenant {}: {}", tenant_id, e);
@@ -73,14 +425,185 @@ pub async fn handle_request(
         }
     };
 
-    if tenant.status != "active" {
-        return (
+    let key = balance_key(&tenant.balance, &req, client);
+    let candidates = balance::split(&tenant, &servers, canary_choice(&req), key.as_deref());
+    let server = state
+        .balancer
+        .pick(&tenant.id, &tenant.balance, &candidates, key.as_deref(), |s| {
+            state.circuits.available(&s.id)
+        })
+        .clone();
+
+    let span = tracing::Span::current();
+    span.record("tenant", tenant.id.as_str());
+    span.record("server", server.name.as_str());
+
+    let mut redirect = rules::route(&rules, &mut req, host);
+    // Nor can a rewrite rule reach it
+    if is_admin_path(req.uri().path()) {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    // Rules see the path under the mount, so their redirects need it back
+    if let (Some(redirect), Some(mount)) = (&mut redirect, &mount) {
+        rewrite_location(redirect, mount, host);
+    }
+
+    // The tenant's access policy is checked before anything is served
+    let refused = if tenant.status == "active" && redirect.is_none() && !tenant.access.is_open() {
+        state
+            .auth
+            .check(&tenant.id, &tenant.access, client, host, &mut req)
+            .await
+    } else {
+        None
+    };
+
+    // Cached responses are served before limits apply
+    let lookup = if tenant.http.cache {
+        HttpCache::lookup(&tenant.id, host, &req)
+    } else {
+        None
+    };
+    let hit = match &lookup {
+        Some(lookup) if tenant.status == "active" && redirect.is_none() && refused.is_none() => {
+            let hit = state.cache.get(lookup).await;
+            state.metrics.http_cache(&tenant.id, hit.is_some());
+            hit
+        }
+        _ => None,
+    };
+
+    let max_body_size = state.limiter.effective(tenant.limits).max_body_size;
+    let mut response = if tenant.status != "active" {
+        (
             StatusCode::SERVICE_UNAVAILABLE,
-            format!("Tenant {} is {}", tenant_id, tenant.status),
+            format!("Tenant {} is {}", tenant.id, tenant.status),
+        )
+            .into_response()
+    } else if let Some(redirect) = redirect {
+        redirect
+    } else if let Some(refused) = refused {
+        refused
+    } else if let Some(hit) = hit {
+        hit
+    } else if exceeds_body_size(&req, max_body_size) {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body too large for tenant {}", tenant.id),
         )
-            .into_response();
+            .into_response()
+    } else {
+        match state.limiter.acquire(&tenant.id, tenant.limits) {
+            Ok(permit) => {
+                let idle = state.body_idle_timeout;
+                let req = req.map(|body| limits::guard_body(body, max_body_size, idle));
+                match start_mirror(state, &tenant, mirror.as_ref(), req).await {
+                    Ok((req, mirrored)) => {
+                        let connection = state.balancer.connect(&server.id);
+                        let mut response = forward(state, &tenant, &server, req).await;
+                        if let Some(mount) = &mount {
+                            rewrite_location(&mut response, mount, host);
+                        }
+                        if let Some(mirrored) = mirrored {
+                            mirrored.finish(response.status());
+                        }
+                        let response = match lookup {
+                            Some(lookup) => state.cache.store(lookup, response),
+                            None => response,
+                        };
+                        // Count the request against the tenant and server
+                        // until its body is sent
+                        on_complete(response, move |_| drop((permit, connection)))
+                    }
+                    Err(response) => response,
+                }
+            }
+            Err(rejection) => {
+                state.metrics.rate_limited(&tenant.id, rejection.as_str());
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, rejection.retry_after_secs().to_string())],
+                    format!("Too many requests for tenant {}", tenant.id),
+                )
+                    .into_response()
+            }
+        }
+    };
+
+    rules::respond(&rules, &mut response);
+    if tenant.http.compress {
+        response.extensions_mut().insert(Compress);
+    }
+    response.extensions_mut().insert(RouteInfo {
+        tenant: tenant.id,
+        server_id: server.id,
+        server: server.name,
+    });
+    response
+}
+
+/// The request's declared body length
+fn content_length(req: &Request<Body>) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Whether the request declares a body longer than `max`. Bodies without a
+/// `Content-Length` are checked as they are read.
+fn exceeds_body_size(req: &Request<Body>, max: Option<u64>) -> bool {
+    max.is_some_and(|max| content_length(req).is_some_and(|len| len > max))
+}
+
+/// Send a copy of the request to the tenant's mirror if it is sampled. The
+/// body is read into memory to send it twice, so bodies over the mirror's
+/// cap, or of unknown length, aren't mirrored.
+async fn start_mirror(
+    state: &AppState,
+    tenant: &Tenant,
+    server: Option<&Server>,
+    req: Request<Body>,
+) -> Result<(Request<Body>, Option<Pending>), Response> {
+    let (Some(mirror), Some(server)) = (&tenant.mirror, server) else {
+        return Ok((req, None));
+    };
+    if !Mirrors::sample(mirror.percent) {
+        return Ok((req, None));
+    }
+    let empty = hyper::body::Body::is_end_stream(req.body());
+    let fits = content_length(&req).is_some_and(|len| len <= mirror.max_body_size);
+    if !(empty || fits) {
+        state.mirrors.skip(&tenant.id);
+        return Ok((req, None));
     }
 
+    let (parts, body) = req.into_parts();
+    let body = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) => {
+            return Err(body_error_response(&e)
+                .unwrap_or_else(|| (StatusCode::BAD_REQUEST, "Failed to read request body").into_response()))
+        }
+    };
+    let pending = state.mirrors.send(&tenant.id, server, &parts, body.clone());
+    Ok((Request::from_parts(parts, Body::from(body)), pending))
+}
+
+/// The response for a request body that broke a limit
+fn body_error_response(e: &(dyn std::error::Error + 'static)) -> Option<Response> {
+    match limits::body_error(e)? {
+        BodyError::TooLarge => Some((StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response()),
+        BodyError::Timeout => {
+            Some((StatusCode::REQUEST_TIMEOUT, "Timed out reading request body").into_response())
+        }
+    }
+}
+
+/// Send the request on to the tenant's server
+async fn forward(state: &AppState, tenant: &Tenant, server: &Server, req: Request<Body>) -> Response {
+    let tenant_id = &tenant.id;
+
     // Build upstream URL
     // The tenement server handles routing to the correct process via its own proxy
     let path = req.uri().path();
@@ -96,10 +619,11 @@ pub async fn handle_request(
     );
 
     // Create HTTP client and proxy the request
-    let client = Client::builder(TokioExecutor::new()).build_http();
+    let client = upstream_client(server);
 
     // Build new request for upstream
     let (parts, body) = req.into_parts();
+    let trace_context = parts.extensions.get::<TraceContext>().cloned();
 
     let upstream_uri: Uri = match upstream_url.parse() {
         Ok(uri) => uri,
@@ -109,67 +633,962 @@ pub async fn handle_request(
         }
     };
 
-    let mut upstream_req = Request::builder()
-        .method(parts.method)
-        .uri(upstream_uri);
-
-    // Copy headers, adding X-Tenant-ID
-    for (key, value) in parts.headers.iter() {
-        // Skip host header (will be set by hyper)
-        if key != "host" {
-            upstream_req = upstream_req.header(key, value);
-        }
-    }
-    upstream_req = upstream_req.header("X-Tenant-ID", &tenant_id);
-
-    let upstream_req = match upstream_req.body(body) {
-        Ok(req) => req,
+    // Copy headers, including X-Request-ID, adding X-Tenant-ID
+    let mut headers = parts.headers.clone();
+    // Skip host header (will be set by hyper)
+    headers.remove(header::HOST);
+    match HeaderValue::from_str(tenant_id) {
+        Ok(value) => headers.insert("x-tenant-id", value),
         Err(e) => {
             tracing::error!("Failed to build upstream request: {}", e);
             return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to build request").into_response();
         }
     };
 
-    // Send request to upstream
-    match client.request(upstream_req).await {
-        Ok(response) => {
-            let (parts, body) = response.into_parts();
-            Response::from_parts(parts, Body::new(body))
+    // Make this request's span the upstream's parent
+    if let Some(context) = &trace_context {
+        headers.insert(TRACEPARENT, context.traceparent().parse().unwrap());
+        headers.remove(TRACESTATE);
+        if let Some(state) = context.tracestate.as_ref().and_then(|s| s.parse().ok()) {
+            headers.insert(TRACESTATE, state);
         }
-        Err(e) => {
-            tracing::error!("Upstream request failed for tenant {}: {}", tenant_id, e);
-            (
-                StatusCode::BAD_GATEWAY,
-                format!("Failed to reach tenant server: {}", e),
+    }
+
+    // A request that failed to connect never reached the server, so it is
+    // safe to send again if it can be replayed: idempotent, with no body
+    let replayable = hyper::body::Body::is_end_stream(&body);
+    let attempts = if is_idempotent(&parts.method) && replayable {
+        1 + state.upstream_retries
+    } else {
+        1
+    };
+    let mut body = Some(body);
+    // Requests without a body are held while the tenant starts, since the
+    // server hasn't handled them
+    let warm_by = state.warmup.filter(|_| replayable).map(|warmup| Instant::now() + warmup);
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+        if let Err(retry_after) = state.circuits.check(&server.id) {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, (retry_after.as_secs_f64().ceil() as u64).max(1).to_string())],
+                format!("Tenant server {} is unavailable", server.name),
             )
-                .into_response()
+                .into_response();
+        }
+
+        let mut upstream_req = Request::new(body.take().unwrap_or_default());
+        *upstream_req.method_mut() = parts.method.clone();
+        *upstream_req.uri_mut() = upstream_uri.clone();
+        *upstream_req.headers_mut() = headers.clone();
+
+        // Send request to upstream
+        let sent = Instant::now();
+        match client.request(upstream_req).await {
+            Ok(response) => {
+                state.circuits.success(&server.id);
+                if tenement::is_starting(&response) {
+                    if warm_by.is_some_and(|at| Instant::now() + WARMUP_POLL < at) {
+                        tracing::debug!("Tenant {} is starting, holding request", tenant_id);
+                        tokio::time::sleep(WARMUP_POLL).await;
+                        attempt -= 1;
+                        continue;
+                    }
+                    return waking_up(tenant_id, &parts.headers);
+                }
+                let (parts, body) = response.into_parts();
+                let mut response = Response::from_parts(parts, Body::new(body));
+                response
+                    .extensions_mut()
+                    .insert(UpstreamLatency(sent.elapsed()));
+                return response;
+            }
+            Err(e) => {
+                // The client's body broke a limit while it was being forwarded
+                if let Some(response) = body_error_response(&e) {
+                    return response;
+                }
+                let kind = if e.is_connect() { "connect" } else { "request" };
+                state.metrics.upstream_error(&server.name, kind);
+                state.circuits.failure(&server.id, &e.to_string());
+                if e.is_connect() && attempt < attempts {
+                    tracing::warn!(
+                        "Upstream connect failed for tenant {} (attempt {} of {}): {}",
+                        tenant_id,
+                        attempt,
+                        attempts,
+                        e
+                    );
+                    tokio::time::sleep(RETRY_BACKOFF * attempt).await;
+                    continue;
+                }
+                tracing::error!("Upstream request failed for tenant {}: {}", tenant_id, e);
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    format!("Failed to reach tenant server: {}", e),
+                )
+                    .into_response();
+            }
         }
     }
 }
 
+/// A 503 for a tenant whose server is still starting: a page that reloads
+/// itself for browsers, plain text for other clients
+fn waking_up(tenant_id: &str, headers: &HeaderMap) -> Response {
+    let retry_after = [(header::RETRY_AFTER, WARMUP_RETRY_AFTER.as_secs().to_string())];
+    let browser = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("text/html"));
+    if !browser {
+        let message = format!("Tenant {} is starting, try again shortly", tenant_id);
+        return (StatusCode::SERVICE_UNAVAILABLE, retry_after, message).into_response();
+    }
+
+    let tenant_id: String = tenant_id
+        .chars()
+        .map(|c| match c {
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '&' => "&amp;".to_string(),
+            '"' => "&quot;".to_string(),
+            c => c.to_string(),
+        })
+        .collect();
+    let page = format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="{secs}">
+<title>Waking up {tenant}</title>
+</head>
+<body style="font-family: sans-serif; text-align: center; margin-top: 20vh">
+<h1>Waking up {tenant}&hellip;</h1>
+<p>It was asleep. This page will reload in a few seconds.</p>
+</body>
+</html>
+"#,
+        secs = WARMUP_RETRY_AFTER.as_secs(),
+        tenant = tenant_id,
+    );
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        retry_after,
+        [(header::CACHE_CONTROL, "no-store")],
+        axum::response::Html(page),
+    )
+        .into_response()
+}
+
+/// A client speaking the server's protocol. Responses are streamed back
+/// frame by frame, so HTTP/2 trailers such as gRPC's `grpc-status` reach
+/// the client.
+pub fn upstream_client(server: &Server) -> Client<hyper_util::client::legacy::connect::HttpConnector, Body> {
+    Client::builder(TokioExecutor::new())
+        .http2_only(server.protocol == Protocol::Http2)
+        .build_http()
+}
+
+/// Methods that may be sent more than once with the same effect
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::{Database, Limits};
+    use crate::limits::RateLimiter;
+    use crate::metrics::Metrics;
+    use crate::provision::Provisioner;
+    use crate::rebalance::Rebalancer;
+    use crate::reconcile::Reconciler;
+    use crate::resolver::{PathRouting, Resolvers};
+    use crate::usage::UsageRecorder;
+    use axum::routing::post;
+    use axum::Router;
+
+    /// Proxy state over a fresh database
+    async fn test_state(limits: Limits) -> AppState {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Arc::new(Database::open(&path).await.unwrap());
+        let provisioner = Arc::new(Provisioner::new(db.clone()));
+        AppState {
+            reconciler: Arc::new(Reconciler::new(db.clone(), provisioner.clone())),
+            rebalancer: Arc::new(Rebalancer::new(db.clone(), provisioner.clone())),
+            provisioner,
+            db,
+            metrics: Arc::new(Metrics::new().unwrap()),
+            routes: Arc::new(RouteCache::new(Duration::ZERO)),
+            usage: Arc::new(UsageRecorder::new()),
+            limiter: Arc::new(RateLimiter::new(limits)),
+            access_log: None,
+            body_idle_timeout: Some(Duration::from_millis(200)),
+            upstream_retries: 2,
+            circuits: Arc::new(crate::circuit::CircuitBreaker::new(3, Duration::from_secs(30))),
+            balancer: Arc::new(crate::balance::LoadBalancer::new()),
+            mirrors: Arc::new(Mirrors::new()),
+            cache: Arc::new(HttpCache::new(1 << 20, 1 << 16, None).unwrap()),
+            resolvers: Arc::new(Resolvers::default()),
+            auth: Arc::new(crate::auth::EdgeAuth::new()),
+            warmup: Some(Duration::from_secs(2)),
+        }
+    }
+
+    /// Serve `app` and return its address
+    async fn start(app: Router) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::server::serve(listener, app, None, None));
+        addr
+    }
+
+    /// Start an upstream that echoes request bodies, and a proxy routing
+    /// `romneys` to it. Returns the proxy's address.
+    async fn start_proxy(limits: Limits) -> SocketAddr {
+        let upstream = start(Router::new().route("/echo", post(|body: String| async { body }))).await;
+        start_proxy_to(upstream, limits).await.0
+    }
+
+    async fn start_proxy_to(upstream: SocketAddr, limits: Limits) -> (SocketAddr, AppState) {
+        let state = test_state(limits).await;
+        state.db.add_server("server-1", &upstream.to_string()).await.unwrap();
+        state.db.add_tenant("romneys", None, None).await.unwrap();
+        let proxy = start(Router::new().fallback(handle_request).with_state(state.clone())).await;
+        (proxy, state)
+    }
 
     #[test]
-    fn test_extract_tenant() {
-        assert_eq!(
-            extract_tenant_from_host("romneys.ourfam.lol"),
-            Some("romneys".to_string())
-        );
-        assert_eq!(
-            extract_tenant_from_host("smiths.example.com"),
-            Some("smiths".to_string())
-        );
-        assert_eq!(
-            extract_tenant_from_host("romneys.ourfam.lol:8080"),
-            Some("romneys".to_string())
-        );
-        assert_eq!(
-            extract_tenant_from_host("romneys.localhost"),
-            Some("romneys".to_string())
-        );
-        assert_eq!(extract_tenant_from_host("localhost:8080"), None);
-        assert_eq!(extract_tenant_from_host("ourfam.lol"), None);
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("Romneys.OurFam.lol"), "romneys.ourfam.lol");
+        assert_eq!(normalize_host("romneys.ourfam.lol."), "romneys.ourfam.lol");
+        assert_eq!(normalize_host("ROMNEYS.localhost.:8080"), "romneys.localhost:8080");
+        assert_eq!(normalize_host("[::1]:8080"), "[::1]:8080");
+    }
+
+    #[test]
+    fn test_mounts() {
+        let uri: Uri = "/t/romneys/photos?page=2".parse().unwrap();
+        assert_eq!(strip_mount(&uri, "/t/romneys").unwrap(), "/photos?page=2");
+        let uri: Uri = "/t/romneys?page=2".parse().unwrap();
+        assert_eq!(strip_mount(&uri, "/t/romneys").unwrap(), "/?page=2");
+
+        let location = |location: &'static str| {
+            let mut response = Response::new(Body::empty());
+            response
+                .headers_mut()
+                .insert(header::LOCATION, HeaderValue::from_static(location));
+            rewrite_location(&mut response, "/t/romneys", "example.com");
+            response.headers()[header::LOCATION].to_str().unwrap().to_string()
+        };
+        assert_eq!(location("/login?next=/"), "/t/romneys/login?next=/");
+        assert_eq!(location("https://example.com/login"), "https://example.com/t/romneys/login");
+        assert_eq!(location("https://example.com:8443/login"), "https://example.com:8443/t/romneys/login");
+        assert_eq!(location("https://other.com/login"), "https://other.com/login");
+        assert_eq!(location("//other.com/login"), "//other.com/login");
+        assert_eq!(location("login"), "login");
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_path() {
+        use axum::routing::get;
+        use hyper_util::client::legacy::Client;
+
+        let upstream = start(
+            Router::new()
+                .route("/photos", get(|req: Request<Body>| async move {
+                    let prefix = req.headers().get("x-forwarded-prefix").cloned();
+                    format!("{} {:?}", req.uri(), prefix)
+                }))
+                .route("/old", get(|| async { axum::response::Redirect::to("/new") })),
+        )
+        .await;
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        for strip in [true, false] {
+            let mut state = test_state(Limits::default()).await;
+            let path = PathRouting::new("/t", strip).unwrap();
+            state.resolvers = Arc::new(Resolvers::new(&[], Some(path), None).unwrap());
+            state.db.add_server("server-1", &upstream.to_string()).await.unwrap();
+            state.db.add_tenant("romneys", None, None).await.unwrap();
+            let proxy = start(Router::new().fallback(handle_request).with_state(state)).await;
+
+            let get = |host: &str, path: &str| {
+                let req = Request::builder()
+                    .uri(format!("http://{}{}", proxy, path))
+                    .header(header::HOST, host)
+                    .body(Body::empty())
+                    .unwrap();
+                client.request(req)
+            };
+            let response = get("example.com", "/t/romneys/photos?page=2").await.unwrap();
+            if strip {
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                assert_eq!(body, r#"/photos?page=2 Some("/t/romneys")"#);
+                let response = get("example.com", "/t/romneys/old").await.unwrap();
+                assert_eq!(response.headers()[header::LOCATION], "/t/romneys/new");
+            } else {
+                // The server sees the full path
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            }
+
+            // Subdomains still work, and unknown tenants are not found
+            let response = get("romneys.ourfam.lol", "/photos").await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let response = get("example.com", "/t/smiths/photos").await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let response = get("example.com", "/photos").await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let response = get("example.com", "/t/romneys/_tenement/tenants").await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_header() {
+        use axum::routing::get;
+        use crate::resolver::ResolverSpec;
+        use hyper_util::client::legacy::Client;
+
+        let upstream = start(Router::new().route(
+            "/",
+            get(|headers: axum::http::HeaderMap| async move { headers["x-tenant-id"].as_bytes().to_vec() }),
+        ))
+        .await;
+        let mut state = test_state(Limits::default()).await;
+        let specs = [ResolverSpec::Header("x-tenant".to_string()), ResolverSpec::Subdomain];
+        state.resolvers = Arc::new(Resolvers::new(&specs, None, None).unwrap());
+        state.routes = Arc::new(RouteCache::new(Duration::from_secs(60)));
+        state.db.add_server("server-1", &upstream.to_string()).await.unwrap();
+        state.db.add_tenant("romneys", None, None).await.unwrap();
+        state.db.add_tenant("smiths", None, None).await.unwrap();
+        let proxy = start(Router::new().fallback(handle_request).with_state(state)).await;
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let get = |host: &str, tenant: Option<&str>| {
+            let mut req = Request::builder()
+                .uri(format!("http://{}/", proxy))
+                .header(header::HOST, host);
+            if let Some(tenant) = tenant {
+                req = req.header("x-tenant", tenant);
+            }
+            let req = req.body(Body::empty()).unwrap();
+            let client = client.clone();
+            async move {
+                let response = client.request(req).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        // The header wins over the subdomain, with routes cached apart
+        assert_eq!(get("romneys.ourfam.lol", None).await.1, "romneys");
+        assert_eq!(get("romneys.ourfam.lol", Some("smiths")).await.1, "smiths");
+        assert_eq!(get("api.ourfam.lol", Some("romneys")).await.1, "romneys");
+        assert_eq!(get("romneys.ourfam.lol", None).await.1, "romneys");
+        let (status, body) = get("ourfam.lol", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("header: x-tenant"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_stay_off_proxy_port() {
+        use axum::routing::get;
+        use hyper_util::client::legacy::Client;
+
+        let upstream = start(Router::new().route("/metrics", get(|| async { "tenant metrics" }))).await;
+        let state = test_state(Limits::default()).await;
+        state.db.add_server("server-1", &upstream.to_string()).await.unwrap();
+        state.db.add_tenant("romneys", None, None).await.unwrap();
+        // The router `slum serve` uses without --admin-port
+        let proxy = start(crate::api_router().merge(crate::proxy_router()).with_state(state)).await;
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let req = Request::builder()
+            .uri(format!("http://{}/metrics", proxy))
+            .header(header::HOST, "romneys.ourfam.lol")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "tenant metrics");
+    }
+
+    #[tokio::test]
+    async fn test_applies_route_rules() {
+        use axum::routing::get;
+        use hyper_util::client::legacy::Client;
+
+        let upstream = start(Router::new().route(
+            "/v2/events",
+            get(|| async { ([(header::SERVER, "tenement")], "events") }),
+        ))
+        .await;
+        let (proxy, state) = start_proxy_to(upstream, Limits::default()).await;
+        let rules = ["remove-header Server", "rewrite /api /v2", "redirect /old /v2/events 301"]
+            .map(|rule| RouteRule::parse(&rule.split(' ').map(String::from).collect::<Vec<_>>()).unwrap());
+        state.db.set_route_rules(Some("romneys"), &rules).await.unwrap();
+        let header = RouteRule::parse(&["set-header".into(), "x-frame-options".into(), "DENY".into()]).unwrap();
+        state.db.set_route_rules(None, &[header]).await.unwrap();
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let get = |path: &str| {
+            let req = Request::builder()
+                .uri(format!("http://{}{}", proxy, path))
+                .header(header::HOST, "romneys.ourfam.lol")
+                .body(Body::empty())
+                .unwrap();
+            client.request(req)
+        };
+        let response = get("/api/events").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-frame-options"], "DENY");
+        assert!(!response.headers().contains_key(header::SERVER));
+        let response = get("/old").await.unwrap();
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "/v2/events");
+        assert_eq!(response.headers()["x-frame-options"], "DENY");
+
+        // Rewrites can't reach the tenement admin API
+        let rule = RouteRule::parse(&["rewrite".into(), "/admin".into(), "/_tenement".into()]).unwrap();
+        state.db.set_route_rules(Some("romneys"), &[rule]).await.unwrap();
+        assert_eq!(get("/admin/tenants").await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_route_rules_under_mount() {
+        use hyper_util::client::legacy::Client;
+
+        let upstream = start(Router::new()).await;
+        let mut state = test_state(Limits::default()).await;
+        let path = PathRouting::new("/t", true).unwrap();
+        state.resolvers = Arc::new(Resolvers::new(&[], Some(path), None).unwrap());
+        state.db.add_server("server-1", &upstream.to_string()).await.unwrap();
+        state.db.add_tenant("romneys", None, None).await.unwrap();
+        let rules = ["redirect /old /new", "force-https"]
+            .map(|rule| RouteRule::parse(&rule.split(' ').map(String::from).collect::<Vec<_>>()).unwrap());
+        state.db.set_route_rules(Some("romneys"), &rules).await.unwrap();
+        let proxy = start(Router::new().fallback(handle_request).with_state(state)).await;
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let get = |path: &str, https: bool| {
+            let mut req = Request::builder()
+                .uri(format!("http://{}{}", proxy, path))
+                .header(header::HOST, "example.com");
+            if https {
+                req = req.header("x-forwarded-proto", "https");
+            }
+            client.request(req.body(Body::empty()).unwrap())
+        };
+        let response = get("/t/romneys/old?page=2", true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::LOCATION], "/t/romneys/new?page=2");
+        let response = get("/t/romneys/photos", false).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "https://example.com/t/romneys/photos");
+    }
+
+    #[tokio::test]
+    async fn test_enforces_access_policy() {
+        use crate::db::{AccessPolicy, BasicUser};
+        use axum::routing::get;
+        use base64::Engine;
+        use hyper_util::client::legacy::Client;
+
+        let upstream = start(Router::new().route(
+            "/",
+            get(|headers: HeaderMap| async move {
+                assert!(!headers.contains_key(header::AUTHORIZATION));
+                "hello"
+            }),
+        ))
+        .await;
+        let (proxy, state) = start_proxy_to(upstream, Limits::default()).await;
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let get = |credentials: Option<&str>| {
+            let mut req = Request::builder()
+                .uri(format!("http://{}/", proxy))
+                .header(header::HOST, "romneys.ourfam.lol");
+            if let Some(credentials) = credentials {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+                req = req.header(header::AUTHORIZATION, format!("Basic {}", encoded));
+            }
+            client.request(req.body(Body::empty()).unwrap())
+        };
+
+        let policy = AccessPolicy {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        state.db.set_tenant_access("romneys", policy).await.unwrap();
+        assert_eq!(get(None).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let policy = AccessPolicy {
+            allow: vec!["127.0.0.0/8".parse().unwrap()],
+            users: vec!["qa:hunter2".parse::<BasicUser>().unwrap()],
+            ..Default::default()
+        };
+        state.db.set_tenant_access("romneys", policy).await.unwrap();
+        assert_eq!(get(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get(Some("qa:nope")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get(Some("qa:hunter2")).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_access_policy_refuses() {
+        use hyper_util::client::legacy::Client;
+
+        let upstream = start(Router::new().fallback(|| async { "hello" })).await;
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let mut state = test_state(Limits::default()).await;
+        state.db = Arc::new(Database::open(&path).await.unwrap());
+        state.db.add_server("server-1", &upstream.to_string()).await.unwrap();
+        state.db.add_tenant("romneys", None, None).await.unwrap();
+        let proxy = start(Router::new().fallback(handle_request).with_state(state)).await;
+
+        let pool = sqlx::SqlitePool::connect(&format!("sqlite:{}", path)).await.unwrap();
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        // Garbage, and a policy from a newer slum with a field this one doesn't know
+        for access in ["garbage", r#"{"allow":["10.0.0.0/8"],"mfa":true}"#] {
+            sqlx::query("UPDATE tenants SET access = ?")
+                .bind(access)
+                .execute(&pool)
+                .await
+                .unwrap();
+            let req = Request::builder()
+                .uri(format!("http://{}/", proxy))
+                .header(header::HOST, "romneys.ourfam.lol")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.request(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR, "{}", access);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_holds_requests_while_starting() {
+        use axum::routing::get;
+        use hyper_util::client::legacy::Client;
+        use std::sync::atomic::AtomicU32;
+
+        // Starting for the first two requests
+        let hits = Arc::new(AtomicU32::new(0));
+        let counted = hits.clone();
+        let upstream = start(Router::new().route(
+            "/",
+            get(move || async move {
+                if counted.fetch_add(1, Ordering::SeqCst) < 2 {
+                    (StatusCode::SERVICE_UNAVAILABLE, [(tenement::STATUS_HEADER, "starting")], "").into_response()
+                } else {
+                    "awake".into_response()
+                }
+            })
+            .post(|| async { (StatusCode::SERVICE_UNAVAILABLE, [(tenement::STATUS_HEADER, "starting")]) }),
+        ))
+        .await;
+        let (proxy, mut state) = start_proxy_to(upstream, Limits::default()).await;
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let send = |method: Method, accept: &str, body: &'static str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("http://{}/", proxy))
+                .header(header::HOST, "romneys.ourfam.lol")
+                .header(header::ACCEPT, accept)
+                .body(Body::from(body))
+                .unwrap();
+            client.request(req)
+        };
+
+        let response = send(Method::GET, "*/*", "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "awake");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // A body can't be replayed, so the client is asked to come back
+        let response = send(Method::POST, "application/json", "{}").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "3");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Tenant romneys is starting, try again shortly");
+
+        // Browsers get a page that reloads itself
+        state.warmup = None;
+        let proxy = start(Router::new().fallback(handle_request).with_state(state)).await;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/", proxy))
+            .header(header::HOST, "romneys.ourfam.lol")
+            .header(header::ACCEPT, "text/html,*/*;q=0.8")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("Waking up romneys"));
+    }
+
+    #[tokio::test]
+    async fn test_route_cache() {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = crate::db::Database::open(&path).await.unwrap();
+        db.add_server("server-1", "10.0.0.1:9000").await.unwrap();
+        db.add_tenant("romneys", None, None).await.unwrap();
+        let (tenant, servers) = db.lookup_tenant_servers("romneys").await.unwrap().unwrap();
+        let route = Route {
+            tenant,
+            servers,
+            mirror: None,
+            rules: Vec::new(),
+        };
+
+        let cache = RouteCache::new(Duration::from_millis(50));
+        cache.insert("romneys.ourfam.lol", &route);
+        assert_eq!(cache.get("romneys.ourfam.lol").unwrap().tenant.id, "romneys");
+        assert!(cache.get("smiths.ourfam.lol").is_none());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(cache.get("romneys.ourfam.lol").is_none());
+
+        // A zero TTL disables caching
+        let cache = RouteCache::new(Duration::ZERO);
+        cache.insert("romneys.ourfam.lol", &route);
+        assert!(cache.get("romneys.ourfam.lol").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_body_limits() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let proxy = start_proxy(Limits {
+            max_body_size: Some(8),
+            ..Default::default()
+        })
+        .await;
+
+        async fn send(proxy: SocketAddr, head: &str, body: &str) -> String {
+            let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+            let req = format!(
+                "POST /echo HTTP/1.1\r\nhost: romneys.ourfam.lol\r\nconnection: close\r\n{}\r\n{}",
+                head, body
+            );
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+
+        let response = send(proxy, "content-length: 5\r\n", "hello").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("hello"));
+
+        // Declared too large
+        let response = send(proxy, "content-length: 9\r\n", "too large").await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+        // Streamed too large
+        let chunked = "5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n";
+        let response = send(proxy, "transfer-encoding: chunked\r\n", chunked).await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+        // Stalled: the connection stays open but no more data arrives
+        let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        let req = "POST /echo HTTP/1.1\r\nhost: romneys.ourfam.lol\r\ncontent-length: 5\r\n\r\nhel";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = [0; 64];
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 408"), "{}", String::from_utf8_lossy(&buf[..n]));
+    }
+
+    #[tokio::test]
+    async fn test_retries_and_circuit_breaker() {
+        use hyper_util::client::legacy::Client;
+
+        // Nothing listens here once the listener is dropped
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        drop(listener);
+        let (proxy, state) = start_proxy_to(upstream, Limits::default()).await;
+        let server_id = state.db.get_server("server-1").await.unwrap().unwrap().id;
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let send = |method: Method, body: &'static str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("http://{}/echo", proxy))
+                .header(header::HOST, "romneys.ourfam.lol")
+                .body(Body::from(body))
+                .unwrap();
+            client.request(req)
+        };
+
+        // A POST with a body isn't retried
+        let response = send(Method::POST, "hello").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(state.circuits.health()[0].failures, 1);
+
+        // A GET is tried three times, which opens the circuit
+        state.circuits.success(&server_id);
+        let response = send(Method::GET, "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let health = state.circuits.health();
+        assert_eq!(health[0].failures, 3);
+        assert_eq!(health[0].state, "open");
+
+        let response = send(Method::GET, "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+    }
+
+    #[tokio::test]
+    async fn test_balances_across_replicas() {
+        use axum::routing::get;
+        use http_body_util::BodyExt;
+        use hyper_util::client::legacy::Client;
+
+        let first = start(Router::new().route("/", get(|| async { "first" }))).await;
+        let second = start(Router::new().route("/", get(|| async { "second" }))).await;
+        let (proxy, state) = start_proxy_to(first, Limits::default()).await;
+        state.db.add_server("server-2", &second.to_string()).await.unwrap();
+        state.db.scale_tenant("romneys", 2, None).await.unwrap();
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let get = |cookie: Option<&str>| {
+            let mut req = Request::builder()
+                .uri(format!("http://{}/", proxy))
+                .header(header::HOST, "romneys.ourfam.lol");
+            if let Some(cookie) = cookie {
+                req = req.header(header::COOKIE, cookie);
+            }
+            let response = client.request(req.body(Body::empty()).unwrap());
+            async move {
+                let body = response.await.unwrap().into_body().collect().await.unwrap();
+                String::from_utf8(body.to_bytes().to_vec()).unwrap()
+            }
+        };
+
+        // Round-robin alternates
+        let a = get(None).await;
+        let b = get(None).await;
+        assert_ne!(a, b);
+
+        // A session cookie sticks to one server
+        let balance = LoadBalance::CookieHash("sid".to_string());
+        state.db.scale_tenant("romneys", 2, Some(&balance)).await.unwrap();
+        let first_pick = get(Some("sid=abc")).await;
+        for _ in 0..5 {
+            assert_eq!(get(Some("sid=abc")).await, first_pick);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirrors_requests() {
+        use axum::routing::get;
+        use http_body_util::BodyExt;
+        use hyper_util::client::legacy::Client;
+
+        let primary = start(
+            Router::new()
+                .route("/", get(|| async { "ok" }))
+                .route("/echo", post(|body: String| async move { body })),
+        )
+        .await;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mirror = {
+            let received = received.clone();
+            start(
+                Router::new()
+                    .route("/", get(|| async { StatusCode::INTERNAL_SERVER_ERROR }))
+                    .route(
+                        "/echo",
+                        post(move |headers: axum::http::HeaderMap, body: String| async move {
+                            assert_eq!(headers[crate::mirror::MIRROR_HEADER], "1");
+                            assert_eq!(headers["x-tenant-id"], "romneys");
+                            received.lock().unwrap().push(body);
+                        }),
+                    ),
+            )
+            .await
+        };
+        let (proxy, state) = start_proxy_to(primary, Limits::default()).await;
+        state.db.add_server("server-2", &mirror.to_string()).await.unwrap();
+        state.db.set_tenant_mirror("romneys", "server-2", 100, 8).await.unwrap();
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let send = |method: Method, path: &str, body: &'static str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("http://{}{}", proxy, path))
+                .header(header::HOST, "romneys.ourfam.lol")
+                .body(Body::from(body))
+                .unwrap();
+            client.request(req)
+        };
+
+        // The client gets the tenant server's response either way
+        let response = send(Method::POST, "/echo", "hello").await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello");
+        let response = send(Method::GET, "/", "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Too large to mirror
+        let response = send(Method::POST, "/echo", "a longer body").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut summary = state.mirrors.summary("romneys");
+        for _ in 0..100 {
+            if summary.mirrored == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            summary = state.mirrors.summary("romneys");
+        }
+        assert_eq!(summary.mirrored, 2);
+        assert_eq!(summary.matched, 1);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.divergences["200 -> 500"], 1);
+        assert_eq!(summary.recent[0].path, "/");
+        assert_eq!(*received.lock().unwrap(), ["hello"]);
+    }
+
+    #[tokio::test]
+    async fn test_caches_and_compresses() {
+        use axum::routing::get;
+        use hyper_util::client::legacy::Client;
+
+        let hits = Arc::new(AtomicU64::new(0));
+        let upstream = {
+            let hits = hits.clone();
+            start(Router::new().route(
+                "/page",
+                get(move || async move {
+                    hits.fetch_add(1, Ordering::Relaxed);
+                    ([(header::CACHE_CONTROL, "max-age=60")], "hello ".repeat(100))
+                }),
+            ))
+            .await
+        };
+        let state = test_state(Limits::default()).await;
+        state.db.add_server("server-1", &upstream.to_string()).await.unwrap();
+        state.db.add_tenant("romneys", None, None).await.unwrap();
+        state.db.add_tenant("smiths", None, None).await.unwrap();
+        let app = Router::new()
+            .fallback(handle_request)
+            .layer(compression())
+            .with_state(state.clone());
+        let proxy = start(app).await;
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let get = |tenant: &str, encoding: Option<&str>| {
+            let mut req = Request::builder()
+                .uri(format!("http://{}/page", proxy))
+                .header(header::HOST, format!("{}.ourfam.lol", tenant));
+            if let Some(encoding) = encoding {
+                req = req.header(header::ACCEPT_ENCODING, encoding);
+            }
+            let req = req.body(Body::empty()).unwrap();
+            let client = client.clone();
+            async move {
+                let response = client.request(req).await.unwrap();
+                let headers = response.headers().clone();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (headers, body)
+            }
+        };
+
+        // Off by default
+        let (headers, _) = get("romneys", Some("gzip")).await;
+        assert!(!headers.contains_key(crate::cache::CACHE_HEADER));
+        assert!(!headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+
+        state.db.set_tenant_cache("romneys", true).await.unwrap();
+        state.db.set_tenant_compress("romneys", true).await.unwrap();
+        state.db.set_tenant_cache("smiths", true).await.unwrap();
+        let (headers, body) = get("romneys", None).await;
+        assert_eq!(headers[crate::cache::CACHE_HEADER], "MISS");
+        assert_eq!(body, "hello ".repeat(100));
+        let (headers, body) = get("romneys", Some("gzip")).await;
+        assert_eq!(headers[crate::cache::CACHE_HEADER], "HIT");
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert!(body.len() < 600);
+        let (headers, _) = get("romneys", Some("br")).await;
+        assert_eq!(headers[header::CONTENT_ENCODING], "br");
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+
+        // Tenants don't share entries, and compression is per tenant
+        let (headers, _) = get("smiths", Some("gzip")).await;
+        assert_eq!(headers[crate::cache::CACHE_HEADER], "MISS");
+        assert!(!headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(hits.load(Ordering::Relaxed), 3);
+
+        assert_eq!(state.cache.purge("romneys"), 1);
+        let (headers, _) = get("romneys", None).await;
+        assert_eq!(headers[crate::cache::CACHE_HEADER], "MISS");
+        assert_eq!(hits.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_over_http2() {
+        use http_body_util::{BodyExt, Full};
+        use hyper_util::client::legacy::Client;
+
+        // A gRPC-style upstream that answers with trailers, over h2c
+        let upstream = start(Router::new().route(
+            "/echo.Echo/Say",
+            post(|req: Request<Body>| async move {
+                let version = format!("{:?}", req.version());
+                let body = req.into_body().collect().await.unwrap().to_bytes();
+                let mut trailers = axum::http::HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                let body = Full::new(body).with_trailers(async move { Some(Ok(trailers)) });
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "application/grpc")
+                    .header("x-upstream-version", version)
+                    .body(Body::new(body))
+                    .unwrap()
+            }),
+        ))
+        .await;
+        let (proxy, state) = start_proxy_to(upstream, Limits::default()).await;
+        state
+            .db
+            .set_server_protocol("server-1", Protocol::Http2)
+            .await
+            .unwrap();
+
+        let client = Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build_http::<Body>();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/echo.Echo/Say", proxy))
+            .header(header::HOST, "romneys.ourfam.lol")
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .header(header::TE, "trailers")
+            .body(Body::from("\0\0\0\0\x02hi"))
+            .unwrap();
+        let response = client.request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), axum::http::Version::HTTP_2);
+        assert_eq!(response.headers()["x-upstream-version"], "HTTP/2.0");
+
+        let collected = response.into_body().collect().await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["grpc-status"], "0");
+        assert_eq!(collected.to_bytes(), "\0\0\0\0\x02hi");
     }
 }
@@ -1,16 +1,18 @@
 //! Python bindings for slum
 
 use pyo3::prelude::*;
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use std::sync::Arc;
 use tokio::runtime::Runtime;
 
 use crate::db;
+use crate::provision::Provisioner;
 
 /// Python wrapper for the slum Database
 #[pyclass]
 pub struct SlumDB {
     db: Arc<db::Database>,
+    provisioner: Arc<Provisioner>,
     runtime: Arc<Runtime>,
 }
 
@@ -28,6 +30,11 @@ pub struct PyServer {
     pub tenant_count: i32,
     #[pyo3(get)]
     pub created_at: String,
+    #[pyo3(get)]
+    pub labels: db::Labels,
+    /// `http1`, or `http2` for cleartext HTTP/2 to the server
+    #[pyo3(get)]
+    pub protocol: String,
 }
 
 /// Tenant information
@@ -44,6 +51,61 @@ pub struct PyTenant {
     pub status: String,
     #[pyo3(get)]
     pub created_at: String,
+    #[pyo3(get)]
+    pub provisioned: bool,
+    #[pyo3(get)]
+    pub provision_error: Option<String>,
+    #[pyo3(get)]
+    pub pinned: bool,
+    #[pyo3(get)]
+    pub constraints: db::Labels,
+    #[pyo3(get)]
+    pub rate_limit: Option<f64>,
+    #[pyo3(get)]
+    pub rate_burst: Option<u32>,
+    #[pyo3(get)]
+    pub max_concurrent: Option<u32>,
+    #[pyo3(get)]
+    pub max_body_size: Option<u64>,
+    /// Servers the tenant also runs on
+    #[pyo3(get)]
+    pub replicas: Vec<String>,
+    /// Canary server IDs and the percentage of clients each gets
+    #[pyo3(get)]
+    pub canaries: std::collections::BTreeMap<String, u8>,
+    #[pyo3(get)]
+    pub balance: String,
+    /// Server that gets copies of the tenant's requests
+    #[pyo3(get)]
+    pub mirror_server: Option<String>,
+    /// Whether the proxy caches the tenant's responses
+    #[pyo3(get)]
+    pub cache: bool,
+    /// Whether the proxy compresses the tenant's responses
+    #[pyo3(get)]
+    pub compress: bool,
+}
+
+/// A tenant's traffic over one interval
+#[pyclass]
+#[derive(Clone)]
+pub struct PyUsage {
+    #[pyo3(get)]
+    pub start: i64,
+    #[pyo3(get)]
+    pub requests: i64,
+    #[pyo3(get)]
+    pub bytes_in: i64,
+    #[pyo3(get)]
+    pub bytes_out: i64,
+    #[pyo3(get)]
+    pub status_4xx: i64,
+    #[pyo3(get)]
+    pub status_5xx: i64,
+    #[pyo3(get)]
+    pub p50_ms: f64,
+    #[pyo3(get)]
+    pub p95_ms: f64,
 }
 
 impl From<db::Server> for PyServer {
@@ -54,6 +116,8 @@ impl From<db::Server> for PyServer {
             address: s.address,
             tenant_count: s.tenant_count,
             created_at: s.created_at,
+            labels: s.labels,
+            protocol: s.protocol.to_string(),
         }
     }
 }
@@ -66,6 +130,44 @@ impl From<db::Tenant> for PyTenant {
             config: t.config,
             status: t.status,
             created_at: t.created_at,
+            provisioned: t.provisioned,
+            provision_error: t.provision_error,
+            pinned: t.pinned,
+            constraints: t.constraints,
+            rate_limit: t.limits.rate,
+            rate_burst: t.limits.burst,
+            max_concurrent: t.limits.max_concurrent,
+            max_body_size: t.limits.max_body_size,
+            canaries: t
+                .replicas
+                .iter()
+                .filter_map(|r| Some((r.server_id.clone(), r.weight?)))
+                .collect(),
+            replicas: t
+                .replicas
+                .into_iter()
+                .filter(|r| !r.is_canary())
+                .map(|r| r.server_id)
+                .collect(),
+            balance: t.balance.to_string(),
+            mirror_server: t.mirror.map(|m| m.server_id),
+            cache: t.http.cache,
+            compress: t.http.compress,
+        }
+    }
+}
+
+impl From<db::Usage> for PyUsage {
+    fn from(u: db::Usage) -> Self {
+        PyUsage {
+            start: u.start,
+            requests: u.requests,
+            bytes_in: u.bytes_in,
+            bytes_out: u.bytes_out,
+            status_4xx: u.status_4xx,
+            status_5xx: u.status_5xx,
+            p50_ms: u.p50_ms,
+            p95_ms: u.p95_ms,
         }
     }
 }
@@ -82,8 +184,10 @@ impl SlumDB {
             db::Database::open(path).await
         }).map_err(|e| PyRuntimeError::new_err(format!("Failed to open database: {}", e)))?;
         
+        let db = Arc::new(db);
         Ok(SlumDB {
-            db: Arc::new(db),
+            provisioner: Arc::new(Provisioner::new(db.clone())),
+            db,
             runtime: Arc::new(runtime),
         })
     }
@@ -103,6 +207,21 @@ impl SlumDB {
         .map_err(|e| PyRuntimeError::new_err(format!("Failed to add server: {}", e)))
     }
 
+    /// Set the protocol the proxy speaks to a server: `http1` or `http2`
+    fn set_server_protocol(&self, server: &str, protocol: &str) -> PyResult<PyServer> {
+        let db = self.db.clone();
+        let server = server.to_string();
+        let protocol = protocol
+            .parse::<db::Protocol>()
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+
+        self.runtime.block_on(async move {
+            db.set_server_protocol(&server, protocol).await
+        })
+        .map(PyServer::from)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set protocol: {}", e)))
+    }
+
     /// List all servers in the fleet
     fn list_servers(&self) -> PyResult<Vec<PyServer>> {
         let db = self.db.clone();
@@ -137,21 +256,47 @@ impl SlumDB {
         .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove server: {}", e)))
     }
 
+    /// Replace a server's labels
+    fn set_server_labels(&self, id_or_name: &str, labels: db::Labels) -> PyResult<PyServer> {
+        let db = self.db.clone();
+        let id_or_name = id_or_name.to_string();
+
+        self.runtime.block_on(async move {
+            db.set_server_labels(&id_or_name, &labels).await
+        })
+        .map(PyServer::from)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set server labels: {}", e)))
+    }
+
     // Tenant operations
 
     /// Add a tenant to the fleet
-    #[pyo3(signature = (id, server=None, config=None))]
-    fn add_tenant(&self, id: &str, server: Option<&str>, config: Option<&str>) -> PyResult<PyTenant> {
+    #[pyo3(signature = (id, server=None, config=None, requires=None))]
+    fn add_tenant(
+        &self,
+        id: &str,
+        server: Option<&str>,
+        config: Option<&str>,
+        requires: Option<db::Labels>,
+    ) -> PyResult<PyTenant> {
+        db::validate_tenant_id(id).map_err(|e| PyValueError::new_err(e.to_string()))?;
         let db = self.db.clone();
         let id = id.to_string();
         let server = server.map(|s| s.to_string());
         let config = config.map(|s| s.to_string());
-        
+        let requires = requires.unwrap_or_default();
+        let provisioner = self.provisioner.clone();
+
         self.runtime.block_on(async move {
-            db.add_tenant(&id, server.as_deref(), config.as_deref()).await
+            let tenant = db
+                .add_tenant_with_constraints(&id, server.as_deref(), config.as_deref(), &requires)
+                .await?;
+            // Failures are recorded on the tenant and retried by `slum serve`
+            provisioner.sync_tenant(&id).await?;
+            Ok(db.get_tenant(&id).await?.unwrap_or(tenant))
         })
         .map(PyTenant::from)
-        .map_err(|e| PyRuntimeError::new_err(format!("Failed to add tenant: {}", e)))
+        .map_err(|e: anyhow::Error| PyRuntimeError::new_err(format!("Failed to add tenant: {}", e)))
     }
 
     /// List all tenants
@@ -177,17 +322,323 @@ impl SlumDB {
         .map_err(|e| PyRuntimeError::new_err(format!("Failed to get tenant: {}", e)))
     }
 
+    /// Pin or unpin a tenant; rebalancing never moves pinned tenants
+    fn set_tenant_pinned(&self, id: &str, pinned: bool) -> PyResult<()> {
+        let db = self.db.clone();
+        let id = id.to_string();
+
+        self.runtime.block_on(async move {
+            db.set_tenant_pinned(&id, pinned).await
+        })
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to pin tenant: {}", e)))
+    }
+
+    /// Run a tenant on `replicas` servers, optionally changing how requests
+    /// are balanced (round-robin, least-conn, client-hash or cookie-hash:NAME)
+    #[pyo3(signature = (id, replicas, balance=None))]
+    fn scale_tenant(&self, id: &str, replicas: u32, balance: Option<&str>) -> PyResult<PyTenant> {
+        let db = self.db.clone();
+        let id = id.to_string();
+        let balance = balance
+            .map(|b| b.parse::<db::LoadBalance>())
+            .transpose()
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+
+        self.runtime.block_on(async move {
+            db.scale_tenant(&id, replicas, balance.as_ref()).await
+        })
+        .map(PyTenant::from)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to scale tenant: {}", e)))
+    }
+
+    /// Send `weight` percent of a tenant's clients to a canary server
+    fn set_canary(&self, id: &str, server: &str, weight: u8) -> PyResult<PyTenant> {
+        let db = self.db.clone();
+        let id = id.to_string();
+        let server = server.to_string();
+
+        self.runtime.block_on(async move {
+            db.set_canary(&id, &server, weight).await
+        })
+        .map(PyTenant::from)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set canary: {}", e)))
+    }
+
+    /// Take a tenant off a canary server
+    fn remove_canary(&self, id: &str, server: &str) -> PyResult<PyTenant> {
+        let db = self.db.clone();
+        let id = id.to_string();
+        let server = server.to_string();
+
+        self.runtime.block_on(async move {
+            db.remove_canary(&id, &server).await
+        })
+        .map(PyTenant::from)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove canary: {}", e)))
+    }
+
+    /// Make a canary server the tenant's primary
+    fn promote_canary(&self, id: &str, server: &str) -> PyResult<PyTenant> {
+        let db = self.db.clone();
+        let id = id.to_string();
+        let server = server.to_string();
+
+        self.runtime.block_on(async move {
+            db.promote_canary(&id, &server).await
+        })
+        .map(PyTenant::from)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to promote canary: {}", e)))
+    }
+
+    /// Mirror `percent` percent of a tenant's requests to a server, up to
+    /// `max_body_size` bytes of body (1 MiB by default)
+    #[pyo3(signature = (id, server, percent=100, max_body_size=None))]
+    fn set_tenant_mirror(
+        &self,
+        id: &str,
+        server: &str,
+        percent: u8,
+        max_body_size: Option<u64>,
+    ) -> PyResult<PyTenant> {
+        let db = self.db.clone();
+        let id = id.to_string();
+        let server = server.to_string();
+        let max_body_size = max_body_size.unwrap_or(1 << 20);
+        let provisioner = self.provisioner.clone();
+
+        self.runtime.block_on(async move {
+            let tenant = db.set_tenant_mirror(&id, &server, percent, max_body_size).await?;
+            provisioner.sync_tenant(&id).await?;
+            Ok::<_, anyhow::Error>(db.get_tenant(&id).await?.unwrap_or(tenant))
+        })
+        .map(PyTenant::from)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set mirror: {}", e)))
+    }
+
+    /// Stop mirroring a tenant's requests
+    fn remove_tenant_mirror(&self, id: &str) -> PyResult<()> {
+        let db = self.db.clone();
+        let id = id.to_string();
+        let provisioner = self.provisioner.clone();
+
+        self.runtime.block_on(async move {
+            db.clear_tenant_mirror(&id).await?;
+            provisioner.sync_tenant(&id).await.map(|_| ())
+        })
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove mirror: {}", e)))
+    }
+
+    /// Route rules in their command-line form: a tenant's, or the fleet's
+    #[pyo3(signature = (tenant=None))]
+    fn route_rules(&self, tenant: Option<&str>) -> PyResult<Vec<String>> {
+        let db = self.db.clone();
+        let tenant = tenant.map(String::from);
+
+        self.runtime.block_on(async move {
+            db.route_rules(tenant.as_deref()).await
+        })
+        .map(|rules| rules.iter().map(ToString::to_string).collect())
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to list rules: {}", e)))
+    }
+
+    /// Add a route rule such as `"redirect /old /new 301"`, at `position` or
+    /// the end, for a tenant or the fleet
+    #[pyo3(signature = (rule, tenant=None, position=None))]
+    fn add_route_rule(&self, rule: &str, tenant: Option<&str>, position: Option<usize>) -> PyResult<()> {
+        let db = self.db.clone();
+        let tenant = tenant.map(String::from);
+        let args: Vec<String> = rule.split_whitespace().map(String::from).collect();
+
+        self.runtime.block_on(async move {
+            let rule = db::RouteRule::parse(&args)?;
+            db.add_route_rule(tenant.as_deref(), rule, position).await
+        })
+        .map(|_| ())
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to add rule: {}", e)))
+    }
+
+    /// Remove the route rule at `position`
+    #[pyo3(signature = (position, tenant=None))]
+    fn remove_route_rule(&self, position: usize, tenant: Option<&str>) -> PyResult<()> {
+        let db = self.db.clone();
+        let tenant = tenant.map(String::from);
+
+        self.runtime.block_on(async move {
+            db.remove_route_rule(tenant.as_deref(), position).await
+        })
+        .map(|_| ())
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove rule: {}", e)))
+    }
+
+    /// Set who may reach a tenant through the proxy: client networks in
+    /// `allow`/`deny` as CIDRs, basic auth `users` as name -> password, and
+    /// a `forward_auth` URL. With no arguments, anyone may.
+    #[pyo3(signature = (id, allow=Vec::new(), deny=Vec::new(), users=std::collections::BTreeMap::new(), forward_auth=None))]
+    fn set_tenant_access(
+        &self,
+        id: &str,
+        allow: Vec<String>,
+        deny: Vec<String>,
+        users: std::collections::BTreeMap<String, String>,
+        forward_auth: Option<String>,
+    ) -> PyResult<()> {
+        let db = self.db.clone();
+        let id = id.to_string();
+        let nets = |nets: Vec<String>| {
+            nets.iter()
+                .map(|n| n.parse().map_err(|_| PyValueError::new_err(format!("Invalid CIDR: {}", n))))
+                .collect::<PyResult<Vec<_>>>()
+        };
+        let policy = db::AccessPolicy {
+            allow: nets(allow)?,
+            deny: nets(deny)?,
+            users: users
+                .into_iter()
+                .map(|(username, password)| db::BasicUser {
+                    username,
+                    password: Some(password),
+                    password_hash: String::new(),
+                })
+                .collect(),
+            forward_auth,
+            ..Default::default()
+        };
+
+        self.runtime.block_on(async move {
+            db.set_tenant_access(&id, policy).await
+        })
+        .map(|_| ())
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set access policy: {}", e)))
+    }
+
+    /// Replace a tenant's config with a JSON object string, or clear it
+    /// with `None`, and provision it
+    #[pyo3(signature = (id, config=None))]
+    fn set_tenant_config(&self, id: &str, config: Option<&str>) -> PyResult<()> {
+        db::parse_config(config).map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let db = self.db.clone();
+        let id = id.to_string();
+        let config = config.map(String::from);
+        let provisioner = self.provisioner.clone();
+
+        self.runtime.block_on(async move {
+            db.set_tenant_config(&id, config.as_deref()).await?;
+            provisioner.sync_tenant(&id).await.map(|_| ())
+        })
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set config: {}", e)))
+    }
+
+    /// Change a tenant's config with a JSON Merge Patch string and
+    /// provision it, returning the new config
+    fn patch_tenant_config(&self, id: &str, patch: &str) -> PyResult<Option<String>> {
+        let patch: serde_json::Value = serde_json::from_str(patch)
+            .map_err(|e| PyValueError::new_err(format!("Patch isn't valid JSON: {}", e)))?;
+        let db = self.db.clone();
+        let id = id.to_string();
+        let provisioner = self.provisioner.clone();
+
+        self.runtime.block_on(async move {
+            let config = db.patch_tenant_config(&id, &patch).await?;
+            provisioner.sync_tenant(&id).await?;
+            Ok::<_, anyhow::Error>(config)
+        })
+        .map(|config| config.map(|c| c.to_string()))
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to patch config: {}", e)))
+    }
+
+    /// Register the JSON Schema string tenant configs must match, or remove
+    /// it with `None`. Returns (tenant id, error) for existing configs that
+    /// don't match.
+    #[pyo3(signature = (schema=None))]
+    fn set_config_schema(&self, schema: Option<&str>) -> PyResult<Vec<(String, String)>> {
+        let schema = schema
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(format!("Schema isn't valid JSON: {}", e)))?;
+        let db = self.db.clone();
+
+        self.runtime.block_on(async move {
+            db.set_config_schema(schema).await
+        })
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set config schema: {}", e)))
+    }
+
+    /// Turn the proxy's HTTP cache on or off for a tenant
+    fn set_tenant_cache(&self, id: &str, enabled: bool) -> PyResult<()> {
+        let db = self.db.clone();
+        let id = id.to_string();
+
+        self.runtime.block_on(async move {
+            db.set_tenant_cache(&id, enabled).await
+        })
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set cache: {}", e)))
+    }
+
+    /// Turn response compression on or off for a tenant
+    fn set_tenant_compress(&self, id: &str, enabled: bool) -> PyResult<()> {
+        let db = self.db.clone();
+        let id = id.to_string();
+
+        self.runtime.block_on(async move {
+            db.set_tenant_compress(&id, enabled).await
+        })
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set compression: {}", e)))
+    }
+
+    /// Set a tenant's request limits; `None` uses the fleet default
+    #[pyo3(signature = (id, rate=None, burst=None, max_concurrent=None, max_body_size=None))]
+    fn set_tenant_limits(
+        &self,
+        id: &str,
+        rate: Option<f64>,
+        burst: Option<u32>,
+        max_concurrent: Option<u32>,
+        max_body_size: Option<u64>,
+    ) -> PyResult<()> {
+        let db = self.db.clone();
+        let id = id.to_string();
+        let limits = db::Limits {
+            rate,
+            burst,
+            max_concurrent,
+            max_body_size,
+        };
+
+        self.runtime.block_on(async move {
+            db.set_tenant_limits(&id, &limits).await
+        })
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to set tenant limits: {}", e)))
+    }
+
     /// Remove a tenant
     fn remove_tenant(&self, id: &str) -> PyResult<()> {
         let db = self.db.clone();
         let id = id.to_string();
         
+        let provisioner = self.provisioner.clone();
+
         self.runtime.block_on(async move {
-            db.remove_tenant(&id).await
+            db.remove_tenant(&id).await?;
+            // Deprovisioning that fails stays queued for `slum serve`
+            provisioner.sync_tenant(&id).await.map(|_| ())
         })
         .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove tenant: {}", e)))
     }
 
+    /// A tenant's usage between `start` and `end` (Unix seconds), in
+    /// `step`-second intervals
+    #[pyo3(signature = (id, start, end, step=3600))]
+    fn tenant_usage(&self, id: &str, start: i64, end: i64, step: i64) -> PyResult<Vec<PyUsage>> {
+        let db = self.db.clone();
+        let id = id.to_string();
+
+        self.runtime.block_on(async move {
+            db.tenant_usage(&id, start, end, step).await
+        })
+        .map(|usage| usage.into_iter().map(PyUsage::from).collect())
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to get tenant usage: {}", e)))
+    }
+
     // Routing operations
 
     /// Lookup tenant and server for routing
@@ -220,5 +671,6 @@ fn slum(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<SlumDB>()?;
     m.add_class::<PyServer>()?;
     m.add_class::<PyTenant>()?;
+    m.add_class::<PyUsage>()?;
     Ok(())
 }
@@ -0,0 +1,480 @@
+//! Fleet rebalancing
+//!
+//! Auto-placement only balances at insert time. The rebalancer computes a move
+//! plan that brings servers back within a threshold of each other, measured in
+//! tenant counts or in observed request load, and executes it a few moves at
+//! a time. Load comes from the recorded usage rollups, so the CLI sees the
+//! same load as the server. Pinned tenants and tenants with replicas stay put, and tenants only
+//! move to servers that carry the labels they require.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::db::{Database, Server, Tenant};
+use crate::provision::Provisioner;
+
+/// What a server's load is measured in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Balance {
+    /// Every tenant counts the same
+    Tenants,
+    /// Tenants are weighted by the requests recorded for them
+    Load,
+}
+
+#[derive(Debug, Clone)]
+pub struct RebalanceOptions {
+    /// Largest acceptable gap between the most and least loaded server, in
+    /// tenants (or tenant-equivalents of load)
+    pub threshold: f64,
+    /// Most moves to make in one run
+    pub max_moves: usize,
+    /// Most moves in flight at once
+    pub max_concurrent: usize,
+    /// How far back `Balance::Load` counts requests
+    pub load_window: Duration,
+}
+
+impl Default for RebalanceOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 1.0,
+            max_moves: 50,
+            max_concurrent: 2,
+            load_window: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Move {
+    pub tenant_id: String,
+    pub from: String,
+    pub from_name: String,
+    pub to: String,
+    pub to_name: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RebalancePlan {
+    pub moves: Vec<Move>,
+    /// Gap between the most and least loaded server before and after the plan
+    pub spread_before: f64,
+    pub spread_after: f64,
+}
+
+/// Per-tenant weights from request counts, scaled so the average tenant
+/// weighs 1. Tenants with no requests keep a small weight so idle servers
+/// still count as emptier than servers with idle tenants.
+pub fn load_weights(tenants: &[Tenant], requests: &HashMap<String, u64>) -> HashMap<String, f64> {
+    let total: u64 = tenants
+        .iter()
+        .map(|t| requests.get(&t.id).copied().unwrap_or(0))
+        .sum();
+    if total == 0 {
+        return HashMap::new();
+    }
+    let mean = total as f64 / tenants.len() as f64;
+    tenants
+        .iter()
+        .map(|t| {
+            let count = requests.get(&t.id).copied().unwrap_or(0) as f64;
+            (t.id.clone(), (count / mean).max(0.01))
+        })
+        .collect()
+}
+
+fn spread(loads: &HashMap<&str, f64>) -> f64 {
+    let max = loads.values().copied().fold(f64::MIN, f64::max);
+    let min = loads.values().copied().fold(f64::MAX, f64::min);
+    if loads.is_empty() {
+        0.0
+    } else {
+        max - min
+    }
+}
+
+/// Compute moves that bring the fleet within `options.threshold`.
+///
+/// Greedy: repeatedly move the tenant from a heavier server to a lighter one
+/// that best halves the gap between them. Every move strictly reduces the sum
+/// of squared loads, so this always terminates. Tenants missing from
+/// `weights` weigh 1.
+pub fn plan(
+    servers: &[Server],
+    tenants: &[Tenant],
+    weights: &HashMap<String, f64>,
+    options: &RebalanceOptions,
+) -> RebalancePlan {
+    let weight = |t: &Tenant| weights.get(&t.id).copied().unwrap_or(1.0);
+    let by_id: HashMap<&str, &Server> = servers.iter().map(|s| (s.id.as_str(), s)).collect();
+
+    let mut placement: HashMap<&str, &str> = tenants
+        .iter()
+        .map(|t| (t.id.as_str(), t.server_id.as_str()))
+        .collect();
+    let mut loads: HashMap<&str, f64> = servers.iter().map(|s| (s.id.as_str(), 0.0)).collect();
+    for t in tenants {
+        if let Some(load) = loads.get_mut(t.server_id.as_str()) {
+            *load += weight(t);
+        }
+    }
+
+    let spread_before = spread(&loads);
+    let mut moves = Vec::new();
+
+    while moves.len() < options.max_moves && spread(&loads) > options.threshold {
+        let mut ordered: Vec<(&str, f64)> = loads.iter().map(|(&id, &l)| (id, l)).collect();
+        ordered.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+
+        let mut best: Option<(&Tenant, &str, &str)> = None;
+        'search: for &(from, from_load) in &ordered {
+            for &(to, to_load) in ordered.iter().rev() {
+                let gap = from_load - to_load;
+                if gap <= options.threshold {
+                    break;
+                }
+                // Closest to half the gap without overshooting it
+                best = tenants
+                    .iter()
+                    .filter(|t| placement.get(t.id.as_str()) == Some(&from))
+                    .filter(|t| !t.pinned && t.replicas.is_empty() && weight(t) < gap)
+                    .filter(|t| by_id[to].satisfies(&t.constraints))
+                    .min_by(|a, b| {
+                        (weight(a) - gap / 2.0)
+                            .abs()
+                            .total_cmp(&(weight(b) - gap / 2.0).abs())
+                            .then(a.id.cmp(&b.id))
+                    })
+                    .map(|t| (t, from, to));
+                if best.is_some() {
+                    break 'search;
+                }
+            }
+        }
+
+        let Some((tenant, from, to)) = best else {
+            break;
+        };
+        let w = weight(tenant);
+        *loads.get_mut(from).unwrap() -= w;
+        *loads.get_mut(to).unwrap() += w;
+        placement.insert(tenant.id.as_str(), to);
+        moves.push(Move {
+            tenant_id: tenant.id.clone(),
+            from: from.to_string(),
+            from_name: by_id[from].name.clone(),
+            to: to.to_string(),
+            to_name: by_id[to].name.clone(),
+        });
+    }
+
+    RebalancePlan {
+        moves,
+        spread_before,
+        spread_after: spread(&loads),
+    }
+}
+
+pub struct Rebalancer {
+    db: Arc<Database>,
+    provisioner: Arc<Provisioner>,
+}
+
+impl Rebalancer {
+    pub fn new(db: Arc<Database>, provisioner: Arc<Provisioner>) -> Self {
+        Self { db, provisioner }
+    }
+
+    /// Plan against the current registry
+    pub async fn plan(
+        &self,
+        balance: Balance,
+        options: &RebalanceOptions,
+    ) -> Result<RebalancePlan> {
+        let servers = self.db.list_servers().await?;
+        let tenants = self.db.list_tenants().await?;
+        let weights = match balance {
+            Balance::Tenants => HashMap::new(),
+            Balance::Load => {
+                let now = chrono::Utc::now().timestamp();
+                let from = now - options.load_window.as_secs() as i64;
+                load_weights(&tenants, &self.db.request_counts(from, now).await?)
+            }
+        };
+        Ok(plan(&servers, &tenants, &weights, options))
+    }
+
+    /// Execute a plan, at most `max_concurrent` moves at a time. Each move
+    /// updates the registry and then provisions the tenant on its new server.
+    /// Returns the number of moves that succeeded.
+    pub async fn execute(&self, plan: &RebalancePlan, max_concurrent: usize) -> Result<usize> {
+        let permits = Arc::new(Semaphore::new(max_concurrent.max(1)));
+        let mut tasks = tokio::task::JoinSet::new();
+
+        for m in &plan.moves {
+            let permit = permits.clone().acquire_owned().await?;
+            let db = self.db.clone();
+            let provisioner = self.provisioner.clone();
+            let m = m.clone();
+            tasks.spawn(async move {
+                let _permit = permit;
+                db.move_tenant(&m.tenant_id, &m.to).await?;
+                let report = provisioner.sync_tenant(&m.tenant_id).await?;
+                for error in &report.errors {
+                    tracing::warn!("Rebalance: failed to {} (will retry)", error);
+                }
+                tracing::info!(
+                    "Rebalance: moved {} {} -> {}",
+                    m.tenant_id,
+                    m.from_name,
+                    m.to_name
+                );
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+
+        let mut moved = 0;
+        while let Some(result) = tasks.join_next().await {
+            match result? {
+                Ok(()) => moved += 1,
+                Err(e) => tracing::error!("Rebalance move failed: {}", e),
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Plan and execute every `interval`
+    pub fn spawn(
+        self: Arc<Self>,
+        interval: Duration,
+        balance: Balance,
+        options: RebalanceOptions,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick fires immediately; skip it so the first run
+            // comes one interval after startup
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let result = async {
+                    let plan = self.plan(balance, &options).await?;
+                    if plan.moves.is_empty() {
+                        return Ok(());
+                    }
+                    tracing::info!(
+                        "Rebalance: {} moves, spread {:.2} -> {:.2}",
+                        plan.moves.len(),
+                        plan.spread_before,
+                        plan.spread_after
+                    );
+                    self.execute(&plan, options.max_concurrent).await?;
+                    Ok::<_, anyhow::Error>(())
+                }
+                .await;
+                if let Err(e) = result {
+                    tracing::error!("Rebalance pass failed: {}", e);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Labels, Usage};
+
+    fn server(id: &str, labels: &[(&str, &str)]) -> Server {
+        Server {
+            id: id.to_string(),
+            name: id.to_string(),
+            address: String::new(),
+            tenant_count: 0,
+            created_at: String::new(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            protocol: Default::default(),
+        }
+    }
+
+    fn tenant(id: &str, server_id: &str) -> Tenant {
+        Tenant {
+            id: id.to_string(),
+            server_id: server_id.to_string(),
+            config: None,
+            status: "active".to_string(),
+            created_at: String::new(),
+            provisioned: true,
+            provision_error: None,
+            pinned: false,
+            constraints: Labels::new(),
+            limits: Default::default(),
+            replicas: Vec::new(),
+            balance: Default::default(),
+            mirror: None,
+            http: Default::default(),
+            access: Default::default(),
+        }
+    }
+
+    fn counts(servers: &[Server], tenants: &[Tenant], plan: &RebalancePlan) -> Vec<usize> {
+        let mut placement: HashMap<&str, &str> = tenants
+            .iter()
+            .map(|t| (t.id.as_str(), t.server_id.as_str()))
+            .collect();
+        for m in &plan.moves {
+            placement.insert(&m.tenant_id, &m.to);
+        }
+        servers
+            .iter()
+            .map(|s| placement.values().filter(|&&id| id == s.id).count())
+            .collect()
+    }
+
+    #[test]
+    fn test_fills_new_server() {
+        let servers = vec![server("s1", &[]), server("s2", &[]), server("s3", &[])];
+        let tenants: Vec<Tenant> = (0..9)
+            .map(|i| tenant(&format!("t{}", i), if i < 5 { "s1" } else { "s2" }))
+            .collect();
+
+        let plan = plan(&servers, &tenants, &HashMap::new(), &RebalanceOptions::default());
+        assert_eq!(plan.spread_before, 5.0);
+        assert_eq!(counts(&servers, &tenants, &plan), vec![3, 3, 3]);
+        assert_eq!(plan.moves.len(), 3);
+    }
+
+    #[test]
+    fn test_within_threshold_is_noop() {
+        let servers = vec![server("s1", &[]), server("s2", &[])];
+        let tenants = vec![tenant("t1", "s1"), tenant("t2", "s1"), tenant("t3", "s2")];
+
+        let plan = plan(&servers, &tenants, &HashMap::new(), &RebalanceOptions::default());
+        assert!(plan.moves.is_empty());
+    }
+
+    #[test]
+    fn test_respects_pins_constraints_and_max_moves() {
+        let servers = vec![server("s1", &[("region", "eu")]), server("s2", &[])];
+        let mut tenants: Vec<Tenant> = (0..6).map(|i| tenant(&format!("t{}", i), "s1")).collect();
+        tenants[0].pinned = true;
+        tenants[1].pinned = true;
+        tenants[2].constraints = Labels::from([("region".to_string(), "eu".to_string())]);
+
+        let plan = plan(&servers, &tenants, &HashMap::new(), &RebalanceOptions::default());
+        let moved: Vec<&str> = plan.moves.iter().map(|m| m.tenant_id.as_str()).collect();
+        assert_eq!(moved, vec!["t3", "t4", "t5"]);
+
+        let options = RebalanceOptions {
+            max_moves: 1,
+            ..Default::default()
+        };
+        let limited = super::plan(&servers, &tenants, &HashMap::new(), &options);
+        assert_eq!(limited.moves.len(), 1);
+    }
+
+    #[test]
+    fn test_balances_by_load() {
+        let servers = vec![server("s1", &[]), server("s2", &[])];
+        let tenants = vec![
+            tenant("busy", "s1"),
+            tenant("quiet-1", "s1"),
+            tenant("quiet-2", "s2"),
+            tenant("quiet-3", "s2"),
+        ];
+        let requests = HashMap::from([
+            ("busy".to_string(), 600),
+            ("quiet-1".to_string(), 100),
+            ("quiet-2".to_string(), 100),
+            ("quiet-3".to_string(), 100),
+        ]);
+
+        // Counts are balanced, but s1 carries most of the load
+        let weights = load_weights(&tenants, &requests);
+        let plan = plan(&servers, &tenants, &weights, &RebalanceOptions::default());
+        let moved: Vec<&str> = plan.moves.iter().map(|m| m.tenant_id.as_str()).collect();
+        assert_eq!(moved, vec!["quiet-1"]);
+        assert!(plan.spread_after < plan.spread_before);
+    }
+
+    #[tokio::test]
+    async fn test_execute_moves_tenants() {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Arc::new(Database::open(&path).await.unwrap());
+        let rebalancer = Rebalancer::new(db.clone(), Arc::new(Provisioner::new(db.clone())));
+
+        db.add_server("server-1", "127.0.0.1:1").await.unwrap();
+        for i in 0..4 {
+            db.add_tenant(&format!("t{}", i), None, None).await.unwrap();
+        }
+        db.add_server("server-2", "127.0.0.1:1").await.unwrap();
+
+        let plan = rebalancer
+            .plan(Balance::Tenants, &RebalanceOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(plan.moves.len(), 2);
+        assert_eq!(rebalancer.execute(&plan, 2).await.unwrap(), 2);
+
+        let counts: Vec<i32> = db
+            .list_servers()
+            .await
+            .unwrap()
+            .iter()
+            .map(|s| s.tenant_count)
+            .collect();
+        assert_eq!(counts, vec![2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_plans_from_recorded_usage() {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Arc::new(Database::open(&path).await.unwrap());
+        let rebalancer = Rebalancer::new(db.clone(), Arc::new(Provisioner::new(db.clone())));
+
+        db.add_server("server-1", "127.0.0.1:1").await.unwrap();
+        db.add_server("server-2", "127.0.0.1:1").await.unwrap();
+        for id in ["busy", "quiet-1", "quiet-2", "quiet-3"] {
+            db.add_tenant(id, None, None).await.unwrap();
+        }
+        let on_busy = db.get_tenant("busy").await.unwrap().unwrap().server_id;
+
+        let minute = chrono::Utc::now().timestamp() / 60 * 60 - 60;
+        let usage = |requests| Usage {
+            start: minute,
+            requests,
+            bytes_in: 0,
+            bytes_out: 0,
+            status_4xx: 0,
+            status_5xx: 0,
+            p50_ms: 0.0,
+            p95_ms: 0.0,
+            latencies: Default::default(),
+        };
+        let tenants = db.list_tenants().await.unwrap();
+        let rollups: Vec<(String, Usage)> = tenants
+            .iter()
+            .map(|t| (t.id.clone(), usage(if t.id == "busy" { 600 } else { 100 })))
+            .collect();
+        db.record_usage(&rollups).await.unwrap();
+
+        // Planning twice sees the same load
+        for _ in 0..2 {
+            let plan = rebalancer
+                .plan(Balance::Load, &RebalanceOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(plan.moves.len(), 1);
+            assert_eq!(plan.moves[0].from, on_busy);
+            assert_ne!(plan.moves[0].tenant_id, "busy");
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Reconciliation against tenement server inventories
+//!
+//! Servers drift from the registry: a rebuilt box forgets its tenants, or a
+//! tenant slum removed is still running somewhere. `Reconciler::check` compares
+//! each server's inventory with the registry, and `repair` hands the
+//! differences to the provisioning outbox.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::Database;
+use crate::provision::Provisioner;
+use crate::tenement::TenementClient;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftKind {
+    /// In the registry on this server, but the server doesn't have it
+    Missing,
+    /// On the server, but not in the registry at all
+    Orphaned,
+    /// On the server, but the registry places it on another server
+    Misplaced,
+}
+
+impl DriftKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DriftKind::Missing => "missing",
+            DriftKind::Orphaned => "orphaned",
+            DriftKind::Misplaced => "misplaced",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Drift {
+    pub kind: DriftKind,
+    pub tenant_id: String,
+    pub server_id: String,
+    pub server_name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnreachableServer {
+    pub server_id: String,
+    pub server_name: String,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    pub servers_checked: usize,
+    pub drift: Vec<Drift>,
+    pub unreachable: Vec<UnreachableServer>,
+    /// Drift entries handed to the provisioner; always 0 for a dry run
+    pub repaired: usize,
+}
+
+pub struct Reconciler {
+    db: Arc<Database>,
+    provisioner: Arc<Provisioner>,
+    client: TenementClient,
+}
+
+impl Reconciler {
+    pub fn new(db: Arc<Database>, provisioner: Arc<Provisioner>) -> Self {
+        Self {
+            db,
+            provisioner,
+            client: TenementClient::new(Duration::from_secs(5)),
+        }
+    }
+
+    /// Compare every server's inventory with the registry without changing anything.
+    ///
+    /// Tenants with provisioning already pending are skipped; the provisioner
+    /// is about to change them anyway.
+    pub async fn check(&self) -> Result<ReconcileReport> {
+        let servers = self.db.list_servers().await?;
+        let tenants = self.db.list_tenants().await?;
+        let deprovisions = self.db.list_deprovisions().await?;
+
+        let placements: HashMap<&str, HashSet<&str>> = tenants
+            .iter()
+            .map(|t| {
+                let servers = std::iter::once(t.server_id.as_str())
+                    .chain(t.replicas.iter().map(|r| r.server_id.as_str()))
+                    .chain(t.mirror.iter().map(|m| m.server_id.as_str()))
+                    .collect();
+                (t.id.as_str(), servers)
+            })
+            .collect();
+        let pending_provision: HashSet<&str> = tenants
+            .iter()
+            .filter(|t| {
+                !t.provisioned
+                    || t.replicas.iter().any(|r| !r.provisioned)
+                    || t.mirror.as_ref().is_some_and(|m| !m.provisioned)
+            })
+            .map(|t| t.id.as_str())
+            .collect();
+        let pending_deprovision: HashSet<(&str, &str)> = deprovisions
+            .iter()
+            .map(|d| (d.tenant_id.as_str(), d.server_id.as_str()))
+            .collect();
+
+        let mut report = ReconcileReport::default();
+
+        for server in &servers {
+            let remote = match self.client.list_tenants(&server.address).await {
+                Ok(remote) => remote,
+                Err(e) => {
+                    report.unreachable.push(UnreachableServer {
+                        server_id: server.id.clone(),
+                        server_name: server.name.clone(),
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            report.servers_checked += 1;
+
+            let drift = |kind, tenant_id: &str| Drift {
+                kind,
+                tenant_id: tenant_id.to_string(),
+                server_id: server.id.clone(),
+                server_name: server.name.clone(),
+            };
+
+            let remote: HashSet<&str> = remote.iter().map(|t| t.id.as_str()).collect();
+            for &id in &remote {
+                if pending_deprovision.contains(&(id, server.id.as_str())) {
+                    continue;
+                }
+                match placements.get(id) {
+                    None => report.drift.push(drift(DriftKind::Orphaned, id)),
+                    Some(servers) if !servers.contains(server.id.as_str()) => {
+                        report.drift.push(drift(DriftKind::Misplaced, id))
+                    }
+                    Some(_) => {}
+                }
+            }
+            for t in &tenants {
+                if placements[t.id.as_str()].contains(server.id.as_str())
+                    && !remote.contains(t.id.as_str())
+                    && !pending_provision.contains(t.id.as_str())
+                {
+                    report.drift.push(drift(DriftKind::Missing, &t.id));
+                }
+            }
+        }
+
+        report
+            .drift
+            .sort_by(|a, b| (&a.server_name, &a.tenant_id).cmp(&(&b.server_name, &b.tenant_id)));
+        Ok(report)
+    }
+
+    /// Check, then queue fixes for all drift and push them to the servers
+    pub async fn repair(&self) -> Result<ReconcileReport> {
+        let mut report = self.check().await?;
+
+        for d in &report.drift {
+            match d.kind {
+                DriftKind::Missing => self.db.mark_unprovisioned(&d.tenant_id).await?,
+                DriftKind::Orphaned | DriftKind::Misplaced => {
+                    self.db.queue_deprovision(&d.tenant_id, &d.server_id).await?
+                }
+            }
+        }
+        report.repaired = report.drift.len();
+
+        if report.repaired > 0 {
+            self.provisioner.sync_all().await?;
+        }
+        Ok(report)
+    }
+
+    /// Check every `interval`, repairing as well if `repair` is set
+    pub fn spawn(self: Arc<Self>, interval: Duration, repair: bool) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let result = if repair {
+                    self.repair().await
+                } else {
+                    self.check().await
+                };
+                match result {
+                    Ok(report) => {
+                        for d in &report.drift {
+                            tracing::warn!(
+                                "Drift: tenant {} is {} on {}",
+                                d.tenant_id,
+                                d.kind.as_str(),
+                                d.server_name
+                            );
+                        }
+                        for s in &report.unreachable {
+                            tracing::warn!(
+                                "Reconcile: server {} unreachable: {}",
+                                s.server_name,
+                                s.error
+                            );
+                        }
+                    }
+                    Err(e) => tracing::error!("Reconcile pass failed: {}", e),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenement::mock::MockTenement;
+
+    async fn setup() -> (Arc<Database>, Reconciler, MockTenement, MockTenement) {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Arc::new(Database::open(&path).await.unwrap());
+        let provisioner = Arc::new(Provisioner::new(db.clone()));
+        let (m1, a1) = MockTenement::start().await;
+        let (m2, a2) = MockTenement::start().await;
+
+        db.add_server("server-1", &a1).await.unwrap();
+        db.add_server("server-2", &a2).await.unwrap();
+        db.add_tenant("romneys", Some("server-1"), None).await.unwrap();
+        db.add_tenant("smiths", Some("server-2"), None).await.unwrap();
+        provisioner.sync_all().await.unwrap();
+
+        (db.clone(), Reconciler::new(db, provisioner), m1, m2)
+    }
+
+    #[tokio::test]
+    async fn test_no_drift() {
+        let (_db, reconciler, _m1, _m2) = setup().await;
+
+        let report = reconciler.check().await.unwrap();
+        assert_eq!(report.servers_checked, 2);
+        assert!(report.drift.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_is_a_placement() {
+        let (db, reconciler, _m1, m2) = setup().await;
+        db.set_tenant_mirror("romneys", "server-2", 10, 1024).await.unwrap();
+        reconciler.provisioner.sync_all().await.unwrap();
+        assert!(m2.has("romneys"));
+        assert!(reconciler.check().await.unwrap().drift.is_empty());
+
+        // A mirror server that lost the tenant gets it back
+        m2.tenants.lock().unwrap().remove("romneys");
+        let report = reconciler.repair().await.unwrap();
+        assert_eq!(report.drift[0].kind, DriftKind::Missing);
+        assert!(m2.has("romneys"));
+    }
+
+    #[tokio::test]
+    async fn test_detects_and_repairs_drift() {
+        let (_db, reconciler, m1, m2) = setup().await;
+
+        // server-1 was rebuilt and lost romneys; server-2 has a leftover and
+        // a copy of romneys
+        m1.tenants.lock().unwrap().clear();
+        {
+            let mut remote = m2.tenants.lock().unwrap();
+            remote.insert("jones".into(), serde_json::Value::Null);
+            remote.insert("romneys".into(), serde_json::Value::Null);
+        }
+
+        let report = reconciler.check().await.unwrap();
+        let drift: Vec<_> = report
+            .drift
+            .iter()
+            .map(|d| (d.kind, d.tenant_id.as_str(), d.server_name.as_str()))
+            .collect();
+        assert_eq!(
+            drift,
+            vec![
+                (DriftKind::Missing, "romneys", "server-1"),
+                (DriftKind::Orphaned, "jones", "server-2"),
+                (DriftKind::Misplaced, "romneys", "server-2"),
+            ]
+        );
+
+        let report = reconciler.repair().await.unwrap();
+        assert_eq!(report.repaired, 3);
+        assert!(m1.has("romneys"));
+        assert!(!m2.has("romneys"));
+        assert!(!m2.has("jones"));
+        assert!(m2.has("smiths"));
+
+        assert!(reconciler.check().await.unwrap().drift.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_server() {
+        let (db, reconciler, _m1, _m2) = setup().await;
+        db.add_server("server-3", "127.0.0.1:1").await.unwrap();
+
+        let report = reconciler.check().await.unwrap();
+        assert_eq!(report.servers_checked, 2);
+        assert_eq!(report.unreachable.len(), 1);
+        assert_eq!(report.unreachable[0].server_name, "server-3");
+    }
+}
@@ -0,0 +1,127 @@
+//! `X-Request-ID` handling
+//!
+//! Every request gets an ID, either the client's or a new UUID. It is set on
+//! the request before routing, so the proxy forwards it upstream, and on
+//! every response, including slum's own errors.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use tracing::Span;
+
+pub const HEADER: &str = "x-request-id";
+
+/// The ID of the current request, in the request's extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Accept client IDs that are safe to log and forward
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+pub async fn middleware(mut req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let value = HeaderValue::from_str(&id).expect("request IDs are visible ASCII");
+
+    req.headers_mut().insert(HEADER, value.clone());
+    req.extensions_mut().insert(RequestId(id));
+
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(HEADER, value);
+    response
+}
+
+/// Span for `TraceLayer`. The proxy fills in `tenant` and `server` once the
+/// request is routed.
+pub fn make_span(req: &Request<Body>) -> Span {
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.as_str())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        request_id,
+        tenant = tracing::field::Empty,
+        server = tracing::field::Empty,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, routing::get, Router};
+    use http_body_util::BodyExt;
+    use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+
+    /// Serve a test app and return its address
+    async fn start() -> String {
+        let app = Router::new()
+            .route(
+                "/echo",
+                get(|req: Request| async move {
+                    let id = req.extensions().get::<RequestId>().unwrap().0.clone();
+                    assert_eq!(req.headers()[HEADER], id.as_str());
+                    id
+                }),
+            )
+            .fallback(|| async { StatusCode::NOT_FOUND })
+            .layer(axum::middleware::from_fn(middleware));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    async fn send(id: Option<&str>, path: &str) -> (StatusCode, String, String) {
+        let addr = start().await;
+        let mut req = Request::builder().uri(format!("http://{}{}", addr, path));
+        if let Some(id) = id {
+            req = req.header(HEADER, id);
+        }
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let response = client.request(req.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let header = response.headers()[HEADER].to_str().unwrap().to_string();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, header, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_accepts_client_id() {
+        let (_, header, body) = send(Some("abc-123"), "/echo").await;
+        assert_eq!(header, "abc-123");
+        assert_eq!(body, "abc-123");
+    }
+
+    #[tokio::test]
+    async fn test_generates_id() {
+        let (_, header, body) = send(None, "/echo").await;
+        assert!(uuid::Uuid::parse_str(&header).is_ok());
+        assert_eq!(body, header);
+
+        // Unsafe client IDs are replaced
+        let (_, header, _) = send(Some("has space"), "/echo").await;
+        assert_ne!(header, "has space");
+    }
+
+    #[tokio::test]
+    async fn test_error_responses_carry_id() {
+        let (status, header, _) = send(Some("abc-123"), "/missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(header, "abc-123");
+    }
+}
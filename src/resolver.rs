@@ -0,0 +1,460 @@
+//! Tenant resolution
+//!
+//! The proxy asks each configured resolver in turn which tenant a request
+//! is for, and the first to find one wins: the host's subdomain, a path
+//! prefix, a header, a query parameter, or a claim in a verified bearer
+//! token. Without configuration, requests are resolved by path (when a
+//! prefix is set) and then by subdomain.
+
+use anyhow::{anyhow, bail, Result};
+use axum::body::Body;
+use axum::http::{header, HeaderName, Request};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use std::fmt;
+use std::str::FromStr;
+
+/// A resolver as given to `--resolve`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolverSpec {
+    Subdomain,
+    Path,
+    Header(String),
+    Query(String),
+    Jwt(String),
+}
+
+impl FromStr for ResolverSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) if !arg.is_empty() => (kind, Some(arg.to_string())),
+            Some(_) => return Err(format!("missing name after ':' in {}", s)),
+            None => (s, None),
+        };
+        match (kind, arg) {
+            ("subdomain", None) => Ok(Self::Subdomain),
+            ("path", None) => Ok(Self::Path),
+            ("header", Some(name)) => Ok(Self::Header(name)),
+            ("query", Some(name)) => Ok(Self::Query(name)),
+            ("jwt", Some(claim)) => Ok(Self::Jwt(claim)),
+            _ => Err(format!(
+                "expected subdomain, path, header:NAME, query:NAME or jwt:CLAIM, got: {}",
+                s
+            )),
+        }
+    }
+}
+
+/// Routing by path, `<prefix>/<tenant>/...`, for deployments without
+/// wildcard DNS
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathRouting {
+    /// Such as `/t`, without a trailing slash; empty for the root
+    prefix: String,
+    /// Forward only the path after the tenant, rewriting `Location`
+    /// headers in responses to match
+    strip: bool,
+}
+
+impl PathRouting {
+    pub fn new(prefix: &str, strip: bool) -> Result<Self> {
+        if !prefix.starts_with('/') || prefix.contains(['?', '#']) {
+            bail!("Path prefix must be a path like /t, got: {}", prefix);
+        }
+        Ok(Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            strip,
+        })
+    }
+
+    /// The tenant a path is for, and the tenant's mount point, such as
+    /// `/t/romneys`
+    fn extract<'a>(&self, path: &'a str) -> Option<(&'a str, &'a str)> {
+        let rest = path.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        let tenant = rest.split('/').next().unwrap_or(rest);
+        if tenant.is_empty() {
+            return None;
+        }
+        Some((tenant, &path[..self.prefix.len() + 1 + tenant.len()]))
+    }
+}
+
+/// The key bearer tokens are verified with
+pub struct JwtKey {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtKey {
+    /// A shared secret, for the HS algorithms
+    pub fn secret(secret: &[u8], algorithm: Algorithm) -> Result<Self> {
+        if !matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            bail!("A JWT secret needs an HS algorithm, got {:?}", algorithm);
+        }
+        Ok(Self {
+            key: DecodingKey::from_secret(secret),
+            validation: Validation::new(algorithm),
+        })
+    }
+
+    /// A PEM public key, for the RS, PS, ES and EdDSA algorithms
+    pub fn public_key(pem: &[u8], algorithm: Algorithm) -> Result<Self> {
+        let key = match algorithm {
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => DecodingKey::from_rsa_pem(pem)?,
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem)?,
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(pem)?,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                bail!("{:?} needs a JWT secret, not a public key", algorithm)
+            }
+        };
+        Ok(Self {
+            key,
+            validation: Validation::new(algorithm),
+        })
+    }
+
+    /// A string claim from a token, if its signature and expiry check out
+    fn claim(&self, token: &str, claim: &str) -> Option<String> {
+        let data = jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+            token,
+            &self.key,
+            &self.validation,
+        );
+        match data {
+            Ok(data) => Some(data.claims.get(claim)?.as_str()?.to_string()),
+            Err(e) => {
+                tracing::debug!("Ignoring bearer token: {}", e);
+                None
+            }
+        }
+    }
+}
+
+enum Resolver {
+    Subdomain,
+    Path(PathRouting),
+    Header(HeaderName),
+    Query(String),
+    Jwt(String),
+}
+
+/// Which tenant a request is for, and how it was found
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolved {
+    /// The host's first label. The host may also be a domain alias.
+    Subdomain(String),
+    /// A path segment under the prefix, mounted at `mount`, such as
+    /// `/t/romneys`
+    Path {
+        tenant: String,
+        mount: String,
+        strip: bool,
+    },
+    /// A header, query parameter or token claim
+    Named(String),
+}
+
+impl Resolved {
+    pub fn tenant_id(&self) -> &str {
+        match self {
+            Resolved::Subdomain(id) | Resolved::Named(id) => id,
+            Resolved::Path { tenant, .. } => tenant,
+        }
+    }
+}
+
+/// Resolvers in priority order
+pub struct Resolvers {
+    resolvers: Vec<Resolver>,
+    jwt: Option<JwtKey>,
+}
+
+impl Default for Resolvers {
+    fn default() -> Self {
+        Self {
+            resolvers: vec![Resolver::Subdomain],
+            jwt: None,
+        }
+    }
+}
+
+impl Resolvers {
+    /// Resolvers for `specs`, or path and subdomain resolution if there are
+    /// none. `path` is required for path resolution and `jwt` for tokens.
+    pub fn new(specs: &[ResolverSpec], path: Option<PathRouting>, jwt: Option<JwtKey>) -> Result<Self> {
+        let default = [ResolverSpec::Path, ResolverSpec::Subdomain];
+        let specs = match specs {
+            [] if path.is_some() => &default[..],
+            [] => &default[1..],
+            specs => specs,
+        };
+        let mut resolvers = Vec::new();
+        for spec in specs {
+            resolvers.push(match spec {
+                ResolverSpec::Subdomain => Resolver::Subdomain,
+                ResolverSpec::Path => Resolver::Path(
+                    path.clone()
+                        .ok_or_else(|| anyhow!("Path resolution needs --path-prefix"))?,
+                ),
+                ResolverSpec::Header(name) => Resolver::Header(
+                    HeaderName::try_from(name.as_str())
+                        .map_err(|_| anyhow!("Invalid header name: {}", name))?,
+                ),
+                ResolverSpec::Query(name) => Resolver::Query(name.clone()),
+                ResolverSpec::Jwt(claim) => {
+                    if jwt.is_none() {
+                        bail!("JWT resolution needs --jwt-secret or --jwt-public-key");
+                    }
+                    Resolver::Jwt(claim.clone())
+                }
+            });
+        }
+        Ok(Self { resolvers, jwt })
+    }
+
+    /// The tenant a request is for, from the first resolver that finds one
+    pub fn resolve(&self, host: &str, req: &Request<Body>) -> Option<Resolved> {
+        self.resolvers.iter().find_map(|resolver| match resolver {
+            Resolver::Subdomain => extract_tenant_from_host(host).map(Resolved::Subdomain),
+            Resolver::Path(routing) => {
+                let (tenant, mount) = routing.extract(req.uri().path())?;
+                Some(Resolved::Path {
+                    tenant: tenant.to_string(),
+                    mount: mount.to_string(),
+                    strip: routing.strip,
+                })
+            }
+            Resolver::Header(name) => {
+                let value = req.headers().get(name)?.to_str().ok()?.trim();
+                (!value.is_empty()).then(|| Resolved::Named(value.to_string()))
+            }
+            Resolver::Query(name) => req
+                .uri()
+                .query()?
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, value)| key == name && !value.is_empty())
+                .map(|(_, value)| Resolved::Named(value.to_string())),
+            Resolver::Jwt(claim) => {
+                let token = req
+                    .headers()
+                    .get(header::AUTHORIZATION)?
+                    .to_str()
+                    .ok()?
+                    .strip_prefix("Bearer ")?;
+                let tenant = self.jwt.as_ref()?.claim(token.trim(), claim)?;
+                (!tenant.is_empty()).then_some(Resolved::Named(tenant))
+            }
+        })
+    }
+}
+
+/// How to name a tenant, for requests that don't
+impl fmt::Display for Resolvers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ways: Vec<String> = self
+            .resolvers
+            .iter()
+            .map(|resolver| match resolver {
+                Resolver::Subdomain => "subdomain like: tenant.yourdomain.com".to_string(),
+                Resolver::Path(routing) => format!("path like: {}/tenant/", routing.prefix),
+                Resolver::Header(name) => format!("header: {}", name),
+                Resolver::Query(name) => format!("query parameter like: ?{}=tenant", name),
+                Resolver::Jwt(claim) => format!("bearer token with claim: {}", claim),
+            })
+            .collect();
+        write!(f, "No tenant specified. Use {}", ways.join(", or "))
+    }
+}
+
+/// Extract tenant ID from Host header
+/// Examples:
+///   romneys.ourfam.lol -> romneys
+///   smiths.example.com -> smiths
+///   localhost:8080 -> None (no subdomain)
+fn extract_tenant_from_host(host: &str) -> Option<String> {
+    // Remove port if present
+    let host = host.split(':').next().unwrap_or(host);
+
+    // Split by dots
+    let parts: Vec<&str> = host.split('.').collect();
+
+    // Need at least 3 parts for a subdomain (tenant.domain.tld)
+    // Or 2 parts if it's tenant.localhost
+    if parts.len() >= 3 || (parts.len() == 2 && parts[1] == "localhost") {
+        Some(parts[0].to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn token(key: &EncodingKey, algorithm: Algorithm, claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(&Header::new(algorithm), &claims, key).unwrap()
+    }
+
+    fn in_an_hour() -> i64 {
+        chrono::Utc::now().timestamp() + 3600
+    }
+
+    #[test]
+    fn test_extract_tenant() {
+        assert_eq!(
+            extract_tenant_from_host("romneys.ourfam.lol"),
+            Some("romneys".to_string())
+        );
+        assert_eq!(
+            extract_tenant_from_host("smiths.example.com"),
+            Some("smiths".to_string())
+        );
+        assert_eq!(
+            extract_tenant_from_host("romneys.ourfam.lol:8080"),
+            Some("romneys".to_string())
+        );
+        assert_eq!(
+            extract_tenant_from_host("romneys.localhost"),
+            Some("romneys".to_string())
+        );
+        assert_eq!(extract_tenant_from_host("localhost:8080"), None);
+        assert_eq!(extract_tenant_from_host("ourfam.lol"), None);
+    }
+
+    #[test]
+    fn test_path_routing() {
+        let routing = PathRouting::new("/t/", true).unwrap();
+        assert_eq!(routing.extract("/t/romneys/photos"), Some(("romneys", "/t/romneys")));
+        assert_eq!(routing.extract("/t/romneys"), Some(("romneys", "/t/romneys")));
+        assert_eq!(routing.extract("/t/"), None);
+        assert_eq!(routing.extract("/tx/romneys"), None);
+        assert_eq!(routing.extract("/photos"), None);
+        let root = PathRouting::new("/", true).unwrap();
+        assert_eq!(root.extract("/romneys/photos"), Some(("romneys", "/romneys")));
+        assert!(PathRouting::new("t", true).is_err());
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!("subdomain".parse(), Ok(ResolverSpec::Subdomain));
+        assert_eq!(
+            "header:X-Tenant".parse(),
+            Ok(ResolverSpec::Header("X-Tenant".to_string()))
+        );
+        assert_eq!("jwt:tid".parse(), Ok(ResolverSpec::Jwt("tid".to_string())));
+        assert!("header".parse::<ResolverSpec>().is_err());
+        assert!("query:".parse::<ResolverSpec>().is_err());
+        assert!("cookie:tenant".parse::<ResolverSpec>().is_err());
+    }
+
+    #[test]
+    fn test_priority() {
+        let secret = b"shh";
+        let jwt = JwtKey::secret(secret, Algorithm::HS256).unwrap();
+        let specs = [
+            ResolverSpec::Header("x-tenant".to_string()),
+            ResolverSpec::Jwt("tenant".to_string()),
+            ResolverSpec::Query("tenant".to_string()),
+            ResolverSpec::Subdomain,
+        ];
+        let resolvers = Resolvers::new(&specs, None, Some(jwt)).unwrap();
+        let resolve = |uri: &str, headers: &[(&str, &str)]| {
+            resolvers
+                .resolve("api.ourfam.lol", &request(uri, headers))
+                .map(|r| r.tenant_id().to_string())
+        };
+        let key = EncodingKey::from_secret(secret);
+        let valid = format!(
+            "Bearer {}",
+            token(&key, Algorithm::HS256, serde_json::json!({"tenant": "smiths", "exp": in_an_hour()}))
+        );
+        let forged = format!(
+            "Bearer {}",
+            token(
+                &EncodingKey::from_secret(b"guess"),
+                Algorithm::HS256,
+                serde_json::json!({"tenant": "smiths", "exp": in_an_hour()})
+            )
+        );
+
+        assert_eq!(
+            resolve("/?tenant=jones", &[("x-tenant", "romneys"), ("authorization", &valid)]).as_deref(),
+            Some("romneys")
+        );
+        assert_eq!(
+            resolve("/?tenant=jones", &[("authorization", &valid)]).as_deref(),
+            Some("smiths")
+        );
+        // Unverified tokens are ignored
+        assert_eq!(
+            resolve("/?a=b&tenant=jones", &[("authorization", &forged)]).as_deref(),
+            Some("jones")
+        );
+        assert_eq!(resolve("/", &[]).as_deref(), Some("api"));
+
+        assert_eq!(
+            resolvers.to_string(),
+            "No tenant specified. Use header: x-tenant, or bearer token with claim: tenant, \
+             or query parameter like: ?tenant=tenant, or subdomain like: tenant.yourdomain.com"
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        let resolvers = Resolvers::new(&[], None, None).unwrap();
+        let req = request("/t/romneys/", &[]);
+        assert_eq!(
+            resolvers.resolve("smiths.ourfam.lol", &req),
+            Some(Resolved::Subdomain("smiths".to_string()))
+        );
+
+        let path = PathRouting::new("/t", true).unwrap();
+        let resolvers = Resolvers::new(&[], Some(path.clone()), None).unwrap();
+        assert_eq!(
+            resolvers.resolve("smiths.ourfam.lol", &req),
+            Some(Resolved::Path {
+                tenant: "romneys".to_string(),
+                mount: "/t/romneys".to_string(),
+                strip: true,
+            })
+        );
+
+        assert!(Resolvers::new(&[ResolverSpec::Path], None, None).is_err());
+        assert!(Resolvers::new(&[ResolverSpec::Jwt("tenant".to_string())], None, None).is_err());
+        assert!(Resolvers::new(&[ResolverSpec::Header("bad header".to_string())], None, None).is_err());
+    }
+
+    #[test]
+    fn test_jwt_public_key() {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let jwt = JwtKey::public_key(key_pair.public_key_pem().as_bytes(), Algorithm::ES256).unwrap();
+        let signing = EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).unwrap();
+
+        let valid = token(&signing, Algorithm::ES256, serde_json::json!({"tid": "romneys", "exp": in_an_hour()}));
+        assert_eq!(jwt.claim(&valid, "tid").as_deref(), Some("romneys"));
+        assert_eq!(jwt.claim(&valid, "sub"), None);
+        let expired = token(&signing, Algorithm::ES256, serde_json::json!({"tid": "romneys", "exp": 1}));
+        assert_eq!(jwt.claim(&expired, "tid"), None);
+        let numeric = token(&signing, Algorithm::ES256, serde_json::json!({"tid": 7, "exp": in_an_hour()}));
+        assert_eq!(jwt.claim(&numeric, "tid"), None);
+
+        assert!(JwtKey::public_key(b"not a key", Algorithm::ES256).is_err());
+        assert!(JwtKey::secret(b"shh", Algorithm::RS256).is_err());
+    }
+}
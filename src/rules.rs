@@ -0,0 +1,184 @@
+//! Route rules
+//!
+//! Tenants can have requests redirected or their paths rewritten before
+//! they are forwarded, and headers changed on the way back, without
+//! touching tenement. Rules run in order, fleet rules first; a redirect
+//! ends the request, and a rewrite changes the path later rules see.
+
+use axum::body::Body;
+use axum::http::{header, HeaderName, HeaderValue, Request, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+
+use crate::db::RouteRule;
+use crate::server::Tls;
+
+/// Apply a tenant's rules to a request, returning the redirect that
+/// answers it, if any
+pub fn route(rules: &[RouteRule], req: &mut Request<Body>, host: &str) -> Option<Response> {
+    for rule in rules {
+        match rule {
+            RouteRule::Redirect { from, to, status } => {
+                let Some(rest) = matches(from, req.uri().path()) else {
+                    continue;
+                };
+                let mut location = format!("{}{}", to.trim_end_matches('/'), rest);
+                if location.is_empty() {
+                    location.push('/');
+                }
+                if let (Some(query), false) = (req.uri().query(), to.contains('?')) {
+                    location = format!("{}?{}", location, query);
+                }
+                let status = StatusCode::from_u16(*status).unwrap_or(StatusCode::FOUND);
+                return Some(redirect(status, &location));
+            }
+            RouteRule::Rewrite { from, to } => {
+                let path = req.uri().path();
+                let Some(rest) = path.strip_prefix(from.trim_end_matches('/')) else {
+                    continue;
+                };
+                if !(rest.is_empty() || rest.starts_with('/')) {
+                    continue;
+                }
+                let mut path = format!("{}{}", to.trim_end_matches('/'), rest);
+                if path.is_empty() {
+                    path.push('/');
+                }
+                let path_and_query = match req.uri().query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path,
+                };
+                if let Ok(uri) = path_and_query.parse::<Uri>() {
+                    *req.uri_mut() = uri;
+                }
+            }
+            RouteRule::ForceHttps if !is_https(req) => {
+                let host = host.split(':').next().unwrap_or(host);
+                let path_and_query = req.uri().path_and_query().map_or("/", |p| p.as_str());
+                let location = format!("https://{}{}", host, path_and_query);
+                return Some(redirect(StatusCode::PERMANENT_REDIRECT, &location));
+            }
+            RouteRule::ForceHttps | RouteRule::SetHeader { .. } | RouteRule::RemoveHeader { .. } => {}
+        }
+    }
+    None
+}
+
+/// Apply a tenant's header rules to a response
+pub fn respond(rules: &[RouteRule], response: &mut Response) {
+    for rule in rules {
+        match rule {
+            RouteRule::SetHeader { name, value } => {
+                if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::from_str(value)) {
+                    response.headers_mut().insert(name, value);
+                }
+            }
+            RouteRule::RemoveHeader { name } => {
+                response.headers_mut().remove(name);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What follows `pattern` in `path`: `""` for an exact match, or for a
+/// pattern ending in `/*`, the path under it
+fn matches<'a>(pattern: &str, path: &'a str) -> Option<&'a str> {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => {
+            let rest = path.strip_prefix(prefix)?;
+            (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+        }
+        None => (path == pattern).then_some(""),
+    }
+}
+
+/// Whether the client connected over HTTPS, to slum or to a proxy in front
+fn is_https(req: &Request<Body>) -> bool {
+    req.extensions().get::<Tls>().is_some()
+        || req
+            .headers()
+            .get("x-forwarded-proto")
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"https"))
+}
+
+fn redirect(status: StatusCode, location: &str) -> Response {
+    match HeaderValue::from_str(location) {
+        Ok(location) => (status, [(header::LOCATION, location)]).into_response(),
+        Err(_) => (StatusCode::BAD_REQUEST, "Invalid redirect").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> RouteRule {
+        RouteRule::parse(&s.split(' ').map(String::from).collect::<Vec<_>>()).unwrap()
+    }
+
+    /// Where a request is redirected, or the path it is forwarded with
+    fn run(rules: &[RouteRule], uri: &str, headers: &[(&str, &str)]) -> String {
+        let mut req = Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        match route(rules, &mut req, "romneys.ourfam.lol:8080") {
+            Some(response) => format!(
+                "{} {}",
+                response.status().as_u16(),
+                response.headers()[header::LOCATION].to_str().unwrap()
+            ),
+            None => req.uri().to_string(),
+        }
+    }
+
+    #[test]
+    fn test_redirects() {
+        let rules = [rule("redirect /old /new 301"), rule("redirect /docs/* https://docs.ourfam.lol/")];
+        assert_eq!(run(&rules, "/old?a=1", &[]), "301 /new?a=1");
+        assert_eq!(run(&rules, "/old/x", &[]), "/old/x");
+        assert_eq!(run(&rules, "/docs", &[]), "302 https://docs.ourfam.lol");
+        assert_eq!(run(&rules, "/docs/a/b", &[]), "302 https://docs.ourfam.lol/a/b");
+        assert_eq!(run(&rules, "/docsx", &[]), "/docsx");
+    }
+
+    #[test]
+    fn test_rewrites_in_order() {
+        let rules = [
+            rule("rewrite /api /v2"),
+            rule("redirect /v2/gone /"),
+            rule("rewrite /v2/legacy /"),
+        ];
+        assert_eq!(run(&rules, "/api/events?page=2", &[]), "/v2/events?page=2");
+        assert_eq!(run(&rules, "/api", &[]), "/v2");
+        assert_eq!(run(&rules, "/apis", &[]), "/apis");
+        assert_eq!(run(&rules, "/api/gone", &[]), "302 /");
+        assert_eq!(run(&rules, "/api/legacy/x", &[]), "/x");
+        assert_eq!(run(&rules, "/api/legacy", &[]), "/");
+    }
+
+    #[test]
+    fn test_force_https() {
+        let rules = [rule("force-https")];
+        assert_eq!(run(&rules, "/a?b=c", &[]), "308 https://romneys.ourfam.lol/a?b=c");
+        assert_eq!(run(&rules, "/a", &[("x-forwarded-proto", "https")]), "/a");
+        let mut req = Request::builder().uri("/a").body(Body::empty()).unwrap();
+        req.extensions_mut().insert(Tls);
+        assert!(route(&rules, &mut req, "romneys.ourfam.lol").is_none());
+    }
+
+    #[test]
+    fn test_headers() {
+        let rules = [
+            rule("set-header X-Frame-Options DENY"),
+            rule("remove-header Server"),
+            rule("set-header Server slum"),
+        ];
+        let mut response = ([(header::SERVER, "tenement"), (header::ETAG, "1")], "ok").into_response();
+        respond(&rules, &mut response);
+        assert_eq!(response.headers()["x-frame-options"], "DENY");
+        assert_eq!(response.headers()[header::SERVER], "slum");
+        assert_eq!(response.headers()[header::ETAG], "1");
+    }
+}
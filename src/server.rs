@@ -0,0 +1,253 @@
+//! HTTP listener
+//!
+//! `axum::serve` doesn't expose hyper's connection settings, so slum runs its
+//! own accept loop to bound how long a client may take to send headers.
+//! Connections speak HTTP/1.1 or HTTP/2: over TLS as negotiated by ALPN,
+//! and in cleartext when the client opens with the HTTP/2 preface (h2c with
+//! prior knowledge).
+
+use anyhow::{anyhow, Context, Result};
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, service::service_fn, Request};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{self, pki_types::PrivateKeyDer, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+
+/// Marks requests that arrived over TLS
+#[derive(Debug, Clone, Copy)]
+pub struct Tls;
+
+/// A TLS acceptor for a PEM certificate chain and private key, offering
+/// HTTP/2 and HTTP/1.1 through ALPN
+pub fn tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(
+        std::fs::File::open(cert_path)
+            .with_context(|| format!("Failed to open {}", cert_path.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("Failed to read certificates from {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates in {}", cert_path.display()));
+    }
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut std::io::BufReader::new(
+        std::fs::File::open(key_path)
+            .with_context(|| format!("Failed to open {}", key_path.display()))?,
+    ))
+    .with_context(|| format!("Failed to read private key from {}", key_path.display()))?
+    .ok_or_else(|| anyhow!("No private key in {}", key_path.display()))?;
+
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serve `app` forever. Connections that don't deliver a request's headers
+/// within `header_read_timeout` are closed, as are TLS connections that
+/// don't finish the handshake in that time.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    header_read_timeout: Option<Duration>,
+    tls: Option<TlsAcceptor>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Usually out of file descriptors; back off instead of spinning
+                tracing::warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let Some(tls) = tls else {
+                return serve_connection(stream, addr, app, header_read_timeout, false).await;
+            };
+            let handshake = tls.accept(stream);
+            let stream = match header_read_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, handshake).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        tracing::debug!("Closed connection from {}: TLS handshake timeout", addr);
+                        return;
+                    }
+                },
+                None => handshake.await,
+            };
+            match stream {
+                Ok(stream) => serve_connection(stream, addr, app, header_read_timeout, true).await,
+                Err(e) => tracing::debug!("TLS handshake with {} failed: {}", addr, e),
+            }
+        });
+    }
+}
+
+async fn serve_connection<I>(
+    io: I,
+    addr: SocketAddr,
+    app: Router,
+    header_read_timeout: Option<Duration>,
+    tls: bool,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(ConnectInfo(addr));
+        if tls {
+            req.extensions_mut().insert(Tls);
+        }
+        app.clone().call(req)
+    });
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(header_read_timeout);
+    builder.http2().timer(TokioTimer::new());
+    if let Err(e) = builder
+        .serve_connection_with_upgrades(TokioIo::new(io), service)
+        .await
+    {
+        if e.downcast_ref::<hyper::Error>().is_some_and(|e| e.is_timeout()) {
+            tracing::debug!("Closed connection from {}: header read timeout", addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::get;
+    use http_body_util::BodyExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// The HTTP version a request arrived with
+    fn version_app() -> Router {
+        Router::new().route("/", get(|req: Request<Body>| async move { format!("{:?}", req.version()) }))
+    }
+
+    #[tokio::test]
+    async fn test_header_read_timeout() {
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, app, Some(Duration::from_millis(200)), None));
+
+        // A complete request is served
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: x\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("ok"));
+
+        // A client that stalls mid-headers is disconnected
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nhost: x\r\n").await.unwrap();
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut buf))
+            .await
+            .expect("connection was not closed");
+        assert!(read.is_ok());
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_h2c_prior_knowledge() {
+        use hyper_util::client::legacy::Client;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, version_app(), None, None));
+
+        let client = Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build_http::<Body>();
+        let response = client
+            .get(format!("http://{}/", addr).parse().unwrap())
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "HTTP/2.0");
+    }
+
+    #[tokio::test]
+    async fn test_tls_alpn() {
+        use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+        use tokio_rustls::TlsConnector;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let acceptor = tls_acceptor(&cert_path, &key_path).unwrap();
+        assert!(tls_acceptor(&key_path, &key_path).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, version_app(), None, Some(acceptor)));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        for (alpn, version) in [("h2", "HTTP/2.0"), ("http/1.1", "HTTP/1.1")] {
+            let mut config = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+            config.alpn_protocols = vec![alpn.as_bytes().to_vec()];
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let stream = TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(alpn.as_bytes()));
+
+            let req = Request::builder()
+                .uri("https://localhost/")
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap();
+            let response = if alpn == "h2" {
+                let (mut sender, conn) =
+                    hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                        .await
+                        .unwrap();
+                tokio::spawn(conn);
+                sender.send_request(req).await.unwrap()
+            } else {
+                let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                    .await
+                    .unwrap();
+                tokio::spawn(conn);
+                sender.send_request(req).await.unwrap()
+            };
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, version);
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! OpenTelemetry tracing
+//!
+//! Each proxied request and management API call becomes a server span,
+//! exported in batches to an OTLP/HTTP collector using the JSON encoding
+//! (`POST {endpoint}/v1/traces`). Incoming W3C `traceparent`/`tracestate`
+//! headers make the span a child of the caller's, and the proxy injects the
+//! span's context into the request it sends upstream.
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use crate::proxy::RouteInfo;
+use crate::request_id::RequestId;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// Spans buffered for export before new ones are dropped
+const QUEUE_SIZE: usize = 4096;
+
+/// Most spans sent in one export request
+const BATCH_SIZE: usize = 512;
+
+/// How often buffered spans are exported
+const EXPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The W3C trace context of a span
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` header (`00-<trace id>-<span id>-<flags>`)
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        let [version, trace_id, span_id, flags] = parts[..] else {
+            return None;
+        };
+        if version.len() != 2 || version == "ff" || flags.len() != 2 {
+            return None;
+        }
+        let trace_id: [u8; 16] = hex::decode(trace_id).ok()?.try_into().ok()?;
+        let span_id: [u8; 8] = hex::decode(span_id).ok()?.try_into().ok()?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+            tracestate: tracestate.map(String::from),
+        })
+    }
+
+    /// A new span, in the same trace as `parent` if given
+    fn child_of(parent: Option<&TraceContext>) -> Self {
+        match parent {
+            Some(parent) => Self {
+                span_id: new_span_id(),
+                ..parent.clone()
+            },
+            None => Self {
+                trace_id: *uuid::Uuid::new_v4().as_bytes(),
+                span_id: new_span_id(),
+                sampled: true,
+                tracestate: None,
+            },
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex::encode(self.trace_id),
+            hex::encode(self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+fn new_span_id() -> [u8; 8] {
+    let mut id = [0; 8];
+    id.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..8]);
+    id
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// A finished span waiting to be exported
+struct SpanData {
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+impl SpanData {
+    fn to_otlp(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::Number(n) => json!({ "intValue": n.to_string() }),
+                    Value::String(s) => json!({ "stringValue": s }),
+                    other => json!({ "stringValue": other.to_string() }),
+                };
+                json!({ "key": key, "value": value })
+            })
+            .collect();
+        let mut span = json!({
+            "traceId": hex::encode(self.context.trace_id),
+            "spanId": hex::encode(self.context.span_id),
+            "name": self.name,
+            // SPAN_KIND_SERVER
+            "kind": 2,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": attributes,
+            // STATUS_CODE_ERROR or STATUS_CODE_UNSET
+            "status": { "code": if self.error { 2 } else { 0 } },
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = json!(hex::encode(parent));
+        }
+        if let Some(state) = &self.context.tracestate {
+            span["traceState"] = json!(state);
+        }
+        span
+    }
+}
+
+pub struct Tracer {
+    sender: mpsc::Sender<SpanData>,
+}
+
+impl Tracer {
+    /// Export to the OTLP/HTTP collector at `endpoint`, e.g. `http://localhost:4318`
+    pub fn start(endpoint: &str, service_name: &str) -> Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/');
+        if !endpoint.starts_with("http://") {
+            return Err(anyhow!("OTLP endpoint must be an http:// URL, got: {}", endpoint));
+        }
+        let url = if endpoint.ends_with("/v1/traces") {
+            endpoint.to_string()
+        } else {
+            format!("{}/v1/traces", endpoint)
+        };
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(export(url, service_name.to_string(), receiver));
+        Ok(Self { sender })
+    }
+
+    fn record(&self, span: SpanData) {
+        // Drop spans rather than slow down requests if the collector is behind
+        let _ = self.sender.try_send(span);
+    }
+}
+
+async fn export(url: String, service_name: String, mut receiver: mpsc::Receiver<SpanData>) {
+    let client: Client<HttpConnector, Full<Bytes>> =
+        Client::builder(TokioExecutor::new()).build_http();
+    let mut batch = Vec::new();
+    let mut ticker = tokio::time::interval(EXPORT_INTERVAL);
+    loop {
+        let closed = tokio::select! {
+            span = receiver.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                    false
+                }
+                None => true,
+            },
+            _ = ticker.tick() => false,
+        };
+
+        if !batch.is_empty() {
+            let spans: Vec<Value> = batch.drain(..).map(|s| s.to_otlp()).collect();
+            if let Err(e) = send(&client, &url, &service_name, spans).await {
+                tracing::warn!("Failed to export spans to {}: {}", url, e);
+            }
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+async fn send(
+    client: &Client<HttpConnector, Full<Bytes>>,
+    url: &str,
+    service_name: &str,
+    spans: Vec<Value>,
+) -> Result<()> {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service_name } }
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "slum", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }]
+        }]
+    });
+    let req = axum::http::Request::post(url)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(serde_json::to_vec(&body)?)))?;
+    let response = tokio::time::timeout(Duration::from_secs(10), client.request(req))
+        .await
+        .map_err(|_| anyhow!("timed out"))??;
+    if !response.status().is_success() {
+        return Err(anyhow!("collector returned {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Start a span for each request. The span's context is put in the
+/// request's extensions for the proxy to inject upstream.
+pub async fn middleware(
+    State(tracer): State<Option<Arc<Tracer>>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(tracer) = tracer else {
+        return next.run(req).await;
+    };
+
+    let parent = req
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|traceparent| {
+            let tracestate = req.headers().get(TRACESTATE).and_then(|v| v.to_str().ok());
+            TraceContext::parse(traceparent, tracestate)
+        });
+    let context = TraceContext::child_of(parent.as_ref());
+
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let name = match req.extensions().get::<MatchedPath>() {
+        Some(route) => format!("{} {}", method, route.as_str()),
+        None => format!("proxy {}", method),
+    };
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+    req.extensions_mut().insert(context.clone());
+
+    let start = SystemTime::now();
+    let response = next.run(req).await;
+    let status = response.status();
+
+    if context.sampled {
+        let mut attributes = vec![
+            ("http.request.method", json!(method)),
+            ("url.path", json!(path)),
+            ("http.response.status_code", json!(status.as_u16())),
+        ];
+        if let Some(id) = request_id {
+            attributes.push(("slum.request_id", json!(id)));
+        }
+        if let Some(route) = response.extensions().get::<RouteInfo>() {
+            attributes.push(("slum.tenant.id", json!(route.tenant)));
+            attributes.push(("slum.server.id", json!(route.server_id)));
+            attributes.push(("slum.server.name", json!(route.server)));
+        }
+        tracer.record(SpanData {
+            parent_span_id: parent.map(|p| p.span_id),
+            context,
+            name,
+            start,
+            end: SystemTime::now(),
+            attributes,
+            error: status.is_server_error(),
+        });
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::post, Router};
+    use hyper_util::client::legacy::Client;
+    use std::sync::Mutex;
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_traceparent() {
+        let context = TraceContext::parse(PARENT, Some("vendor=1")).unwrap();
+        assert_eq!(hex::encode(context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex::encode(context.span_id), "00f067aa0ba902b7");
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), PARENT);
+
+        let child = TraceContext::child_of(Some(&context));
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_ne!(child.span_id, context.span_id);
+        assert_eq!(child.tracestate.as_deref(), Some("vendor=1"));
+
+        assert!(TraceContext::parse("00-abc-def-01", None).is_none());
+        assert!(TraceContext::parse(
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            None
+        )
+        .is_none());
+        assert!(TraceContext::parse(&PARENT.replacen("00", "ff", 1), None).is_none());
+    }
+
+    /// Stand-in OTLP collector that keeps the spans it receives
+    async fn start_collector() -> (Arc<Mutex<Vec<Value>>>, String) {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let received = spans.clone();
+        let app = Router::new().route(
+            "/v1/traces",
+            post(move |axum::Json(body): axum::Json<Value>| async move {
+                let batch = body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default();
+                received.lock().unwrap().extend(batch);
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (spans, format!("http://{}", addr))
+    }
+
+    #[tokio::test]
+    async fn test_exports_spans_and_injects_context() {
+        let (spans, endpoint) = start_collector().await;
+        let tracer = Some(Arc::new(Tracer::start(&endpoint, "slum-test").unwrap()));
+
+        // Stands in for the proxy: report the context it would inject upstream
+        let app = Router::new()
+            .fallback(|req: Request| async move {
+                let context = req.extensions().get::<TraceContext>().unwrap().clone();
+                let mut response = Response::new(Body::from(context.traceparent()));
+                response.extensions_mut().insert(RouteInfo {
+                    tenant: "romneys".into(),
+                    server_id: "server-id-1".into(),
+                    server: "server-1".into(),
+                });
+                response
+            })
+            .layer(axum::middleware::from_fn_with_state(tracer, middleware));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let req = axum::http::Request::get(format!("http://{}/events", addr))
+            .header(TRACEPARENT, PARENT)
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(req).await.unwrap();
+        let body = http_body_util::BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let injected = TraceContext::parse(std::str::from_utf8(&body).unwrap(), None).unwrap();
+        assert_eq!(hex::encode(injected.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+
+        let mut span = None;
+        for _ in 0..50 {
+            if let Some(s) = spans.lock().unwrap().first() {
+                span = Some(s.clone());
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let span = span.expect("collector received no spans");
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["spanId"], hex::encode(injected.span_id));
+        assert_eq!(span["name"], "proxy GET");
+        let attributes = span["attributes"].as_array().unwrap();
+        assert!(attributes.contains(&json!({
+            "key": "slum.tenant.id",
+            "value": { "stringValue": "romneys" }
+        })));
+        assert!(attributes.contains(&json!({
+            "key": "slum.server.id",
+            "value": { "stringValue": "server-id-1" }
+        })));
+    }
+}
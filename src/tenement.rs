@@ -0,0 +1,205 @@
+//! Client for the tenement admin API
+//!
+//! Each tenement server exposes its admin endpoints under `/_tenement`:
+//!
+//! ```text
+//! GET    /_tenement/tenants                                      [{"id": "..."}, ...]
+//! PUT    /_tenement/tenants/:id   {"id": "...", "config": ...}   provision or update
+//! DELETE /_tenement/tenants/:id                                  deprovision
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use axum::http::{Method, Request, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Path prefix reserved for the tenement admin API. The proxy never forwards it.
+pub const ADMIN_PREFIX: &str = "/_tenement";
+
+/// Header tenement sets, to `starting`, on the 503 it answers with while a
+/// tenant's process starts
+pub const STATUS_HEADER: &str = "x-tenement-status";
+
+/// Whether a response says the tenant's process is still starting
+pub fn is_starting<B>(response: &axum::http::Response<B>) -> bool {
+    response.status() == StatusCode::SERVICE_UNAVAILABLE
+        && response
+            .headers()
+            .get(STATUS_HEADER)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"starting"))
+}
+
+/// A tenant as reported by a tenement server
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemoteTenant {
+    pub id: String,
+}
+
+#[derive(Clone)]
+pub struct TenementClient {
+    client: Client<HttpConnector, Full<Bytes>>,
+    timeout: Duration,
+}
+
+impl TenementClient {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            timeout,
+        }
+    }
+
+    /// List the tenants a server currently has
+    pub async fn list_tenants(&self, address: &str) -> Result<Vec<RemoteTenant>> {
+        let (status, body) = self
+            .send(Method::GET, address, &format!("{}/tenants", ADMIN_PREFIX), None)
+            .await?;
+        if !status.is_success() {
+            return Err(upstream_error(status, &body));
+        }
+        serde_json::from_slice(&body).context("Invalid tenant inventory from tenement")
+    }
+
+    /// Create or update a tenant on a server. Tenement treats this as idempotent.
+    pub async fn provision(&self, address: &str, tenant_id: &str, config: Option<&str>) -> Result<()> {
+        // Send JSON configs as JSON, anything else as a plain string
+        let config = config.map(|c| {
+            serde_json::from_str::<serde_json::Value>(c)
+                .unwrap_or_else(|_| serde_json::Value::String(c.to_string()))
+        });
+        let body = serde_json::json!({ "id": tenant_id, "config": config });
+
+        let (status, body) = self
+            .send(Method::PUT, address, &tenant_path(tenant_id), Some(body))
+            .await?;
+        if !status.is_success() {
+            return Err(upstream_error(status, &body));
+        }
+        Ok(())
+    }
+
+    /// Remove a tenant from a server. A tenant the server doesn't know about
+    /// is already gone, so 404 counts as success.
+    pub async fn deprovision(&self, address: &str, tenant_id: &str) -> Result<()> {
+        let (status, body) = self
+            .send(Method::DELETE, address, &tenant_path(tenant_id), None)
+            .await?;
+        if !status.is_success() && status != StatusCode::NOT_FOUND {
+            return Err(upstream_error(status, &body));
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        address: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<(StatusCode, Bytes)> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", address, path));
+        let body = match body {
+            Some(json) => {
+                req = req.header("content-type", "application/json");
+                Full::new(Bytes::from(serde_json::to_vec(&json)?))
+            }
+            None => Full::new(Bytes::new()),
+        };
+        let req = req.body(body)?;
+
+        let response = tokio::time::timeout(self.timeout, async {
+            let response = self.client.request(req).await?;
+            let status = response.status();
+            let body = response.into_body().collect().await?.to_bytes();
+            Ok::<_, anyhow::Error>((status, body))
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out after {:?} calling {}", self.timeout, address))??;
+
+        Ok(response)
+    }
+}
+
+fn tenant_path(tenant_id: &str) -> String {
+    format!("{}/tenants/{}", ADMIN_PREFIX, tenant_id)
+}
+
+fn upstream_error(status: StatusCode, body: &[u8]) -> anyhow::Error {
+    anyhow!(
+        "Tenement returned {}: {}",
+        status,
+        String::from_utf8_lossy(body).trim()
+    )
+}
+
+/// In-process stand-in for a tenement server's admin API
+#[cfg(test)]
+pub mod mock {
+    use axum::{
+        extract::{Path, State},
+        http::StatusCode,
+        routing::{get, put},
+        Json, Router,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    pub struct MockTenement {
+        /// Tenant id -> last config received
+        pub tenants: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+    }
+
+    impl MockTenement {
+        /// Serve on an ephemeral port, returning the mock and its address
+        pub async fn start() -> (Self, String) {
+            let mock = Self::default();
+            let app = Router::new()
+                .route("/_tenement/tenants", get(list))
+                .route(
+                    "/_tenement/tenants/:id",
+                    put(provision).delete(deprovision),
+                )
+                .with_state(mock.clone());
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            (mock, address)
+        }
+
+        pub fn has(&self, tenant_id: &str) -> bool {
+            self.tenants.lock().unwrap().contains_key(tenant_id)
+        }
+    }
+
+    async fn list(State(mock): State<MockTenement>) -> Json<Vec<serde_json::Value>> {
+        let mut ids: Vec<String> = mock.tenants.lock().unwrap().keys().cloned().collect();
+        ids.sort();
+        Json(ids.into_iter().map(|id| serde_json::json!({ "id": id })).collect())
+    }
+
+    async fn provision(
+        State(mock): State<MockTenement>,
+        Path(id): Path<String>,
+        Json(body): Json<serde_json::Value>,
+    ) -> StatusCode {
+        mock.tenants.lock().unwrap().insert(id, body["config"].clone());
+        StatusCode::NO_CONTENT
+    }
+
+    async fn deprovision(State(mock): State<MockTenement>, Path(id): Path<String>) -> StatusCode {
+        match mock.tenants.lock().unwrap().remove(&id) {
+            Some(_) => StatusCode::NO_CONTENT,
+            None => StatusCode::NOT_FOUND,
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Per-tenant usage rollups
+//!
+//! The proxy records each request into in-memory per-minute buckets, and a
+//! background task writes finished minutes to the `tenant_usage` table and
+//! prunes rows past the retention window. Nothing touches the database on
+//! the request path.
+
+use anyhow::{anyhow, Result};
+use axum::http::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::db::{Database, Latencies, Usage};
+
+/// How often finished minutes are written to the database
+const FLUSH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often rollups past the retention window are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Default)]
+struct Bucket {
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    status_4xx: u64,
+    status_5xx: u64,
+    latencies: Latencies,
+}
+
+impl Bucket {
+    fn add(&mut self, status: StatusCode, latency: Duration, bytes_in: u64, bytes_out: u64) {
+        self.requests += 1;
+        self.bytes_in += bytes_in;
+        self.bytes_out += bytes_out;
+        if status.is_client_error() {
+            self.status_4xx += 1;
+        } else if status.is_server_error() {
+            self.status_5xx += 1;
+        }
+
+        self.latencies.record(latency.as_secs_f64() * 1000.0);
+    }
+
+    /// Fold in a bucket for the same minute
+    fn merge(&mut self, other: Bucket) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.status_4xx += other.status_4xx;
+        self.status_5xx += other.status_5xx;
+        self.latencies.merge(&other.latencies);
+    }
+
+    fn to_usage(&self, minute: i64) -> Usage {
+        Usage {
+            start: minute,
+            requests: self.requests as i64,
+            bytes_in: self.bytes_in as i64,
+            bytes_out: self.bytes_out as i64,
+            status_4xx: self.status_4xx as i64,
+            status_5xx: self.status_5xx as i64,
+            p50_ms: self.latencies.percentile(0.50),
+            p95_ms: self.latencies.percentile(0.95),
+            latencies: self.latencies.clone(),
+        }
+    }
+}
+
+/// Start of the minute containing `now` (Unix seconds)
+fn minute_of(now: i64) -> i64 {
+    now - now.rem_euclid(60)
+}
+
+#[derive(Default)]
+pub struct UsageRecorder {
+    buckets: Mutex<HashMap<(String, i64), Bucket>>,
+}
+
+impl UsageRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a finished request. `started` is when it arrived (Unix seconds).
+    pub fn record(
+        &self,
+        tenant: &str,
+        started: i64,
+        status: StatusCode,
+        latency: Duration,
+        bytes_in: u64,
+        bytes_out: u64,
+    ) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry((tenant.to_string(), minute_of(started)))
+            .or_default()
+            .add(status, latency, bytes_in, bytes_out);
+    }
+
+    /// Write buckets for minutes before `now`'s to the database. Buckets
+    /// that fail to write are kept for the next flush.
+    pub async fn flush(&self, db: &Database, now: i64) -> Result<usize> {
+        let current = minute_of(now);
+        let finished: Vec<_> = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let keys: Vec<_> = buckets
+                .keys()
+                .filter(|(_, minute)| *minute < current)
+                .cloned()
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| buckets.remove_entry(&key))
+                .collect()
+        };
+        if finished.is_empty() {
+            return Ok(0);
+        }
+
+        let rollups: Vec<(String, Usage)> = finished
+            .iter()
+            .map(|((tenant, minute), bucket)| (tenant.clone(), bucket.to_usage(*minute)))
+            .collect();
+        if let Err(e) = db.record_usage(&rollups).await {
+            // Requests that finished since are in new buckets for the same keys
+            let mut buckets = self.buckets.lock().unwrap();
+            for (key, bucket) in finished {
+                buckets.entry(key).or_default().merge(bucket);
+            }
+            return Err(e);
+        }
+        Ok(rollups.len())
+    }
+
+    /// Flush in the background, deleting rollups older than `retention` if set
+    pub fn spawn(
+        self: Arc<Self>,
+        db: Arc<Database>,
+        retention: Option<Duration>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+            let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = flush.tick() => {
+                        let now = chrono::Utc::now().timestamp();
+                        if let Err(e) = self.flush(&db, now).await {
+                            tracing::error!("Failed to write usage rollups: {}", e);
+                        }
+                    }
+                    _ = prune.tick() => {
+                        let Some(retention) = retention else { continue };
+                        let before = chrono::Utc::now().timestamp() - retention.as_secs() as i64;
+                        match db.prune_usage(before).await {
+                            Ok(0) => {}
+                            Ok(n) => tracing::info!("Pruned {} usage rollups", n),
+                            Err(e) => tracing::error!("Failed to prune usage rollups: {}", e),
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Parse a time given as Unix seconds or RFC 3339
+pub fn parse_time(s: &str) -> Result<i64> {
+    if let Ok(secs) = s.parse::<i64>() {
+        return Ok(secs);
+    }
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map(|t| t.timestamp())
+        .map_err(|_| anyhow!("Invalid time {:?}: expected Unix seconds or RFC 3339", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_flush() {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Database::open(&path).await.unwrap();
+        let recorder = UsageRecorder::new();
+
+        for (status, ms) in [(200, 10), (200, 20), (404, 30), (502, 40)] {
+            recorder.record(
+                "romneys",
+                3605,
+                StatusCode::from_u16(status).unwrap(),
+                Duration::from_millis(ms),
+                5,
+                10,
+            );
+        }
+
+        // The current minute is still open
+        assert_eq!(recorder.flush(&db, 3630).await.unwrap(), 0);
+        assert_eq!(recorder.flush(&db, 3660).await.unwrap(), 1);
+
+        let usage = db.tenant_usage("romneys", 0, 7200, 60).await.unwrap();
+        assert_eq!(usage.len(), 1);
+        let u = &usage[0];
+        assert_eq!(u.start, 3600);
+        assert_eq!(u.requests, 4);
+        assert_eq!((u.bytes_in, u.bytes_out), (20, 40));
+        assert_eq!((u.status_4xx, u.status_5xx), (1, 1));
+        assert!((u.p50_ms - 20.0).abs() < 1.0);
+        assert!((u.p95_ms - 40.0).abs() < 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_keeps_buckets_that_fail_to_write() {
+        let path = format!("/tmp/slum-test-{}.db", uuid::Uuid::new_v4());
+        let db = Database::open(&path).await.unwrap();
+        let recorder = UsageRecorder::new();
+        recorder.record("romneys", 3605, StatusCode::OK, Duration::from_millis(10), 5, 10);
+
+        let pool = sqlx::SqlitePool::connect(&format!("sqlite:{}", path)).await.unwrap();
+        sqlx::query("DROP TABLE tenant_usage").execute(&pool).await.unwrap();
+        assert!(recorder.flush(&db, 3660).await.is_err());
+
+        // A late request for the same minute joins the kept bucket
+        recorder.record("romneys", 3659, StatusCode::OK, Duration::from_millis(20), 5, 10);
+        Database::open(&path).await.unwrap();
+        assert_eq!(recorder.flush(&db, 3660).await.unwrap(), 1);
+        let usage = db.tenant_usage("romneys", 0, 7200, 60).await.unwrap();
+        assert_eq!(usage[0].requests, 2);
+        assert_eq!(usage[0].bytes_in, 10);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("3600").unwrap(), 3600);
+        assert_eq!(parse_time("1970-01-01T01:00:00Z").unwrap(), 3600);
+        assert!(parse_time("yesterday").is_err());
+    }
+}